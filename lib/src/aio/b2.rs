@@ -16,7 +16,7 @@ use hyper_native_tls::NativeTlsClient;
 use sgdata::SGData;
 
 use super::Metadata;
-use super::{Backend, BackendThread};
+use super::{Backend, BackendThread, ListSender, WriteOutcome};
 use crate::aio;
 use crate::config;
 
@@ -190,8 +190,8 @@ impl BackendThread for B2Thread {
         path: PathBuf,
         sg: SGData,
         idempotent: bool,
-    ) -> io::Result<()> {
-        Ok(())
+    ) -> io::Result<WriteOutcome> {
+        Ok(WriteOutcome::Written)
     }
 
     fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
@@ -217,7 +217,7 @@ impl BackendThread for B2Thread {
                     .list_all_file_names(
                         &self.bucket,
                         1000,
-                        Some(&path.to_string_lossy()),
+                        Some(&aio::path_to_key(&path)),
                         None,
                         &self.client,
                     )
@@ -233,16 +233,12 @@ impl BackendThread for B2Thread {
             .drain(..)
             .map(|i| i.file_name)
             .chain(files.drain(..).map(|i| i.file_name))
-            .map(PathBuf::from)
+            .map(|key| aio::key_to_path(&key))
             .collect();
         Ok(v)
     }
 
-    fn list_recursively(
-        &mut self,
-        path: PathBuf,
-        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
-    ) {
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
         unimplemented!();
     }
 }
@@ -1,14 +1,140 @@
-use std::io;
 use std::path::PathBuf;
 use std::sync::mpsc;
+use std::{error, fmt, io};
 
+use serde::{Deserialize, Serialize};
 use sgdata::SGData;
 
+use super::{ProgressEvent, ProgressFn};
+
+/// Paths per batch sent by `BackendThread::list_recursively`, by default
+pub const DEFAULT_LIST_BATCH_SIZE: usize = 100;
+
+type PathBatch = io::Result<Vec<PathBuf>>;
+
+#[derive(Clone)]
+enum ListTx {
+    Unbounded(mpsc::Sender<PathBatch>),
+    Bounded(mpsc::SyncSender<PathBatch>),
+}
+
+/// Sending end of `BackendThread::list_recursively`
+///
+/// Backends send the paths in batches of up to `batch_size`. Sending to a
+/// bounded one blocks while the receiver is behind, so a slow receiver
+/// pauses the listing instead of letting the batches pile up.
+#[derive(Clone)]
+pub struct ListSender {
+    tx: ListTx,
+    batch_size: usize,
+    /// Told about every batch sent
+    progress: Option<ProgressFn>,
+}
+
+impl ListSender {
+    /// Channel buffering any number of batches
+    pub fn channel(batch_size: usize) -> (Self, mpsc::Receiver<PathBatch>) {
+        assert!(batch_size > 0);
+        let (tx, rx) = mpsc::channel();
+        let tx = ListSender {
+            tx: ListTx::Unbounded(tx),
+            batch_size,
+            progress: None,
+        };
+        (tx, rx)
+    }
+
+    /// Channel buffering up to `capacity` batches
+    pub fn sync_channel(
+        capacity: usize,
+        batch_size: usize,
+    ) -> (Self, mpsc::Receiver<PathBatch>) {
+        assert!(batch_size > 0);
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        let tx = ListSender {
+            tx: ListTx::Bounded(tx),
+            batch_size,
+            progress: None,
+        };
+        (tx, rx)
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    pub(crate) fn with_progress(self, progress: Option<ProgressFn>) -> Self {
+        ListSender { progress, ..self }
+    }
+
+    pub fn send(
+        &self,
+        batch: PathBatch,
+    ) -> Result<(), mpsc::SendError<PathBatch>> {
+        if let (Some(progress), Ok(paths)) = (&self.progress, &batch) {
+            progress(ProgressEvent::ListBatch { count: paths.len() });
+        }
+        match self.tx {
+            ListTx::Unbounded(ref tx) => tx.send(batch),
+            ListTx::Bounded(ref tx) => tx.send(batch),
+        }
+    }
+}
+
+/// Space of the storage a backend keeps objects in, in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FsStats {
+    pub total: u64,
+    /// Space that can still be written to, which can be less than the
+    /// free space (e.g. with space reserved for root)
+    pub available: u64,
+}
+
 /// A lock held on the backend
 ///
 /// It doesn't do much, except unlock on `drop`.
 pub trait Lock {}
 
+/// Process that took a lock, as recorded by the backend
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub pid: u32,
+    /// Host the process runs on, if known
+    pub hostname: Option<String>,
+    /// When the lock was taken
+    pub time: chrono::DateTime<chrono::Utc>,
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "process {} on {} since {}",
+            self.pid,
+            self.hostname.as_deref().unwrap_or("unknown host"),
+            self.time.to_rfc3339()
+        )
+    }
+}
+
+/// Inner error of a lock that timed out (see `AsyncIO::lock_exclusive_timeout`)
+#[derive(Debug)]
+pub struct Locked {
+    /// Who holds the lock, if the backend knows
+    pub owner: Option<LockInfo>,
+}
+
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.owner {
+            Some(ref owner) => write!(f, "repository locked by {}", owner),
+            None => write!(f, "repository locked"),
+        }
+    }
+}
+
+impl error::Error for Locked {}
+
 /// Backend API
 ///
 /// Backend is thread-safe, and the actual work
@@ -25,8 +151,124 @@ pub trait Backend: Send + Sync {
     /// Use to protect operations that only add new data, like `write`.
     fn lock_shared(&self) -> io::Result<Box<dyn Lock>>;
 
+    /// Like `lock_exclusive`, but `None` instead of waiting for the lock
+    ///
+    /// The default implementation waits anyway. Backends wrapping another
+    /// one should pass it through.
+    fn try_lock_exclusive(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.lock_exclusive().map(Some)
+    }
+
+    /// Like `lock_shared`, but `None` instead of waiting for the lock
+    fn try_lock_shared(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.lock_shared().map(Some)
+    }
+
+    /// Process that took the lock last, if known, without taking it
+    ///
+    /// Only meant to tell the user who to wait for (or kill), when a lock
+    /// can't be taken. The owner may have released the lock since.
+    fn lock_owner(&self) -> io::Result<Option<LockInfo>> {
+        Ok(None)
+    }
+
     /// Spawn a new thread object of the backend.
     fn new_thread(&self) -> io::Result<Box<dyn BackendThread>>;
+
+    /// Remove temporary objects left behind by writers that died
+    ///
+    /// Called when the repository is opened, so it must only remove objects
+    /// whose writer is known to be gone. Returns the number of objects
+    /// removed.
+    fn remove_orphaned_tmp(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    /// Total and available space of the storage
+    ///
+    /// Fails with `Unsupported` for backends that can't tell.
+    fn stat_fs(&self) -> io::Result<FsStats> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "backend does not report its space",
+        ))
+    }
+}
+
+/// What a successful `BackendThread::write` did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Written,
+    /// Nothing was written, as the object of an idempotent write was
+    /// already stored
+    AlreadyPresent,
+}
+
+/// How durable a write is once it completes
+///
+/// Only matters to backends storing objects as files, like `Local`.
+/// Others ignore it: their writes are as durable as the storage makes
+/// them once complete.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DurabilityMode {
+    /// Leave it to the OS when to write the data to the disk
+    None,
+    /// Sync the data of the file before putting it in place (what
+    /// `write` does)
+    FsyncFile,
+    /// Also sync the directory of the file after putting it in place
+    ///
+    /// Required for crash-consistent updates of objects that are replaced
+    /// in place, like names and configs: without it, a synced file can
+    /// still be missing, or have its old content, after a crash.
+    FsyncFileAndDir,
+}
+
+/// Modification applied as a part of `BackendThread::batch`
+pub enum BatchOp {
+    Write {
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    },
+    Remove(PathBuf),
+    Rename {
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    },
+}
+
+/// Failure of an operation of a `BackendThread::batch`
+#[derive(Debug)]
+pub struct BatchError {
+    /// Number of operations applied before the failed one
+    pub completed: usize,
+    pub error: io::Error,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "operation {} of batch failed: {}",
+            self.completed + 1,
+            self.error
+        )
+    }
+}
+
+impl error::Error for BatchError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Keeps the kind of the failed operation's error, and the `BatchError`
+/// as its inner error
+impl From<BatchError> for io::Error {
+    fn from(e: BatchError) -> io::Error {
+        io::Error::new(e.error.kind(), e)
+    }
 }
 
 pub trait BackendThread: Send {
@@ -38,23 +280,146 @@ pub trait BackendThread: Send {
         dst_path: PathBuf,
     ) -> io::Result<()>;
 
+    /// Store `sg` as the object at `path`
+    ///
+    /// Must be atomic: readers see either the object replaced, if any, or
+    /// all of `sg`, never a part of it, even if the write is interrupted.
+    /// `Local` writes to a temporary file, and renames it in place. With
+    /// `idempotent`, an object already at `path` is kept as it is.
     fn write(
         &mut self,
         path: PathBuf,
         sg: SGData,
         idempotent: bool,
-    ) -> io::Result<()>;
+    ) -> io::Result<WriteOutcome>;
+
+    /// Like `write`, but as durable as `mode` tells
+    ///
+    /// The default implementation ignores `mode`. Backends wrapping
+    /// another one should pass it through.
+    fn write_durable(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+        _mode: DurabilityMode,
+    ) -> io::Result<WriteOutcome> {
+        self.write(path, sg, idempotent)
+    }
 
     fn read(&mut self, path: PathBuf) -> io::Result<SGData>;
 
+    /// Open `path` for reading its content progressively
+    ///
+    /// The default implementation reads the whole object at once.
+    /// Backends that can avoid it should override it.
+    fn read_stream(
+        &mut self,
+        path: PathBuf,
+    ) -> io::Result<Box<dyn io::Read + Send>> {
+        let sg = self.read(path)?;
+        Ok(Box::new(io::Cursor::new(sg.to_linear_vec())))
+    }
+
     fn remove(&mut self, path: PathBuf) -> io::Result<()>;
 
     fn read_metadata(&mut self, path: PathBuf) -> io::Result<super::Metadata>;
+
+    /// Like `read_metadata`, but return `None` if `path` does not exist
+    ///
+    /// Backends that can't tell a missing object from other errors
+    /// without an extra query should override it.
+    fn stat(&mut self, path: PathBuf) -> io::Result<Option<super::Metadata>> {
+        match self.read_metadata(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            res => res.map(Some),
+        }
+    }
+
+    /// Tell if there's an object at `path`
+    ///
+    /// Backends that can tell without getting the metadata (like a
+    /// `HEAD` request) should override it.
+    fn exists(&mut self, path: PathBuf) -> io::Result<bool> {
+        self.stat(path).map(|metadata| metadata.is_some())
+    }
+
     fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>>;
 
-    fn list_recursively(
+    /// List all the objects under `path`, recursively
+    ///
+    /// Paths sent to `tx` are relative to the root of the backend, just
+    /// like the ones passed to other methods, in batches of up to
+    /// `tx.batch_size()`.
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender);
+
+    /// Copy a single object
+    ///
+    /// The default implementation reads the whole object and writes it back.
+    fn copy(&mut self, src_path: PathBuf, dst_path: PathBuf) -> io::Result<()> {
+        let sg = self.read(src_path)?;
+        self.write(dst_path, sg, false).map(|_| ())
+    }
+
+    /// Apply `ops` one after another, in order
+    ///
+    /// Stops at the first operation that fails: all the ones before it
+    /// have been applied, none of the ones after it have, and the failed
+    /// one is left as the failing method leaves it. The default
+    /// implementation calls the methods one by one; backends that can
+    /// keep the same guarantees more efficiently should override it.
+    fn batch(&mut self, ops: Vec<BatchOp>) -> Result<(), BatchError> {
+        for (completed, op) in ops.into_iter().enumerate() {
+            let res = match op {
+                BatchOp::Write {
+                    path,
+                    sg,
+                    idempotent,
+                } => self.write(path, sg, idempotent).map(|_| ()),
+                BatchOp::Remove(path) => self.remove(path),
+                BatchOp::Rename { src_path, dst_path } => {
+                    self.rename(src_path, dst_path)
+                }
+            };
+            res.map_err(|error| BatchError { completed, error })?;
+        }
+        Ok(())
+    }
+
+    /// Copy all the objects under `src_path` to `dst_path`
+    ///
+    /// The default implementation copies objects one by one. Backends that
+    /// can copy a whole sub-tree in bulk should override it.
+    fn copy_prefix(
         &mut self,
-        path: PathBuf,
-        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
-    );
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let (tx, rx) = ListSender::channel(DEFAULT_LIST_BATCH_SIZE);
+        self.list_recursively(src_path.clone(), tx);
+
+        for batch in rx {
+            let batch = match batch {
+                Ok(batch) => batch,
+                // nothing to copy
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for path in batch {
+                let rel_path = path.strip_prefix(&src_path).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "listed path {} outside of {}",
+                            path.display(),
+                            src_path.display()
+                        ),
+                    )
+                })?;
+                let dst = dst_path.join(rel_path);
+                self.copy(path, dst)?;
+            }
+        }
+        Ok(())
+    }
 }
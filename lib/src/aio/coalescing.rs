@@ -0,0 +1,818 @@
+//! Backend wrapper coalescing small objects into packs
+//!
+//! A pack is stored as two objects: its data, which is the content of
+//! the objects one after another, and its header, which has the same
+//! path with the `.idx` extension. Header layout (integers are
+//! big-endian):
+//!
+//! ```text
+//! header := MAGIC pack-len:u64 count:u64 entry*
+//! entry  := key-len:u32 key:[u8; key-len] offset:u64 len:u64
+//! ```
+//!
+//! `key` is the path of the object (see `path_to_key`), and `offset` and
+//! `len` its place in the data. A header is written after the data and
+//! removed before it, so any header found describes a complete pack.
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{io, mem};
+
+use rand::Rng;
+use sgdata::SGData;
+
+use super::{key_to_path, path_to_key};
+use super::{Backend, BackendThread, DurabilityMode, FsStats, ListSender};
+use super::{Lock, LockInfo, Metadata, WriteOutcome};
+
+/// Directory of the packs, in the root of the inner backend
+const PACKS_DIR: &str = "coalesced";
+
+/// Extension of the headers of the packs
+const HEADER_EXT: &str = "idx";
+
+const MAGIC: &[u8; 8] = b"RDEDUPCO";
+
+/// Location of an object in a pack
+#[derive(Clone)]
+struct Entry {
+    pack: PathBuf,
+    offset: usize,
+    len: usize,
+}
+
+/// Path, offset and length of an object, as stored in a header
+type PackEntry = (PathBuf, usize, usize);
+
+/// Pack read into the index
+struct Pack {
+    /// Length of the data of the pack
+    len: usize,
+    /// Objects still in the pack
+    objects: HashSet<PathBuf>,
+}
+
+enum Lookup {
+    /// Not written to a pack yet
+    Buffered(SGData),
+    Packed(Entry),
+    Missing,
+}
+
+#[derive(Default)]
+struct State {
+    /// Objects not written to a pack yet, in the order they were written
+    pending: Vec<(PathBuf, SGData)>,
+    pending_len: usize,
+    /// Objects being written to a new pack
+    flushing: Vec<(PathBuf, SGData)>,
+    /// Objects written to packs
+    index: HashMap<PathBuf, Entry>,
+    packs: HashMap<PathBuf, Pack>,
+    /// Whether the packs stored were read into `index` yet
+    loaded: bool,
+    /// Packs with objects renamed or removed since their header was
+    /// written
+    dirty: HashSet<PathBuf>,
+}
+
+struct Shared {
+    threshold: usize,
+    flush_size: usize,
+    /// Locked only to look up or change the objects, never during inner
+    /// I/O
+    state: Mutex<State>,
+    /// Held while writing packs and headers, and while renaming or
+    /// removing objects in them, so they are changed by one thread at a
+    /// time. Locked before `state`, if both are.
+    writing: Mutex<()>,
+}
+
+/// Backend storing small objects of another backend in combined packs
+///
+/// Idempotent writes (of content-addressed objects, like chunks) of at
+/// most `threshold` bytes are buffered, and written together as a single
+/// pack once `flush_size` bytes are buffered. Larger idempotent writes
+/// go straight to the inner backend, and any other operation writes the
+/// buffered objects first, so e.g. a name is never stored before its
+/// chunks. Anything still buffered when the backend is dropped is written
+/// then, ignoring errors.
+///
+/// Reads, listings, renames and removals of the objects in packs work
+/// just like for the objects stored directly. Renames and removals only
+/// change the headers of the packs in memory, and the changed headers are
+/// written together, once per pack, when the buffered objects are. A pack
+/// less than half used is rewritten with just the objects left. Only the
+/// headers are read to find the objects in packs: on first access, and
+/// again for the packs written by other processes when `read` doesn't
+/// find an object.
+pub struct Coalescing {
+    inner: Box<dyn Backend>,
+    shared: Arc<Shared>,
+}
+
+pub struct CoalescingThread {
+    inner: Box<dyn BackendThread>,
+    shared: Arc<Shared>,
+}
+
+impl Coalescing {
+    pub fn new(
+        inner: Box<dyn Backend>,
+        threshold: usize,
+        flush_size: usize,
+    ) -> Self {
+        Coalescing {
+            inner,
+            shared: Arc::new(Shared {
+                threshold,
+                flush_size,
+                state: Mutex::new(State::default()),
+                writing: Mutex::new(()),
+            }),
+        }
+    }
+}
+
+impl Drop for Coalescing {
+    fn drop(&mut self) {
+        {
+            let state = self.shared.state.lock().unwrap();
+            if state.pending.is_empty() && state.dirty.is_empty() {
+                return;
+            }
+        }
+        if let Ok(mut inner) = self.inner.new_thread() {
+            let _ = self.shared.flush(&mut *inner);
+        }
+    }
+}
+
+impl Backend for Coalescing {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive()
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared()
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.inner.try_lock_exclusive()
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.inner.try_lock_shared()
+    }
+
+    fn lock_owner(&self) -> io::Result<Option<LockInfo>> {
+        self.inner.lock_owner()
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(CoalescingThread {
+            inner: self.inner.new_thread()?,
+            shared: Arc::clone(&self.shared),
+        }))
+    }
+
+    fn remove_orphaned_tmp(&self) -> io::Result<usize> {
+        self.inner.remove_orphaned_tmp()
+    }
+
+    fn stat_fs(&self) -> io::Result<FsStats> {
+        self.inner.stat_fs()
+    }
+}
+
+/// `path` without the `.` components, as passed by `list(".")`
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+fn new_pack_path() -> PathBuf {
+    let id: [u8; 16] = rand::thread_rng().gen();
+    Path::new(PACKS_DIR).join(hex::encode(id))
+}
+
+fn header_path(pack: &Path) -> PathBuf {
+    pack.with_extension(HEADER_EXT)
+}
+
+/// Path of the pack of the header listed as `path`, or `None` if it's
+/// not one (like the data of a pack, or a temporary object of the inner
+/// backend)
+fn pack_path(path: &Path) -> Option<PathBuf> {
+    if path.extension() != Some(OsStr::new(HEADER_EXT)) {
+        return None;
+    }
+    let name = path.file_stem()?.to_str()?;
+    if name.len() == 32 && name.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some(Path::new(PACKS_DIR).join(name))
+    } else {
+        None
+    }
+}
+
+/// Data of a pack of `objects`, with their paths, offsets and lengths
+fn encode(objects: &[(PathBuf, SGData)]) -> (SGData, Vec<PackEntry>) {
+    let mut pack = SGData::empty();
+    let mut entries = vec![];
+    let mut offset = 0;
+    for (path, sg) in objects {
+        entries.push((path.clone(), offset, sg.len()));
+        offset += sg.len();
+        for part in sg.as_parts() {
+            pack.push_arcref(part.clone());
+        }
+    }
+    (pack, entries)
+}
+
+fn encode_header(len: usize, entries: &[PackEntry]) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&(len as u64).to_be_bytes());
+    header.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+    for (path, offset, len) in entries {
+        let key = path_to_key(path);
+        header.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        header.extend_from_slice(key.as_bytes());
+        header.extend_from_slice(&(*offset as u64).to_be_bytes());
+        header.extend_from_slice(&(*len as u64).to_be_bytes());
+    }
+    header
+}
+
+fn be_usize(bytes: &[u8]) -> usize {
+    u64::from_be_bytes(bytes.try_into().unwrap()) as usize
+}
+
+/// Length of the data of the pack of `header`, and the paths, offsets
+/// and lengths of its objects
+fn decode_header(header: &[u8]) -> io::Result<(usize, Vec<PackEntry>)> {
+    let truncated = || {
+        io::Error::new(io::ErrorKind::InvalidData, "coalesced pack truncated")
+    };
+    let mut rest = header;
+    let mut take = |len: usize| -> io::Result<&[u8]> {
+        if rest.len() < len {
+            return Err(truncated());
+        }
+        let (bytes, tail) = rest.split_at(len);
+        rest = tail;
+        Ok(bytes)
+    };
+
+    if take(MAGIC.len())? != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a coalesced pack",
+        ));
+    }
+    let pack_len = be_usize(take(8)?);
+    let count = be_usize(take(8)?);
+    let mut entries = vec![];
+    for _ in 0..count {
+        let key_len = u32::from_be_bytes(take(4)?.try_into().unwrap());
+        let key = String::from_utf8_lossy(take(key_len as usize)?);
+        let path = key_to_path(&key);
+        let offset = be_usize(take(8)?);
+        let len = be_usize(take(8)?);
+        match offset.checked_add(len) {
+            Some(end) if end <= pack_len => entries.push((path, offset, len)),
+            _ => return Err(truncated()),
+        }
+    }
+    Ok((pack_len, entries))
+}
+
+fn remove_if_exists(
+    inner: &mut dyn BackendThread,
+    path: PathBuf,
+) -> io::Result<()> {
+    match inner.remove(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+impl State {
+    fn buffered(&self, path: &Path) -> Option<&SGData> {
+        self.pending
+            .iter()
+            .chain(&self.flushing)
+            .find(|(buffered, _)| buffered == path)
+            .map(|(_, sg)| sg)
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        self.index.contains_key(path) || self.buffered(path).is_some()
+    }
+
+    fn lookup(&self, path: &Path) -> Lookup {
+        if let Some(sg) = self.buffered(path) {
+            return Lookup::Buffered(sg.clone());
+        }
+        match self.index.get(path) {
+            Some(entry) => Lookup::Packed(entry.clone()),
+            None => Lookup::Missing,
+        }
+    }
+
+    /// Add the objects of `pack` to the index
+    ///
+    /// An object already in another pack is removed from it, as after
+    /// rewriting a pack, or a crash while doing so.
+    fn insert_pack(
+        &mut self,
+        pack: &Path,
+        len: usize,
+        entries: Vec<PackEntry>,
+    ) {
+        let mut objects = HashSet::new();
+        for (path, offset, len) in entries {
+            let entry = Entry {
+                pack: pack.to_owned(),
+                offset,
+                len,
+            };
+            if let Some(old) = self.index.insert(path.clone(), entry) {
+                if old.pack != pack {
+                    self.packs
+                        .get_mut(&old.pack)
+                        .unwrap()
+                        .objects
+                        .remove(&path);
+                    self.dirty.insert(old.pack);
+                }
+            }
+            objects.insert(path);
+        }
+        self.packs.insert(pack.to_owned(), Pack { len, objects });
+    }
+
+    /// Remove `path` if it's coalesced, returning whether it was
+    fn remove(&mut self, path: &Path) -> bool {
+        if let Some(i) = self.pending.iter().position(|(p, _)| p == path) {
+            let (_, sg) = self.pending.remove(i);
+            self.pending_len -= sg.len();
+            return true;
+        }
+        match self.index.remove(path) {
+            Some(entry) => {
+                self.packs
+                    .get_mut(&entry.pack)
+                    .unwrap()
+                    .objects
+                    .remove(path);
+                self.dirty.insert(entry.pack);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Rename `src_path` if it's coalesced, returning whether it was
+    fn rename(&mut self, src_path: &Path, dst_path: &Path) -> bool {
+        if !self.contains(src_path) {
+            return false;
+        }
+        if src_path == dst_path {
+            return true;
+        }
+        self.remove(dst_path);
+        if let Some((path, _)) =
+            self.pending.iter_mut().find(|(p, _)| p == src_path)
+        {
+            *path = dst_path.to_owned();
+            return true;
+        }
+        let entry = self.index.remove(src_path).unwrap();
+        let objects = &mut self.packs.get_mut(&entry.pack).unwrap().objects;
+        objects.remove(src_path);
+        objects.insert(dst_path.to_owned());
+        self.dirty.insert(entry.pack.clone());
+        self.index.insert(dst_path.to_owned(), entry);
+        true
+    }
+
+    /// Paths of the objects in packs or buffered, under `path`
+    fn coalesced_under<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> impl Iterator<Item = &'a PathBuf> + 'a {
+        self.pending
+            .iter()
+            .chain(&self.flushing)
+            .map(|(path, _)| path)
+            .chain(self.index.keys())
+            .filter(move |coalesced| coalesced.starts_with(path))
+    }
+}
+
+impl Shared {
+    fn ensure_loaded(&self, inner: &mut dyn BackendThread) -> io::Result<()> {
+        if self.state.lock().unwrap().loaded {
+            return Ok(());
+        }
+        let _writing = self.writing.lock().unwrap();
+        if self.state.lock().unwrap().loaded {
+            return Ok(());
+        }
+        self.load(inner)
+    }
+
+    /// Read the headers of the packs not read into the index yet
+    ///
+    /// `writing` must be held.
+    fn load(&self, inner: &mut dyn BackendThread) -> io::Result<()> {
+        let listed = inner.list(PathBuf::from(PACKS_DIR))?;
+        let known: HashSet<_> =
+            self.state.lock().unwrap().packs.keys().cloned().collect();
+
+        let mut headers = vec![];
+        for pack in listed.iter().filter_map(|path| pack_path(path)) {
+            if known.contains(&pack) {
+                continue;
+            }
+            let header = match inner.read(header_path(&pack)) {
+                Ok(header) => header.to_linear_vec(),
+                // removed by another process since
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            headers.push((pack, decode_header(&header)?));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        for (pack, (len, entries)) in headers {
+            state.insert_pack(&pack, len, entries);
+        }
+        state.loaded = true;
+        Ok(())
+    }
+
+    /// Write the buffered objects and the changed headers
+    fn flush(&self, inner: &mut dyn BackendThread) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        self.flush_locked(inner)
+    }
+
+    /// `flush`, with `writing` held
+    fn flush_locked(&self, inner: &mut dyn BackendThread) -> io::Result<()> {
+        let pending = {
+            let mut state = self.state.lock().unwrap();
+            state.pending_len = 0;
+            state.flushing = mem::take(&mut state.pending);
+            state.flushing.clone()
+        };
+        if !pending.is_empty() {
+            let res = self.write_pack(inner, &pending);
+            let mut state = self.state.lock().unwrap();
+            let mut flushing = mem::take(&mut state.flushing);
+            match res {
+                Ok((pack, len, entries)) => {
+                    state.insert_pack(&pack, len, entries)
+                }
+                Err(e) => {
+                    state.pending_len +=
+                        flushing.iter().map(|(_, sg)| sg.len()).sum::<usize>();
+                    flushing.append(&mut state.pending);
+                    state.pending = flushing;
+                    return Err(e);
+                }
+            }
+        }
+
+        loop {
+            let (pack, len, entries) = {
+                let state = self.state.lock().unwrap();
+                let pack = match state.dirty.iter().next() {
+                    Some(pack) => pack.clone(),
+                    None => return Ok(()),
+                };
+                let info = &state.packs[&pack];
+                let entries: Vec<_> = info
+                    .objects
+                    .iter()
+                    .map(|path| {
+                        let entry = &state.index[path];
+                        (path.clone(), entry.offset, entry.len)
+                    })
+                    .collect();
+                (pack, info.len, entries)
+            };
+            self.update_pack(inner, &pack, len, entries)?;
+        }
+    }
+
+    /// Write `objects` as a new pack, returning its path, length and
+    /// entries
+    fn write_pack(
+        &self,
+        inner: &mut dyn BackendThread,
+        objects: &[(PathBuf, SGData)],
+    ) -> io::Result<(PathBuf, usize, Vec<PackEntry>)> {
+        let pack = new_pack_path();
+        let (sg, entries) = encode(objects);
+        let len = sg.len();
+        inner.write(pack.clone(), sg, false)?;
+        let header = SGData::from_single(encode_header(len, &entries));
+        inner.write(header_path(&pack), header, false)?;
+        Ok((pack, len, entries))
+    }
+
+    /// Store the changed `entries` of `pack`: remove it if none are left,
+    /// move them to a new pack if less than half of it is used, or just
+    /// rewrite its header otherwise
+    fn update_pack(
+        &self,
+        inner: &mut dyn BackendThread,
+        pack: &Path,
+        len: usize,
+        entries: Vec<PackEntry>,
+    ) -> io::Result<()> {
+        if entries.is_empty() {
+            remove_if_exists(inner, header_path(pack))?;
+            remove_if_exists(inner, pack.to_owned())?;
+            let mut state = self.state.lock().unwrap();
+            state.packs.remove(pack);
+            state.dirty.remove(pack);
+            return Ok(());
+        }
+
+        let used: usize = entries.iter().map(|(_, _, len)| len).sum();
+        if used * 2 < len {
+            let data = inner.read(pack.to_owned())?.to_linear();
+            let objects = entries
+                .into_iter()
+                .map(|(path, offset, len)| {
+                    let object =
+                        data.get(offset..offset + len).ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("{} truncated", pack.display()),
+                            )
+                        })?;
+                    Ok((path, SGData::from_single(object.to_vec())))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            let (new_pack, len, entries) = self.write_pack(inner, &objects)?;
+            // leaves `pack` empty, to be removed next
+            self.state
+                .lock()
+                .unwrap()
+                .insert_pack(&new_pack, len, entries);
+            return Ok(());
+        }
+
+        let header = SGData::from_single(encode_header(len, &entries));
+        inner.write(header_path(pack), header, false)?;
+        self.state.lock().unwrap().dirty.remove(pack);
+        Ok(())
+    }
+}
+
+impl CoalescingThread {
+    fn lookup(&mut self, path: &Path) -> io::Result<Lookup> {
+        self.shared.ensure_loaded(&mut *self.inner)?;
+        Ok(self.shared.state.lock().unwrap().lookup(path))
+    }
+
+    fn read_packed(&mut self, entry: &Entry) -> io::Result<SGData> {
+        let data = self.inner.read(entry.pack.clone())?.to_linear();
+        let object = data
+            .get(entry.offset..entry.offset + entry.len)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} truncated", entry.pack.display()),
+                )
+            })?;
+        Ok(SGData::from_single(object.to_vec()))
+    }
+}
+
+impl BackendThread for CoalescingThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        let path = normalize(&path);
+        self.shared.ensure_loaded(&mut *self.inner)?;
+        let removed_any = {
+            let _writing = self.shared.writing.lock().unwrap();
+            let removed_any = {
+                let mut state = self.shared.state.lock().unwrap();
+                let coalesced: Vec<_> =
+                    state.coalesced_under(&path).cloned().collect();
+                for coalesced in &coalesced {
+                    state.remove(coalesced);
+                }
+                !coalesced.is_empty()
+            };
+            self.shared.flush_locked(&mut *self.inner)?;
+            removed_any
+        };
+
+        match self.inner.remove_dir_all(path) {
+            Err(ref e)
+                if e.kind() == io::ErrorKind::NotFound && removed_any =>
+            {
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        self.shared.ensure_loaded(&mut *self.inner)?;
+        {
+            let _writing = self.shared.writing.lock().unwrap();
+            let mut state = self.shared.state.lock().unwrap();
+            if state.rename(&src_path, &dst_path) {
+                return Ok(());
+            }
+            drop(state);
+            self.shared.flush_locked(&mut *self.inner)?;
+        }
+        self.inner.rename(src_path, dst_path)
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<WriteOutcome> {
+        if !idempotent {
+            self.shared.flush(&mut *self.inner)?;
+            return self.inner.write(path, sg, idempotent);
+        }
+        if sg.len() > self.shared.threshold {
+            return self.inner.write(path, sg, idempotent);
+        }
+
+        self.shared.ensure_loaded(&mut *self.inner)?;
+        let flush = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.contains(&path) {
+                return Ok(WriteOutcome::AlreadyPresent);
+            }
+            state.pending_len += sg.len();
+            state.pending.push((path, sg));
+            state.pending_len >= self.shared.flush_size
+        };
+        if flush {
+            self.shared.flush(&mut *self.inner)?;
+        }
+        Ok(WriteOutcome::Written)
+    }
+
+    /// Durable writes are not coalesced: objects waiting to be packed are
+    /// not stored at all until the pack is written
+    fn write_durable(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+        mode: DurabilityMode,
+    ) -> io::Result<WriteOutcome> {
+        if mode != DurabilityMode::FsyncFileAndDir {
+            return self.write(path, sg, idempotent);
+        }
+        self.shared.flush(&mut *self.inner)?;
+        self.inner.write_durable(path, sg, idempotent, mode)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        match self.lookup(&path)? {
+            Lookup::Buffered(sg) => return Ok(sg),
+            Lookup::Packed(entry) => match self.read_packed(&entry) {
+                // its pack was rewritten in the meantime
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                res => return res,
+            },
+            Lookup::Missing => {}
+        }
+
+        match self.inner.read(path.clone()) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                // Could have been coalesced by another process since
+                {
+                    let _writing = self.shared.writing.lock().unwrap();
+                    self.shared.load(&mut *self.inner)?;
+                }
+                let lookup = self.shared.state.lock().unwrap().lookup(&path);
+                match lookup {
+                    Lookup::Buffered(sg) => Ok(sg),
+                    Lookup::Packed(entry) => self.read_packed(&entry),
+                    Lookup::Missing => self.inner.read(path),
+                }
+            }
+            res => res,
+        }
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.shared.ensure_loaded(&mut *self.inner)?;
+        {
+            let _writing = self.shared.writing.lock().unwrap();
+            if self.shared.state.lock().unwrap().remove(&path) {
+                return Ok(());
+            }
+            self.shared.flush_locked(&mut *self.inner)?;
+        }
+        self.inner.remove(path)
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        let len = match self.lookup(&path)? {
+            Lookup::Buffered(sg) => sg.len(),
+            Lookup::Packed(entry) => entry.len,
+            Lookup::Missing => return self.inner.read_metadata(path),
+        };
+        Ok(Metadata {
+            len: len as u64,
+            is_file: true,
+            modified: None,
+        })
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        let path = normalize(&path);
+        let mut list = self.inner.list(path.clone())?;
+        if path.as_os_str().is_empty() {
+            list.retain(|listed| {
+                listed.file_name() != Some(OsStr::new(PACKS_DIR))
+            });
+        }
+
+        let mut names: HashSet<_> = list
+            .iter()
+            .filter_map(|listed| listed.file_name())
+            .map(|name| name.to_owned())
+            .collect();
+        self.shared.ensure_loaded(&mut *self.inner)?;
+        let state = self.shared.state.lock().unwrap();
+        for coalesced in state.coalesced_under(&path) {
+            let name =
+                match coalesced.strip_prefix(&path).unwrap().iter().next() {
+                    Some(name) => name,
+                    None => continue,
+                };
+            if names.insert(name.to_owned()) {
+                list.push(path.join(name));
+            }
+        }
+        Ok(list)
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
+        let path = normalize(&path);
+        let (inner_tx, inner_rx) = ListSender::channel(tx.batch_size());
+        self.inner.list_recursively(path.clone(), inner_tx);
+        // objects under `path` can all be coalesced
+        let mut not_found = None;
+        for batch in inner_rx {
+            if let Err(ref e) = batch {
+                if e.kind() == io::ErrorKind::NotFound {
+                    not_found = batch.err();
+                    continue;
+                }
+            }
+            let batch = batch.map(|batch| {
+                batch
+                    .into_iter()
+                    .filter(|listed| !listed.starts_with(PACKS_DIR))
+                    .collect()
+            });
+            if tx.send(batch).is_err() {
+                return;
+            }
+        }
+
+        if let Err(e) = self.shared.ensure_loaded(&mut *self.inner) {
+            let _ = tx.send(Err(e));
+            return;
+        }
+        let coalesced: Vec<_> = self
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .coalesced_under(&path)
+            .cloned()
+            .collect();
+        if !coalesced.is_empty() {
+            let _ = tx.send(Ok(coalesced));
+        } else if let Some(e) = not_found {
+            let _ = tx.send(Err(e));
+        }
+    }
+}
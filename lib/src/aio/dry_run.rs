@@ -0,0 +1,148 @@
+//! Backend pretending to change another one, to preview operations
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use sgdata::SGData;
+
+use super::{Backend, BackendThread, FsStats, ListSender, WriteOutcome};
+use super::{Lock, LockInfo, Metadata};
+
+/// Backend reading from another one, but not changing anything in it
+///
+/// Writes, removals and renames succeed without doing anything. Writes
+/// still tell if the object was already there, like a write to the
+/// `inner` backend would, so `WriteStats` count the new data as if it
+/// was written. Objects "written" are remembered, so writing one again
+/// finds it already present, but reading one fails like it was never
+/// written.
+pub struct DryRun {
+    inner: Box<dyn Backend>,
+    written: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+pub struct DryRunThread {
+    inner: Box<dyn BackendThread>,
+    written: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl DryRun {
+    pub fn new(inner: Box<dyn Backend>) -> Self {
+        DryRun {
+            inner,
+            written: Default::default(),
+        }
+    }
+}
+
+impl Backend for DryRun {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive()
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared()
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.inner.try_lock_exclusive()
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.inner.try_lock_shared()
+    }
+
+    fn lock_owner(&self) -> io::Result<Option<LockInfo>> {
+        self.inner.lock_owner()
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(DryRunThread {
+            inner: self.inner.new_thread()?,
+            written: self.written.clone(),
+        }))
+    }
+
+    fn remove_orphaned_tmp(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    fn stat_fs(&self) -> io::Result<FsStats> {
+        self.inner.stat_fs()
+    }
+}
+
+impl BackendThread for DryRunThread {
+    fn remove_dir_all(&mut self, _path: PathBuf) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        _src_path: PathBuf,
+        _dst_path: PathBuf,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        _sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<WriteOutcome> {
+        if idempotent && self.inner.stat(path.clone())?.is_some() {
+            return Ok(WriteOutcome::AlreadyPresent);
+        }
+        let new = self.written.lock().unwrap().insert(path);
+        Ok(if new || !idempotent {
+            WriteOutcome::Written
+        } else {
+            WriteOutcome::AlreadyPresent
+        })
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        self.inner.read(path)
+    }
+
+    fn read_stream(
+        &mut self,
+        path: PathBuf,
+    ) -> io::Result<Box<dyn io::Read + Send>> {
+        self.inner.read_stream(path)
+    }
+
+    fn remove(&mut self, _path: PathBuf) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.inner.read_metadata(path)
+    }
+
+    fn stat(&mut self, path: PathBuf) -> io::Result<Option<Metadata>> {
+        self.inner.stat(path)
+    }
+
+    fn exists(&mut self, path: PathBuf) -> io::Result<bool> {
+        self.inner.exists(path)
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(path)
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
+        self.inner.list_recursively(path, tx)
+    }
+
+    fn copy_prefix(
+        &mut self,
+        _src_path: PathBuf,
+        _dst_path: PathBuf,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
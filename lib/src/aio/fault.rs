@@ -0,0 +1,319 @@
+//! Backend failing operations on demand, for testing
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{io, thread};
+
+use sgdata::SGData;
+
+use super::{Backend, BackendThread, DurabilityMode, FsStats, ListSender};
+use super::{Lock, LockInfo, Metadata, WriteOutcome};
+
+/// Kind of backend operation a `Rule` applies to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Write,
+    /// `read` and `read_stream`
+    Read,
+    /// `read_metadata` and `stat`
+    ReadMetadata,
+    /// `list` and `list_recursively`
+    List,
+    Remove,
+    RemoveDirAll,
+    /// Applies to the source path
+    Rename,
+    Any,
+}
+
+/// What happens to an operation a `Rule` applies to
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Fail with an error of the kind, without doing anything
+    Error(io::ErrorKind),
+    /// Flip a bit of the data read or written (other operations are not
+    /// affected)
+    Corrupt,
+    /// Wait before doing the operation
+    Delay(Duration),
+    /// Panic, like a backend hitting a bug
+    Panic,
+}
+
+/// Fault to inject into some of the operations of a `FaultInjecting`
+/// backend
+#[derive(Clone, Debug)]
+pub struct Rule {
+    op: Op,
+    path: Option<PathBuf>,
+    nth: Option<usize>,
+    fault: Fault,
+}
+
+impl Rule {
+    /// Inject `fault` into every operation of the kind `op`
+    pub fn new(op: Op, fault: Fault) -> Self {
+        Rule {
+            op,
+            path: None,
+            nth: None,
+            fault,
+        }
+    }
+
+    /// Only apply to `path`, or to paths under it
+    pub fn path<P: Into<PathBuf>>(self, path: P) -> Self {
+        Rule {
+            path: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Only apply to the `n`th operation the rule matches, counting from 1
+    pub fn nth(self, n: usize) -> Self {
+        assert!(n > 0);
+        Rule {
+            nth: Some(n),
+            ..self
+        }
+    }
+
+    fn matches(&self, op: Op, path: &Path) -> bool {
+        let path_matches = match self.path {
+            Some(ref prefix) => path.starts_with(prefix),
+            None => true,
+        };
+        (self.op == Op::Any || self.op == op) && path_matches
+    }
+}
+
+struct RuleState {
+    rule: Rule,
+    /// Operations matched so far
+    matched: usize,
+}
+
+/// Rules of a `FaultInjecting` backend
+///
+/// Rules can be added and cleared while the backend is in use. A
+/// `BackendSelectFn` can't capture anything, so this is usually a
+/// `static`.
+pub struct Faults {
+    rules: Mutex<Vec<RuleState>>,
+    injected: Mutex<usize>,
+}
+
+impl Faults {
+    pub const fn new() -> Self {
+        Faults {
+            rules: Mutex::new(Vec::new()),
+            injected: Mutex::new(0),
+        }
+    }
+
+    pub fn add(&self, rule: Rule) {
+        self.rules
+            .lock()
+            .unwrap()
+            .push(RuleState { rule, matched: 0 });
+    }
+
+    /// Remove all the rules
+    pub fn clear(&self) {
+        self.rules.lock().unwrap().clear();
+    }
+
+    /// Number of faults injected so far
+    pub fn injected(&self) -> usize {
+        *self.injected.lock().unwrap()
+    }
+
+    /// Faults to inject into an operation, in the order of the rules
+    fn check(&self, op: Op, path: &Path) -> Vec<Fault> {
+        let mut faults = vec![];
+        for state in self.rules.lock().unwrap().iter_mut() {
+            if !state.rule.matches(op, path) {
+                continue;
+            }
+            state.matched += 1;
+            if state.rule.nth.is_none() || state.rule.nth == Some(state.matched)
+            {
+                faults.push(state.rule.fault.clone());
+            }
+        }
+        *self.injected.lock().unwrap() += faults.len();
+        faults
+    }
+
+    /// Delay and fail the operation as the rules say
+    ///
+    /// Returns if the data of the operation is to be corrupted.
+    fn inject(&self, op: Op, path: &Path) -> io::Result<bool> {
+        let mut corrupt = false;
+        for fault in self.check(op, path) {
+            match fault {
+                Fault::Error(kind) => {
+                    return Err(io::Error::new(
+                        kind,
+                        format!(
+                            "injected fault: {:?} of {}",
+                            op,
+                            path.display()
+                        ),
+                    ))
+                }
+                Fault::Corrupt => corrupt = true,
+                Fault::Delay(delay) => thread::sleep(delay),
+                Fault::Panic => {
+                    panic!("injected panic: {:?} of {}", op, path.display())
+                }
+            }
+        }
+        Ok(corrupt)
+    }
+}
+
+fn corrupt(sg: SGData) -> SGData {
+    let mut data = sg.to_linear_vec();
+    if let Some(byte) = data.last_mut() {
+        *byte ^= 1;
+    }
+    SGData::from_single(data)
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Backend injecting faults into the operations of another one
+///
+/// Every operation is checked against the `Faults` rules, then passed
+/// through to the `inner` backend, unless it's to fail. Meant to test
+/// how errors of the backend are handled.
+pub struct FaultInjecting {
+    inner: Box<dyn Backend>,
+    faults: &'static Faults,
+}
+
+pub struct FaultInjectingThread {
+    inner: Box<dyn BackendThread>,
+    faults: &'static Faults,
+}
+
+impl FaultInjecting {
+    pub fn new(inner: Box<dyn Backend>, faults: &'static Faults) -> Self {
+        FaultInjecting { inner, faults }
+    }
+}
+
+impl Backend for FaultInjecting {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive()
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared()
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.inner.try_lock_exclusive()
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.inner.try_lock_shared()
+    }
+
+    fn lock_owner(&self) -> io::Result<Option<LockInfo>> {
+        self.inner.lock_owner()
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(FaultInjectingThread {
+            inner: self.inner.new_thread()?,
+            faults: self.faults,
+        }))
+    }
+
+    fn remove_orphaned_tmp(&self) -> io::Result<usize> {
+        self.inner.remove_orphaned_tmp()
+    }
+
+    fn stat_fs(&self) -> io::Result<FsStats> {
+        self.inner.stat_fs()
+    }
+}
+
+impl BackendThread for FaultInjectingThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        self.faults.inject(Op::RemoveDirAll, &path)?;
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        self.faults.inject(Op::Rename, &src_path)?;
+        self.inner.rename(src_path, dst_path)
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<WriteOutcome> {
+        let sg = if self.faults.inject(Op::Write, &path)? {
+            corrupt(sg)
+        } else {
+            sg
+        };
+        self.inner.write(path, sg, idempotent)
+    }
+
+    fn write_durable(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+        mode: DurabilityMode,
+    ) -> io::Result<WriteOutcome> {
+        let sg = if self.faults.inject(Op::Write, &path)? {
+            corrupt(sg)
+        } else {
+            sg
+        };
+        self.inner.write_durable(path, sg, idempotent, mode)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        let corrupted = self.faults.inject(Op::Read, &path)?;
+        let sg = self.inner.read(path)?;
+        Ok(if corrupted { corrupt(sg) } else { sg })
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.faults.inject(Op::Remove, &path)?;
+        self.inner.remove(path)
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.faults.inject(Op::ReadMetadata, &path)?;
+        self.inner.read_metadata(path)
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.faults.inject(Op::List, &path)?;
+        self.inner.list(path)
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
+        match self.faults.inject(Op::List, &path) {
+            Ok(_) => self.inner.list_recursively(path, tx),
+            Err(e) => tx.send(Err(e)).expect("send failed"),
+        }
+    }
+}
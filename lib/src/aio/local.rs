@@ -1,27 +1,175 @@
 // {{{ use and mod
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
-use std::{fs, io, mem};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{fs, io, mem, process};
 
 use fs2::FileExt;
-use rand::distributions::Alphanumeric;
-use rand::Rng;
 use sgdata::SGData;
 use walkdir::WalkDir;
 
-use super::{Backend, BackendThread};
-use super::{Lock, Metadata};
+use super::{Backend, BackendThread, DurabilityMode, FsStats, ListSender};
+use super::{Lock, LockInfo, Metadata, WriteOutcome};
 use crate::config;
 use crate::INGRESS_BUFFER_SIZE;
 // }}}
 
-impl Lock for fs::File {}
+/// Lock held on the lock file, with the record of its owner (see
+/// `record_lock_owner`), removed when released
+struct LocalLock {
+    _file: fs::File,
+    owner_path: PathBuf,
+}
+
+impl Lock for LocalLock {}
+
+impl Drop for LocalLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.owner_path);
+    }
+}
+
+/// Add `path` to the message of `e`, keeping its kind
+fn with_path(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+/// Write all the parts of `sg`, with as few (vectored) writes as possible
+///
+/// Chunks often consist of many small buffers, and a `write` per part
+/// would cost a syscall each.
+fn write_all_parts(file: &mut fs::File, sg: &SGData) -> io::Result<()> {
+    let mut slices: Vec<io::IoSlice> = sg
+        .as_parts()
+        .iter()
+        .filter(|part| !part.is_empty())
+        .map(|part| io::IoSlice::new(part))
+        .collect();
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        match file.write_vectored(slices) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(n) => io::IoSlice::advance_slices(&mut slices, n),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Create the parent directory of `path`
+fn create_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => fs::create_dir_all(dir).map_err(with_path(dir)),
+        None => Ok(()),
+    }
+}
 
 pub(crate) fn lock_file_path(path: &Path) -> PathBuf {
     path.join(config::LOCK_FILE)
 }
 
+/// Whether `path` is a directory `Local` keeps for itself, hidden from
+/// listings
+fn is_internal_dir(root: &Path, path: &Path) -> bool {
+    path == root.join(config::TMP_DIR)
+        || path == root.join(config::LOCK_OWNERS_DIR)
+}
+
+static NEXT_LOCK_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Note this process as an owner of the lock, for `lock_owner`, in a
+/// file of its own in `config::LOCK_OWNERS_DIR`
+///
+/// Not in the lock file itself: every holder of a shared lock has a
+/// record, and a file locked shared can't be written to on Windows.
+fn record_lock_owner(path: &Path, file: fs::File) -> io::Result<LocalLock> {
+    let owner = LockInfo {
+        pid: process::id(),
+        hostname: hostname(),
+        time: chrono::Utc::now(),
+    };
+    let owner = serde_yaml::to_string(&owner).map_err(io::Error::other)?;
+    let owner_path = path.join(config::LOCK_OWNERS_DIR).join(format!(
+        "{}.{}",
+        process::id(),
+        NEXT_LOCK_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    if fs::write(&owner_path, &owner).is_err() {
+        create_parent_dir(&owner_path)?;
+        fs::write(&owner_path, &owner).map_err(with_path(&owner_path))?;
+    }
+    Ok(LocalLock {
+        _file: file,
+        owner_path,
+    })
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let res = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+    };
+    if res != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Marks temporary files: `<target>.tmp.<pid>.<thread>.<counter>`
+///
+/// Files are written to a temporary file in `config::TMP_DIR` first, and
+/// then renamed to the target. Keeping them all in one directory means
+/// orphaned ones can be found without walking the whole repository.
+/// `<target>` is the file name of the target. `<thread>` is unique among
+/// the `LocalThread`s of the process, and `<counter>` among the temporary
+/// files of a `LocalThread`, so the names never collide. `<pid>` tells if
+/// the file could still be renamed, or was orphaned by a writer that died
+/// (see `Local::remove_orphaned_tmp`).
+const TMP_INFIX: &str = ".tmp.";
+
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Id of the process that created the temporary file at `path`, or `None`
+/// if it's not one
+fn tmp_path_pid(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+    let suffix = &name[name.rfind(TMP_INFIX)? + TMP_INFIX.len()..];
+    let mut parts = suffix.split('.');
+    let pid = parts.next()?.parse().ok()?;
+    parts.next()?.parse::<u64>().ok()?;
+    parts.next()?.parse::<u64>().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(pid)
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // Signal 0 only checks if the process exists
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// No way to tell, so assume it is
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
 #[derive(Debug)]
 pub struct Local {
     path: PathBuf,
@@ -30,43 +178,206 @@ pub struct Local {
 #[derive(Debug)]
 pub struct LocalThread {
     path: PathBuf,
-    rand_ext: String,
+    id: u64,
+    tmp_counter: u64,
 }
 
 impl Backend for Local {
     fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
-        let lock_path = lock_file_path(&self.path);
-
-        let file = fs::File::create(&lock_path)?;
+        let file = self.open_lock_file()?;
         file.lock_exclusive()?;
 
-        Ok(Box::new(file))
+        Ok(Box::new(record_lock_owner(&self.path, file)?))
     }
 
     fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
-        let lock_path = lock_file_path(&self.path);
-
-        let file = fs::File::create(&lock_path)?;
+        let file = self.open_lock_file()?;
         file.lock_shared()?;
 
-        Ok(Box::new(file))
+        Ok(Box::new(record_lock_owner(&self.path, file)?))
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        let file = self.open_lock_file()?;
+        match FileExt::try_lock_exclusive(&file) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+
+        Ok(Some(Box::new(record_lock_owner(&self.path, file)?)))
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        let file = self.open_lock_file()?;
+        match FileExt::try_lock_shared(&file) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+
+        Ok(Some(Box::new(record_lock_owner(&self.path, file)?)))
+    }
+
+    /// The holder that took the lock first, if several hold it shared
+    ///
+    /// Records of processes on this host that are not running anymore are
+    /// ignored, as are the ones that can't be read (e.g. still being
+    /// written).
+    fn lock_owner(&self) -> io::Result<Option<LockInfo>> {
+        let entries =
+            match fs::read_dir(self.path.join(config::LOCK_OWNERS_DIR)) {
+                Ok(entries) => entries,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            };
+        let this_host = hostname();
+        let mut first: Option<LockInfo> = None;
+        for entry in entries {
+            let owner = match fs::read_to_string(entry?.path()) {
+                Ok(owner) => owner,
+                // released in the meantime
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let owner: LockInfo = match serde_yaml::from_str(&owner) {
+                Ok(owner) => owner,
+                Err(_) => continue,
+            };
+            if owner.hostname == this_host && !process_alive(owner.pid) {
+                continue;
+            }
+            if first.as_ref().is_none_or(|first| owner.time < first.time) {
+                first = Some(owner);
+            }
+        }
+        Ok(first)
+    }
+
+    fn stat_fs(&self) -> io::Result<FsStats> {
+        let stats = fs2::statvfs(&self.path)?;
+        Ok(FsStats {
+            total: stats.total_space(),
+            available: stats.available_space(),
+        })
     }
 
     fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
         Ok(Box::new(LocalThread {
             path: self.path.clone(),
-            rand_ext: rand::thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(20)
-                .collect::<String>(),
+            id: NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed),
+            tmp_counter: 0,
         }))
     }
+
+    /// Remove temporary files (see `TMP_INFIX`) of processes that are not
+    /// running anymore
+    ///
+    /// Only processes on this host are considered, so don't share the
+    /// directory between hosts writing to it at the same time.
+    fn remove_orphaned_tmp(&self) -> io::Result<usize> {
+        let entries = match fs::read_dir(self.path.join(config::TMP_DIR)) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            match tmp_path_pid(&entry.path()) {
+                Some(pid) if pid != process::id() && !process_alive(pid) => {
+                    match fs::remove_file(entry.path()) {
+                        Ok(()) => removed += 1,
+                        // someone else removed it
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(removed)
+    }
 }
 
 impl Local {
     pub fn new(path: PathBuf) -> Self {
         Local { path }
     }
+
+    /// Open the lock file, creating the directory if needed (e.g. for a
+    /// shard of `Sharded` nothing was written to yet)
+    fn open_lock_file(&self) -> io::Result<fs::File> {
+        let path = lock_file_path(&self.path);
+        let open = || {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+        };
+        match open() {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                create_parent_dir(&path)?;
+                open().map_err(with_path(&path))
+            }
+            res => res,
+        }
+    }
+}
+
+impl LocalThread {
+    fn next_tmp_path(&mut self, path: &Path) -> PathBuf {
+        let mut name = path.file_name().expect("no file name").to_owned();
+        name.push(format!(
+            "{}{}.{}.{}",
+            TMP_INFIX,
+            process::id(),
+            self.id,
+            self.tmp_counter
+        ));
+        self.tmp_counter += 1;
+        self.path.join(config::TMP_DIR).join(name)
+    }
+
+    /// Create a temporary file to write `path` through
+    fn create_tmp_file(
+        &mut self,
+        path: &Path,
+    ) -> io::Result<(PathBuf, fs::File)> {
+        let tmp_path = self.next_tmp_path(path);
+        let file = match fs::File::create(&tmp_path) {
+            Ok(file) => Ok(file),
+            Err(_) => {
+                create_parent_dir(&tmp_path)?;
+                fs::File::create(&tmp_path)
+            }
+        }
+        .map_err(with_path(&tmp_path))?;
+        Ok((tmp_path, file))
+    }
+}
+
+/// Rename the temporary file at `tmp_path` to `path`, creating the
+/// directory of `path` if needed
+fn rename_tmp(tmp_path: &Path, path: &Path) -> io::Result<()> {
+    match fs::rename(tmp_path, path) {
+        Ok(()) => Ok(()),
+        Err(_) => {
+            create_parent_dir(path)?;
+            fs::rename(tmp_path, path).map_err(with_path(path))
+        }
+    }
 }
 
 impl BackendThread for LocalThread {
@@ -86,8 +397,8 @@ impl BackendThread for LocalThread {
         match fs::rename(&src_path, &dst_path) {
             Ok(_) => Ok(()),
             Err(_e) => {
-                fs::create_dir_all(dst_path.parent().unwrap())?;
-                fs::rename(&src_path, &dst_path)
+                create_parent_dir(&dst_path)?;
+                fs::rename(&src_path, &dst_path).map_err(with_path(&src_path))
             }
         }
     }
@@ -97,31 +408,40 @@ impl BackendThread for LocalThread {
         path: PathBuf,
         sg: SGData,
         idempotent: bool,
-    ) -> io::Result<()> {
+    ) -> io::Result<WriteOutcome> {
+        self.write_durable(path, sg, idempotent, DurabilityMode::FsyncFile)
+    }
+
+    fn write_durable(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+        mode: DurabilityMode,
+    ) -> io::Result<WriteOutcome> {
         let path = self.path.join(path);
         // check if exists on disk
         // remove from `in_progress` if it does
         if idempotent && path.exists() {
-            return Ok(());
+            return Ok(WriteOutcome::AlreadyPresent);
         }
 
-        let tmp_path = path.with_extension(format!("{}.tmp", self.rand_ext));
-        let mut chunk_file = match fs::File::create(&tmp_path) {
-            Ok(file) => Ok(file),
-            Err(_) => {
-                fs::create_dir_all(path.parent().unwrap())?;
-                fs::File::create(&tmp_path)
-            }
-        }?;
+        let (tmp_path, mut chunk_file) = self.create_tmp_file(&path)?;
 
-        for data_part in sg.as_parts() {
-            chunk_file.write_all(data_part)?;
-        }
+        write_all_parts(&mut chunk_file, &sg).map_err(with_path(&tmp_path))?;
 
-        chunk_file.sync_data()?;
-        fs::rename(&tmp_path, &path)?;
+        if mode != DurabilityMode::None {
+            chunk_file.sync_data().map_err(with_path(&tmp_path))?;
+        }
+        rename_tmp(&tmp_path, &path)?;
+        if mode == DurabilityMode::FsyncFileAndDir {
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            fs::File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .map_err(with_path(dir))?;
+        }
 
-        Ok(())
+        Ok(WriteOutcome::Written)
     }
 
     fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
@@ -142,6 +462,13 @@ impl BackendThread for LocalThread {
         }
     }
 
+    fn read_stream(
+        &mut self,
+        path: PathBuf,
+    ) -> io::Result<Box<dyn io::Read + Send>> {
+        Ok(Box::new(fs::File::open(self.path.join(path))?))
+    }
+
     fn remove(&mut self, path: PathBuf) -> io::Result<()> {
         let path = self.path.join(path);
         fs::remove_file(&path)
@@ -153,6 +480,7 @@ impl BackendThread for LocalThread {
         Ok(Metadata {
             len: md.len(),
             is_file: md.is_file(),
+            modified: md.modified().ok(),
         })
     }
 
@@ -161,12 +489,13 @@ impl BackendThread for LocalThread {
         let mut v = Vec::with_capacity(128);
 
         let dir = fs::read_dir(path);
-
         match dir {
             Ok(dir) => {
                 for entry in dir {
                     let entry = entry?;
-                    v.push(entry.path());
+                    if !is_internal_dir(&self.path, &entry.path()) {
+                        v.push(entry.path());
+                    }
                 }
                 Ok(v)
             }
@@ -175,27 +504,35 @@ impl BackendThread for LocalThread {
         }
     }
 
-    fn list_recursively(
-        &mut self,
-        path: PathBuf,
-        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
-    ) {
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
         let path = self.path.join(path);
 
         if !path.exists() {
+            let e = io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found", path.display()),
+            );
+            tx.send(Err(e)).expect("send failed");
             return;
         }
 
         let mut v = Vec::with_capacity(128);
 
-        for path in WalkDir::new(path) {
+        for path in WalkDir::new(path)
+            .into_iter()
+            .filter_entry(|e| !is_internal_dir(&self.path, e.path()))
+        {
             match path {
                 Ok(path) => {
                     if !path.file_type().is_file() {
                         continue;
                     }
-                    v.push(path.path().into());
-                    if v.len() > 100 {
+                    let path = path
+                        .path()
+                        .strip_prefix(&self.path)
+                        .expect("walked path outside of the backend");
+                    v.push(path.into());
+                    if v.len() >= tx.batch_size() {
                         tx.send(Ok(mem::replace(&mut v, vec![])))
                             .expect("send failed")
                     }
@@ -207,6 +544,38 @@ impl BackendThread for LocalThread {
             tx.send(Ok(v)).expect("send failed")
         }
     }
+
+    fn copy_prefix(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let src_path = self.path.join(src_path);
+        let dst_path = self.path.join(dst_path);
+
+        if !src_path.exists() {
+            return Ok(());
+        }
+
+        for entry in WalkDir::new(&src_path) {
+            let entry = entry?;
+            let rel_path = entry
+                .path()
+                .strip_prefix(&src_path)
+                .expect("walked path outside of the source");
+            let dst = dst_path.join(rel_path);
+
+            if entry.file_type().is_dir() {
+                fs::create_dir_all(&dst)?;
+            } else if entry.file_type().is_file() {
+                let (tmp_path, tmp_file) = self.create_tmp_file(&dst)?;
+                drop(tmp_file);
+                fs::copy(entry.path(), &tmp_path)?;
+                rename_tmp(&tmp_path, &dst)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 // vim: foldmethod=marker foldmarker={{{,}}}
@@ -0,0 +1,292 @@
+//! Backend keeping everything in memory, for testing
+use std::collections::{BTreeSet, HashMap};
+use std::path::{self, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::SystemTime;
+use std::{io, mem};
+
+use sgdata::SGData;
+
+use super::{Backend, BackendThread, ListSender, WriteOutcome};
+use super::{Lock, Metadata};
+
+struct Object {
+    data: SGData,
+    modified: SystemTime,
+}
+
+type Objects = Arc<Mutex<HashMap<PathBuf, Object>>>;
+
+/// State of the repository lock
+#[derive(Default)]
+struct LockState {
+    shared: usize,
+    exclusive: bool,
+}
+
+#[derive(Default)]
+struct RepoLock {
+    state: Mutex<LockState>,
+    cond: Condvar,
+}
+
+/// Lock held on a `Memory` backend, released on `drop`
+struct MemoryLock {
+    lock: Arc<RepoLock>,
+    exclusive: bool,
+}
+
+impl Lock for MemoryLock {}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock().unwrap();
+        if self.exclusive {
+            state.exclusive = false;
+        } else {
+            state.shared -= 1;
+        }
+        self.lock.cond.notify_all();
+    }
+}
+
+/// Backend storing objects in a map in memory
+///
+/// Nothing touches the disk, so tests using it are fast and don't depend
+/// on the filesystem. Clones share the same objects (and lock), so a clone
+/// kept aside can be used to reopen the repo, or to inspect what was
+/// stored. Directories are not stored: one exists as long as any object
+/// is stored under it.
+#[derive(Clone, Default)]
+pub struct Memory {
+    objects: Objects,
+    lock: Arc<RepoLock>,
+}
+
+pub struct MemoryThread {
+    objects: Objects,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory::default()
+    }
+
+    /// Paths of all the objects stored, sorted
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let objects = self.objects.lock().unwrap();
+        let mut paths: Vec<_> = objects.keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    /// Take the lock, if `wait` for it, or `None` if it's held
+    fn lock(&self, exclusive: bool, wait: bool) -> Option<Box<dyn Lock>> {
+        let mut state = self.lock.state.lock().unwrap();
+        while state.exclusive || (exclusive && state.shared > 0) {
+            if !wait {
+                return None;
+            }
+            state = self.lock.cond.wait(state).unwrap();
+        }
+        if exclusive {
+            state.exclusive = true;
+        } else {
+            state.shared += 1;
+        }
+        Some(Box::new(MemoryLock {
+            lock: self.lock.clone(),
+            exclusive,
+        }))
+    }
+}
+
+impl Backend for Memory {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(self.lock(true, true).expect("lock taken"))
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(self.lock(false, true).expect("lock taken"))
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        Ok(self.lock(true, false))
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        Ok(self.lock(false, false))
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(MemoryThread {
+            objects: self.objects.clone(),
+        }))
+    }
+}
+
+/// `path` without `.` components, as used for the keys of the map
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != path::Component::CurDir)
+        .collect()
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+/// Paths of the objects under the directory `path`
+fn paths_under(
+    objects: &HashMap<PathBuf, Object>,
+    path: &Path,
+) -> Vec<PathBuf> {
+    objects
+        .keys()
+        .filter(|key| key.starts_with(path) && *key != path)
+        .cloned()
+        .collect()
+}
+
+impl BackendThread for MemoryThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        let path = normalize(&path);
+        let mut objects = self.objects.lock().unwrap();
+        let paths = paths_under(&objects, &path);
+        if paths.is_empty() {
+            return Err(not_found(&path));
+        }
+        for path in paths {
+            objects.remove(&path);
+        }
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let src_path = normalize(&src_path);
+        let dst_path = normalize(&dst_path);
+        let mut objects = self.objects.lock().unwrap();
+
+        if let Some(object) = objects.remove(&src_path) {
+            objects.insert(dst_path, object);
+            return Ok(());
+        }
+
+        // a directory, moved with everything under it
+        let paths = paths_under(&objects, &src_path);
+        if paths.is_empty() {
+            return Err(not_found(&src_path));
+        }
+        for path in paths {
+            let object = objects.remove(&path).expect("listed object missing");
+            let rel_path = path
+                .strip_prefix(&src_path)
+                .expect("listed object outside of the directory");
+            objects.insert(dst_path.join(rel_path), object);
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<WriteOutcome> {
+        let path = normalize(&path);
+        let mut objects = self.objects.lock().unwrap();
+        if idempotent && objects.contains_key(&path) {
+            return Ok(WriteOutcome::AlreadyPresent);
+        }
+        objects.insert(
+            path,
+            Object {
+                data: sg,
+                modified: SystemTime::now(),
+            },
+        );
+        Ok(WriteOutcome::Written)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        let path = normalize(&path);
+        let objects = self.objects.lock().unwrap();
+        match objects.get(&path) {
+            Some(object) => Ok(object.data.clone()),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        let path = normalize(&path);
+        match self.objects.lock().unwrap().remove(&path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        let path = normalize(&path);
+        let objects = self.objects.lock().unwrap();
+        if let Some(object) = objects.get(&path) {
+            return Ok(Metadata {
+                len: object.data.len() as u64,
+                is_file: true,
+                modified: Some(object.modified),
+            });
+        }
+        if paths_under(&objects, &path).is_empty() {
+            return Err(not_found(&path));
+        }
+        Ok(Metadata {
+            len: 0,
+            is_file: false,
+            modified: None,
+        })
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        let path = normalize(&path);
+        let objects = self.objects.lock().unwrap();
+        // objects and directories right under `path`, once each
+        let entries: BTreeSet<_> = paths_under(&objects, &path)
+            .into_iter()
+            .filter_map(|key| {
+                key.strip_prefix(&path)
+                    .ok()?
+                    .components()
+                    .next()
+                    .map(|component| path.join(component))
+            })
+            .collect();
+        Ok(entries.into_iter().collect())
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
+        let path = normalize(&path);
+        let mut paths = paths_under(&self.objects.lock().unwrap(), &path);
+        if paths.is_empty() {
+            tx.send(Err(not_found(&path))).expect("send failed");
+            return;
+        }
+        paths.sort();
+
+        let mut v = Vec::with_capacity(128);
+        for path in paths {
+            v.push(path);
+            if v.len() >= tx.batch_size() {
+                tx.send(Ok(mem::take(&mut v))).expect("send failed")
+            }
+        }
+        if !v.is_empty() {
+            tx.send(Ok(v)).expect("send failed")
+        }
+    }
+}
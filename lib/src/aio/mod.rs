@@ -1,9 +1,10 @@
 //! Asynchronous IO operations & backends
 use std::cell::RefCell;
-use std::collections::HashSet;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{self, Path, PathBuf};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
 use std::{io, thread};
 
 use dangerous_option::DangerousOption as AutoOption;
@@ -14,10 +15,26 @@ use slog::{Level, Logger};
 use slog_perf::TimeReporter;
 use url::Url;
 
+use crate::util::MemoryPermit;
+use crate::Error;
+
 pub(crate) mod local;
 pub(crate) use self::local::Local;
 pub(crate) mod b2;
 pub(crate) use self::b2::B2;
+pub(crate) mod null;
+
+pub(crate) mod memory;
+
+pub(crate) mod coalescing;
+
+pub(crate) mod sharded;
+
+pub(crate) mod fault;
+
+pub(crate) mod retrying;
+
+pub(crate) mod dry_run;
 
 pub(crate) mod backend;
 use self::backend::*;
@@ -27,13 +44,37 @@ struct WriteArgs {
     path: PathBuf,
     data: SGData,
     idempotent: bool,
+    /// Refuse to overwrite an object modified more recently than that
+    protect: Option<Duration>,
+    durability: DurabilityMode,
     complete_tx: Option<mpsc::Sender<io::Result<()>>>,
+    /// Accounts for `data`, released once written
+    permit: Option<MemoryPermit>,
+}
+
+/// Key of the object at `path`, for backends storing objects by name
+///
+/// Paths within the repo are `PathBuf`s, separated the way the host OS
+/// does it. Keys are always separated with `/`, so a repo written on one
+/// OS can be used on another.
+pub fn path_to_key(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Path of the object stored under `key` (see `path_to_key`)
+pub fn key_to_path(key: &str) -> PathBuf {
+    key.split('/').filter(|c| !c.is_empty()).collect()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Metadata {
     pub len: u64,
     pub is_file: bool,
+    /// Last modification time, if the backend keeps track of it
+    pub modified: Option<SystemTime>,
 }
 
 /// A result of async io operation
@@ -48,8 +89,27 @@ pub struct AsyncIOResult<T> {
 
 impl<T> AsyncIOResult<T> {
     /// Block until result arrives
-    pub fn wait(self) -> io::Result<T> {
-        self.rx.recv().expect("No `AsyncIO` thread response")
+    ///
+    /// Fails with `Error::WorkerDied` if the worker processing the
+    /// operation died (panicked) before responding.
+    pub fn wait(self) -> Result<T, Error> {
+        match self.rx.recv() {
+            Ok(res) => Ok(res?),
+            Err(_) => Err(Error::WorkerDied),
+        }
+    }
+
+    /// Like `wait`, but give up after `timeout`
+    ///
+    /// Returns `Ok(None)` if the result didn't arrive in time. The
+    /// operation keeps going, and its result can still be waited for.
+    #[allow(dead_code)]
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<Option<T>, Error> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(res) => Ok(Some(res?)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::WorkerDied),
+        }
     }
 }
 
@@ -57,6 +117,130 @@ impl<T> AsyncIOResult<T> {
 pub struct WriteStats {
     pub new_chunks: usize,
     pub new_bytes: u64,
+    /// Idempotent writes of objects already stored, so not written again
+    pub deduped_chunks: usize,
+    pub deduped_bytes: u64,
+    /// Most chunk data buffered at once by a `Repo::write`
+    ///
+    /// Not a counter, so it's taken from `self` by `since`.
+    pub peak_buffered: u64,
+    /// Bytes of data stored by a `Repo::write`, if known
+    ///
+    /// Like `peak_buffered`, taken from `self` by `since`.
+    pub data_bytes: Option<u64>,
+    /// Set if a `Repo::write` deduplicated much worse than the names
+    /// stored before it (see `Repo::set_dedup_check`)
+    pub low_dedup: bool,
+}
+
+impl WriteStats {
+    fn since(&self, earlier: &WriteStats) -> WriteStats {
+        WriteStats {
+            new_chunks: self.new_chunks - earlier.new_chunks,
+            new_bytes: self.new_bytes - earlier.new_bytes,
+            deduped_chunks: self.deduped_chunks - earlier.deduped_chunks,
+            deduped_bytes: self.deduped_bytes - earlier.deduped_bytes,
+            peak_buffered: self.peak_buffered,
+            data_bytes: self.data_bytes,
+            low_dedup: self.low_dedup,
+        }
+    }
+}
+
+/// Data read through `AsyncIO::read`
+#[derive(Clone, Debug, Default)]
+pub struct ReadStats {
+    pub chunks_read: usize,
+    pub bytes_read: u64,
+    /// Reads served from the read cache, without reading the backend
+    /// (not counted in `chunks_read`)
+    pub cached_reads: usize,
+}
+
+impl ReadStats {
+    fn since(&self, earlier: &ReadStats) -> ReadStats {
+        ReadStats {
+            chunks_read: self.chunks_read - earlier.chunks_read,
+            bytes_read: self.bytes_read - earlier.bytes_read,
+            cached_reads: self.cached_reads - earlier.cached_reads,
+        }
+    }
+}
+
+/// Activity of the `AsyncIO` workers, reported as it happens (see
+/// `AsyncIO::set_progress`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Object written, or found already stored by an idempotent write
+    ChunkWritten { bytes: u64, deduplicated: bool },
+    /// Object read, from the backend or the read cache
+    ChunkRead { bytes: u64 },
+    /// Batch of paths listed by `list_recursively`
+    ListBatch { count: usize },
+}
+
+/// Callback receiving `ProgressEvent`s, called by the worker threads
+pub type ProgressFn = Arc<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// Current time, as seen by the stats history
+pub type ClockFn = Arc<dyn Fn() -> Instant + Send + Sync>;
+
+/// Point-in-time copy of all the `AsyncIO` counters
+///
+/// Counters are cumulative since the pool was started. To get the
+/// numbers for a single operation, take a snapshot before it, and
+/// compare it with one taken after it, using `since`.
+#[derive(Clone, Debug)]
+pub struct StatsSnapshot {
+    pub write: WriteStats,
+    pub read: ReadStats,
+}
+
+impl StatsSnapshot {
+    /// Counters accumulated between `earlier` and `self`
+    pub fn since(&self, earlier: &StatsSnapshot) -> StatsSnapshot {
+        StatsSnapshot {
+            write: self.write.since(&earlier.write),
+            read: self.read.since(&earlier.read),
+        }
+    }
+}
+
+/// Snapshots of the counters with the time they were taken at, to tell the
+/// activity over a recent time window
+struct StatsHistory {
+    capacity: usize,
+    /// Shortest time between two snapshots
+    interval: Duration,
+    clock: ClockFn,
+    samples: VecDeque<(Instant, StatsSnapshot)>,
+}
+
+impl StatsHistory {
+    fn record(&mut self, now: Instant, snapshot: StatsSnapshot) {
+        if let Some(&(last, _)) = self.samples.back() {
+            if now.duration_since(last) < self.interval {
+                return;
+            }
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, snapshot));
+    }
+
+    /// Newest snapshot taken by `start`, or the oldest one if there's none
+    fn at(&self, start: Option<Instant>) -> &StatsSnapshot {
+        let taken = match start {
+            Some(start) => self
+                .samples
+                .iter()
+                .take_while(|&&(at, _)| at <= start)
+                .count(),
+            None => 0,
+        };
+        &self.samples[taken.saturating_sub(1)].1
+    }
 }
 // }}}
 
@@ -67,12 +251,27 @@ pub struct WriteStats {
 enum Message {
     Write(WriteArgs),
     Read(PathBuf, mpsc::Sender<io::Result<SGData>>),
+    ReadStream(PathBuf, mpsc::Sender<io::Result<Box<dyn io::Read + Send>>>),
     ReadMetadata(PathBuf, mpsc::Sender<io::Result<Metadata>>),
+    ReadMetadataBatch(
+        Vec<PathBuf>,
+        mpsc::Sender<io::Result<Vec<io::Result<Metadata>>>>,
+    ),
+    Stat(PathBuf, mpsc::Sender<io::Result<Option<Metadata>>>),
+    Exists(PathBuf, mpsc::Sender<io::Result<bool>>),
     List(PathBuf, mpsc::Sender<io::Result<Vec<PathBuf>>>),
-    ListRecursively(PathBuf, mpsc::Sender<io::Result<Vec<PathBuf>>>),
+    ListRecursively(PathBuf, ListSender),
     Remove(PathBuf, mpsc::Sender<io::Result<()>>),
     RemoveDirAll(PathBuf, mpsc::Sender<io::Result<()>>),
     Rename(PathBuf, PathBuf, mpsc::Sender<io::Result<()>>),
+    Batch(Vec<BatchOp>, mpsc::Sender<io::Result<()>>),
+}
+
+/// `Message` with the id of the operation that sent it, if any (see
+/// `AsyncIO::with_op`)
+struct Envelope {
+    op: Option<Arc<str>>,
+    message: Message,
 }
 // }}}
 
@@ -86,18 +285,43 @@ pub struct AsyncIO {
     shared: Arc<AsyncIOShared>,
     /// tx endpoind of mpmc queue used to send jobs
     /// to the pool.
-    tx: AutoOption<crossbeam_channel::Sender<Message>>,
+    tx: AutoOption<crossbeam_channel::Sender<Envelope>>,
+    /// Id of the operation the jobs are sent by
+    op: Option<Arc<str>>,
 }
 
 impl AsyncIO {
+    /// Start a pool of `thread_num` workers, 4 per CPU if `None`
+    ///
+    /// Up to as many jobs are queued for the workers, before sending more
+    /// blocks.
     pub(crate) fn new(
         backend: Box<dyn Backend + Send + Sync>,
+        thread_num: Option<usize>,
+        log: Logger,
+    ) -> io::Result<Self> {
+        AsyncIO::with_write_rate(backend, thread_num, None, log)
+    }
+
+    /// Like `new`, but write at most `write_rate` bytes per second, if
+    /// set
+    ///
+    /// The rate is shared by all the workers. Up to a second worth of
+    /// writes can go through at once, after a pause.
+    pub(crate) fn with_write_rate(
+        backend: Box<dyn Backend + Send + Sync>,
+        thread_num: Option<usize>,
+        write_rate: Option<u64>,
         log: Logger,
     ) -> io::Result<Self> {
-        let thread_num = 4 * num_cpus::get();
+        let thread_num = thread_num.unwrap_or_else(|| 4 * num_cpus::get());
+        assert!(thread_num > 0);
+        assert!(write_rate != Some(0));
         let (tx, rx) = crossbeam_channel::bounded(thread_num);
 
-        let shared = AsyncIOThreadShared::new();
+        let mut shared = AsyncIOThreadShared::new();
+        shared.write_rate =
+            write_rate.map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate))));
 
         let mut spawn_res: Vec<io::Result<_>> = (0..thread_num)
             .map(|_| {
@@ -131,9 +355,34 @@ impl AsyncIO {
         Ok(AsyncIO {
             shared: Arc::new(shared),
             tx: AutoOption::new(tx),
+            op: None,
         })
     }
 
+    /// Handle sending the jobs of the operation identified by `op`
+    ///
+    /// Workers log with `op` in the context while processing them.
+    pub(crate) fn with_op(&self, op: &str) -> AsyncIO {
+        let mut aio = self.clone();
+        aio.op = Some(op.into());
+        aio
+    }
+
+    fn send(
+        &self,
+        message: Message,
+    ) -> Result<(), crossbeam_channel::SendError<Envelope>> {
+        self.tx.send(Envelope {
+            op: self.op.clone(),
+            message,
+        })
+    }
+
+    /// See `Backend::remove_orphaned_tmp`
+    pub(crate) fn remove_orphaned_tmp(&self) -> io::Result<usize> {
+        self.shared.backend.remove_orphaned_tmp()
+    }
+
     pub(crate) fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
         self.shared.backend.lock_exclusive()
     }
@@ -142,14 +391,129 @@ impl AsyncIO {
         self.shared.backend.lock_shared()
     }
 
+    /// Like `lock_exclusive`, but waiting at most `timeout` for the lock
+    ///
+    /// Meant for locks a crashed process could have left behind, e.g. on a
+    /// network filesystem that doesn't release them. Fails with
+    /// `io::ErrorKind::TimedOut`, and a `Locked` inner error telling who
+    /// holds the lock, if the backend knows.
+    pub(crate) fn lock_exclusive_timeout(
+        &self,
+        timeout: Duration,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.lock_timeout(timeout, |backend| backend.try_lock_exclusive())
+    }
+
+    /// Like `lock_shared`, but waiting at most `timeout` for the lock
+    pub(crate) fn lock_shared_timeout(
+        &self,
+        timeout: Duration,
+    ) -> io::Result<Box<dyn Lock>> {
+        self.lock_timeout(timeout, |backend| backend.try_lock_shared())
+    }
+
+    /// Call `try_lock` until it takes the lock, waiting longer and longer
+    /// in between, until `timeout` passes
+    fn lock_timeout(
+        &self,
+        timeout: Duration,
+        try_lock: impl Fn(&dyn Backend) -> io::Result<Option<Box<dyn Lock>>>,
+    ) -> io::Result<Box<dyn Lock>> {
+        let backend = &*self.shared.backend;
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_millis(10);
+        loop {
+            if let Some(lock) = try_lock(backend)? {
+                return Ok(lock);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                let owner = backend.lock_owner()?;
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    Locked { owner },
+                ));
+            }
+            thread::sleep(backoff.min(deadline - now));
+            backoff = (backoff * 2).min(Duration::from_secs(1));
+        }
+    }
+
+    /// Total and available space of the backend's storage
+    ///
+    /// See `Backend::stat_fs`.
+    pub fn space(&self) -> io::Result<FsStats> {
+        self.shared.backend.stat_fs()
+    }
+
+    /// Keep up to `max_bytes` of the data read last in memory, and serve
+    /// reads of it without reading the backend
+    ///
+    /// Meant for reading the same objects over and over, like the chunks
+    /// of repetitive data. A read waiting for one of the same path in
+    /// progress gets the data it read. Objects larger than `max_bytes`
+    /// are not cached. Writing, removing and renaming an object through
+    /// the pool drops it from the cache, but changes made to the backend
+    /// in other ways are not noticed. `None` disables caching (the
+    /// default), and replacing the cache empties it.
+    pub(crate) fn set_read_cache(&self, max_bytes: Option<u64>) {
+        self.shared.stats.inner.lock().unwrap().read_cache =
+            max_bytes.map(ReadCache::new);
+    }
+
+    /// Call `progress` with every `ProgressEvent` of the workers, or stop
+    /// with `None`
+    ///
+    /// It's called by the worker threads, right after the operations, so
+    /// it has to be cheap, and must not block.
+    pub fn set_progress(&self, progress: Option<ProgressFn>) {
+        self.shared.stats.inner.lock().unwrap().progress = progress;
+    }
+
+    /// Number of workers in the pool
+    pub fn thread_num(&self) -> usize {
+        self.shared.join.len()
+    }
+
     pub fn stats(&self) -> AsyncIOThreadShared {
         self.shared.stats.clone()
     }
 
+    /// Stop the worker pool, after processing all the queued operations
+    ///
+    /// Unlike dropping the last handle, fails with the first error of a
+    /// write nobody waited for (`write_checked` and alike), or of a
+    /// worker that panicked, instead of panicking. Fails without stopping
+    /// anything if other handles to the pool still exist.
+    pub fn shutdown(self) -> io::Result<()> {
+        let shared = self.shared.clone();
+        // closes the queue, unless other handles keep it open
+        drop(self);
+        match Arc::try_unwrap(shared) {
+            Ok(mut shared) => shared.join_all(),
+            Err(_) => {
+                Err(io::Error::other("AsyncIO is still used by other handles"))
+            }
+        }
+    }
+
+    /// Stop the worker pool from processing operations
+    ///
+    /// Operations already being processed finish, the queued ones wait
+    /// until `resume`. Since the queue is bounded, submitting operations
+    /// to a paused pool will eventually block.
+    pub fn pause(&self) {
+        self.shared.stats.pause.set(true)
+    }
+
+    /// Resume processing operations after `pause`
+    pub fn resume(&self) {
+        self.shared.stats.pause.set(false)
+    }
+
     pub fn list(&self, path: PathBuf) -> AsyncIOResult<Vec<PathBuf>> {
         let (tx, rx) = mpsc::channel();
-        self.tx
-            .send(Message::List(path, tx))
+        self.send(Message::List(path, tx))
             .expect("aio tx closed: list");
         AsyncIOResult { rx }
     }
@@ -160,9 +524,34 @@ impl AsyncIO {
         &self,
         path: PathBuf,
     ) -> Box<dyn Iterator<Item = io::Result<PathBuf>>> {
-        let (tx, rx) = mpsc::channel();
-        self.tx
-            .send(Message::ListRecursively(path, tx))
+        let (tx, rx) = ListSender::channel(DEFAULT_LIST_BATCH_SIZE);
+        self.list_recursively_to(path, tx, rx)
+    }
+
+    /// Like `list_recursively`, but buffer at most `capacity` batches of
+    /// up to `batch_size` paths
+    ///
+    /// The worker doing the listing waits while the iterator is behind,
+    /// so the memory used stays bounded. It can't do anything else in
+    /// the meantime: with a single worker, waiting for other operations
+    /// before the iterator is done deadlocks.
+    pub fn list_recursively_bounded(
+        &self,
+        path: PathBuf,
+        capacity: usize,
+        batch_size: usize,
+    ) -> Box<dyn Iterator<Item = io::Result<PathBuf>>> {
+        let (tx, rx) = ListSender::sync_channel(capacity, batch_size);
+        self.list_recursively_to(path, tx, rx)
+    }
+
+    fn list_recursively_to(
+        &self,
+        path: PathBuf,
+        tx: ListSender,
+        rx: mpsc::Receiver<io::Result<Vec<PathBuf>>>,
+    ) -> Box<dyn Iterator<Item = io::Result<PathBuf>>> {
+        self.send(Message::ListRecursively(path, tx))
             .expect("aio tx closed: list_recursively");
 
         let iter = rx.into_iter().flat_map(|batch| match batch {
@@ -176,14 +565,67 @@ impl AsyncIO {
 
     pub fn write(&self, path: PathBuf, sg: SGData) -> AsyncIOResult<()> {
         let (tx, rx) = mpsc::channel();
-        self.tx
-            .send(Message::Write(WriteArgs {
-                path,
-                data: sg,
-                idempotent: false,
-                complete_tx: Some(tx),
-            }))
-            .expect("aio tx closed: write");
+        self.send(Message::Write(WriteArgs {
+            path,
+            data: sg,
+            idempotent: false,
+            protect: None,
+            durability: DurabilityMode::FsyncFile,
+            complete_tx: Some(tx),
+            permit: None,
+        }))
+        .expect("aio tx closed: write");
+        AsyncIOResult { rx }
+    }
+
+    /// Like `write`, but as durable as `mode` tells
+    ///
+    /// Use `DurabilityMode::FsyncFileAndDir` for objects replaced in place,
+    /// like names, so they survive a crash either old or new.
+    pub fn write_durable(
+        &self,
+        path: PathBuf,
+        sg: SGData,
+        mode: DurabilityMode,
+    ) -> AsyncIOResult<()> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Message::Write(WriteArgs {
+            path,
+            data: sg,
+            idempotent: false,
+            protect: None,
+            durability: mode,
+            complete_tx: Some(tx),
+            permit: None,
+        }))
+        .expect("aio tx closed: write_durable");
+        AsyncIOResult { rx }
+    }
+
+    /// Like `write_durable` with `DurabilityMode::FsyncFileAndDir`, but
+    /// refuse to overwrite an existing object modified within the last
+    /// `window`
+    ///
+    /// Meant for objects that are not content-addressed, where an
+    /// accidental overwrite loses data. Fails with `AlreadyExists` then;
+    /// use `write_durable` to force the overwrite.
+    pub fn write_protected(
+        &self,
+        path: PathBuf,
+        sg: SGData,
+        window: Duration,
+    ) -> AsyncIOResult<()> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Message::Write(WriteArgs {
+            path,
+            data: sg,
+            idempotent: false,
+            protect: Some(window),
+            durability: DurabilityMode::FsyncFileAndDir,
+            complete_tx: Some(tx),
+            permit: None,
+        }))
+        .expect("aio tx closed: write_protected");
         AsyncIOResult { rx }
     }
 
@@ -195,14 +637,16 @@ impl AsyncIO {
         sg: SGData,
     ) -> AsyncIOResult<()> {
         let (tx, rx) = mpsc::channel();
-        self.tx
-            .send(Message::Write(WriteArgs {
-                path,
-                data: sg,
-                idempotent: true,
-                complete_tx: Some(tx),
-            }))
-            .expect("aio tx closed: write_idempotent");
+        self.send(Message::Write(WriteArgs {
+            path,
+            data: sg,
+            idempotent: true,
+            protect: None,
+            durability: DurabilityMode::FsyncFile,
+            complete_tx: Some(tx),
+            permit: None,
+        }))
+        .expect("aio tx closed: write_idempotent");
         AsyncIOResult { rx }
     }
 
@@ -211,69 +655,138 @@ impl AsyncIO {
     // TODO: No need for it anymore
     #[allow(dead_code)]
     pub fn write_checked(&self, path: PathBuf, sg: SGData) {
-        self.tx
-            .send(Message::Write(WriteArgs {
-                path,
-                data: sg,
-                idempotent: false,
-                complete_tx: None,
-            }))
-            .expect("aio tx closed: write_checked");
-    }
-
-    pub fn write_checked_idempotent(&self, path: PathBuf, sg: SGData) {
-        self.tx
-            .send(Message::Write(WriteArgs {
-                path,
-                data: sg,
-                idempotent: true,
-                complete_tx: None,
-            }))
-            .expect("aio tx closed: write_checked_idempotent");
+        self.send(Message::Write(WriteArgs {
+            path,
+            data: sg,
+            idempotent: false,
+            protect: None,
+            durability: DurabilityMode::FsyncFile,
+            complete_tx: None,
+            permit: None,
+        }))
+        .expect("aio tx closed: write_checked");
+    }
+
+    /// Like `write_checked`, but idempotent, and releasing `permit` once
+    /// the data is written
+    pub(crate) fn write_checked_idempotent(
+        &self,
+        path: PathBuf,
+        sg: SGData,
+        permit: MemoryPermit,
+    ) {
+        self.send(Message::Write(WriteArgs {
+            path,
+            data: sg,
+            idempotent: true,
+            protect: None,
+            durability: DurabilityMode::FsyncFile,
+            complete_tx: None,
+            permit: Some(permit),
+        }))
+        .expect("aio tx closed: write_checked_idempotent");
     }
 
     pub fn read(&self, path: PathBuf) -> AsyncIOResult<SGData> {
         let (tx, rx) = mpsc::channel();
-        self.tx
-            .send(Message::Read(path, tx))
+        self.send(Message::Read(path, tx))
             .expect("aio tx closed: read");
         AsyncIOResult { rx }
     }
 
+    /// Like `read`, but return a reader of the content, instead of all of
+    /// it at once
+    ///
+    /// How much memory it saves depends on the backend (see
+    /// `BackendThread::read_stream`).
+    #[allow(dead_code)]
+    pub fn read_stream(
+        &self,
+        path: PathBuf,
+    ) -> AsyncIOResult<Box<dyn io::Read + Send>> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Message::ReadStream(path, tx))
+            .expect("aio tx closed: read_stream");
+        AsyncIOResult { rx }
+    }
+
     pub(crate) fn read_metadata(
         &self,
         path: PathBuf,
     ) -> AsyncIOResult<Metadata> {
         let (tx, rx) = mpsc::channel();
-        self.tx
-            .send(Message::ReadMetadata(path, tx))
+        self.send(Message::ReadMetadata(path, tx))
             .expect("aio tx closed: read_metadata");
         AsyncIOResult { rx }
     }
 
+    /// Metadata of each of `paths`, in order
+    ///
+    /// Sent to a single worker as one message, saving the per-message
+    /// overhead of `read_metadata` when statting many objects.
+    pub(crate) fn read_metadata_batch(
+        &self,
+        paths: Vec<PathBuf>,
+    ) -> AsyncIOResult<Vec<io::Result<Metadata>>> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Message::ReadMetadataBatch(paths, tx))
+            .expect("aio tx closed: read_metadata_batch");
+        AsyncIOResult { rx }
+    }
+
+    /// Metadata of `path`, or `None` if it does not exist
+    pub(crate) fn stat(
+        &self,
+        path: PathBuf,
+    ) -> AsyncIOResult<Option<Metadata>> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Message::Stat(path, tx))
+            .expect("aio tx closed: stat");
+        AsyncIOResult { rx }
+    }
+
+    /// Tell if there's an object at `path` (see `BackendThread::exists`)
+    pub(crate) fn exists(&self, path: PathBuf) -> AsyncIOResult<bool> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Message::Exists(path, tx))
+            .expect("aio tx closed: exists");
+        AsyncIOResult { rx }
+    }
+
     pub fn remove(&self, path: PathBuf) -> AsyncIOResult<()> {
         let (tx, rx) = mpsc::channel();
-        self.tx
-            .send(Message::Remove(path, tx))
+        self.send(Message::Remove(path, tx))
             .expect("aio tx closed: remove");
         AsyncIOResult { rx }
     }
 
     pub fn remove_dir_all(&self, path: PathBuf) -> AsyncIOResult<()> {
         let (tx, rx) = mpsc::channel();
-        self.tx
-            .send(Message::RemoveDirAll(path, tx))
+        self.send(Message::RemoveDirAll(path, tx))
             .expect("aio tx closed: remove_dir_all");
         AsyncIOResult { rx }
     }
 
     pub fn rename(&self, src: PathBuf, dst: PathBuf) -> AsyncIOResult<()> {
         let (tx, rx) = mpsc::channel();
-        self.tx
-            .send(Message::Rename(src, dst, tx))
+        self.send(Message::Rename(src, dst, tx))
             .expect("aio tx closed: rename");
         AsyncIOResult { rx }
     }
+
+    /// Apply `ops` in order, stopping at the first failure
+    ///
+    /// See `BackendThread::batch`. The error of a failed batch has the
+    /// `BatchError`, telling how many operations were applied, as its
+    /// inner error. Other operations on the paths of the batch wait for
+    /// it to finish (and the other way around), and its writes are
+    /// counted in the `WriteStats` as new.
+    pub fn batch(&self, ops: Vec<BatchOp>) -> AsyncIOResult<()> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Message::Batch(ops, tx))
+            .expect("aio tx closed: batch");
+        AsyncIOResult { rx }
+    }
 }
 
 impl Drop for AsyncIO {
@@ -281,7 +794,7 @@ impl Drop for AsyncIO {
         // It is important that the tx is dropped before `shared` is.
         // Otherwise join on worker threads will hang, as they are never
         // going to receive termination.
-        AutoOption::take_unchecked(&mut self.tx);
+        AutoOption::take_checked(&mut self.tx);
     }
 }
 // }}}
@@ -297,8 +810,41 @@ pub struct AsyncIOShared {
     backend: Box<dyn Backend + Send + Sync>,
 }
 
+impl AsyncIOShared {
+    /// Wait for the workers to process all the queued operations and
+    /// finish, once the queue is closed
+    ///
+    /// Returns the first error of a write nobody waited for, if any.
+    fn join_all(&mut self) -> io::Result<()> {
+        self.stats.pause.set(false);
+        trace!(self.log, "Waiting for all threads to finish");
+        let mut res = Ok(());
+        for join in self.join.drain(..) {
+            if let Err(panic) = join.join() {
+                let msg = panic
+                    .downcast_ref::<String>()
+                    .map(String::as_str)
+                    .or_else(|| panic.downcast_ref::<&str>().copied())
+                    .unwrap_or("unknown error");
+                if res.is_ok() {
+                    res = Err(io::Error::other(format!(
+                        "AsyncIO worker thread panicked: {}",
+                        msg
+                    )));
+                }
+            }
+        }
+        match self.stats.inner.lock().unwrap().write_error.take() {
+            Some(e) => Err(e),
+            None => res,
+        }
+    }
+}
+
 impl Drop for AsyncIOShared {
     fn drop(&mut self) {
+        // paused workers would never get to the end of the queue
+        self.stats.pause.set(false);
         trace!(self.log, "Waiting for all threads to finish");
         for join in self.join.drain(..) {
             join.join().expect("AsyncIO worker thread panicked")
@@ -309,9 +855,38 @@ impl Drop for AsyncIOShared {
 struct AsyncIOSharedInner {
     /// Keeps tracks of `write` stats.
     write_stats: WriteStats,
+    /// Keeps tracks of `read` stats.
+    read_stats: ReadStats,
+    /// Kept once `AsyncIOThreadShared::record_history` is called
+    history: Option<StatsHistory>,
     /// PathBufs being currently processed by the pool.
     /// Used to synchronize operations between each other.
     in_progress: HashSet<PathBuf>,
+    /// First error of a write nobody waited for
+    write_error: Option<io::Error>,
+    /// Data recently read, if caching is enabled
+    read_cache: Option<ReadCache>,
+    progress: Option<ProgressFn>,
+}
+
+impl AsyncIOSharedInner {
+    fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            write: self.write_stats.clone(),
+            read: self.read_stats.clone(),
+        }
+    }
+
+    /// Add the current counters to the history, if kept
+    fn record_history(&mut self) {
+        if self.history.is_some() {
+            let snapshot = self.snapshot();
+            if let Some(history) = self.history.as_mut() {
+                let now = (history.clock)();
+                history.record(now, snapshot);
+            }
+        }
+    }
 }
 
 impl Drop for AsyncIOSharedInner {
@@ -320,9 +895,162 @@ impl Drop for AsyncIOSharedInner {
     }
 }
 
+/// Data of the objects read last, up to a total size
+///
+/// Objects are evicted least recently read first. Keys have `.`
+/// components removed, so different spellings of a path share an entry.
+struct ReadCache {
+    max_bytes: u64,
+    bytes: u64,
+    /// Data and the `clock` value of the last read of each object
+    entries: HashMap<PathBuf, (SGData, u64)>,
+    /// Objects by the `clock` value of their last read
+    lru: BTreeMap<u64, PathBuf>,
+    clock: u64,
+}
+
+impl ReadCache {
+    fn new(max_bytes: u64) -> Self {
+        ReadCache {
+            max_bytes,
+            bytes: 0,
+            entries: Default::default(),
+            lru: Default::default(),
+            clock: 0,
+        }
+    }
+
+    fn key(path: &Path) -> PathBuf {
+        path.components()
+            .filter(|component| *component != path::Component::CurDir)
+            .collect()
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn get(&mut self, path: &Path) -> Option<SGData> {
+        let key = Self::key(path);
+        let now = self.tick();
+        let (sg, used) = self.entries.get_mut(&key)?;
+        self.lru.remove(used);
+        *used = now;
+        self.lru.insert(now, key);
+        Some(sg.clone())
+    }
+
+    fn insert(&mut self, path: &Path, sg: SGData) {
+        let len = sg.len() as u64;
+        if len > self.max_bytes {
+            return;
+        }
+        let key = Self::key(path);
+        self.remove(&key);
+        while self.bytes + len > self.max_bytes {
+            let oldest = match self.lru.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            let key = self.lru[&oldest].clone();
+            self.remove(&key);
+        }
+        let now = self.tick();
+        self.bytes += len;
+        self.lru.insert(now, key.clone());
+        self.entries.insert(key, (sg, now));
+    }
+
+    fn remove(&mut self, key: &Path) {
+        if let Some((sg, used)) = self.entries.remove(key) {
+            self.lru.remove(&used);
+            self.bytes -= sg.len() as u64;
+        }
+    }
+
+    /// Drop `path`, and everything under it
+    fn invalidate(&mut self, path: &Path) {
+        let prefix = Self::key(path);
+        let stale: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|key| key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+}
+
+/// Limit of the bytes per second going through
+///
+/// Holds up to a second worth of bytes. Taking more than there is
+/// leaves the bucket in debt, which the taker waits out, so the ones
+/// taking after it wait for their bytes too.
+struct TokenBucket {
+    rate: u64,
+    /// Bytes that can be taken without waiting (negative if in debt)
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take `bytes`, and return how long to wait before using them
+    fn take(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.refilled = now;
+        self.tokens =
+            (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+/// Flag that workers of a paused pool wait on
+#[derive(Default)]
+struct PauseGate {
+    paused: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl PauseGate {
+    fn set(&self, paused: bool) {
+        *self.paused.lock().unwrap() = paused;
+        self.cond.notify_all();
+    }
+
+    /// Block until not paused
+    fn wait(&self) {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused {
+            paused = self.cond.wait(paused).unwrap();
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AsyncIOThreadShared {
     inner: Arc<Mutex<AsyncIOSharedInner>>,
+    pause: Arc<PauseGate>,
+    /// Limit of the bytes written per second, if any
+    write_rate: Option<Arc<Mutex<TokenBucket>>>,
+    /// Notified when a path stops being in progress
+    in_progress_done: Arc<Condvar>,
 }
 
 impl AsyncIOThreadShared {
@@ -331,18 +1059,130 @@ impl AsyncIOThreadShared {
             write_stats: WriteStats {
                 new_bytes: 0,
                 new_chunks: 0,
+                deduped_bytes: 0,
+                deduped_chunks: 0,
+                peak_buffered: 0,
+                data_bytes: None,
+                low_dedup: false,
             },
+            read_stats: Default::default(),
             in_progress: Default::default(),
+            history: None,
+            write_error: None,
+            read_cache: None,
+            progress: None,
         };
 
         AsyncIOThreadShared {
             inner: Arc::new(Mutex::new(inner)),
+            pause: Default::default(),
+            write_rate: None,
+            in_progress_done: Default::default(),
         }
     }
 
-    pub fn get_stats(&self) -> WriteStats {
+    /// Lock the shared data once `path` is not in progress, and mark it
+    /// as in progress
+    ///
+    /// Returns `None` without waiting if `path` is in progress and
+    /// `!wait`.
+    fn start_in_progress(
+        &self,
+        path: &Path,
+        wait: bool,
+    ) -> Option<MutexGuard<'_, AsyncIOSharedInner>> {
+        let mut sh = self.inner.lock().unwrap();
+        while sh.in_progress.contains(path) {
+            if !wait {
+                return None;
+            }
+            sh = self.in_progress_done.wait(sh).unwrap();
+        }
+        sh.in_progress.insert(path.to_owned());
+        Some(sh)
+    }
+
+    /// Mark `path` as no longer in progress, and wake up the workers
+    /// waiting for it
+    fn finish_in_progress(&self, sh: &mut AsyncIOSharedInner, path: &Path) {
+        sh.in_progress.remove(path);
+        self.in_progress_done.notify_all();
+    }
+
+    /// Wait until writing `bytes` keeps under the write rate limit
+    fn throttle_write(&self, bytes: u64) {
+        if let Some(ref bucket) = self.write_rate {
+            let wait = bucket.lock().unwrap().take(bytes);
+            thread::sleep(wait);
+        }
+    }
+
+    /// Tell the progress callback about `event`, if there is one
+    fn report(&self, event: ProgressEvent) {
+        let progress = self.inner.lock().unwrap().progress.clone();
+        if let Some(progress) = progress {
+            progress(event);
+        }
+    }
+
+    /// Drop the cached data of `path`, and of everything under it
+    fn invalidate_cached(&self, path: &Path) {
+        if let Some(cache) = self.inner.lock().unwrap().read_cache.as_mut() {
+            cache.invalidate(path);
+        }
+    }
+
+    /// Capture all the counters at once
+    ///
+    /// Workers update counters under the same lock, so
+    /// the snapshot is always consistent.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.inner.lock().unwrap().snapshot()
+    }
+
+    /// Start keeping up to `capacity` snapshots of the counters, taken as
+    /// they change, at most once per `interval`
+    ///
+    /// Replaces the snapshots kept so far, if any.
+    pub fn record_history(&self, capacity: usize, interval: Duration) {
+        self.record_history_with_clock(
+            capacity,
+            interval,
+            Arc::new(Instant::now),
+        )
+    }
+
+    /// Like `record_history`, but timing the snapshots (and the windows
+    /// of `since`) with `clock`
+    pub fn record_history_with_clock(
+        &self,
+        capacity: usize,
+        interval: Duration,
+        clock: ClockFn,
+    ) {
+        assert!(capacity > 0);
+        let mut sh = self.inner.lock().unwrap();
+        let mut samples = VecDeque::with_capacity(capacity);
+        samples.push_back((clock(), sh.snapshot()));
+        sh.history = Some(StatsHistory {
+            capacity,
+            interval,
+            clock,
+            samples,
+        });
+    }
+
+    /// Counters accumulated over the last `window`
+    ///
+    /// Counted from the newest snapshot taken before the window, so up to
+    /// the `interval` of the history before it can be included. A window
+    /// starting before the oldest snapshot kept is counted from it
+    /// instead. `None` unless `record_history` was called.
+    pub fn since(&self, window: Duration) -> Option<StatsSnapshot> {
         let sh = self.inner.lock().unwrap();
-        sh.write_stats.clone()
+        let history = sh.history.as_ref()?;
+        let start = history.at((history.clock)().checked_sub(window));
+        Some(sh.snapshot().since(start))
     }
 }
 // }}}
@@ -351,7 +1191,7 @@ impl AsyncIOThreadShared {
 /// A single thread in the worker pool.
 struct AsyncIOThread {
     shared: AsyncIOThreadShared,
-    rx: crossbeam_channel::Receiver<Message>,
+    rx: crossbeam_channel::Receiver<Envelope>,
     log: Logger,
     time_reporter: TimeReporter,
     backend: RefCell<Box<dyn BackendThread>>,
@@ -362,15 +1202,15 @@ struct PendingGuard<'a, 'b>(&'a AsyncIOThread, &'b PathBuf);
 
 impl<'a, 'b> Drop for PendingGuard<'a, 'b> {
     fn drop(&mut self) {
-        let mut sh = self.0.shared.inner.lock().unwrap();
-        sh.in_progress.remove(self.1);
+        let shared = &self.0.shared;
+        shared.finish_in_progress(&mut shared.inner.lock().unwrap(), self.1);
     }
 }
 
 impl AsyncIOThread {
     fn new(
         shared: AsyncIOThreadShared,
-        rx: crossbeam_channel::Receiver<Message>,
+        rx: crossbeam_channel::Receiver<Envelope>,
         backend: Box<dyn BackendThread>,
         log: Logger,
     ) -> Self {
@@ -392,18 +1232,43 @@ impl AsyncIOThread {
         loop {
             self.time_reporter.start("rx");
 
-            if let Ok(msg) = self.rx.recv() {
-                match msg {
+            if let Ok(Envelope { op, message }) = self.rx.recv() {
+                // a message received just before pausing is held, not lost
+                self.shared.pause.wait();
+                let log = self.log.clone();
+                if let Some(op) = op {
+                    self.log = log.new(o!("op" => op.to_string()));
+                }
+                match message {
                     Message::Write(WriteArgs {
                         path,
                         data,
                         idempotent,
+                        protect,
+                        durability,
                         complete_tx,
-                    }) => self.write(path, data, idempotent, complete_tx),
+                        permit,
+                    }) => {
+                        self.write(
+                            path,
+                            data,
+                            idempotent,
+                            protect,
+                            durability,
+                            complete_tx,
+                        );
+                        drop(permit);
+                    }
                     Message::Read(path, tx) => self.read(path, tx),
+                    Message::ReadStream(path, tx) => self.read_stream(path, tx),
                     Message::ReadMetadata(path, tx) => {
                         self.read_metadata(path, tx)
                     }
+                    Message::ReadMetadataBatch(paths, tx) => {
+                        self.read_metadata_batch(paths, tx)
+                    }
+                    Message::Stat(path, tx) => self.stat(path, tx),
+                    Message::Exists(path, tx) => self.exists(path, tx),
                     Message::List(path, tx) => self.list(path, tx),
                     Message::ListRecursively(path, tx) => {
                         self.list_recursively(path, tx)
@@ -415,52 +1280,118 @@ impl AsyncIOThread {
                     Message::Rename(src_path, dst_path, tx) => {
                         self.rename(src_path, dst_path, tx)
                     }
+                    Message::Batch(ops, tx) => self.batch(ops, tx),
                 }
+                self.log = log;
             } else {
                 break;
             }
         }
     }
 
+    /// Fail if `path` exists and was modified within `window`
+    fn check_overwrite_window(
+        &self,
+        path: &Path,
+        window: Option<Duration>,
+    ) -> io::Result<()> {
+        let window = match window {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+
+        let modified = match self.backend.borrow_mut().stat(path.to_owned())? {
+            Some(metadata) => metadata.modified.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "backend does not track modification time",
+                )
+            })?,
+            None => return Ok(()),
+        };
+
+        // modification time in the future (clock skew) counts as fresh
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age < window {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} modified {}s ago, within overwrite protection window",
+                    path.display(),
+                    age.as_secs()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
     fn write_inner(
         &mut self,
         path: PathBuf,
         sg: SGData,
         idempotent: bool,
+        protect: Option<Duration>,
+        durability: DurabilityMode,
     ) -> io::Result<()> {
         // check `in_progress` and add atomically
         // if not already there
-        loop {
+        if self.shared.start_in_progress(&path, !idempotent).is_none() {
+            // being written by another worker
             let mut sh = self.shared.inner.lock().unwrap();
+            sh.write_stats.deduped_bytes += sg.len() as u64;
+            sh.write_stats.deduped_chunks += 1;
+            sh.record_history();
+            drop(sh);
+            self.shared.report(ProgressEvent::ChunkWritten {
+                bytes: sg.len() as u64,
+                deduplicated: true,
+            });
+            return Ok(());
+        }
 
-            if sh.in_progress.contains(&path) {
-                if idempotent {
-                    return Ok(());
-                } else {
-                    // a bit lame, but will do, since this should not really
-                    // happen in practice anyway
-                    drop(sh);
-                    thread::sleep(std::time::Duration::from_millis(1000));
-                }
-            } else {
-                sh.in_progress.insert(path.clone());
-                break;
-            }
+        if let Err(e) = self.check_overwrite_window(&path, protect) {
+            let mut sh = self.shared.inner.lock().unwrap();
+            self.shared.finish_in_progress(&mut sh, &path);
+            return Err(e);
         }
 
         let len = sg.len();
-        let res = self
-            .backend
-            .borrow_mut()
-            .write(path.clone(), sg, idempotent);
+        self.shared.throttle_write(len as u64);
+        let res = self.backend.borrow_mut().write_durable(
+            path.clone(),
+            sg,
+            idempotent,
+            durability,
+        );
         {
             let mut sh = self.shared.inner.lock().unwrap();
-            sh.in_progress.remove(&path);
-            sh.write_stats.new_bytes += len as u64;
-            sh.write_stats.new_chunks += 1;
+            self.shared.finish_in_progress(&mut sh, &path);
+            if let Some(cache) = sh.read_cache.as_mut() {
+                cache.invalidate(&path);
+            }
+            match res {
+                Ok(WriteOutcome::Written) => {
+                    sh.write_stats.new_bytes += len as u64;
+                    sh.write_stats.new_chunks += 1;
+                }
+                Ok(WriteOutcome::AlreadyPresent) => {
+                    sh.write_stats.deduped_bytes += len as u64;
+                    sh.write_stats.deduped_chunks += 1;
+                }
+                Err(_) => {}
+            }
+            sh.record_history();
+        }
+        if let Ok(outcome) = res {
+            self.shared.report(ProgressEvent::ChunkWritten {
+                bytes: len as u64,
+                deduplicated: outcome == WriteOutcome::AlreadyPresent,
+            });
         }
 
-        res
+        res.map(|_| ())
     }
 
     fn write(
@@ -468,18 +1399,27 @@ impl AsyncIOThread {
         path: PathBuf,
         sg: SGData,
         idempotent: bool,
+        protect: Option<Duration>,
+        durability: DurabilityMode,
         tx: Option<mpsc::Sender<io::Result<()>>>,
     ) {
         trace!(self.log, "write"; "path" => %path.display());
 
         self.time_reporter.start("read");
-        let res = self.write_inner(path, sg, idempotent);
+        let res = self.write_inner(path, sg, idempotent, protect, durability);
 
         if let Some(tx) = tx {
             self.time_reporter.start("write send response");
             tx.send(res).expect("send failed")
-        } else {
-            res.unwrap();
+        } else if let Err(e) = res {
+            let msg = e.to_string();
+            self.shared
+                .inner
+                .lock()
+                .unwrap()
+                .write_error
+                .get_or_insert(e);
+            panic!("write failed: {}", msg);
         }
     }
 
@@ -487,19 +1427,7 @@ impl AsyncIOThread {
         &'a self,
         path: &'path PathBuf,
     ) -> PendingGuard<'a, 'path> {
-        loop {
-            let mut sh = self.shared.inner.lock().unwrap();
-
-            if sh.in_progress.contains(path) {
-                // a bit lame, but will do, since this should not really
-                // happen in practice anyway
-                drop(sh);
-                thread::sleep(std::time::Duration::from_millis(1000));
-            } else {
-                sh.in_progress.insert(path.clone());
-                break;
-            }
-        }
+        self.shared.start_in_progress(path, true);
         PendingGuard(self, path)
     }
 
@@ -507,11 +1435,61 @@ impl AsyncIOThread {
         trace!(self.log, "read"; "path" => %path.display());
 
         self.time_reporter.start("read");
+        let res = match self.read_cached(&path) {
+            Some(sg) => Ok(sg),
+            None => self.read_backend(&path),
+        };
+        if let Ok(ref sg) = res {
+            self.shared.report(ProgressEvent::ChunkRead {
+                bytes: sg.len() as u64,
+            });
+        }
+        self.time_reporter.start("read send response");
+        tx.send(res).expect("send failed")
+    }
+
+    fn read_backend(&self, path: &PathBuf) -> io::Result<SGData> {
+        let _guard = self.pending_wait_and_insert(path);
+        // another read of the same path might have been waited for
+        if let Some(sg) = self.read_cached(path) {
+            return Ok(sg);
+        }
+        let res = self.backend.borrow_mut().read(path.clone());
+        if let Ok(ref sg) = res {
+            // cached before other reads waiting for the path get to it
+            let mut sh = self.shared.inner.lock().unwrap();
+            sh.read_stats.chunks_read += 1;
+            sh.read_stats.bytes_read += sg.len() as u64;
+            if let Some(cache) = sh.read_cache.as_mut() {
+                cache.insert(path, sg.clone());
+            }
+            sh.record_history();
+        }
+        res
+    }
+
+    /// Data of `path` from the read cache, if there
+    fn read_cached(&self, path: &Path) -> Option<SGData> {
+        let mut sh = self.shared.inner.lock().unwrap();
+        let sg = sh.read_cache.as_mut()?.get(path)?;
+        sh.read_stats.cached_reads += 1;
+        sh.record_history();
+        Some(sg)
+    }
+
+    fn read_stream(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Box<dyn io::Read + Send>>>,
+    ) {
+        trace!(self.log, "read-stream"; "path" => %path.display());
+
+        self.time_reporter.start("read-stream");
         let res = {
             let _guard = self.pending_wait_and_insert(&path);
-            self.backend.borrow_mut().read(path.clone())
+            self.backend.borrow_mut().read_stream(path.clone())
         };
-        self.time_reporter.start("read send response");
+        self.time_reporter.start("read-stream send response");
         tx.send(res).expect("send failed")
     }
 
@@ -532,6 +1510,57 @@ impl AsyncIOThread {
         tx.send(res).expect("send failed")
     }
 
+    fn read_metadata_batch(
+        &mut self,
+        paths: Vec<PathBuf>,
+        tx: mpsc::Sender<io::Result<Vec<io::Result<Metadata>>>>,
+    ) {
+        trace!(self.log, "read-metadata-batch"; "paths" => paths.len());
+
+        self.time_reporter.start("read-metadata-batch");
+        let res = paths
+            .into_iter()
+            .map(|path| {
+                let _guard = self.pending_wait_and_insert(&path);
+                self.backend.borrow_mut().read_metadata(path.clone())
+            })
+            .collect();
+
+        self.time_reporter
+            .start("read-metadata-batch send response");
+        tx.send(Ok(res)).expect("send failed")
+    }
+
+    fn stat(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Option<Metadata>>>,
+    ) {
+        trace!(self.log, "stat"; "path" => %path.display());
+
+        self.time_reporter.start("stat");
+        let res = {
+            let _guard = self.pending_wait_and_insert(&path);
+            self.backend.borrow_mut().stat(path.clone())
+        };
+
+        self.time_reporter.start("stat send response");
+        tx.send(res).expect("send failed")
+    }
+
+    fn exists(&mut self, path: PathBuf, tx: mpsc::Sender<io::Result<bool>>) {
+        trace!(self.log, "exists"; "path" => %path.display());
+
+        self.time_reporter.start("exists");
+        let res = {
+            let _guard = self.pending_wait_and_insert(&path);
+            self.backend.borrow_mut().exists(path.clone())
+        };
+
+        self.time_reporter.start("exists send response");
+        tx.send(res).expect("send failed")
+    }
+
     fn list(
         &mut self,
         path: PathBuf,
@@ -545,15 +1574,14 @@ impl AsyncIOThread {
         tx.send(res).expect("send failed")
     }
 
-    fn list_recursively(
-        &mut self,
-        path: PathBuf,
-        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
-    ) {
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
         trace!(self.log, "list"; "path" => %path.display());
         self.time_reporter.start("list");
 
-        self.backend.borrow_mut().list_recursively(path, tx)
+        let progress = self.shared.inner.lock().unwrap().progress.clone();
+        self.backend
+            .borrow_mut()
+            .list_recursively(path, tx.with_progress(progress))
     }
 
     fn remove(&mut self, path: PathBuf, tx: mpsc::Sender<io::Result<()>>) {
@@ -562,7 +1590,9 @@ impl AsyncIOThread {
         self.time_reporter.start("remove");
         let res = {
             let _guard = self.pending_wait_and_insert(&path);
-            self.backend.borrow_mut().remove(path.clone())
+            let res = self.backend.borrow_mut().remove(path.clone());
+            self.shared.invalidate_cached(&path);
+            res
         };
         self.time_reporter.start("remove send response");
         tx.send(res).expect("send failed")
@@ -576,7 +1606,8 @@ impl AsyncIOThread {
         trace!(self.log, "remove-dir-all"; "path" => %path.display());
 
         self.time_reporter.start("remove-dir-all");
-        let res = self.backend.borrow_mut().remove_dir_all(path);
+        let res = self.backend.borrow_mut().remove_dir_all(path.clone());
+        self.shared.invalidate_cached(&path);
 
         self.time_reporter.start("remove send response");
         tx.send(res).expect("send failed")
@@ -599,13 +1630,85 @@ impl AsyncIOThread {
         let res = {
             let _guard = self.pending_wait_and_insert(&src_path);
             let _guard = self.pending_wait_and_insert(&dst_path);
-            self.backend
+            let res = self
+                .backend
                 .borrow_mut()
-                .rename(src_path.clone(), dst_path.clone())
+                .rename(src_path.clone(), dst_path.clone());
+            self.shared.invalidate_cached(&src_path);
+            self.shared.invalidate_cached(&dst_path);
+            res
         };
         self.time_reporter.start("remove send response");
         tx.send(res).expect("send failed")
     }
+
+    fn batch(&mut self, ops: Vec<BatchOp>, tx: mpsc::Sender<io::Result<()>>) {
+        trace!(self.log, "batch"; "ops" => ops.len());
+
+        self.time_reporter.start("batch");
+        // sorted, so batches sharing paths wait for each other in the
+        // same order
+        let mut changed: Vec<PathBuf> = ops
+            .iter()
+            .flat_map(|op| match op {
+                BatchOp::Write { path, .. } | BatchOp::Remove(path) => {
+                    vec![path.clone()]
+                }
+                BatchOp::Rename { src_path, dst_path } => {
+                    vec![src_path.clone(), dst_path.clone()]
+                }
+            })
+            .collect();
+        changed.sort();
+        changed.dedup();
+        let write_lens: Vec<Option<u64>> = ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Write { sg, .. } => Some(sg.len() as u64),
+                _ => None,
+            })
+            .collect();
+        self.shared
+            .throttle_write(write_lens.iter().flatten().sum());
+
+        let res = {
+            let _guards: Vec<_> = changed
+                .iter()
+                .map(|path| self.pending_wait_and_insert(path))
+                .collect();
+            let res = self.backend.borrow_mut().batch(ops);
+            for path in &changed {
+                self.shared.invalidate_cached(path);
+            }
+            res
+        };
+
+        // A batch doesn't tell if an idempotent write found the object
+        // already stored, so all the writes applied count as new.
+        let completed = match res {
+            Ok(()) => write_lens.len(),
+            Err(ref e) => e.completed,
+        };
+        let written: Vec<u64> =
+            write_lens[..completed].iter().flatten().copied().collect();
+        {
+            let mut sh = self.shared.inner.lock().unwrap();
+            for &len in &written {
+                sh.write_stats.new_bytes += len;
+                sh.write_stats.new_chunks += 1;
+            }
+            sh.record_history();
+        }
+        for len in written {
+            self.shared.report(ProgressEvent::ChunkWritten {
+                bytes: len,
+                deduplicated: false,
+            });
+        }
+
+        self.time_reporter.start("batch send response");
+        tx.send(res.map_err(Into::into)).expect("send failed")
+    }
 }
 // }}}
 
@@ -0,0 +1,115 @@
+//! Backend storing nothing, for benchmarking
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sgdata::SGData;
+
+use super::{Backend, BackendThread, ListSender, WriteOutcome};
+use super::{Lock, Metadata};
+
+struct NullLock;
+
+impl Lock for NullLock {}
+
+/// Backend discarding everything written to it
+///
+/// No object ever exists, so everything written is new (and counted as
+/// such in `WriteStats`). Useful to measure the chunking, hashing,
+/// compression and encryption throughput without the cost of storage.
+///
+/// Reads fail with `NotFound`, unless `with_read_len` is used to make them
+/// return zeros instead. Recursive listings always fail with `NotFound`.
+#[derive(Debug, Default)]
+pub struct Null {
+    read_len: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct NullThread {
+    read_len: Option<usize>,
+}
+
+impl Backend for Null {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(NullLock))
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(NullLock))
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(NullThread {
+            read_len: self.read_len,
+        }))
+    }
+}
+
+impl Null {
+    pub fn new() -> Self {
+        Null { read_len: None }
+    }
+
+    /// Make every read return `len` zeros
+    ///
+    /// Note that stores to a name will fail, since the name would then
+    /// always seem to exist already.
+    pub fn with_read_len(len: usize) -> Self {
+        Null {
+            read_len: Some(len),
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not stored by the null backend", path.display()),
+    )
+}
+
+impl BackendThread for NullThread {
+    fn remove_dir_all(&mut self, _path: PathBuf) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        _src_path: PathBuf,
+        _dst_path: PathBuf,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        _path: PathBuf,
+        _sg: SGData,
+        _idempotent: bool,
+    ) -> io::Result<WriteOutcome> {
+        Ok(WriteOutcome::Written)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        match self.read_len {
+            Some(len) => Ok(SGData::from_single(vec![0u8; len])),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn remove(&mut self, _path: PathBuf) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        Err(not_found(&path))
+    }
+
+    fn list(&mut self, _path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
+        tx.send(Err(not_found(&path))).expect("send failed")
+    }
+}
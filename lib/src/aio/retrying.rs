@@ -0,0 +1,197 @@
+//! Backend retrying operations that fail with transient errors
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{io, thread};
+
+use sgdata::SGData;
+
+use super::{Backend, BackendThread, DurabilityMode, FsStats, ListSender};
+use super::{Lock, LockInfo, Metadata, WriteOutcome};
+
+/// When and how often to retry a failed operation
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts at an operation in total, including the first one
+    pub attempts: u32,
+    /// Wait before the first retry, doubled before each next one
+    pub backoff: Duration,
+    /// Tells if an error of the kind is transient, and worth a retry
+    pub retryable: fn(io::ErrorKind) -> bool,
+}
+
+impl RetryPolicy {
+    /// Call `f` until it succeeds, fails with an error that is not
+    /// retryable, or runs out of attempts
+    fn retry<T>(&self, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(ref e)
+                    if attempt < self.attempts
+                        && (self.retryable)(e.kind()) =>
+                {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Backend retrying the failed operations of another one
+///
+/// Meant for storage with transient failures, like network filesystems.
+/// Every operation of the `inner` backend failing with an error the
+/// `RetryPolicy` deems transient is tried again, after a growing wait.
+/// Other errors fail the operation right away. Locking, and recursive
+/// listings (which can fail after sending part of the paths), are not
+/// retried.
+pub struct Retrying {
+    inner: Box<dyn Backend>,
+    policy: RetryPolicy,
+}
+
+pub struct RetryingThread {
+    inner: Box<dyn BackendThread>,
+    policy: RetryPolicy,
+}
+
+impl Retrying {
+    pub fn new(inner: Box<dyn Backend>, policy: RetryPolicy) -> Self {
+        assert!(policy.attempts > 0);
+        Retrying { inner, policy }
+    }
+}
+
+impl Backend for Retrying {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive()
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared()
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.inner.try_lock_exclusive()
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        self.inner.try_lock_shared()
+    }
+
+    fn lock_owner(&self) -> io::Result<Option<LockInfo>> {
+        self.inner.lock_owner()
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(RetryingThread {
+            inner: self.policy.retry(|| self.inner.new_thread())?,
+            policy: self.policy,
+        }))
+    }
+
+    fn remove_orphaned_tmp(&self) -> io::Result<usize> {
+        self.policy.retry(|| self.inner.remove_orphaned_tmp())
+    }
+
+    fn stat_fs(&self) -> io::Result<FsStats> {
+        self.policy.retry(|| self.inner.stat_fs())
+    }
+}
+
+impl BackendThread for RetryingThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.policy.retry(|| inner.remove_dir_all(path.clone()))
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.policy
+            .retry(|| inner.rename(src_path.clone(), dst_path.clone()))
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<WriteOutcome> {
+        let inner = &mut self.inner;
+        self.policy
+            .retry(|| inner.write(path.clone(), sg.clone(), idempotent))
+    }
+
+    fn write_durable(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+        mode: DurabilityMode,
+    ) -> io::Result<WriteOutcome> {
+        let inner = &mut self.inner;
+        self.policy.retry(|| {
+            inner.write_durable(path.clone(), sg.clone(), idempotent, mode)
+        })
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        let inner = &mut self.inner;
+        self.policy.retry(|| inner.read(path.clone()))
+    }
+
+    fn read_stream(
+        &mut self,
+        path: PathBuf,
+    ) -> io::Result<Box<dyn io::Read + Send>> {
+        let inner = &mut self.inner;
+        self.policy.retry(|| inner.read_stream(path.clone()))
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.policy.retry(|| inner.remove(path.clone()))
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        let inner = &mut self.inner;
+        self.policy.retry(|| inner.read_metadata(path.clone()))
+    }
+
+    fn stat(&mut self, path: PathBuf) -> io::Result<Option<Metadata>> {
+        let inner = &mut self.inner;
+        self.policy.retry(|| inner.stat(path.clone()))
+    }
+
+    fn exists(&mut self, path: PathBuf) -> io::Result<bool> {
+        let inner = &mut self.inner;
+        self.policy.retry(|| inner.exists(path.clone()))
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        let inner = &mut self.inner;
+        self.policy.retry(|| inner.list(path.clone()))
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
+        self.inner.list_recursively(path, tx)
+    }
+
+    fn copy_prefix(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.policy
+            .retry(|| inner.copy_prefix(src_path.clone(), dst_path.clone()))
+    }
+}
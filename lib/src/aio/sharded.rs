@@ -0,0 +1,357 @@
+//! Backend partitioning chunks between several backends
+use std::collections::HashSet;
+use std::path::{self, Path, PathBuf};
+use std::sync::Mutex;
+use std::{ffi, io};
+
+use serde::{Deserialize, Serialize};
+use sgdata::SGData;
+
+use super::{Backend, BackendThread, DurabilityMode, FsStats, ListSender};
+use super::{Lock, LockInfo, Metadata, WriteOutcome};
+use crate::config;
+use crate::DIGEST_SIZE;
+
+/// Routing of the shards, stored in the first one
+pub(crate) const SHARDS_YML_FILE: &str = "shards.yml";
+
+/// Number of leading digest bytes routing a chunk
+const PREFIX_BYTES: usize = 2;
+
+/// Routing function of a `Sharded` backend
+///
+/// Recorded when a repo is created, so opening it with a different
+/// number of shards fails, instead of looking for chunks in the wrong
+/// ones.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Routing {
+    shards: usize,
+    /// A chunk is stored in the shard indexed by the big-endian number
+    /// made of this many leading bytes of its digest, modulo `shards`
+    prefix_bytes: usize,
+}
+
+/// Locks of all the shards, released together
+impl Lock for Vec<Box<dyn Lock>> {}
+
+/// Backend storing each chunk in one of several backends, by its digest
+///
+/// Chunks are routed by the leading bytes of their digest (see
+/// `Routing`), so they are always written to, read from, and checked for
+/// in the same shard. All the other objects (config, names, ...) are
+/// stored in the first shard, along with the routing. Listings and
+/// directory removals span all the shards, and locks are taken on all of
+/// them, in order.
+pub struct Sharded {
+    shards: Vec<Box<dyn Backend>>,
+    /// Set once the routing is checked against the recorded one
+    checked: Mutex<bool>,
+}
+
+pub struct ShardedThread {
+    shards: Vec<Box<dyn BackendThread>>,
+}
+
+impl Sharded {
+    pub fn new(shards: Vec<Box<dyn Backend>>) -> Self {
+        assert!(!shards.is_empty(), "no shards");
+        Sharded {
+            shards,
+            checked: Mutex::new(false),
+        }
+    }
+
+    fn routing(&self) -> Routing {
+        Routing {
+            shards: self.shards.len(),
+            prefix_bytes: PREFIX_BYTES,
+        }
+    }
+
+    /// Index of the shard storing the object at `path`
+    pub fn shard(&self, path: &Path) -> usize {
+        shard(path, self.shards.len())
+    }
+
+    /// Record the routing in the first shard, or check it's the one
+    /// recorded already
+    fn check_routing(&self, thread: &mut dyn BackendThread) -> io::Result<()> {
+        let mut checked = self.checked.lock().unwrap();
+        if *checked {
+            return Ok(());
+        }
+
+        let path = PathBuf::from(SHARDS_YML_FILE);
+        match thread.read(path.clone()) {
+            Ok(data) => {
+                let recorded: Routing = serde_yaml::from_slice(
+                    &data.to_linear_vec(),
+                )
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("couldn't parse {}: {}", SHARDS_YML_FILE, e),
+                    )
+                })?;
+                if recorded != self.routing() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "repo is sharded between {} backends, not {}",
+                            recorded.shards,
+                            self.shards.len()
+                        ),
+                    ));
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let routing = serde_yaml::to_string(&self.routing())
+                    .expect("yaml serialization failed");
+                thread.write(
+                    path,
+                    SGData::from_single(routing.into_bytes()),
+                    false,
+                )?;
+            }
+            Err(e) => return Err(e),
+        }
+        *checked = true;
+        Ok(())
+    }
+}
+
+/// Digest of the chunk stored at `path`, if it's one
+fn chunk_digest(path: &Path) -> Option<Vec<u8>> {
+    let mut components = path.iter();
+    components.next()?;
+    if components.next()? != ffi::OsStr::new(config::DATA_SUBDIR) {
+        return None;
+    }
+    let digest = hex::decode(path.file_name()?.to_str()?).ok()?;
+    if digest.len() == DIGEST_SIZE {
+        Some(digest)
+    } else {
+        None
+    }
+}
+
+fn shard(path: &Path, shards: usize) -> usize {
+    match chunk_digest(path) {
+        Some(digest) => {
+            let prefix = digest[..PREFIX_BYTES]
+                .iter()
+                .fold(0usize, |prefix, &byte| prefix << 8 | byte as usize);
+            prefix % shards
+        }
+        None => 0,
+    }
+}
+
+impl Backend for Sharded {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        let locks = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock_exclusive())
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Box::new(locks))
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        let locks = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock_shared())
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Box::new(locks))
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        let locks = self
+            .shards
+            .iter()
+            .map(|shard| shard.try_lock_exclusive())
+            .collect::<io::Result<Option<Vec<_>>>>()?;
+        Ok(locks.map(|locks| Box::new(locks) as Box<dyn Lock>))
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Option<Box<dyn Lock>>> {
+        let locks = self
+            .shards
+            .iter()
+            .map(|shard| shard.try_lock_shared())
+            .collect::<io::Result<Option<Vec<_>>>>()?;
+        Ok(locks.map(|locks| Box::new(locks) as Box<dyn Lock>))
+    }
+
+    /// The owner of the first shard's lock that is known
+    fn lock_owner(&self) -> io::Result<Option<LockInfo>> {
+        for shard in &self.shards {
+            if let Some(owner) = shard.lock_owner()? {
+                return Ok(Some(owner));
+            }
+        }
+        Ok(None)
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        let mut shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.new_thread())
+            .collect::<io::Result<Vec<_>>>()?;
+        self.check_routing(&mut *shards[0])?;
+        Ok(Box::new(ShardedThread { shards }))
+    }
+
+    fn remove_orphaned_tmp(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        for shard in &self.shards {
+            removed += shard.remove_orphaned_tmp()?;
+        }
+        Ok(removed)
+    }
+
+    /// Space of all the shards, added up
+    ///
+    /// Shards on the same filesystem each count all of its space.
+    fn stat_fs(&self) -> io::Result<FsStats> {
+        let mut total = FsStats {
+            total: 0,
+            available: 0,
+        };
+        for shard in &self.shards {
+            let stats = shard.stat_fs()?;
+            total.total += stats.total;
+            total.available += stats.available;
+        }
+        Ok(total)
+    }
+}
+
+impl ShardedThread {
+    fn shard(&mut self, path: &Path) -> &mut dyn BackendThread {
+        let i = shard(path, self.shards.len());
+        &mut *self.shards[i]
+    }
+}
+
+impl BackendThread for ShardedThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        let mut removed = false;
+        let mut not_found = None;
+        for shard in &mut self.shards {
+            match shard.remove_dir_all(path.clone()) {
+                Ok(()) => removed = true,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    not_found = Some(e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        match not_found {
+            Some(e) if !removed => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let src = shard(&src_path, self.shards.len());
+        let dst = shard(&dst_path, self.shards.len());
+        if src == dst {
+            return self.shards[src].rename(src_path, dst_path);
+        }
+
+        let sg = self.shards[src].read(src_path.clone())?;
+        self.shards[dst].write(dst_path, sg, false)?;
+        self.shards[src].remove(src_path)
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<WriteOutcome> {
+        self.shard(&path).write(path, sg, idempotent)
+    }
+
+    fn write_durable(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+        mode: DurabilityMode,
+    ) -> io::Result<WriteOutcome> {
+        self.shard(&path).write_durable(path, sg, idempotent, mode)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        self.shard(&path).read(path)
+    }
+
+    fn read_stream(
+        &mut self,
+        path: PathBuf,
+    ) -> io::Result<Box<dyn io::Read + Send>> {
+        self.shard(&path).read_stream(path)
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.shard(&path).remove(path)
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.shard(&path).read_metadata(path)
+    }
+
+    fn stat(&mut self, path: PathBuf) -> io::Result<Option<Metadata>> {
+        self.shard(&path).stat(path)
+    }
+
+    fn exists(&mut self, path: PathBuf) -> io::Result<bool> {
+        self.shard(&path).exists(path)
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        // The routing is kept out of sight, so the repo still looks empty
+        // when it's created
+        let is_root = path
+            .components()
+            .all(|component| component == path::Component::CurDir);
+        let mut names = HashSet::new();
+        if is_root {
+            names.insert(Some(ffi::OsString::from(SHARDS_YML_FILE)));
+        }
+        let mut list = vec![];
+        for shard in &mut self.shards {
+            for listed in shard.list(path.clone())? {
+                let name = listed.file_name().map(|name| name.to_owned());
+                if names.insert(name) {
+                    list.push(listed);
+                }
+            }
+        }
+        Ok(list)
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: ListSender) {
+        // missing from some of the shards is fine, as long as it's in one
+        let mut listed = false;
+        for shard in &mut self.shards {
+            if let Ok(None) = shard.stat(path.clone()) {
+                continue;
+            }
+            shard.list_recursively(path.clone(), tx.clone());
+            listed = true;
+        }
+        if !listed {
+            // reports it missing
+            self.shards[0].list_recursively(path, tx);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Self-contained archive of a single stored name
+//!
+//! Layout (integers are big-endian):
+//!
+//! ```text
+//! archive := MAGIC version:u32 header-len:u64 header frame* end
+//! frame   := kind:u8 digest:[u8; DIGEST_SIZE] len:u64 data:[u8; len]
+//! end     := 0:u8
+//! ```
+//!
+//! `header` is YAML (see `Header`), `kind` is `1` for data and `2` for
+//! index chunks. Chunks are stored as plaintext (decrypted and
+//! decompressed), so they can be imported into a repo with different
+//! encryption and compression settings.
+use std::io;
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::util::{as_hex, from_hex};
+use crate::{DataAddress, DataType, Digest, DigestRef, DIGEST_SIZE};
+
+const MAGIC: &[u8; 8] = b"RDEDUPAR";
+const VERSION: u32 = 1;
+
+const KIND_END: u8 = 0;
+const KIND_DATA: u8 = 1;
+const KIND_INDEX: u8 = 2;
+
+/// Largest header accepted, to not allocate garbage lengths
+const HEADER_LEN_MAX: u64 = 1024 * 1024;
+
+/// Parameters of the exporting repo needed to interpret the archive
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Header {
+    /// Digests in the archive (and in the index chunks) are calculated
+    /// with it
+    pub(crate) hashing: config::Hashing,
+    pub(crate) index_format: config::IndexFormat,
+    /// See `config::Repo::chunking_fingerprint`
+    pub(crate) chunking: String,
+    #[serde(serialize_with = "as_hex", deserialize_with = "from_hex")]
+    pub(crate) digest: Vec<u8>,
+    pub(crate) index_level: u32,
+}
+
+impl Header {
+    pub(crate) fn new(config: &config::Repo, address: &DataAddress) -> Self {
+        Header {
+            hashing: config.hashing,
+            index_format: config.index_format,
+            chunking: config.chunking_fingerprint(),
+            digest: address.digest.0.clone(),
+            index_level: address.index_level,
+        }
+    }
+
+    pub(crate) fn data_address(&self) -> DataAddress {
+        DataAddress {
+            digest: Digest(self.digest.clone()),
+            index_level: self.index_level,
+        }
+    }
+
+    pub(crate) fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let header = serde_yaml::to_string(self)
+            .expect("yaml serialization failed")
+            .into_bytes();
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        writer.write_all(&(header.len() as u64).to_be_bytes())?;
+        writer.write_all(&header)
+    }
+
+    pub(crate) fn read(reader: &mut dyn Read) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not an rdedup archive".into()));
+        }
+
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_be_bytes(version);
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported archive version {}",
+                version
+            )));
+        }
+
+        let len = read_u64(reader)?;
+        if len > HEADER_LEN_MAX {
+            return Err(invalid_data("archive header too long".into()));
+        }
+        let header = read_exact_vec(reader, len)?;
+        serde_yaml::from_slice(&header).map_err(|e| {
+            invalid_data(format!("couldn't parse archive header: {}", e))
+        })
+    }
+}
+
+/// Chunk stored in the archive
+pub(crate) struct Chunk {
+    pub(crate) data_type: DataType,
+    pub(crate) digest: Vec<u8>,
+    pub(crate) data: Vec<u8>,
+}
+
+pub(crate) fn write_chunk(
+    writer: &mut dyn Write,
+    data_type: DataType,
+    digest: DigestRef<'_>,
+    data: &[u8],
+) -> io::Result<()> {
+    assert_eq!(digest.0.len(), DIGEST_SIZE);
+    let kind = match data_type {
+        DataType::Data => KIND_DATA,
+        DataType::Index => KIND_INDEX,
+    };
+
+    writer.write_all(&[kind])?;
+    writer.write_all(digest.0)?;
+    writer.write_all(&(data.len() as u64).to_be_bytes())?;
+    writer.write_all(data)
+}
+
+pub(crate) fn write_end(writer: &mut dyn Write) -> io::Result<()> {
+    writer.write_all(&[KIND_END])
+}
+
+/// Read the next chunk, or `None` at the end of the archive
+pub(crate) fn read_chunk(reader: &mut dyn Read) -> io::Result<Option<Chunk>> {
+    let mut kind = [0u8; 1];
+    reader.read_exact(&mut kind)?;
+    let data_type = match kind[0] {
+        KIND_END => return Ok(None),
+        KIND_DATA => DataType::Data,
+        KIND_INDEX => DataType::Index,
+        kind => {
+            return Err(invalid_data(format!(
+                "unknown archive frame kind {}",
+                kind
+            )))
+        }
+    };
+
+    let digest = read_exact_vec(reader, DIGEST_SIZE as u64)?;
+    let len = read_u64(reader)?;
+    let data = read_exact_vec(reader, len)?;
+
+    Ok(Some(Chunk {
+        data_type,
+        digest,
+        data,
+    }))
+}
+
+fn read_u64(reader: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Like `read_exact`, but without trusting `len` for the allocation
+fn read_exact_vec(reader: &mut dyn Read, len: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    reader.take(len).read_to_end(&mut data)?;
+    if (data.len() as u64) != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "archive truncated",
+        ));
+    }
+    Ok(data)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
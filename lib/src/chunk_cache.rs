@@ -0,0 +1,132 @@
+//! Local cache of the chunks of files written with `Repo::write_file`
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::index::IndexEntry;
+use crate::util::{as_hex, from_hex};
+use crate::Digest;
+
+#[derive(Serialize, Deserialize)]
+struct CachedChunk {
+    #[serde(serialize_with = "as_hex", deserialize_with = "from_hex")]
+    digest: Vec<u8>,
+    len: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedFile {
+    len: u64,
+    modified: SystemTime,
+    chunks: Vec<CachedChunk>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CacheData {
+    /// Chunking settings the chunks were produced with
+    params: Option<String>,
+    files: BTreeMap<PathBuf, CachedFile>,
+}
+
+/// Chunks of files, by path
+///
+/// An entry is valid as long as the size and the modification time of
+/// the file don't change. Kept in a local file (not in the repo), so it
+/// can be reused between repos using the same chunking settings.
+pub struct ChunkCache {
+    path: PathBuf,
+    data: CacheData,
+}
+
+impl ChunkCache {
+    /// Load the cache from `path`, or start an empty one if it's missing
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let data = match fs::read(&path) {
+            Ok(bytes) => serde_yaml::from_slice(&bytes).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("couldn't parse yaml: {}", e),
+                )
+            })?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                CacheData::default()
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(ChunkCache { path, data })
+    }
+
+    /// Write the cache back to the file it was opened from
+    pub fn save(&self) -> io::Result<()> {
+        let serialized_str = serde_yaml::to_string(&self.data)
+            .expect("yaml serialization failed");
+
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serialized_str)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    pub(crate) fn get(
+        &self,
+        path: &Path,
+        len: u64,
+        modified: SystemTime,
+        params: &str,
+    ) -> Option<Vec<IndexEntry>> {
+        if self.data.params.as_deref() != Some(params) {
+            return None;
+        }
+
+        self.data
+            .files
+            .get(path)
+            .filter(|file| file.len == len && file.modified == modified)
+            .map(|file| {
+                file.chunks
+                    .iter()
+                    .map(|chunk| {
+                        IndexEntry::new(Digest(chunk.digest.clone()), chunk.len)
+                    })
+                    .collect()
+            })
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        path: &Path,
+        len: u64,
+        modified: SystemTime,
+        params: &str,
+        entries: Vec<IndexEntry>,
+    ) {
+        if self.data.params.as_deref() != Some(params) {
+            self.data = CacheData {
+                params: Some(params.to_owned()),
+                files: BTreeMap::new(),
+            };
+        }
+
+        let chunks = entries
+            .into_iter()
+            .map(|entry| CachedChunk {
+                digest: entry.digest.0,
+                len: entry.len.expect("written chunk length known"),
+            })
+            .collect();
+
+        self.data.files.insert(
+            path.to_owned(),
+            CachedFile {
+                len,
+                modified,
+                chunks,
+            },
+        );
+    }
+}
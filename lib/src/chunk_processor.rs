@@ -1,4 +1,3 @@
-use std::io;
 use std::sync::mpsc;
 
 use sgdata::SGData;
@@ -10,12 +9,16 @@ use super::{DataType, Repo};
 use crate::compression::ArcCompression;
 use crate::encryption::ArcEncrypter;
 use crate::hashing::ArcHasher;
+use crate::index::IndexEntry;
+use crate::util::MemoryPermit;
 use crate::{Digest, Generation};
 
 pub(crate) struct Message {
     pub data: (u64, SGData),
     pub data_type: DataType,
-    pub response_tx: mpsc::Sender<(u64, Digest)>,
+    pub response_tx: mpsc::Sender<(u64, IndexEntry)>,
+    /// Accounts for `data` until it's written (or found to exist)
+    pub permit: MemoryPermit,
 }
 
 pub(crate) struct ChunkProcessor {
@@ -73,10 +76,12 @@ impl ChunkProcessor {
                     data,
                     response_tx,
                     data_type,
+                    permit,
                 } = input;
                 let (sg_id, sg) = data;
 
                 let digest = Digest(self.hasher.calculate_digest(&sg));
+                let len = sg.len() as u64;
 
                 let mut found = false;
                 // lookup all generations in order, starting from current one
@@ -92,8 +97,12 @@ impl ChunkProcessor {
                         digest.as_digest_ref(),
                         gen_str,
                     );
-                    match self.aio.read_metadata(chunk_path.clone()).wait() {
-                        Ok(_metadata) => {
+                    let res = {
+                        let _permit = self.repo.probe_permit();
+                        self.aio.exists(chunk_path.clone()).wait()
+                    };
+                    match res {
+                        Ok(true) => {
                             found = true;
                             if gen_str == &last_gen_str {
                                 trace!(self.log, "already exists"; "path" => %chunk_path.display());
@@ -134,9 +143,9 @@ impl ChunkProcessor {
                             }
                             break;
                         }
-                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Ok(false) => {}
                         Err(e) => panic!(
-                            "read_metadata failed for {}, err: {}",
+                            "exists failed for {}, err: {}",
                             chunk_path.display(),
                             e
                         ),
@@ -171,11 +180,12 @@ impl ChunkProcessor {
                             &last_gen_str,
                         ),
                         sg,
+                        permit,
                     );
                 }
                 timer.start("tx-digest");
                 response_tx
-                    .send((sg_id, digest))
+                    .send((sg_id, IndexEntry::new(digest, len)))
                     .expect("chunk_processor: digests_tx.send")
             } else {
                 return;
@@ -1,8 +1,10 @@
-use std::mem;
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::{cmp, mem};
 
 use owning_ref::ArcRef;
 
+use crate::config::ChunkingTail;
 use crate::rollsum;
 use crate::rollsum::CDC;
 use crate::SGData;
@@ -76,26 +78,185 @@ impl Chunking for FastCDC {
     }
 }
 
+/// Edges every `size` bytes
+pub(crate) struct Fixed {
+    size: usize,
+    /// Bytes of the current chunk seen so far
+    pos: usize,
+}
+
+impl Fixed {
+    pub fn new(bits: u32) -> Self {
+        Fixed {
+            size: 1 << bits,
+            pos: 0,
+        }
+    }
+}
+
+impl Chunking for Fixed {
+    fn find_chunk<'a>(
+        &mut self,
+        buf: &'a [u8],
+    ) -> Option<(&'a [u8], &'a [u8])> {
+        let missing = self.size - self.pos;
+        if buf.len() < missing {
+            self.pos += buf.len();
+            return None;
+        }
+        self.pos = 0;
+        Some(buf.split_at(missing))
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Bytes before an edge the secondary hash of `DualCondition` covers
+///
+/// Wider than the rolling windows of the primary algorithms, so it sees
+/// data they don't.
+const SECONDARY_WINDOW: usize = 256;
+
+/// Edge refinement requiring a second, independent condition
+///
+/// An edge found by the `primary` algorithm is accepted only if
+/// the top `secondary_bits` of a (FNV-1a) hash of the
+/// `SECONDARY_WINDOW` bytes before it are zero. Since that window is
+/// wider than the primary rolling window, the hash is different for
+/// repetitions of otherwise identical data, which breaks up long runs of
+/// pathologically short chunks on structured data. It only depends on
+/// the bytes near the edge, so like the primary edges, the decisions
+/// are the same again shortly after a change to the data.
+pub(crate) struct DualCondition {
+    primary: Box<dyn Chunking>,
+    secondary_bits: u32,
+    /// Up to `SECONDARY_WINDOW` bytes before the data being chunked
+    history: Vec<u8>,
+}
+
+impl DualCondition {
+    pub fn new(primary: Box<dyn Chunking>, secondary_bits: u32) -> Self {
+        assert!(secondary_bits > 0 && secondary_bits < 64);
+        DualCondition {
+            primary,
+            secondary_bits,
+            history: Vec::with_capacity(SECONDARY_WINDOW),
+        }
+    }
+
+    /// Hash of the `SECONDARY_WINDOW` bytes before `buf[end]`
+    fn window_hash(&self, buf: &[u8], end: usize) -> u64 {
+        let from_buf = &buf[end.saturating_sub(SECONDARY_WINDOW)..end];
+        let from_history = &self.history[self
+            .history
+            .len()
+            .saturating_sub(SECONDARY_WINDOW - from_buf.len())..];
+        from_history
+            .iter()
+            .chain(from_buf)
+            .fold(FNV_OFFSET_BASIS, |hash, &b| {
+                (hash ^ u64::from(b)).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    /// Keep the end of `data`, chunked, as the bytes before the next one
+    fn push_history(&mut self, data: &[u8]) {
+        if data.len() >= SECONDARY_WINDOW {
+            self.history.clear();
+            self.history
+                .extend_from_slice(&data[data.len() - SECONDARY_WINDOW..]);
+        } else {
+            let excess = (self.history.len() + data.len())
+                .saturating_sub(SECONDARY_WINDOW);
+            self.history.drain(..excess);
+            self.history.extend_from_slice(data);
+        }
+    }
+}
+
+impl Chunking for DualCondition {
+    fn find_chunk<'a>(
+        &mut self,
+        buf: &'a [u8],
+    ) -> Option<(&'a [u8], &'a [u8])> {
+        let mut offset = 0;
+        while let Some((last, _)) = self.primary.find_chunk(&buf[offset..]) {
+            offset += last.len();
+            let hash = self.window_hash(buf, offset);
+            if hash >> (64 - self.secondary_bits) == 0 {
+                self.push_history(&buf[..offset]);
+                return Some((&buf[..offset], &buf[offset..]));
+            }
+        }
+        self.push_history(buf);
+        None
+    }
+}
+
+/// Longest chunk, past which an edge is forced
+///
+/// Content-defined chunking puts no upper bound on the chunk size, but
+/// chunks are made contiguous to be compressed and encrypted, so they
+/// must fit in a single allocation, of at most `isize::MAX` bytes. On
+/// 32-bit platforms that's only 2GiB of input without an edge.
+pub(crate) const MAX_CHUNK_LEN: usize = isize::MAX as usize;
+
+/// Shortest chunk returned, but for the only one of an empty input
+const MIN_CHUNK_LEN: usize = 64;
+
 pub(crate) struct Chunker<I> {
     iter: I,
     /// Pieces of chunk to return next, but yet
     /// not complete
     incomplete_chunk: SGData,
+    /// Length of `incomplete_chunk`, kept to avoid summing its parts
+    incomplete_len: usize,
+    max_len: usize,
     /// Data that wasn't chunked yet
     pending: Option<ArcRef<Vec<u8>, [u8]>>,
 
-    chunks_returned: usize,
+    chunks_returned: u64,
     chunking: Box<dyn Chunking>,
+
+    tail: ChunkingTail,
+    /// Chunks already found, but held back until it's known
+    /// if the final one needs to be merged with the previous one
+    lookahead: VecDeque<SGData>,
+    exhausted: bool,
 }
 
 impl<I> Chunker<I> {
-    pub fn new(iter: I, chunking: Box<dyn Chunking>) -> Self {
+    pub fn new(
+        iter: I,
+        chunking: Box<dyn Chunking>,
+        tail: ChunkingTail,
+    ) -> Self {
+        Self::with_max_len(iter, chunking, tail, MAX_CHUNK_LEN)
+    }
+
+    /// Chunker forcing an edge in chunks reaching `max_len` bytes
+    ///
+    /// With `ChunkingTail::Merge`, the final chunk is left unmerged if
+    /// the merged one would be longer.
+    pub fn with_max_len(
+        iter: I,
+        chunking: Box<dyn Chunking>,
+        tail: ChunkingTail,
+        max_len: usize,
+    ) -> Self {
+        assert!(max_len >= MIN_CHUNK_LEN);
         Chunker {
             iter,
             incomplete_chunk: SGData::empty(),
+            incomplete_len: 0,
+            max_len,
             pending: None,
             chunks_returned: 0,
             chunking,
+            tail,
+            lookahead: VecDeque::new(),
+            exhausted: false,
         }
     }
 }
@@ -104,19 +265,58 @@ impl<I: Iterator<Item = Vec<u8>>> Iterator for Chunker<I> {
     type Item = SGData;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let min_size = match self.tail {
+            ChunkingTail::Emit => return self.next_chunk(),
+            ChunkingTail::Merge { min_size } => min_size,
+        };
+
+        // To tell if the chunk after the current one is the last one,
+        // we need to look two chunks ahead.
+        while self.lookahead.len() < 3 && !self.exhausted {
+            match self.next_chunk() {
+                Some(sg) => self.lookahead.push_back(sg),
+                None => self.exhausted = true,
+            }
+        }
+
+        let mut chunk = self.lookahead.pop_front()?;
+        if self.exhausted
+            && self.lookahead.len() == 1
+            && (self.lookahead[0].len() as u64) < min_size
+            && self.lookahead[0].len() <= self.max_len - chunk.len()
+        {
+            let last = self.lookahead.pop_front().unwrap();
+            for part in last.as_parts() {
+                chunk.push_arcref(part.clone());
+            }
+        }
+        Some(chunk)
+    }
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Chunker<I> {
+    fn next_chunk(&mut self) -> Option<SGData> {
         loop {
             if let Some(buf) = self.pending.take().or_else(|| {
                 self.iter
                     .next()
                     .map(|v| ArcRef::new(Arc::new(v)).map(|a| a.as_slice()))
             }) {
-                if let Some((last, rest)) = self.chunking.find_chunk(&*buf) {
-                    debug_assert_eq!(last.len() + rest.len(), buf.len());
+                // Only look for an edge within the room left in the chunk,
+                // and force one at its end if there's none
+                let room = self.max_len - self.incomplete_len;
+                let window = cmp::min(buf.len(), room);
+                let edge = match self.chunking.find_chunk(&buf[..window]) {
+                    Some((last, _)) => Some(last.len()),
+                    None if window == room => Some(room),
+                    None => None,
+                };
+                if let Some(edge) = edge {
                     self.incomplete_chunk
-                        .push_arcref(buf.clone().map(|cur| &cur[..last.len()]));
-                    if !rest.is_empty() {
-                        self.pending =
-                            Some(buf.clone().map(|cur| &cur[last.len()..]))
+                        .push_arcref(buf.clone().map(|cur| &cur[..edge]));
+                    self.incomplete_len += edge;
+                    if edge < buf.len() {
+                        self.pending = Some(buf.clone().map(|cur| &cur[edge..]))
                     };
 
                     // While cryptographic hashes should not have collisions,
@@ -126,23 +326,16 @@ impl<I: Iterator<Item = Vec<u8>>> Iterator for Chunker<I> {
                     // encryption). To prevent that we
                     // impose a 64-byte minimum limit on chunks, no matter what
                     // do chunker returns.
-                    if self.incomplete_chunk.len() >= 64 {
-                        self.chunks_returned += 1;
-                        return Some(mem::replace(
-                            &mut self.incomplete_chunk,
-                            SGData::empty(),
-                        ));
+                    if self.incomplete_len >= MIN_CHUNK_LEN {
+                        return Some(self.take_chunk());
                     } else {
                         continue;
                     }
                 }
+                self.incomplete_len += buf.len();
                 self.incomplete_chunk.push_arcref(buf);
             } else if !self.incomplete_chunk.is_empty() {
-                self.chunks_returned += 1;
-                return Some(mem::replace(
-                    &mut self.incomplete_chunk,
-                    SGData::empty(),
-                ));
+                return Some(self.take_chunk());
             } else if self.chunks_returned == 0 {
                 // at least one, zero sized chunk
                 self.chunks_returned += 1;
@@ -152,4 +345,10 @@ impl<I: Iterator<Item = Vec<u8>>> Iterator for Chunker<I> {
             }
         }
     }
+
+    fn take_chunk(&mut self) -> SGData {
+        self.chunks_returned += 1;
+        self.incomplete_len = 0;
+        mem::replace(&mut self.incomplete_chunk, SGData::empty())
+    }
 }
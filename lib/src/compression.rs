@@ -1,7 +1,7 @@
 #[cfg(feature = "with-xz2")]
 use std::cmp;
 use std::io;
-#[cfg(feature = "with-zstd")]
+#[cfg(any(feature = "with-bzip2", feature = "with-zstd"))]
 use std::io::Read;
 #[cfg(any(
     feature = "with-bzip2",
@@ -109,13 +109,13 @@ impl Compression for Bzip2 {
     }
 
     fn decompress(&self, buf: SGData) -> io::Result<SGData> {
-        let mut decompressor =
-            bzip2::write::BzDecoder::new(Vec::with_capacity(buf.len()));
-
-        for sg_part in buf.as_parts() {
-            decompressor.write_all(sg_part)?;
-        }
-        Ok(SGData::from_single(decompressor.finish()?))
+        // Not the writing decoder: finishing it loops forever on data that
+        // isn't a complete bzip2 stream, which gets here when other
+        // compressions are tried (see `DefaultChunkAccessor::decompress`)
+        let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
+        let mut decompressor = bzip2::read::BzDecoder::new(SGReader::new(&buf));
+        decompressor.read_to_end(&mut backing)?;
+        Ok(SGData::from_single(backing))
     }
 }
 
@@ -158,8 +158,13 @@ impl Compression for Xz2 {
     fn decompress(&self, buf: SGData) -> io::Result<SGData> {
         let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
         {
+            // Data that isn't xz at all gets here when other compressions
+            // are tried (see `DefaultChunkAccessor::decompress`), so it
+            // must fail, not panic
+            let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
             let mut decompressor =
-                lzma::LzmaWriter::new_decompressor(&mut backing).unwrap();
+                lzma::LzmaWriter::new_decompressor(&mut backing)
+                    .map_err(invalid)?;
             for sg_part in buf.as_parts() {
                 // compressor.write can sometimes return zero, so we can't just
                 // use write_all; see
@@ -167,11 +172,18 @@ impl Compression for Xz2 {
                 let todo = sg_part.len();
                 let mut index = 0;
                 while index < todo {
-                    let bytes = decompressor.write(&sg_part[index..]).unwrap();
+                    let bytes = decompressor.write(&sg_part[index..])?;
+                    if bytes == 0 {
+                        // no progress: data past the end of the stream
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "trailing data after xz stream",
+                        ));
+                    }
                     index += bytes;
                 }
             }
-            decompressor.finish().unwrap();
+            decompressor.finish().map_err(invalid)?;
         }
         Ok(SGData::from_single(backing))
     }
@@ -19,6 +19,12 @@ pub enum Chunking {
     Gear { chunk_bits: u32 },
     #[serde(rename = "fastcdc")]
     FastCDC { chunk_bits: u32 },
+    /// Blocks of `2^chunk_bits` bytes, no matter the content
+    ///
+    /// Only deduplicates data that is not shifted by insertions or
+    /// removals. Useful as a baseline and for fixed-size records.
+    #[serde(rename = "fixed")]
+    Fixed { chunk_bits: u32 },
 }
 
 /// Default implementation for the `Chunking`
@@ -35,9 +41,60 @@ impl Chunking {
         match self {
             Chunking::Bup { chunk_bits: bits }
             | Chunking::Gear { chunk_bits: bits }
-            | Chunking::FastCDC { chunk_bits: bits } => {
-                30 >= bits && bits >= 10
-            }
+            | Chunking::FastCDC { chunk_bits: bits }
+            | Chunking::Fixed { chunk_bits: bits } => 30 >= bits && bits >= 10,
+        }
+    }
+
+    pub fn chunk_bits(self) -> u32 {
+        match self {
+            Chunking::Bup { chunk_bits }
+            | Chunking::Gear { chunk_bits }
+            | Chunking::FastCDC { chunk_bits }
+            | Chunking::Fixed { chunk_bits } => chunk_bits,
+        }
+    }
+
+    /// Average size of the chunks, in bytes
+    ///
+    /// Secondary bits don't change it, see `valid_secondary_bits`.
+    pub fn avg_chunk_size(self) -> u64 {
+        1 << self.chunk_bits()
+    }
+
+    fn with_chunk_bits(self, chunk_bits: u32) -> Chunking {
+        match self {
+            Chunking::Bup { .. } => Chunking::Bup { chunk_bits },
+            Chunking::Gear { .. } => Chunking::Gear { chunk_bits },
+            Chunking::FastCDC { .. } => Chunking::FastCDC { chunk_bits },
+            Chunking::Fixed { .. } => Chunking::Fixed { chunk_bits },
+        }
+    }
+
+    /// Check if `secondary_bits` can be used with this chunking
+    ///
+    /// The secondary condition takes over `secondary_bits` of `chunk_bits`,
+    /// so the average chunk size stays the same. Fixed-size blocks can't
+    /// be refined.
+    pub fn valid_secondary_bits(self, secondary_bits: u32) -> bool {
+        if let Chunking::Fixed { .. } = self {
+            return false;
+        }
+        secondary_bits >= 1 && secondary_bits <= self.chunk_bits() / 2
+    }
+
+    /// Like `to_engine`, but with optional dual-condition edge
+    /// refinement.
+    pub(crate) fn to_engine_refined(
+        self,
+        secondary_bits: Option<u32>,
+    ) -> Box<dyn chunking::Chunking> {
+        match secondary_bits {
+            None => self.to_engine(),
+            Some(bits) => Box::new(chunking::DualCondition::new(
+                self.with_chunk_bits(self.chunk_bits() - bits).to_engine(),
+                bits,
+            )),
         }
     }
 
@@ -52,6 +109,23 @@ impl Chunking {
             Chunking::FastCDC { chunk_bits } => {
                 Box::new(chunking::FastCDC::new(chunk_bits))
             }
+            Chunking::Fixed { chunk_bits } => {
+                Box::new(chunking::Fixed::new(chunk_bits))
+            }
         }
     }
 }
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+/// `ChunkingTail` is the policy for the final, partial chunk of a stream
+pub enum ChunkingTail {
+    /// The final chunk is emitted as it is, no matter how small it is
+    #[default]
+    #[serde(rename = "emit")]
+    Emit,
+    /// The final chunk is merged with the previous one when it is smaller
+    /// than `min_size` bytes
+    #[serde(rename = "merge")]
+    Merge { min_size: u64 },
+}
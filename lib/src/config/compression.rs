@@ -1,6 +1,8 @@
+use std::io;
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
+use sgdata::SGData;
 
 use crate::compression;
 
@@ -33,6 +35,56 @@ impl Default for Compression {
 }
 
 impl Compression {
+    /// Id of the compression in the header of data chunks (see
+    /// `Repo::codec_header`)
+    pub(crate) fn id(&self) -> u8 {
+        match *self {
+            Compression::None => 0,
+            #[cfg(feature = "with-deflate")]
+            Compression::Deflate(_) => 1,
+            #[cfg(feature = "with-xz2")]
+            Compression::Xz2(_) => 2,
+            #[cfg(feature = "with-bzip2")]
+            Compression::Bzip2(_) => 3,
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(_) => 4,
+        }
+    }
+
+    /// Compression with the id `id`
+    ///
+    /// At the default level, which doesn't matter for decompressing.
+    pub(crate) fn from_id(id: u8) -> io::Result<Compression> {
+        match id {
+            0 => Ok(Compression::None),
+            #[cfg(feature = "with-deflate")]
+            1 => Ok(Compression::Deflate(Deflate::new(0))),
+            #[cfg(feature = "with-xz2")]
+            2 => Ok(Compression::Xz2(Xz2::new(0))),
+            #[cfg(feature = "with-bzip2")]
+            3 => Ok(Compression::Bzip2(Bzip2::new(0))),
+            #[cfg(feature = "with-zstd")]
+            4 => Ok(Compression::Zstd(Zstd::new(0))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("chunk compressed with unsupported compression {}", id),
+            )),
+        }
+    }
+
+    /// Engine for data chunks, with the header telling their compression
+    /// if `header` is set
+    pub(crate) fn to_chunk_engine(
+        self,
+        header: bool,
+    ) -> compression::ArcCompression {
+        if header {
+            Arc::new(WithHeader(self))
+        } else {
+            self.to_engine()
+        }
+    }
+
     pub(crate) fn to_engine(&self) -> compression::ArcCompression {
         match *self {
             Compression::None => Arc::new(compression::NoCompression),
@@ -49,6 +101,42 @@ impl Compression {
         }
     }
 }
+/// Compression prepending a byte with the id of the compression to the
+/// data, and decompressing data compressed with any of them
+struct WithHeader(Compression);
+
+impl compression::Compression for WithHeader {
+    fn compress(&self, buf: SGData) -> io::Result<SGData> {
+        let compressed = self.0.to_engine().compress(buf)?;
+        let mut sg = SGData::from_single(vec![self.0.id()]);
+        for part in compressed.as_parts() {
+            sg.push_arcref(part.clone());
+        }
+        Ok(sg)
+    }
+
+    fn decompress(&self, buf: SGData) -> io::Result<SGData> {
+        let mut parts = buf.as_parts().iter().filter(|part| !part.is_empty());
+        let id = match parts.next() {
+            Some(first) => first.clone(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "chunk without compression header",
+                ))
+            }
+        };
+        let mut compressed = SGData::empty();
+        compressed.push_arcref(id.clone().map(|part| &part[1..]));
+        for part in parts {
+            compressed.push_arcref(part.clone());
+        }
+        Compression::from_id(id[0])?
+            .to_engine()
+            .decompress(compressed)
+    }
+}
+
 #[cfg(feature = "with-deflate")]
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Deflate {
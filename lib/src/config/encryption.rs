@@ -4,7 +4,7 @@ use std::sync::Arc;
 use serde::{Deserialize, Serialize};
 
 use crate::PassphraseFn;
-use crate::{config, encryption};
+use crate::{box_, config, encryption};
 
 /// Types of supported encryption
 #[derive(Serialize, Deserialize, Clone)]
@@ -18,6 +18,27 @@ pub(crate) enum Encryption {
     Curve25519(encryption::Curve25519),
 }
 
+impl Encryption {
+    /// Type of the encryption, as in the config
+    pub(crate) fn type_name(&self) -> &'static str {
+        match *self {
+            Encryption::None => "none",
+            Encryption::Curve25519(_) => "curve25519_blake2b_salsa20_poly1305",
+        }
+    }
+
+    /// Bytes added to every chunk by encrypting it
+    pub(crate) fn overhead(&self) -> u64 {
+        match *self {
+            Encryption::None => 0,
+            // ephemeral public key, and the MAC of the box
+            Encryption::Curve25519(_) => {
+                (box_::PUBLICKEYBYTES + box_::MACBYTES) as u64
+            }
+        }
+    }
+}
+
 impl encryption::EncryptionEngine for Encryption {
     fn change_passphrase(
         &mut self,
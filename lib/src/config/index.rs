@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+use crate::index;
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+/// `IndexFormat` is the binary layout of entries stored in the index
+///
+/// Repositories created before the format was recorded in the config
+/// use `Digest`.
+pub enum IndexFormat {
+    /// Just the digest of each chunk
+    #[default]
+    #[serde(rename = "digest")]
+    Digest,
+    /// Digest of each chunk followed by its plaintext length, as
+    /// a little-endian `u64`
+    #[serde(rename = "digest-len")]
+    DigestLen,
+    /// Digest of each chunk, a flags byte, and the fields the flags
+    /// announce; currently only the plaintext length as a LEB128 varint
+    #[serde(rename = "compact")]
+    Compact,
+}
+
+impl IndexFormat {
+    /// Format used by newly created repositories
+    pub fn newest() -> IndexFormat {
+        IndexFormat::Compact
+    }
+
+    pub(crate) fn to_codec(self, digest_size: usize) -> index::Codec {
+        index::Codec::new(self, digest_size)
+    }
+}
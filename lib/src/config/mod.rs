@@ -12,22 +12,31 @@ use crate::aio;
 use crate::hashing;
 use crate::pwhash;
 use crate::settings;
-use crate::{PassphraseFn, SGData};
+use crate::{DataType, PassphraseFn, SGData};
 
 mod chunking;
 mod compression;
 mod encryption;
+mod index;
 
 pub(crate) use self::chunking::*;
 pub(crate) use self::compression::*;
 pub(crate) use self::encryption::*;
+pub(crate) use self::index::*;
 // }}}
 
 pub const REPO_VERSION_LOWEST: u32 = 3;
-pub const REPO_VERSION_CURRENT: u32 = 3;
+pub const REPO_VERSION_CURRENT: u32 = 5;
+/// Lowest version with the compression recorded in each data chunk
+const REPO_VERSION_CODEC_HEADER: u32 = 5;
 
 pub const DATA_SUBDIR: &str = "chunk";
 pub const LOCK_FILE: &str = ".lock";
+/// Directory of the records of who holds the lock, of backends locking
+/// a file
+pub const LOCK_OWNERS_DIR: &str = ".lock-owners";
+/// Directory of the temporary files of backends writing to a filesystem
+pub const TMP_DIR: &str = ".tmp";
 pub const CONFIG_YML_FILE: &str = "config.yml";
 
 // {{{ PWHash
@@ -88,6 +97,10 @@ impl Default for Hashing {
 }
 
 impl Hashing {
+    pub(crate) fn all() -> Vec<Hashing> {
+        vec![Hashing::Sha256, Hashing::Blake2b]
+    }
+
     pub(crate) fn to_hasher(&self) -> hashing::ArcHasher {
         match *self {
             Hashing::Sha256 => Arc::new(hashing::Sha256),
@@ -142,12 +155,28 @@ pub(crate) struct Repo {
     #[serde(default)]
     pub chunking: Chunking,
     #[serde(default)]
+    pub chunking_secondary_bits: Option<u32>,
+    #[serde(default)]
+    pub chunking_tail: ChunkingTail,
+    #[serde(default)]
     pub hashing: Hashing,
     #[serde(default)]
     pub compression: Compression,
     pub encryption: Encryption,
+    /// Encryption chunks are being re-encrypted to, while that's in
+    /// progress (see `Repo::reencrypt`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_next: Option<Encryption>,
     #[serde(default)]
     pub nesting: Nesting,
+    #[serde(default)]
+    pub index_format: IndexFormat,
+    /// Keep previous versions of names stored to again
+    #[serde(default)]
+    pub name_versioning: bool,
+    /// Refuse all operations that remove data
+    #[serde(default)]
+    pub safe_mode: bool,
 }
 
 impl Repo {
@@ -163,26 +192,96 @@ impl Repo {
             settings::Encryption::None => Encryption::None,
         };
 
+        if let Some(bits) = settings.chunking_secondary_bits {
+            if !settings.chunking.0.valid_secondary_bits(bits) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "secondary chunking bits must be between 1 and half of \
+                     the chunk bits, and can't be used with fixed chunking",
+                ));
+            }
+        }
+
         Ok(Repo {
             version: REPO_VERSION_CURRENT,
             pwhash,
             chunking: settings.chunking.0,
+            chunking_secondary_bits: settings.chunking_secondary_bits,
+            chunking_tail: settings.chunking_tail,
             encryption,
+            encryption_next: None,
             compression: settings
                 .compression
                 .to_config(settings.compression_level),
             nesting: settings.nesting.to_config(),
             hashing: settings.hashing.to_config(),
+            index_format: IndexFormat::newest(),
+            name_versioning: settings.name_versioning,
+            safe_mode: settings.safe_mode,
         })
     }
 
-    pub fn write(&self, aio: &aio::AsyncIO) -> super::Result<()> {
+    pub(crate) fn chunking_engine(&self) -> Box<dyn crate::chunking::Chunking> {
+        self.chunking
+            .to_engine_refined(self.chunking_secondary_bits)
+    }
+
+    /// Identifies the settings that determine chunk edges and digests
+    pub(crate) fn chunking_fingerprint(&self) -> String {
+        serde_yaml::to_string(&(
+            &self.chunking,
+            self.chunking_secondary_bits,
+            &self.chunking_tail,
+            self.hashing,
+        ))
+        .expect("yaml serialization failed")
+    }
+
+    /// Whether data chunks start with a byte telling their compression
+    ///
+    /// Otherwise they all have the compression of the repo.
+    pub(crate) fn codec_header(&self) -> bool {
+        self.version >= REPO_VERSION_CODEC_HEADER
+    }
+
+    /// Engine (de)compressing the data chunks
+    pub(crate) fn compression_engine(
+        &self,
+    ) -> crate::compression::ArcCompression {
+        self.compression.to_chunk_engine(self.codec_header())
+    }
+
+    /// Length of a chunk of plaintext length `len` as stored, if it
+    /// doesn't depend on the data
+    ///
+    /// That's the case unless the chunk is compressed.
+    pub(crate) fn stored_len(
+        &self,
+        data_type: DataType,
+        len: u64,
+    ) -> Option<u64> {
+        match data_type {
+            DataType::Index => Some(len),
+            DataType::Data if self.compression == Compression::None => {
+                let header = if self.codec_header() { 1 } else { 0 };
+                Some(len + header + self.encryption.overhead())
+            }
+            DataType::Data => None,
+        }
+    }
+
+    pub(crate) fn index_codec(&self) -> crate::index::Codec {
+        self.index_format.to_codec(crate::DIGEST_SIZE)
+    }
+
+    pub fn write(&self, aio: &aio::AsyncIO) -> io::Result<()> {
         let config_str =
             serde_yaml::to_string(self).expect("yaml serialization failed");
 
-        aio.write(
+        aio.write_durable(
             CONFIG_YML_FILE.into(),
             SGData::from_single(config_str.into_bytes()),
+            aio::backend::DurabilityMode::FsyncFileAndDir,
         )
         .wait()?;
 
@@ -210,7 +309,7 @@ impl Repo {
 fn check_version(version_int: u32) -> io::Result<()> {
     if version_int > REPO_VERSION_CURRENT {
         return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
+            io::ErrorKind::Unsupported,
             format!(
                 "repo version {} higher than \
                  supported {}; update?",
@@ -222,7 +321,7 @@ fn check_version(version_int: u32) -> io::Result<()> {
     // minimum repo version is also the smallest value of a u32
     if version_int < REPO_VERSION_LOWEST {
         return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
+            io::ErrorKind::Unsupported,
             format!(
                 "repo version {} lower than \
                  lowest supported {}; \
@@ -36,7 +36,7 @@ pub(crate) trait EncryptionEngine {
 }
 
 pub trait Encrypter {
-    fn encrypt(&self, buf: SGData, digest: &[u8]) -> super::Result<SGData>;
+    fn encrypt(&self, buf: SGData, digest: &[u8]) -> io::Result<SGData>;
 }
 
 pub trait Decrypter {
@@ -59,6 +59,24 @@ impl Decrypter for NopDecrypter {
     }
 }
 
+/// Decrypter trying a new key first, and an old one if that fails
+///
+/// Used while chunks are re-encrypted under a new key (see
+/// `Repo::reencrypt`), so both the chunks done and the ones left can be
+/// read.
+pub(crate) struct FallbackDecrypter {
+    pub(crate) new: ArcDecrypter,
+    pub(crate) old: ArcDecrypter,
+}
+
+impl Decrypter for FallbackDecrypter {
+    fn decrypt(&self, buf: SGData, digest: &[u8]) -> io::Result<SGData> {
+        self.new
+            .decrypt(buf.clone(), digest)
+            .or_else(|_| self.old.decrypt(buf, digest))
+    }
+}
+
 /// Configuration of repository encryption
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Curve25519 {
@@ -74,7 +92,7 @@ impl Curve25519 {
     pub(crate) fn new(
         passphrase_f: PassphraseFn<'_>,
         pwhash: &dyn pwhash::PWHash,
-    ) -> super::Result<Self> {
+    ) -> io::Result<Self> {
         let (pk, sk) = box_::gen_keypair();
         let passphrase = passphrase_f()?;
 
@@ -123,7 +141,7 @@ impl Curve25519 {
         })?)
     }
 
-    fn unseal_encrypt(&self) -> super::Result<box_::PublicKey> {
+    fn unseal_encrypt(&self) -> io::Result<box_::PublicKey> {
         Ok(self.pub_key)
     }
 }
@@ -175,7 +193,7 @@ struct Curve25519Encrypter {
 }
 
 impl Encrypter for Curve25519Encrypter {
-    fn encrypt(&self, buf: SGData, digest: &[u8]) -> super::Result<SGData> {
+    fn encrypt(&self, buf: SGData, digest: &[u8]) -> io::Result<SGData> {
         let nonce = box_::Nonce::from_slice(&digest[0..box_::NONCEBYTES])
             .expect("Nonce::from_slice failed");
 
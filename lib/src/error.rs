@@ -1,21 +1,137 @@
+//! Errors of the `Repo` API
+//!
+//! Everything below `Repo` (backends, `AsyncIO` operations, the chunk
+//! pipeline) fails with `io::Error`, its kind telling the failures apart.
+//! `Error` sorts out the ones a caller might want to act on, converting
+//! from `io::Error` on the way out, so they don't have to be told apart by
+//! their messages.
+use std::error;
+use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::result;
 
+use crate::backends::{LockInfo, Locked};
+use crate::reading::DigestMismatch;
+
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    /// Any other failure, like one of the backend
     Io(io::Error),
-    NotFound,
-    Exists,
-    InvalidPubKey,
-    DecryptionFailed
+    /// A chunk read is not the data its digest identifies
+    DigestMismatch {
+        expected: Vec<u8>,
+        /// Digest of the data read
+        found: Vec<u8>,
+    },
+    /// Timed out waiting for the lock of the repository (see
+    /// `Repo::set_lock_timeout`)
+    Locked {
+        /// Who holds the lock, if the backend knows
+        owner: Option<LockInfo>,
+    },
+    /// Something looked up in the repository (like a name) that doesn't
+    /// exist, by its path in a generation
+    NotFound(PathBuf),
+    /// A worker thread died (panicked) before finishing its job
+    WorkerDied,
 }
 
-impl From<io::Error> for Error {
-    fn from(err: io::Error) -> Error {
-        Error::Io(err)
+impl Error {
+    /// Like `io::Error::new`
+    pub fn new<E>(kind: io::ErrorKind, error: E) -> Error
+    where
+        E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Error::Io(io::Error::new(kind, error))
+    }
+
+    /// The `io::ErrorKind` of the error, for callers that only care
+    /// about it
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::Io(ref e) => e.kind(),
+            Error::DigestMismatch { .. } => io::ErrorKind::InvalidData,
+            Error::Locked { .. } => io::ErrorKind::TimedOut,
+            Error::NotFound(_) => io::ErrorKind::NotFound,
+            Error::WorkerDied => io::ErrorKind::BrokenPipe,
+        }
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::DigestMismatch {
+                ref expected,
+                ref found,
+            } => write!(
+                f,
+                "{} corrupted, data read: {}",
+                hex::encode(expected),
+                hex::encode(found)
+            ),
+            Error::Locked { ref owner } => write!(
+                f,
+                "{}",
+                Locked {
+                    owner: owner.clone()
+                }
+            ),
+            Error::NotFound(ref path) => {
+                write!(f, "not found: {}", path.display())
+            }
+            Error::WorkerDied => {
+                write!(f, "async-io worker terminated unexpectedly")
+            }
+        }
+    }
+}
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => e.get_ref().map(|e| e as _),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Sorts out the failures `Error` has variants for, which are the
+    /// inner errors of the `io::Error`s they are passed around as
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = e.into_inner().expect("checked above");
+            return *inner.downcast::<Error>().expect("checked above");
+        }
+        if let Some(mismatch) = DigestMismatch::find(&e) {
+            return Error::DigestMismatch {
+                expected: mismatch.expected.clone(),
+                found: mismatch.found.clone(),
+            };
+        }
+        if let Some(locked) =
+            e.get_ref().and_then(|inner| inner.downcast_ref::<Locked>())
+        {
+            return Error::Locked {
+                owner: locked.owner.clone(),
+            };
+        }
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    /// The `io::Error` of the same kind, keeping `e` as the inner error so
+    /// it converts back
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
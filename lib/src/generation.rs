@@ -128,9 +128,10 @@ impl Generation {
         let config_str =
             serde_yaml::to_string(&config).expect("yaml serialization failed");
 
-        aio.write(
+        aio.write_durable(
             self.config_path(),
             SGData::from_single(config_str.into_bytes()),
+            aio::backend::DurabilityMode::FsyncFileAndDir,
         )
         .wait()?;
 
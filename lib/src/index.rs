@@ -0,0 +1,264 @@
+//! Encoding of entries stored in the index chunks
+//!
+//! The index of a stored data is a stream of entries, one for every chunk
+//! of the data, in order. Just like the data itself, the stream is then
+//! chunked, and stored as chunks of `DataType::Index`, so entries can
+//! span chunk boundaries. See `config::IndexFormat` for the formats.
+use std::convert::TryInto;
+use std::io;
+
+use crate::config::IndexFormat;
+use crate::Digest;
+
+/// `IndexFormat::Compact` flag: the varint length follows the flags byte
+const FLAG_LEN: u8 = 0x01;
+const FLAGS_KNOWN: u8 = FLAG_LEN;
+
+/// Maximum size of the LEB128 encoding of `u64`
+const VARINT_MAX_SIZE: usize = 10;
+
+pub(crate) fn encode_varint(mut value: u64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Decode the LEB128 varint from the beginning of `buf`
+///
+/// Returns the value and the number of bytes it took, or `None` if `buf`
+/// ends before the varint does.
+pub(crate) fn decode_varint(buf: &[u8]) -> io::Result<Option<(u64, usize)>> {
+    let mut value = 0u64;
+    for (i, &byte) in buf.iter().enumerate().take(VARINT_MAX_SIZE) {
+        let bits = u64::from(byte & 0x7f);
+        let shift = 7 * i as u32;
+        if (i == VARINT_MAX_SIZE - 1 && byte > 1) || (i > 0 && byte == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid varint in index entry",
+            ));
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    if buf.len() >= VARINT_MAX_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unterminated varint in index entry",
+        ));
+    }
+    Ok(None)
+}
+
+/// A single chunk reference in the index
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct IndexEntry {
+    pub(crate) digest: Digest,
+    /// Length of the (plaintext) data of the chunk, if recorded
+    pub(crate) len: Option<u64>,
+}
+
+impl IndexEntry {
+    pub(crate) fn new(digest: Digest, len: u64) -> Self {
+        IndexEntry {
+            digest,
+            len: Some(len),
+        }
+    }
+}
+
+/// Encoder/decoder of the index entries in a given format
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Codec {
+    format: IndexFormat,
+    digest_size: usize,
+}
+
+impl Codec {
+    pub(crate) fn new(format: IndexFormat, digest_size: usize) -> Self {
+        Codec {
+            format,
+            digest_size,
+        }
+    }
+
+    pub(crate) fn encode(&self, entry: &IndexEntry, out: &mut Vec<u8>) {
+        debug_assert_eq!(entry.digest.0.len(), self.digest_size);
+        out.extend_from_slice(&entry.digest.0);
+        match self.format {
+            IndexFormat::Digest => {}
+            IndexFormat::DigestLen => {
+                let len = entry.len.expect("entry length required");
+                out.extend_from_slice(&len.to_le_bytes());
+            }
+            IndexFormat::Compact => match entry.len {
+                Some(len) => {
+                    out.push(FLAG_LEN);
+                    encode_varint(len, out);
+                }
+                None => out.push(0),
+            },
+        }
+    }
+
+    pub(crate) fn encode_to_vec(&self, entry: &IndexEntry) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.digest_size + 8);
+        self.encode(entry, &mut out);
+        out
+    }
+
+    /// Decode the entry from the beginning of `buf`
+    ///
+    /// Returns the entry and the number of bytes it took, or `None` if
+    /// `buf` does not contain the whole entry yet.
+    pub(crate) fn decode(
+        &self,
+        buf: &[u8],
+    ) -> io::Result<Option<(IndexEntry, usize)>> {
+        if buf.len() < self.digest_size {
+            return Ok(None);
+        }
+        let digest = Digest(buf[..self.digest_size].to_vec());
+        let rest = &buf[self.digest_size..];
+
+        match self.format {
+            IndexFormat::Digest => {
+                Ok(Some((IndexEntry { digest, len: None }, self.digest_size)))
+            }
+            IndexFormat::DigestLen => {
+                if rest.len() < 8 {
+                    return Ok(None);
+                }
+                let len = u64::from_le_bytes(rest[..8].try_into().unwrap());
+                Ok(Some((IndexEntry::new(digest, len), self.digest_size + 8)))
+            }
+            IndexFormat::Compact => {
+                let flags = match rest.first() {
+                    Some(&flags) => flags,
+                    None => return Ok(None),
+                };
+                if flags & !FLAGS_KNOWN != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown index entry flags: {:#04x}", flags),
+                    ));
+                }
+                let header_size = self.digest_size + 1;
+                if flags & FLAG_LEN == 0 {
+                    return Ok(Some((
+                        IndexEntry { digest, len: None },
+                        header_size,
+                    )));
+                }
+                Ok(decode_varint(&rest[1..])?.map(|(len, size)| {
+                    (IndexEntry::new(digest, len), header_size + size)
+                }))
+            }
+        }
+    }
+}
+
+/// Decoded top chunk of an index
+///
+/// The top chunk of every index holds whole entries (if they did not fit
+/// in a single chunk, they would have been indexed again), so it can be
+/// decoded on its own. Entries in other index chunks can span chunk
+/// boundaries, and are decoded with `Decoder`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct IndexNode {
+    /// `index_level` of the data address this is the top chunk of;
+    /// entries reference chunks of the level below
+    pub(crate) level: u32,
+    pub(crate) entries: Vec<IndexEntry>,
+}
+
+impl IndexNode {
+    pub(crate) fn decode(
+        level: u32,
+        bytes: &[u8],
+        codec: Codec,
+    ) -> io::Result<Self> {
+        if level == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "data address of level 0 has no index",
+            ));
+        }
+
+        let mut entries = vec![];
+        let mut decoder = Decoder::new(codec);
+        decoder.push(bytes, |entry| {
+            entries.push(entry);
+            Ok(())
+        })?;
+        decoder.finish()?;
+
+        Ok(IndexNode { level, entries })
+    }
+}
+
+/// Streaming decoder of index entries
+///
+/// Buffers partial entries between `push` calls, as index chunk boundaries
+/// do not have to align with the entries.
+pub(crate) struct Decoder {
+    codec: Codec,
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub(crate) fn new(codec: Codec) -> Self {
+        Decoder { codec, buf: vec![] }
+    }
+
+    /// Feed more bytes of the index stream, calling `f` on every
+    /// complete entry
+    pub(crate) fn push<F>(&mut self, bytes: &[u8], mut f: F) -> io::Result<()>
+    where
+        F: FnMut(IndexEntry) -> io::Result<()>,
+    {
+        self.buf.extend_from_slice(bytes);
+
+        let mut consumed = 0;
+        let res = loop {
+            match self.codec.decode(&self.buf[consumed..]) {
+                Ok(Some((entry, len))) => {
+                    consumed += len;
+                    if let Err(e) = f(entry) {
+                        break Err(e);
+                    }
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.buf.drain(..consumed);
+        res
+    }
+
+    /// Check that the stream did not end in the middle of an entry
+    pub(crate) fn finish(&self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "index stream ends with {} bytes of a partial entry",
+                    self.buf.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
@@ -6,6 +6,11 @@ use std::io::Result;
 use std::path::PathBuf;
 
 use crate::aio;
+use crate::backends::DEFAULT_LIST_BATCH_SIZE;
+
+/// Batches of paths listed ahead of a `StoredChunks`, so walking a huge
+/// repository uses bounded memory
+const LIST_CAPACITY: usize = 16;
 
 /// `StoredChunks` is an iterator for the list of chunks stored in a path,
 /// it will crawl the directory structure looking for chunks that have valid
@@ -18,14 +23,23 @@ pub struct StoredChunks {
 }
 
 impl StoredChunks {
-    #[allow(dead_code)] // tests
     pub fn new(
         aio: &aio::AsyncIO,
         rel_path: PathBuf,
         digest_size: usize,
         log: Logger,
     ) -> Result<StoredChunks> {
-        let paths = aio.list_recursively(rel_path);
+        // The worker listing waits while the iterator is behind, so it
+        // must not be the only one serving the reads of its user.
+        let paths = if aio.thread_num() > 1 {
+            aio.list_recursively_bounded(
+                rel_path,
+                LIST_CAPACITY,
+                DEFAULT_LIST_BATCH_SIZE,
+            )
+        } else {
+            aio.list_recursively(rel_path)
+        };
 
         Ok(StoredChunks {
             paths,
@@ -52,6 +66,10 @@ impl Iterator for StoredChunks {
             if let Some(next) = next {
                 let name = match next {
                     Ok(name) => name,
+                    // no chunks stored yet
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        continue
+                    }
                     Err(e) => return Some(Err(e)),
                 };
 
@@ -1,11 +1,16 @@
 // {{{ use and mod
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::io;
-use std::io::{Error, Read, Result, Write};
+use std::io::{Read, Write};
 use std::iter::Iterator;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{mpsc, Arc};
 
+use rand::Rng;
 use sgdata::SGData;
 use slog::{info, o, warn, FnValue, Level, Logger};
 use slog_perf::TimeReporter;
@@ -14,16 +19,27 @@ use url::Url;
 
 use rdedup_cdc as rollsum;
 
+mod error;
+pub use self::error::{Error, Result};
+
 mod iterators;
+use crate::iterators::StoredChunks;
 
 mod config;
 
 mod aio;
 use crate::aio::*;
+pub use crate::aio::{
+    ProgressEvent, ProgressFn, ReadStats, StatsSnapshot, WriteStats,
+};
 
 mod chunking;
 mod hashing;
 
+mod index;
+
+mod archive;
+
 mod chunk_processor;
 use crate::chunk_processor::*;
 
@@ -52,14 +68,31 @@ use self::generation::*;
 mod name;
 use self::name::*;
 
+mod pin;
+use self::pin::*;
+
+mod chunk_cache;
+pub use self::chunk_cache::ChunkCache;
+
+mod progress;
+pub use self::progress::Progress;
+
+mod sparse;
+pub use self::sparse::SparseFile;
+
 mod misc;
 use self::misc::*;
 // }}}
 
 // Fancy reexport of backends API and particular backends structs
 pub mod backends {
-    pub use crate::aio::backend::{Backend, BackendThread, Lock};
+    pub use crate::aio::backend::{
+        Backend, BackendThread, BatchError, BatchOp, DurabilityMode, FsStats,
+        ListSender, Lock, LockInfo, Locked, WriteOutcome,
+        DEFAULT_LIST_BATCH_SIZE,
+    };
     pub use crate::aio::Metadata;
+    pub use crate::aio::{key_to_path, path_to_key};
 
     pub mod local {
         pub use crate::aio::local::{Local, LocalThread};
@@ -68,6 +101,36 @@ pub mod backends {
     pub mod b2 {
         pub use crate::aio::b2::{Auth, B2Thread, Lock, B2};
     }
+
+    pub mod null {
+        pub use crate::aio::null::{Null, NullThread};
+    }
+
+    pub mod memory {
+        pub use crate::aio::memory::{Memory, MemoryThread};
+    }
+
+    pub mod coalescing {
+        pub use crate::aio::coalescing::{Coalescing, CoalescingThread};
+    }
+
+    pub mod sharded {
+        pub use crate::aio::sharded::{Sharded, ShardedThread};
+    }
+
+    pub mod fault {
+        pub use crate::aio::fault::{
+            Fault, FaultInjecting, FaultInjectingThread, Faults, Op, Rule,
+        };
+    }
+
+    pub mod retrying {
+        pub use crate::aio::retrying::{RetryPolicy, Retrying, RetryingThread};
+    }
+
+    pub mod dry_run {
+        pub use crate::aio::dry_run::{DryRun, DryRunThread};
+    }
 }
 
 type ArcDecrypter = Arc<dyn encryption::Decrypter + Send + Sync + 'static>;
@@ -106,11 +169,176 @@ pub struct VerifyResults {
     pub errors: Vec<(Vec<u8>, Error)>,
 }
 
+pub struct RepairResults {
+    pub scanned: usize,
+    /// Chunks replaced with their copies in the mirror
+    pub repaired: Vec<Vec<u8>>,
+    /// Chunks that failed to read, and had no intact copy in the mirror
+    pub unrepairable: Vec<(Vec<u8>, Error)>,
+}
+
+/// Chunk that could not be read during lenient read
+pub struct RestoreGap {
+    /// Offset of the gap in the restored data
+    pub offset: u64,
+    /// Length of the gap (filled with zeros)
+    pub len: u64,
+    pub digest: Vec<u8>,
+    pub error: Error,
+}
+
+pub struct RestoreReport {
+    pub gaps: Vec<RestoreGap>,
+}
+
+/// A version of a stored name
+pub struct NameVersionInfo {
+    pub version: u64,
+    /// `None` for names stored by older rdedup versions
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Check of how well the data of every `write` deduplicates (see
+/// `Repo::set_dedup_check`)
+#[derive(Clone, Copy, Debug)]
+pub struct DedupCheck {
+    /// Number of the most recently stored names to compare with
+    pub baseline: usize,
+    /// Fraction of the deduplication ratio of the baseline names that a
+    /// `write` must reach
+    pub min_fraction: f64,
+    /// Fail the `write`, instead of only warning about it
+    pub abort: bool,
+}
+
+/// Selection of names by their tags (see `Repo::tag`)
+///
+/// Matches names that have all the `tagged` tags, and none of the
+/// `not_tagged` ones. The default filter matches every name.
+#[derive(Clone, Debug, Default)]
+pub struct TagFilter {
+    pub tagged: Vec<String>,
+    pub not_tagged: Vec<String>,
+}
+
+impl TagFilter {
+    fn is_empty(&self) -> bool {
+        self.tagged.is_empty() && self.not_tagged.is_empty()
+    }
+
+    fn matches(&self, tags: &BTreeSet<String>) -> bool {
+        self.tagged.iter().all(|tag| tags.contains(tag))
+            && !self.not_tagged.iter().any(|tag| tags.contains(tag))
+    }
+}
+
+/// Address of data stored in the repository
+///
+/// Identifies the data just like a name does, so it can be read even if
+/// no name refers to it anymore (see `read_root`). Formatted as
+/// `<hashing>:<digest-hex>:<index-level>`. The hashing is `None` for
+/// addresses in the older `<digest-hex>:<index-level>` format.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RootAddress {
+    pub hashing: Option<settings::Hashing>,
+    pub digest: Vec<u8>,
+    pub index_level: u32,
+}
+
+impl RootAddress {
+    fn data_address(&self) -> DataAddress {
+        DataAddress {
+            digest: Digest(self.digest.clone()),
+            index_level: self.index_level,
+        }
+    }
+}
+
+impl fmt::Display for RootAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref hashing) = self.hashing {
+            write!(f, "{}:", hashing.name())?;
+        }
+        write!(f, "{}:{}", hex::encode(&self.digest), self.index_level)
+    }
+}
+
+impl std::str::FromStr for RootAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid root address: {}", s),
+            )
+        };
+        let mut parts: Vec<_> = s.split(':').collect();
+        let hashing = match parts.len() {
+            2 => None,
+            3 => Some(
+                settings::Hashing::from_name(parts.remove(0))
+                    .ok_or_else(invalid)?,
+            ),
+            _ => return Err(invalid()),
+        };
+        // Length is checked against the repo by `read_root`
+        let digest = hex::decode(parts[0])
+            .ok()
+            .filter(|digest| !digest.is_empty())
+            .ok_or_else(invalid)?;
+        let index_level = parts[1].parse().map_err(|_| invalid())?;
+        Ok(RootAddress {
+            hashing,
+            digest,
+            index_level,
+        })
+    }
+}
+
+/// Source of the data to `write`
+enum WriteInput<R> {
+    /// Data to chunk, and optionally where to send its chunk entries
+    Reader(R, Option<mpsc::Sender<index::IndexEntry>>),
+    /// Entries of data already chunked and stored
+    Entries(Vec<index::IndexEntry>),
+    /// Archive created by `export`
+    Archive(R),
+}
+
 pub struct DuResults {
     pub chunks: usize,
     pub bytes: u64,
 }
 
+/// Space reclaimed by `gc`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcResults {
+    pub chunks_removed: usize,
+    /// Stored size of the chunks removed
+    pub bytes_freed: u64,
+}
+
+/// Error of the operation `op` (see `Repo::in_op`), keeping the original
+/// one as its source
+#[derive(Debug)]
+struct InOp {
+    op: String,
+    error: io::Error,
+}
+
+impl fmt::Display for InOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (operation {})", self.error, self.op)
+    }
+}
+
+impl std::error::Error for InOp {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
 /// A decryption handle
 ///
 /// Used as an argument to operations that decrypt data.
@@ -140,18 +368,100 @@ pub struct Repo {
     log: slog::Logger,
 
     aio: aio::AsyncIO,
+
+    /// Number of chunk processing threads used by `write`
+    ///
+    /// `None` means one per CPU.
+    write_threads: Option<usize>,
+    io_threads: Option<usize>,
+    /// Limit of bytes written to the backend per second
+    write_rate: Option<u64>,
+    /// Size of the cache of data read from the backend, if any
+    read_cache: Option<u64>,
+    /// Called with the `ProgressEvent`s of the backend I/O
+    progress: Option<ProgressFn>,
+
+    /// Maximum number of data chunks a single `write` can produce
+    max_chunks: Option<u64>,
+
+    /// Limit of chunk existence checks in progress during `write`
+    probe_limit: Option<Arc<Semaphore>>,
+
+    /// Limit of chunk data buffered at once by `write`, in bytes
+    max_buffered: Option<u64>,
+
+    /// Number of chunks `write` samples to pick the compression
+    compression_sample: Option<usize>,
+
+    dedup_check: Option<DedupCheck>,
+
+    /// Make `write` change nothing, only counting what it would store
+    dry_run: bool,
+
+    /// How long to wait for the lock of the repository, `None` meaning
+    /// forever
+    lock_timeout: Option<std::time::Duration>,
+
+    /// Don't let `write` replace names written within it
+    overwrite_protection: Option<std::time::Duration>,
+
+    /// Space that must be available for `write` to store anything
+    min_space: Option<u64>,
+}
+
+/// Tell if `data`, as stored, is the chunk identified by `digest`
+///
+/// Index chunks are stored as they are, and data chunks encrypted and
+/// compressed by `compression`.
+fn stored_chunk_intact(
+    data: SGData,
+    digest: &[u8],
+    decrypter: &ArcDecrypter,
+    compression: &ArcCompression,
+    hashers: &[hashing::ArcHasher],
+) -> bool {
+    let matches = |data: &SGData| {
+        hashers
+            .iter()
+            .any(|hasher| hasher.calculate_digest(data) == digest)
+    };
+    if matches(&data) {
+        return true;
+    }
+    let data = match decrypter.decrypt(data, digest) {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+    compression
+        .decompress(data)
+        .map(|data| matches(&data))
+        .unwrap_or(false)
 }
 
 impl Repo {
     pub fn unlock_decrypt(
         &self,
         pass: PassphraseFn<'_>,
-    ) -> io::Result<DecryptHandle> {
+    ) -> Result<DecryptHandle> {
         info!(self.log, "Opening read handle");
-        let decrypter = self
-            .config
-            .encryption
-            .decrypter(pass, &self.config.pwhash)?;
+        let decrypter = match self.config.encryption_next {
+            // re-encryption in progress, chunks use either key
+            Some(ref next) => {
+                let passphrase = pass()?;
+                let pass = || Ok(passphrase.clone());
+                Arc::new(encryption::FallbackDecrypter {
+                    new: next.decrypter(&pass, &self.config.pwhash)?,
+                    old: self
+                        .config
+                        .encryption
+                        .decrypter(&pass, &self.config.pwhash)?,
+                })
+            }
+            None => self
+                .config
+                .encryption
+                .decrypter(pass, &self.config.pwhash)?,
+        };
 
         Ok(DecryptHandle { decrypter })
     }
@@ -159,11 +469,13 @@ impl Repo {
     pub fn unlock_encrypt(
         &self,
         pass: PassphraseFn<'_>,
-    ) -> io::Result<EncryptHandle> {
+    ) -> Result<EncryptHandle> {
         info!(self.log, "Opening write handle");
         let encrypter = self
             .config
-            .encryption
+            .encryption_next
+            .as_ref()
+            .unwrap_or(&self.config.encryption)
             .encrypter(pass, &self.config.pwhash)?;
 
         Ok(EncryptHandle { encrypter })
@@ -223,13 +535,13 @@ impl Repo {
             .unwrap_or_else(|| Logger::root(slog::Discard, o!()));
 
         let backend = backend_select(&url)?;
-        let aio = aio::AsyncIO::new(backend, log.clone())?;
+        let aio = aio::AsyncIO::new(backend, None, log.clone())?;
 
         Repo::ensure_repo_empty_or_new(&aio)?;
         let config = config::Repo::new_from_settings(passphrase, settings)?;
         config.write(&aio)?;
 
-        let compression = config.compression.to_engine();
+        let compression = config.compression_engine();
         let hasher = config.hashing.to_hasher();
 
         Ok(Repo {
@@ -240,6 +552,20 @@ impl Repo {
             hasher,
             log,
             aio,
+            write_threads: None,
+            io_threads: None,
+            max_chunks: None,
+            probe_limit: None,
+            max_buffered: None,
+            compression_sample: None,
+            dedup_check: None,
+            write_rate: None,
+            read_cache: None,
+            progress: None,
+            dry_run: false,
+            lock_timeout: None,
+            overwrite_protection: None,
+            min_space: None,
         })
     }
 
@@ -256,13 +582,13 @@ impl Repo {
             .unwrap_or_else(|| Logger::root(slog::Discard, o!()));
 
         let backend = backend_select(url)?;
-        let aio = aio::AsyncIO::new(backend, log.clone())?;
+        let aio = aio::AsyncIO::new(backend, None, log.clone())?;
 
         let config = config::Repo::read(&aio)?;
 
-        let compression = config.compression.to_engine();
+        let compression = config.compression_engine();
         let hasher = config.hashing.to_hasher();
-        Ok(Repo {
+        let repo = Repo {
             url: url.clone(),
             backend_select,
             config,
@@ -270,7 +596,24 @@ impl Repo {
             hasher,
             log,
             aio,
-        })
+            write_threads: None,
+            io_threads: None,
+            max_chunks: None,
+            probe_limit: None,
+            max_buffered: None,
+            compression_sample: None,
+            dedup_check: None,
+            write_rate: None,
+            read_cache: None,
+            progress: None,
+            dry_run: false,
+            lock_timeout: None,
+            overwrite_protection: None,
+            min_space: None,
+        };
+        // Only the files of dead processes go, so no lock is needed
+        repo.remove_orphaned_tmp();
+        Ok(repo)
     }
 
     /// Change the passphrase
@@ -279,7 +622,7 @@ impl Repo {
         old_p: PassphraseFn<'_>,
         new_p: PassphraseFn<'_>,
     ) -> Result<()> {
-        let _lock = self.aio.lock_exclusive();
+        let _lock = self.lock_exclusive()?;
 
         if self.config.version == 0 {
             Err(Error::new(
@@ -297,13 +640,170 @@ impl Repo {
         }
     }
 
+    /// Re-encrypt all the data under a new key
+    ///
+    /// Unlike `change_passphrase`, which only seals the same key with
+    /// another passphrase, this generates a new key (sealed with the same
+    /// passphrase), and rewrites every chunk encrypted with the old one.
+    /// Handles unlocked before can't decrypt the data anymore. Meant for
+    /// when the key might have been compromised; change the passphrase
+    /// afterwards too, if it might have been as well.
+    ///
+    /// The new key is recorded in the config before any chunk is
+    /// rewritten, and until they all are, data is encrypted with the new
+    /// key and decrypted with either. An interrupted re-encryption thus
+    /// leaves all data readable, and is finished by running it again.
+    /// Returns the number of chunks re-encrypted.
+    ///
+    /// Chunks that decrypt with neither key, and are not (unencrypted)
+    /// index chunks either, are corrupted. They are all reported, and the
+    /// old key is kept, so they stay readable if restored (e.g. from a
+    /// mirror, see `repair`) before running it again.
+    pub fn reencrypt(&mut self, pass: PassphraseFn<'_>) -> Result<usize> {
+        let _lock = self.lock_exclusive()?;
+        self.ensure_not_safe_mode("re-encrypt")?;
+
+        let mut config = config::Repo::read(&self.aio)?;
+        if let config::Encryption::None = config.encryption {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "repo is not encrypted",
+            ));
+        }
+
+        let passphrase = pass()?;
+        let pass = || Ok(passphrase.clone());
+        let decrypter = config.encryption.decrypter(&pass, &config.pwhash)?;
+        let next = match config.encryption_next {
+            Some(ref next) => {
+                info!(self.log, "Resuming previous re-encryption");
+                next.clone()
+            }
+            None => {
+                let next = config::Encryption::Curve25519(
+                    encryption::Curve25519::new(&pass, &config.pwhash)?,
+                );
+                config.encryption_next = Some(next.clone());
+                config.write(&self.aio)?;
+                next
+            }
+        };
+        let encrypter = next.encrypter(&pass, &config.pwhash)?;
+        let next_decrypter = next.decrypter(&pass, &config.pwhash)?;
+
+        let mut count = 0;
+        let mut failed = vec![];
+        for gen in self.read_generations()? {
+            let gen_str = gen.to_string();
+            let digests = StoredChunks::new(
+                &self.aio,
+                PathBuf::from(&gen_str).join(config::DATA_SUBDIR),
+                DIGEST_SIZE,
+                self.log.clone(),
+            )?;
+            for digest in digests {
+                let digest = digest?;
+                let path =
+                    self.chunk_rel_path_by_digest(DigestRef(&digest), &gen_str);
+                let data = self.aio.read(path.clone()).wait()?;
+                let data = match decrypter.decrypt(data.clone(), &digest) {
+                    Ok(data) => data,
+                    // Index chunks are not encrypted
+                    Err(_) if self.hasher.calculate_digest(&data) == digest => {
+                        continue
+                    }
+                    // Re-encrypted by an interrupted run
+                    Err(_) if next_decrypter.decrypt(data, &digest).is_ok() => {
+                        continue
+                    }
+                    Err(e) => {
+                        warn!(self.log, "Couldn't decrypt chunk";
+                              "path" => %path.display(), "err" => %e);
+                        failed.push(path);
+                        continue;
+                    }
+                };
+                self.aio
+                    .write(path, encrypter.encrypt(data, &digest)?)
+                    .wait()?;
+                count += 1;
+            }
+        }
+
+        if let Some(path) = failed.first() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} chunks (like {}) decrypt with neither key; \
+                     kept the old key, so they can still be restored",
+                    failed.len(),
+                    path.display()
+                ),
+            ));
+        }
+
+        config.encryption = next;
+        config.encryption_next = None;
+        config.write(&self.aio)?;
+        self.config = config;
+        info!(self.log, "Re-encryption finished"; "chunks" => count);
+        Ok(count)
+    }
+
+    /// Enable or disable safe mode
+    ///
+    /// In safe mode all operations that remove or replace any stored data
+    /// fail, while storing new data works as usual. These are:
+    ///
+    /// * `rm`
+    /// * `rename` replacing an existing name
+    /// * `prune`
+    /// * `prune_versions`
+    /// * `gc`
+    /// * `reencrypt`, which rewrites every chunk
+    /// * `repair`, which replaces chunks
+    pub fn set_safe_mode(&mut self, enable: bool) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
+
+        let mut config = config::Repo::read(&self.aio)?;
+        config.safe_mode = enable;
+        config.write(&self.aio)?;
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn is_safe_mode(&self) -> bool {
+        self.config.safe_mode
+    }
+
+    /// Average size of the data chunks the repo's chunking produces
+    pub fn avg_chunk_size(&self) -> u64 {
+        self.config.chunking.avg_chunk_size()
+    }
+
+    /// Fail if the repo is in safe mode
+    ///
+    /// Reads the config again, as safe mode could have been enabled since
+    /// the repo was opened. Call with the repo locked.
+    fn ensure_not_safe_mode(&self, operation: &str) -> Result<()> {
+        if config::Repo::read(&self.aio)?.safe_mode {
+            return Err(Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("repo is in safe mode, refusing to {}", operation),
+            ));
+        }
+        Ok(())
+    }
+
     /// Write a chunk of data to the repo.
     fn chunk_and_write_data_thread<'a>(
         &'a self,
         input_data_iter: Box<dyn Iterator<Item = Vec<u8>> + Send + 'a>,
         process_tx: crossbeam_channel::Sender<chunk_processor::Message>,
         aio: aio::AsyncIO,
+        budget: Arc<MemoryBudget>,
         data_type: DataType,
+        entries_tx: Option<mpsc::Sender<index::IndexEntry>>,
     ) -> io::Result<DataAddress> {
         // Note: This channel is intentionally unbounded
         // The processing loop runs in sort of a loop (actually more of a
@@ -325,8 +825,13 @@ impl Repo {
             );
             timer.start("spawn-chunker");
 
-            scope.spawn({
+            let max_chunks = match data_type {
+                DataType::Data => self.max_chunks,
+                DataType::Index => None,
+            };
+            let chunker = scope.spawn({
                 let process_tx = process_tx.clone();
+                let budget = Arc::clone(&budget);
                 move |_| {
                     let mut timer = slog_perf::TimeReporter::new_with_level(
                         "chunker",
@@ -336,7 +841,8 @@ impl Repo {
 
                     let chunker = chunking::Chunker::new(
                         input_data_iter,
-                        self.config.chunking.to_engine(),
+                        self.config.chunking_engine(),
+                        self.config.chunking_tail,
                     );
 
                     let mut data = util::EnumerateU64::new(chunker);
@@ -346,147 +852,637 @@ impl Repo {
                     {
                         timer.start("tx");
                         let (i, sg) = i_sg;
+                        if let Some(max_chunks) = max_chunks {
+                            if i >= max_chunks {
+                                return Err(chunk_limit_error(max_chunks));
+                            }
+                        }
+                        timer.start("tx-wait-buffered");
+                        let permit = budget.acquire(sg.len() as u64);
+                        timer.start("tx");
                         process_tx
                             .send(chunk_processor::Message {
                                 data: (i as u64, sg),
                                 response_tx: digests_tx.clone(),
                                 data_type,
+                                permit,
                             })
                             .expect("chunk process tx channel closed")
                     }
                     drop(digests_tx);
+                    Ok(())
                 }
             });
 
             timer.start("sorting-recv-create");
-            let mut digests_rx = SortingIterator::new(digests_rx.into_iter());
+            let digests_rx = SortingIterator::new(digests_rx.into_iter())
+                .inspect(move |entry| {
+                    if let Some(ref entries_tx) = entries_tx {
+                        entries_tx
+                            .send(entry.clone())
+                            .expect("entries tx channel closed")
+                    }
+                });
 
             timer.start("digest-rx");
-            let first_digest =
-                digests_rx.next().expect("At least one index digest");
-
-            if let Some(second_digest) =
-                timer.start_with("digest-rx", || digests_rx.next())
-            {
-                let mut two_first = vec![first_digest, second_digest];
-                let mut address = self.chunk_and_write_data_thread(
-                    Box::new(
-                        two_first
-                            .drain(..)
-                            .chain(digests_rx)
-                            .map(|digest| digest.0),
-                    ),
-                    process_tx,
-                    aio.clone(),
-                    DataType::Index,
-                )?;
-
-                address.index_level += 1;
-                Ok(address)
-            } else {
-                Ok(DataAddress {
-                    index_level: 0,
-                    digest: first_digest,
-                })
-            }
+            let address = self.write_index(
+                Box::new(digests_rx),
+                process_tx,
+                aio,
+                budget,
+            )?;
+            chunker.join().expect("chunker thread panicked")?;
+            Ok(address)
         })
         .expect("chunker thread failed")
     }
 
+    /// Write the index of data consisting of chunks of `entries`
+    ///
+    /// Data of a single chunk needs no index.
+    fn write_index<'a>(
+        &'a self,
+        mut entries: Box<dyn Iterator<Item = index::IndexEntry> + Send + 'a>,
+        process_tx: crossbeam_channel::Sender<chunk_processor::Message>,
+        aio: aio::AsyncIO,
+        budget: Arc<MemoryBudget>,
+    ) -> io::Result<DataAddress> {
+        let first_entry = entries.next().expect("At least one index digest");
+
+        if let Some(second_entry) = entries.next() {
+            let codec = self.config.index_codec();
+            let mut two_first = vec![first_entry, second_entry];
+            let mut address = self.chunk_and_write_data_thread(
+                Box::new(
+                    two_first
+                        .drain(..)
+                        .chain(entries)
+                        .map(move |entry| codec.encode_to_vec(&entry)),
+                ),
+                process_tx,
+                aio,
+                budget,
+                DataType::Index,
+                None,
+            )?;
+
+            address.index_level += 1;
+            Ok(address)
+        } else {
+            Ok(DataAddress {
+                index_level: 0,
+                digest: first_entry.digest,
+            })
+        }
+    }
+
     /// Number of threads to use to parallelize CPU-intense part of
     /// the workload.
     fn write_cpu_thread_num(&self) -> usize {
-        num_cpus::get()
+        self.write_threads.unwrap_or_else(num_cpus::get)
     }
 
-    fn input_reader_thread<R>(
-        &self,
-        reader: R,
-        chunker_tx: mpsc::SyncSender<Vec<u8>>,
-    ) where
-        R: Read + Send,
-    {
-        let mut time = TimeReporter::new_with_level(
-            "input-reader",
+    /// Set the number of threads processing chunks during `write`
+    ///
+    /// The stored data (and thus the root digest) does not depend on it:
+    /// chunk edges are determined by the input only, and the index is
+    /// always assembled in input order no matter which thread finished
+    /// first. `None` uses one thread per CPU (the default).
+    pub fn set_write_thread_num(&mut self, num: Option<usize>) -> Result<()> {
+        if num == Some(0) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "number of write threads must be greater than zero",
+            ));
+        }
+        self.write_threads = num;
+        Ok(())
+    }
+
+    /// Set the number of threads doing backend I/O
+    ///
+    /// As many backend operations run at once, so it can be lowered for
+    /// backends where concurrent access causes contention, or raised
+    /// for high-latency ones. `None` uses four threads per CPU (the
+    /// default).
+    pub fn set_io_thread_num(&mut self, num: Option<usize>) -> Result<()> {
+        if num == Some(0) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "number of I/O threads must be greater than zero",
+            ));
+        }
+        let backend = (self.backend_select)(&self.url)?;
+        self.aio = aio::AsyncIO::with_write_rate(
+            backend,
+            num,
+            self.write_rate,
             self.log.clone(),
-            Level::Debug,
-        );
+        )?;
+        self.aio.set_read_cache(self.read_cache);
+        self.aio.set_progress(self.progress.clone());
+        self.io_threads = num;
+        Ok(())
+    }
 
-        let r2vi = ReaderVecIter::new(reader, INGRESS_BUFFER_SIZE);
-        let mut while_ok = WhileOk::new(r2vi);
+    /// Number of threads doing backend I/O (see `set_io_thread_num`)
+    pub fn io_thread_num(&self) -> usize {
+        self.aio.thread_num()
+    }
 
-        while let Some(buf) = time.start_with("input", || while_ok.next()) {
-            time.start("tx");
-            chunker_tx.send(buf).expect("chunker tx channel closed")
+    /// Limit the bytes written to the backend per second
+    ///
+    /// Use to leave some bandwidth of a slow remote backend for others.
+    /// The limit is shared by all the I/O threads, and a second worth of
+    /// bytes can be written at once after a pause. `None` means no limit
+    /// (the default).
+    pub fn set_write_rate(&mut self, rate: Option<u64>) -> Result<()> {
+        if rate == Some(0) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "write rate must be greater than zero",
+            ));
         }
+        let backend = (self.backend_select)(&self.url)?;
+        self.aio = aio::AsyncIO::with_write_rate(
+            backend,
+            self.io_threads,
+            rate,
+            self.log.clone(),
+        )?;
+        self.aio.set_read_cache(self.read_cache);
+        self.aio.set_progress(self.progress.clone());
+        self.write_rate = rate;
+        Ok(())
+    }
 
-        if let Some(e) = while_ok.finish() {
-            panic!("Input thread error: {}", e)
+    /// Keep up to `max_bytes` of the data read from the backend last in
+    /// memory
+    ///
+    /// Chunks read again while cached, like the ones repeated in the data
+    /// loaded, are not read from the backend again. `None` disables the
+    /// cache (the default).
+    pub fn set_read_cache(&mut self, max_bytes: Option<u64>) -> Result<()> {
+        if max_bytes == Some(0) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "read cache size must be greater than zero",
+            ));
         }
+        self.aio.set_read_cache(max_bytes);
+        self.read_cache = max_bytes;
+        Ok(())
     }
 
-    fn get_chunk_accessor(
-        &self,
-        decrypter: Option<ArcDecrypter>,
-        compression: ArcCompression,
-        generations: Vec<Generation>,
-    ) -> DefaultChunkAccessor<'_> {
-        DefaultChunkAccessor::new(self, decrypter, compression, generations)
+    /// Call `progress` with every `ProgressEvent` of the backend I/O,
+    /// like a chunk read or written, or stop with `None` (the default)
+    ///
+    /// It's called by the I/O threads, right after the operations, so it
+    /// has to be cheap, and must not block.
+    pub fn set_progress(&mut self, progress: Option<ProgressFn>) {
+        self.aio.set_progress(progress.clone());
+        self.progress = progress;
     }
 
-    fn get_recording_chunk_accessor<'a>(
-        &'a self,
-        accessed: &'a mut HashSet<Vec<u8>>,
-        decrypter: Option<ArcDecrypter>,
-        compression: ArcCompression,
-        generations: Vec<Generation>,
-    ) -> RecordingChunkAccessor<'a> {
-        RecordingChunkAccessor::new(
-            self,
-            accessed,
-            decrypter,
-            compression,
-            generations,
-        )
+    /// Limit the number of data chunks a single `write` can produce
+    ///
+    /// A `write` exceeding it fails without storing the name. This guards
+    /// against runaway inputs (or chunking settings) filling up the repo.
+    /// `None` means no limit (the default).
+    pub fn set_max_chunks(&mut self, max: Option<u64>) -> Result<()> {
+        if max == Some(0) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "maximum number of chunks must be greater than zero",
+            ));
+        }
+        self.max_chunks = max;
+        Ok(())
     }
 
-    fn wipe_generation_maybe(
-        &self,
-        gen: Generation,
-        min_age_secs: u64,
-    ) -> io::Result<()> {
-        let gen_config = match gen.load_config(&self.aio) {
-            Ok(c) => c,
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-                info!(
-                    self.log,
-                    "Generation config file not found. Rerun GC later to finish";
-                );
-
-                return Ok(());
-            }
-            Err(e) => return Err(e),
-        };
+    /// Limit the number of checks if a chunk is already stored, that
+    /// `write` has in progress at once
+    ///
+    /// Every chunk processing thread (see `set_write_thread_num`) does one
+    /// check at a time, so only a limit lower than the number of them has
+    /// an effect. Use to not overwhelm a backend with slow checks while
+    /// still using all the CPUs. `None` means no limit (the default).
+    pub fn set_max_probes(&mut self, max: Option<usize>) -> Result<()> {
+        if max == Some(0) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "maximum number of probes must be greater than zero",
+            ));
+        }
+        self.probe_limit = max.map(|max| Arc::new(Semaphore::new(max)));
+        Ok(())
+    }
 
-        if gen_config.created + chrono::Duration::seconds(min_age_secs as i64)
-            > chrono::Utc::now()
-        {
-            info!(
-                self.log,
-                "Generation is not old enough. Rerun GC later to finish";
-                "gen" => FnValue(|_| gen.to_string()),
-                "gen-created" => gen_config.created.to_rfc3339(),
-                "now" => chrono::Utc::now().to_rfc3339(),
-            );
-            return Ok(());
+    /// Limit the bytes of chunks `write` has buffered at once
+    ///
+    /// Counts chunks from when they are cut from the input until they are
+    /// stored (or found to be stored already), so a slow backend makes
+    /// the input be read slower, instead of the chunks piling up in
+    /// memory. A chunk is let through if nothing else is buffered, so the
+    /// limit can be exceeded by a chunk larger than it. The most buffered
+    /// is reported in `WriteStats::peak_buffered` either way. `None` means
+    /// no limit (the default).
+    pub fn set_max_buffered(&mut self, max: Option<u64>) -> Result<()> {
+        if max == Some(0) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "maximum buffered size must be greater than zero",
+            ));
         }
-        info!(
-            self.log,
-            "Reclaiming old generation finished. Deleting...";
-            "gen" => FnValue(|_| gen.to_string()),
-        );
+        self.max_buffered = max;
+        Ok(())
+    }
 
-        // Make sure chunks are successfully removed before
+    /// Pick the compression of the data of every `write` by sampling it
+    ///
+    /// The first `chunks` chunks of the data are compressed with the
+    /// repo's compression. Unless that saves at least 1/16 of their size,
+    /// the data is stored uncompressed. The choice is recorded with the
+    /// name, and each chunk tells how it was compressed, so names
+    /// deduplicate with each other regardless. `None` always uses the
+    /// repo's compression (the default).
+    ///
+    /// Not supported by repos created before chunks told their compression
+    /// (version 4 and lower).
+    pub fn set_compression_sample(
+        &mut self,
+        chunks: Option<usize>,
+    ) -> Result<()> {
+        if chunks == Some(0) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "number of chunks to sample must be greater than zero",
+            ));
+        }
+        if chunks.is_some() && !self.config.codec_header() {
+            return Err(Error::new(
+                io::ErrorKind::Unsupported,
+                "chunks of this repo don't tell their compression, \
+                 so it can't differ between names",
+            ));
+        }
+        self.compression_sample = chunks;
+        Ok(())
+    }
+
+    /// Compare how well the data of every `write` deduplicates with the
+    /// names stored before it
+    ///
+    /// The deduplication ratio is the length of the data per byte of new
+    /// chunks stored for it. If it's below `min_fraction` of the ratio of
+    /// the `baseline` names stored last (taken together), a warning is
+    /// logged and `WriteStats::low_dedup` set, or with `abort`, the
+    /// `write` fails without storing the name. A sudden drop usually
+    /// means something is off, like changed chunking settings, and would
+    /// otherwise only show as the repo growing. Names stored by older
+    /// rdedup versions don't count for the baseline, and without any
+    /// names to compare with, the check passes. `None` disables the check
+    /// (the default).
+    pub fn set_dedup_check(&mut self, check: Option<DedupCheck>) -> Result<()> {
+        if let Some(check) = check {
+            if check.baseline == 0 {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "dedup check baseline must be at least one name",
+                ));
+            }
+            if !(check.min_fraction > 0.0 && check.min_fraction <= 1.0) {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "dedup check fraction must be in (0, 1]",
+                ));
+            }
+        }
+        self.dedup_check = check;
+        Ok(())
+    }
+
+    /// Make `write` (and alike) go through the data without storing
+    /// anything
+    ///
+    /// Chunks are still looked up in the repo, so the `WriteStats`
+    /// returned tell how many new chunks and bytes would be stored.
+    /// Neither the name nor the chunk cache of `write_file` are updated.
+    /// Disabled by default.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Wait at most `timeout` for the lock of the repository
+    ///
+    /// Operations that can't take the lock in time fail with
+    /// `Error::Locked`, telling who holds it, if the backend knows. Meant
+    /// for locks a crashed process could have left behind, e.g. on a
+    /// network filesystem. `None` (the default) waits forever.
+    pub fn set_lock_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.lock_timeout = timeout;
+    }
+
+    /// Make `write` (and alike) refuse to store a new version over a name
+    /// written within the last `window`
+    ///
+    /// Guards against scripts that re-run and replace the backup they just
+    /// made. The write fails with `AlreadyExists` then, after storing the
+    /// data, which a `write` with the protection off (the default) can
+    /// reuse to force the new version. Only applies with name versioning,
+    /// as without it, existing names are never replaced.
+    pub fn set_overwrite_protection(
+        &mut self,
+        window: Option<std::time::Duration>,
+    ) {
+        self.overwrite_protection = window;
+    }
+
+    /// Make `write` (and alike) fail before storing anything if less than
+    /// `bytes` of space is available to the repository (see `space`)
+    ///
+    /// The write fails with `io::ErrorKind::StorageFull` then, or with
+    /// `Unsupported` if the backend can't tell. Not checked by dry runs.
+    /// Disabled by default.
+    pub fn set_min_space(&mut self, bytes: Option<u64>) {
+        self.min_space = bytes;
+    }
+
+    /// Total and available space of the storage of the repository
+    ///
+    /// Fails with `io::ErrorKind::Unsupported` for backends that can't
+    /// tell, like the remote ones.
+    pub fn space(&self) -> Result<backends::FsStats> {
+        Ok(self.aio.space()?)
+    }
+
+    /// Keep up to `capacity` timestamped snapshots of the backend stats,
+    /// taken as they change, at most once per `interval`, for
+    /// `stats_since`
+    ///
+    /// The stats are the ones of this `Repo` (and its clones) only, since
+    /// it was opened.
+    pub fn set_stats_history(
+        &mut self,
+        capacity: usize,
+        interval: std::time::Duration,
+    ) -> Result<()> {
+        if capacity == 0 {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "number of stats snapshots must be greater than zero",
+            ));
+        }
+        self.aio.stats().record_history(capacity, interval);
+        Ok(())
+    }
+
+    /// Backend stats accumulated over the last `window`, if kept (see
+    /// `set_stats_history`)
+    ///
+    /// Activity up to the history `interval` before the window can be
+    /// included, and a window starting before the oldest snapshot kept
+    /// only covers from it.
+    pub fn stats_since(
+        &self,
+        window: std::time::Duration,
+    ) -> Option<StatsSnapshot> {
+        self.aio.stats().since(window)
+    }
+
+    /// Stop accessing the backend, e.g. while it's taken offline for
+    /// maintenance
+    ///
+    /// Operations of this `Repo` (and its clones) in progress in other
+    /// threads wait until `resume`, losing nothing. Backend operations
+    /// already started finish first.
+    pub fn pause(&self) {
+        self.aio.pause()
+    }
+
+    /// Resume accessing the backend after `pause`
+    pub fn resume(&self) {
+        self.aio.resume()
+    }
+
+    /// Wait for all the backend I/O to finish, and stop the I/O threads
+    ///
+    /// Unlike dropping the `Repo`, fails with the first error of a
+    /// backend write nothing waited for, instead of losing it. Fails
+    /// without stopping anything if clones of the `Repo` still exist.
+    pub fn close(self) -> Result<()> {
+        Ok(self.aio.shutdown()?)
+    }
+
+    /// Run `f` on a clone of the repo for a single operation, with a new
+    /// unique id
+    ///
+    /// The id is in the context (as `op`) of all the log records of the
+    /// operation, including the ones of the `AsyncIO` workers processing
+    /// its jobs, and in the `Error::Io` it fails with. The workers' own
+    /// timing records span operations, so they are left without it.
+    fn in_op<T>(&self, f: impl FnOnce(&Repo) -> Result<T>) -> Result<T> {
+        let op = hex::encode(rand::thread_rng().gen::<[u8; 8]>());
+        let mut repo = self.clone();
+        repo.log = self.log.new(o!("op" => op.clone()));
+        repo.aio = self.aio.with_op(&op);
+        f(&repo).map_err(|error| match error {
+            Error::Io(error) => Error::new(
+                error.kind(),
+                InOp {
+                    op: op.clone(),
+                    error,
+                },
+            ),
+            error => error,
+        })
+    }
+
+    /// Pick the compression for the data of `reader` (see
+    /// `set_compression_sample`)
+    ///
+    /// Returns the data read from `reader` for sampling too, which is to
+    /// be stored before the rest of it.
+    fn sample_compression<R: Read>(
+        &self,
+        reader: &mut R,
+        chunks: usize,
+    ) -> io::Result<(config::Compression, Vec<u8>)> {
+        let mut read = vec![];
+        if self.config.compression == config::Compression::None {
+            return Ok((config::Compression::None, read));
+        }
+
+        let mut input =
+            WhileOk::new(ReaderVecIter::new(reader, INGRESS_BUFFER_SIZE));
+        let sample: Vec<_> = chunking::Chunker::new(
+            input.by_ref().inspect(|buf| read.extend_from_slice(buf)),
+            self.config.chunking_engine(),
+            self.config.chunking_tail,
+        )
+        .take(chunks)
+        .collect();
+        if let Some(e) = input.finish() {
+            return Err(e);
+        }
+
+        let len: u64 = sample.iter().map(|sg| sg.len() as u64).sum();
+        let mut compressed_len = 0;
+        for sg in sample {
+            compressed_len += self.compression.compress(sg)?.len() as u64;
+        }
+
+        let compression = if compressed_len <= len - len / 16 {
+            self.config.compression
+        } else {
+            config::Compression::None
+        };
+        info!(self.log, "Sampled compression";
+            "len" => len,
+            "compressed-len" => compressed_len,
+            "compression" => ?compression,
+        );
+        Ok((compression, read))
+    }
+
+    /// Wait until checking if a chunk is stored is within the limit
+    ///
+    /// See `set_max_probes`.
+    fn probe_permit(&self) -> Option<SemaphoreGuard<'_>> {
+        self.probe_limit.as_ref().map(|limit| limit.acquire())
+    }
+
+    /// Send the data of `reader` to the chunker, counting its bytes in
+    /// `len`
+    fn input_reader_thread<R>(
+        &self,
+        reader: R,
+        chunker_tx: mpsc::SyncSender<Vec<u8>>,
+        len: &AtomicU64,
+    ) where
+        R: Read + Send,
+    {
+        let mut time = TimeReporter::new_with_level(
+            "input-reader",
+            self.log.clone(),
+            Level::Debug,
+        );
+
+        let r2vi = ReaderVecIter::new(reader, INGRESS_BUFFER_SIZE);
+        let mut while_ok = WhileOk::new(r2vi);
+
+        while let Some(buf) = time.start_with("input", || while_ok.next()) {
+            time.start("tx");
+            len.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            if chunker_tx.send(buf).is_err() {
+                // chunker gave up (see `set_max_chunks`)
+                return;
+            }
+        }
+
+        if let Some(e) = while_ok.finish() {
+            panic!("Input thread error: {}", e)
+        }
+    }
+
+    fn get_chunk_accessor(
+        &self,
+        decrypter: Option<ArcDecrypter>,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+    ) -> DefaultChunkAccessor<'_> {
+        DefaultChunkAccessor::new(self, decrypter, compression, generations)
+    }
+
+    fn get_recording_chunk_accessor<'a>(
+        &'a self,
+        accessed: &'a mut HashSet<Vec<u8>>,
+        decrypter: Option<ArcDecrypter>,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+    ) -> RecordingChunkAccessor<'a> {
+        RecordingChunkAccessor::new(
+            self,
+            accessed,
+            decrypter,
+            compression,
+            generations,
+        )
+    }
+
+    /// This repo, but reading data as stored with `params`
+    ///
+    /// Data of names is read according to the params recorded with it
+    /// (if any), not the current repo config.
+    fn with_params(
+        &self,
+        params: Option<&NameParams>,
+    ) -> Result<Cow<'_, Repo>> {
+        let params = match params {
+            Some(params) => params,
+            None => return Ok(Cow::Borrowed(self)),
+        };
+
+        if params.encryption != self.config.encryption.type_name() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "data stored with {} encryption, but the repo uses {}",
+                    params.encryption,
+                    self.config.encryption.type_name()
+                ),
+            ));
+        }
+
+        let mut repo = self.clone();
+        repo.config.hashing = params.hashing;
+        repo.config.index_format = params.index_format;
+        // Otherwise the chunks tell
+        if !repo.config.codec_header() {
+            repo.config.compression = params.compression;
+            repo.compression = repo.config.compression_engine();
+        }
+        repo.hasher = params.hashing.to_hasher();
+        Ok(Cow::Owned(repo))
+    }
+
+    fn wipe_generation_maybe(
+        &self,
+        gen: Generation,
+        min_age_secs: u64,
+    ) -> io::Result<GcResults> {
+        let gen_config = match gen.load_config(&self.aio) {
+            Ok(c) => c,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                info!(
+                    self.log,
+                    "Generation config file not found. Rerun GC later to finish";
+                );
+
+                return Ok(GcResults::default());
+            }
+            Err(e) => return Err(e),
+        };
+
+        if gen_config.created + chrono::Duration::seconds(min_age_secs as i64)
+            > chrono::Utc::now()
+        {
+            info!(
+                self.log,
+                "Generation is not old enough. Rerun GC later to finish";
+                "gen" => FnValue(|_| gen.to_string()),
+                "gen-created" => gen_config.created.to_rfc3339(),
+                "now" => chrono::Utc::now().to_rfc3339(),
+            );
+            return Ok(GcResults::default());
+        }
+        info!(
+            self.log,
+            "Reclaiming old generation finished. Deleting...";
+            "gen" => FnValue(|_| gen.to_string()),
+        );
+
+        // Make sure chunks are successfully removed before
         // attempting to delete the generation dir itself
         // so that we don't leave garbage with no Generation
         // config file.
@@ -499,12 +1495,10 @@ impl Repo {
             || (),
         )?;
 
+        let data_dir = PathBuf::from(gen.to_string()).join(config::DATA_SUBDIR);
+        let results = self.stored_size(data_dir.clone())?;
         substitute_err_not_found(
-            self.aio
-                .remove_dir_all(
-                    PathBuf::from(gen.to_string()).join(config::DATA_SUBDIR),
-                )
-                .wait(),
+            self.aio.remove_dir_all(data_dir).wait(),
             || (),
         )?;
 
@@ -512,7 +1506,38 @@ impl Repo {
             .remove_dir_all(PathBuf::from(gen.to_string()))
             .wait()?;
 
-        Ok(())
+        info!(self.log, "Generation deleted";
+            "gen" => FnValue(|_| gen.to_string()),
+            "chunks" => results.chunks_removed,
+            "bytes" => results.bytes_freed,
+        );
+        Ok(results)
+    }
+
+    /// Number and total size of the objects stored under `dir`
+    fn stored_size(&self, dir: PathBuf) -> io::Result<GcResults> {
+        let mut pending = vec![];
+        let mut batch = vec![];
+        for path in self.aio.list_recursively(dir) {
+            match path {
+                Ok(path) => batch.push(path),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            if batch.len() == aio::backend::DEFAULT_LIST_BATCH_SIZE {
+                pending
+                    .push(self.aio.read_metadata_batch(mem::take(&mut batch)));
+            }
+        }
+        pending.push(self.aio.read_metadata_batch(batch));
+        let mut results = GcResults::default();
+        for batch in pending {
+            for metadata in batch.wait()? {
+                results.chunks_removed += 1;
+                results.bytes_freed += metadata?.len;
+            }
+        }
+        Ok(results)
     }
 
     fn update_name_to(
@@ -530,18 +1555,17 @@ impl Repo {
             "gen" => FnValue(|_| cur_gen.to_string())
         );
         let name = Name::load_from_any(name_str, generations, &self.aio)?;
-        let data_address: DataAddress = name.into();
 
         let accessor = GenerationUpdateChunkAccessor::new(
             self,
             Arc::clone(&self.compression),
             generations.to_vec(),
         );
-        {
+        for version in name.versions() {
             let traverser = ReadContext::new(&accessor);
             traverser.read_recursively(ReadRequest::new(
                 DataType::Data,
-                data_address.as_ref(),
+                version.data_address().as_ref(),
                 None,
                 self.log.clone(),
             ))?;
@@ -557,7 +1581,7 @@ impl Repo {
         da: DataAddressRef<'_>,
         reachable_digests: &mut HashSet<Vec<u8>>,
         generations: Vec<Generation>,
-    ) -> Result<()> {
+    ) -> io::Result<()> {
         reachable_digests.insert(da.digest.0.into());
 
         let accessor = self.get_recording_chunk_accessor(
@@ -576,7 +1600,6 @@ impl Repo {
     }
 
     /// Return all reachable chunks
-    #[allow(dead_code)] // tests
     fn list_reachable_chunks(&self) -> Result<HashSet<Vec<u8>>> {
         let generations = self.read_generations()?;
         let mut reachable_digests = HashSet::new();
@@ -584,13 +1607,14 @@ impl Repo {
         for name_str in &all_names {
             match Name::load_from_any(name_str, &generations, &self.aio) {
                 Ok(name) => {
-                    let data_address: DataAddress = name.into();
                     info!(self.log, "processing"; "name" => name_str);
-                    self.reachable_recursively_insert(
-                        data_address.as_ref(),
-                        &mut reachable_digests,
-                        generations.clone(),
-                    )?;
+                    for version in name.versions() {
+                        self.reachable_recursively_insert(
+                            version.data_address().as_ref(),
+                            &mut reachable_digests,
+                            generations.clone(),
+                        )?;
+                    }
                 }
                 Err(e) => {
                     info!(
@@ -617,25 +1641,442 @@ impl Repo {
         )
     }
 
-    pub fn list_names(&self) -> io::Result<Vec<String>> {
-        let _lock = self.aio.lock_shared();
-        Name::list_all(&self.read_generations()?, &self.aio)
+    pub fn list_names(&self) -> Result<Vec<String>> {
+        let _lock = self.lock_shared()?;
+        Ok(Name::list_all(&self.read_generations()?, &self.aio)?)
     }
 
     /// Remove a stored name from repo
+    ///
+    /// Pinned names can't be removed.
     pub fn rm(&self, name: &str) -> Result<()> {
-        let _lock = self.aio.lock_exclusive();
-        Name::remove_any(name, &self.read_generations()?, &self.aio)
+        let _lock = self.lock_exclusive()?;
+        self.ensure_not_safe_mode("remove names")?;
+        Pins::load(&self.aio)?.ensure_not_pinned(name)?;
+        Ok(Name::remove_any(
+            name,
+            &self.read_generations()?,
+            &self.aio,
+        )?)
     }
 
-    pub fn gc(&self, min_age_secs: u64) -> Result<()> {
-        let _lock = self.aio.lock_exclusive();
+    /// Rename a stored name, without touching its data
+    ///
+    /// Versions, tags and the pin (if any) move along with the name.
+    /// Fails if `new_name` already exists, unless `force` is set; a pinned
+    /// name is never replaced, and none is in safe mode.
+    pub fn rename(
+        &self,
+        name: &str,
+        new_name: &str,
+        force: bool,
+    ) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
+        if name == new_name {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't rename a name to itself: {}", name),
+            ));
+        }
+        let mut pins = Pins::load(&self.aio)?;
+        if force {
+            self.ensure_not_safe_mode("replace names")?;
+            pins.ensure_not_pinned(new_name)?;
+        }
+        Name::rename(
+            name,
+            new_name,
+            force,
+            &self.read_generations()?,
+            &self.aio,
+        )?;
+        if pins.remove(name) {
+            pins.insert(new_name);
+            pins.write(&self.aio)?;
+        }
+        Ok(())
+    }
+
+    /// Pin a stored name, protecting it from removal
+    ///
+    /// `gc` never removes data reachable from any name, so pinned
+    /// names are roots just like every other name.
+    pub fn pin(&self, name: &str) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
+        let generations = self.read_generations()?;
+        Name::load_from_any(name, &generations, &self.aio)?;
+
+        let mut pins = Pins::load(&self.aio)?;
+        if pins.insert(name) {
+            pins.write(&self.aio)?;
+        }
+        Ok(())
+    }
+
+    /// Unpin a stored name, so it can be removed again
+    pub fn unpin(&self, name: &str) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
+        let mut pins = Pins::load(&self.aio)?;
+        if !pins.remove(name) {
+            return Err(Error::new(
+                io::ErrorKind::NotFound,
+                format!("name not pinned: {}", name),
+            ));
+        }
+        Ok(pins.write(&self.aio)?)
+    }
+
+    /// List pinned names
+    pub fn list_pinned(&self) -> Result<Vec<String>> {
+        let _lock = self.lock_shared()?;
+        Ok(Pins::load(&self.aio)?.list())
+    }
+
+    /// Add `tag` to the tags of a stored name
+    ///
+    /// Tags only select names for `list_names_tagged` and `prune`. Tagged
+    /// or not, every name is a root for `gc`.
+    pub fn tag(&self, name: &str, tag: &str) -> Result<()> {
+        if tag.is_empty() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "tag can't be empty",
+            ));
+        }
+
+        let _lock = self.lock_exclusive()?;
+        Name::set_tag(name, tag, true, &self.read_generations()?, &self.aio)?;
+        Ok(())
+    }
+
+    /// Remove `tag` from the tags of a stored name
+    pub fn untag(&self, name: &str, tag: &str) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
+        if !Name::set_tag(
+            name,
+            tag,
+            false,
+            &self.read_generations()?,
+            &self.aio,
+        )? {
+            return Err(Error::new(
+                io::ErrorKind::NotFound,
+                format!("name not tagged {}: {}", tag, name),
+            ));
+        }
+        Ok(())
+    }
+
+    /// List tags of a stored name
+    pub fn list_tags(&self, name: &str) -> Result<Vec<String>> {
+        let _lock = self.lock_shared()?;
+        let name =
+            Name::load_from_any(name, &self.read_generations()?, &self.aio)?;
+        Ok(name.tags.into_iter().collect())
+    }
+
+    /// List stored names matching `filter`
+    pub fn list_names_tagged(&self, filter: &TagFilter) -> Result<Vec<String>> {
+        let _lock = self.lock_shared()?;
+        self.list_names_tagged_locked(filter)
+    }
+
+    fn list_names_tagged_locked(
+        &self,
+        filter: &TagFilter,
+    ) -> Result<Vec<String>> {
+        let generations = self.read_generations()?;
+
+        let mut names = vec![];
+        for name_str in Name::list_all(&generations, &self.aio)? {
+            let name = Name::load_from_any(&name_str, &generations, &self.aio)?;
+            if filter.matches(&name.tags) {
+                names.push(name_str);
+            }
+        }
+        Ok(names)
+    }
+
+    /// Remove all stored names matching `filter`
+    ///
+    /// Pinned names are kept. To not remove everything by mistake,
+    /// `filter` must select by at least one tag.
+    ///
+    /// Returns the names removed.
+    pub fn prune(&self, filter: &TagFilter) -> Result<Vec<String>> {
+        if filter.is_empty() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "prune needs at least one tag to select names by",
+            ));
+        }
+
+        let _lock = self.lock_exclusive()?;
+        self.ensure_not_safe_mode("prune names")?;
+
+        let pins = Pins::load(&self.aio)?;
+        let generations = self.read_generations()?;
+
+        let mut removed = vec![];
+        for name in self.list_names_tagged_locked(filter)? {
+            if pins.contains(&name) {
+                info!(self.log, "Keeping pinned name"; "name" => &name);
+                continue;
+            }
+            Name::remove_any(&name, &generations, &self.aio)?;
+            removed.push(name);
+        }
+        Ok(removed)
+    }
+
+    /// Address of the data stored as `name_str`
+    ///
+    /// Keep it to be able to `read_root` the data even if the name is lost.
+    pub fn root_address(&self, name_str: &str) -> Result<RootAddress> {
+        let _lock = self.lock_shared()?;
+
+        let generations = self.read_generations()?;
+        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        Ok(self.root_address_of(name.into()))
+    }
+
+    fn root_address_of(&self, data_address: DataAddress) -> RootAddress {
+        RootAddress {
+            hashing: Some(settings::Hashing::from_config(self.config.hashing)),
+            digest: data_address.digest.0,
+            index_level: data_address.index_level,
+        }
+    }
+
+    /// Fail if `root` can't be an address in this repo
+    ///
+    /// Reading a digest calculated with a different hashing would just
+    /// fail to find the chunk, which is confusing.
+    fn check_root_address(&self, root: &RootAddress) -> Result<()> {
+        let repo_hashing = settings::Hashing::from_config(self.config.hashing);
+        if let Some(ref hashing) = root.hashing {
+            if *hashing != repo_hashing {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "root address uses {} hashing, but the repo uses {}",
+                        hashing.name(),
+                        repo_hashing.name()
+                    ),
+                ));
+            }
+        }
+        if root.digest.len() != DIGEST_SIZE {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "root address digest is {} bytes long, but {} digests \
+                     of the repo are {}",
+                    root.digest.len(),
+                    repo_hashing.name(),
+                    DIGEST_SIZE
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Find the roots of all the data stored in the repo
+    ///
+    /// Every stored chunk is read, looking for index chunks that no other
+    /// index chunk refers to. Data stored as a single chunk has no index,
+    /// and can't be found this way. See `recover_names`.
+    pub fn scan_roots(&self) -> Result<Vec<RootAddress>> {
+        let _lock = self.lock_shared()?;
+        Ok(self.scan_roots_locked()?)
+    }
+
+    /// Store every root found by `scan_roots` that no name refers to as
+    /// `<prefix><digest-hex>`
+    ///
+    /// Returns the names stored.
+    pub fn recover_names(&self, prefix: &str) -> Result<Vec<String>> {
+        let _lock = self.lock_shared()?;
+
+        let roots = self.scan_roots_locked()?;
+        let named = self.list_reachable_chunks()?;
+        let generations = self.read_generations()?;
+
+        let mut names = vec![];
+        for root in roots {
+            if named.contains(&root.digest) {
+                continue;
+            }
+            let name_str = format!("{}{}", prefix, hex::encode(&root.digest));
+            let mut name: Name = root.data_address().into();
+            name.params = Some(NameParams::new(&self.config));
+            name.write_as(&name_str, *generations.last().unwrap(), &self.aio)?;
+            names.push(name_str);
+        }
+        Ok(names)
+    }
+
+    fn scan_roots_locked(&self) -> io::Result<Vec<RootAddress>> {
+        let generations = self.read_generations()?;
+        let codec = self.config.index_codec();
+
+        // digest of every index chunk -> digest in its first entry
+        let mut index_chunks: HashMap<Vec<u8>, Option<Vec<u8>>> =
+            HashMap::new();
+        for gen in &generations {
+            let dir = PathBuf::from(gen.to_string()).join(config::DATA_SUBDIR);
+            // drain the listing, even on errors
+            let paths: Vec<_> = self.aio.list_recursively(dir).collect();
+            for path in paths {
+                let path = match path {
+                    Ok(path) => path,
+                    // no chunks stored in the generation
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        continue
+                    }
+                    Err(e) => return Err(e),
+                };
+                let digest = match path
+                    .file_name()
+                    .and_then(|file| file.to_str())
+                    .and_then(|file| hex::decode(file).ok())
+                {
+                    Some(digest) if digest.len() == DIGEST_SIZE => digest,
+                    _ => continue,
+                };
+                if index_chunks.contains_key(&digest) {
+                    continue;
+                }
+
+                // Index chunks are stored as they are (unlike the data
+                // chunks), so only they match their digest.
+                let data = self.aio.read(path).wait()?;
+                if self.hasher.calculate_digest(&data) != digest {
+                    continue;
+                }
+                let first = codec
+                    .decode(&data.to_linear_vec())
+                    .ok()
+                    .flatten()
+                    .map(|(entry, _)| entry.digest.0);
+                index_chunks.insert(digest, first);
+            }
+        }
+
+        // The index of an index is an index chunk too
+        let level_of = |digest: &Vec<u8>| {
+            let mut level = 1;
+            let mut cur = digest;
+            while let Some(Some(first)) = index_chunks.get(cur) {
+                if !index_chunks.contains_key(first)
+                    || level as usize > index_chunks.len()
+                {
+                    break;
+                }
+                level += 1;
+                cur = first;
+            }
+            level
+        };
+        let mut candidates: Vec<_> = index_chunks
+            .keys()
+            .map(|digest| {
+                self.root_address_of(DataAddress {
+                    digest: Digest(digest.clone()),
+                    index_level: level_of(digest),
+                })
+            })
+            .collect();
+        // Visit the roots before the index chunks they refer to
+        candidates.sort_by(|a, b| {
+            b.index_level
+                .cmp(&a.index_level)
+                .then_with(|| a.digest.cmp(&b.digest))
+        });
+
+        let mut reachable = HashSet::new();
+        let mut roots = vec![];
+        for candidate in candidates {
+            if reachable.contains(&candidate.digest) {
+                continue;
+            }
+            let mut accessed = HashSet::new();
+            match self.reachable_recursively_insert(
+                candidate.data_address().as_ref(),
+                &mut accessed,
+                generations.clone(),
+            ) {
+                Ok(()) => {
+                    reachable.extend(accessed);
+                    roots.push(candidate);
+                }
+                Err(e) => info!(
+                    self.log,
+                    "skipped index chunk";
+                    "digest" => hex::encode(&candidate.digest),
+                    "error" => e.to_string()
+                ),
+            }
+        }
+        Ok(roots)
+    }
+
+    /// List all the versions of a stored name, oldest first
+    ///
+    /// Unless name versioning is enabled in the repo, there's only one.
+    pub fn list_versions(&self, name: &str) -> Result<Vec<NameVersionInfo>> {
+        let _lock = self.lock_shared()?;
+        let name =
+            Name::load_from_any(name, &self.read_generations()?, &self.aio)?;
+        Ok(name
+            .versions()
+            .iter()
+            .map(|version| NameVersionInfo {
+                version: version.version,
+                created: version.created,
+            })
+            .collect())
+    }
+
+    /// Remove all but `keep` newest previous versions of a stored name
+    ///
+    /// The current version is always kept. Data of the removed versions
+    /// is reclaimed by `gc`. Returns the number of versions removed.
+    pub fn prune_versions(&self, name: &str, keep: usize) -> Result<usize> {
+        let _lock = self.lock_exclusive()?;
+        self.ensure_not_safe_mode("prune versions")?;
+        Pins::load(&self.aio)?.ensure_not_pinned(name)?;
+        Ok(Name::prune_history(
+            name,
+            keep,
+            &self.read_generations()?,
+            &self.aio,
+        )?)
+    }
+
+    /// Reclaim the space of chunks no name refers to
+    ///
+    /// A new generation is started, and every name is moved to it, after
+    /// moving all the chunks it uses. Only once the oldest generation has
+    /// no names left is it removed, along with the chunks still in it,
+    /// which is all that is ever deleted. Everything is done under the
+    /// exclusive lock, and a chunk is always in one of the generations
+    /// searched when reading, so an interrupted `gc` leaves all names
+    /// (and their previous versions) readable, and the next one picks up
+    /// where it left off, even if names were stored in between.
+    ///
+    /// The oldest generation is kept until it is at least `min_age_secs`
+    /// old. Returns what was removed with it, if it was.
+    pub fn gc(&self, min_age_secs: u64) -> Result<GcResults> {
+        let _lock = self.lock_exclusive()?;
+        self.ensure_not_safe_mode("gc")?;
+        self.remove_orphaned_tmp();
 
         let generations = self.read_generations()?;
 
         if generations.is_empty() {
             info!(self.log, "Nothing in the repository yet, nothing to gc");
-            return Ok(());
+            return Ok(GcResults::default());
         }
 
         if generations.len() == 1 {
@@ -659,7 +2100,7 @@ impl Repo {
                     "One generation left - GC cycle complete";
                     "gen" => FnValue(|_| generations[0].to_string())
                 );
-                return Ok(());
+                return Ok(GcResults::default());
             }
             let gen_oldest = generations[0];
             let gen_cur = generations.last().unwrap();
@@ -673,54 +2114,310 @@ impl Repo {
                 "gen" => FnValue(|_| gen_oldest.to_string())
             );
             if names.is_empty() {
-                self.wipe_generation_maybe(gen_oldest, min_age_secs)?;
-                return Ok(());
+                return Ok(
+                    self.wipe_generation_maybe(gen_oldest, min_age_secs)?
+                );
             }
             self.update_name_to(&names[0], *gen_cur, &generations)?;
         }
     }
 
-    pub fn read<W: Write>(
+    /// Read the data stored as `name_str` into `writer`
+    ///
+    /// Safe to run while other processes store data: both only take the
+    /// shared lock, and a name is stored only after all its chunks are,
+    /// so it never references a chunk still being written. Chunks moved
+    /// to the current generation by a concurrent `write` are still found.
+    /// Only operations removing data, like `gc`, and writes of versioned
+    /// names take the exclusive lock, and wait for reads to finish (and
+    /// the other way around).
+    pub fn read<W: Write>(
+        &self,
+        name_str: &str,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        self.in_op(|repo| {
+            let _lock = repo.lock_shared()?;
+
+            let generations = repo.read_generations()?;
+
+            let name = Name::load_from_any(name_str, &generations, &repo.aio)?;
+            let repo = repo.with_params(name.params.as_ref())?;
+            let data_address: DataAddress = name.into();
+
+            let accessor = repo.get_chunk_accessor(
+                Some(Arc::clone(&dec.decrypter)),
+                Arc::clone(&repo.compression),
+                generations,
+            );
+            let traverser = ReadContext::new(&accessor);
+            Ok(traverser.read_recursively(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(writer),
+                repo.log.clone(),
+            ))?)
+        })
+    }
+
+    /// Like `read`, but only write the data from `offset` on
+    ///
+    /// Meant to resume an interrupted `read` to a file, from its length.
+    /// Data chunks before `offset` are skipped without being read, if
+    /// their lengths are stored in the index (see `read_lenient`), so
+    /// resuming doesn't cost reading everything again. Fails if the data
+    /// is shorter than `offset`.
+    pub fn read_from<W: Write>(
+        &self,
+        name_str: &str,
+        offset: u64,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        self.in_op(|repo| {
+            let _lock = repo.lock_shared()?;
+
+            let generations = repo.read_generations()?;
+
+            let name = Name::load_from_any(name_str, &generations, &repo.aio)?;
+            let repo = repo.with_params(name.params.as_ref())?;
+            let data_address: DataAddress = name.into();
+
+            let accessor = repo.get_chunk_accessor(
+                Some(Arc::clone(&dec.decrypter)),
+                Arc::clone(&repo.compression),
+                generations,
+            );
+            let traverser = ReadContext::new_from(&accessor, offset);
+            traverser.read_recursively(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(writer),
+                repo.log.clone(),
+            ))?;
+
+            let skip_left = traverser.skip_left();
+            if skip_left > 0 {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "offset {} past the end of {} ({} bytes)",
+                        offset,
+                        name_str,
+                        offset - skip_left
+                    ),
+                ));
+            }
+            Ok(())
+        })
+    }
+
+    /// Like `read`, but read a given version of the name
+    ///
+    /// See `list_versions`.
+    pub fn read_version<W: Write>(
+        &self,
+        name_str: &str,
+        version: u64,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        self.in_op(|repo| {
+            let _lock = repo.lock_shared()?;
+
+            let generations = repo.read_generations()?;
+
+            let name = Name::load_from_any(name_str, &generations, &repo.aio)?;
+            let name_version = name
+                .versions()
+                .into_iter()
+                .find(|v| v.version == version)
+                .ok_or_else(|| {
+                    Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "version {} of {} not found",
+                            version, name_str
+                        ),
+                    )
+                })?;
+            let repo = repo.with_params(name_version.params.as_ref())?;
+            let data_address = name_version.data_address();
+
+            let accessor = repo.get_chunk_accessor(
+                Some(Arc::clone(&dec.decrypter)),
+                Arc::clone(&repo.compression),
+                generations,
+            );
+            let traverser = ReadContext::new(&accessor);
+            Ok(traverser.read_recursively(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(writer),
+                repo.log.clone(),
+            ))?)
+        })
+    }
+
+    /// Like `read`, but read the data at `root`
+    ///
+    /// This works even if no name refers to the data anymore, as long as
+    /// it was not removed by `gc`.
+    pub fn read_root<W: Write>(
+        &self,
+        root: &RootAddress,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        self.check_root_address(root)?;
+
+        self.in_op(|repo| {
+            let _lock = repo.lock_shared()?;
+
+            let generations = repo.read_generations()?;
+
+            let accessor = repo.get_chunk_accessor(
+                Some(Arc::clone(&dec.decrypter)),
+                Arc::clone(&repo.compression),
+                generations,
+            );
+            let traverser = ReadContext::new(&accessor);
+            Ok(traverser.read_recursively(ReadRequest::new(
+                DataType::Data,
+                root.data_address().as_ref(),
+                Some(writer),
+                repo.log.clone(),
+            ))?)
+        })
+    }
+
+    /// Like `read`, but zero-fill data chunks that can't be read
+    ///
+    /// This requires the lengths of chunks stored in the index, so it only
+    /// helps with data written by a repository using a recent index format.
+    /// Missing index chunks still fail the read.
+    pub fn read_lenient<W: Write>(
+        &self,
+        name_str: &str,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<RestoreReport> {
+        self.in_op(|repo| {
+            let _lock = repo.lock_shared()?;
+
+            let generations = repo.read_generations()?;
+
+            let name = Name::load_from_any(name_str, &generations, &repo.aio)?;
+            let repo = repo.with_params(name.params.as_ref())?;
+            let data_address: DataAddress = name.into();
+
+            let accessor = repo.get_chunk_accessor(
+                Some(Arc::clone(&dec.decrypter)),
+                Arc::clone(&repo.compression),
+                generations,
+            );
+            let traverser = ReadContext::new_lenient(&accessor);
+            traverser.read_recursively(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(writer),
+                repo.log.clone(),
+            ))?;
+            Ok(RestoreReport {
+                gaps: traverser.into_gaps(),
+            })
+        })
+    }
+
+    pub fn du(&self, name_str: &str, dec: &DecryptHandle) -> Result<DuResults> {
+        let _lock = self.lock_shared()?;
+
+        let generations = self.read_generations()?;
+        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        let repo = self.with_params(name.params.as_ref())?;
+        let data_address: DataAddress = name.into();
+
+        let mut counter = CounterWriter::new();
+        let accessor = VerifyingChunkAccessor::new(
+            &repo,
+            Some(Arc::clone(&dec.decrypter)),
+            Arc::clone(&repo.compression),
+            generations,
+        );
+        {
+            let traverser = ReadContext::new(&accessor);
+            traverser.read_recursively(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(&mut counter),
+                self.log.clone(),
+            ))?;
+        }
+        Ok(DuResults {
+            chunks: accessor.get_results().scanned,
+            bytes: counter.count,
+        })
+    }
+
+    pub fn verify(
         &self,
         name_str: &str,
-        writer: &mut W,
         dec: &DecryptHandle,
-    ) -> Result<()> {
-        let _lock = self.aio.lock_shared();
+    ) -> Result<VerifyResults> {
+        let _lock = self.lock_shared()?;
 
         let generations = self.read_generations()?;
 
         let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        let repo = self.with_params(name.params.as_ref())?;
         let data_address: DataAddress = name.into();
 
-        let accessor = self.get_chunk_accessor(
+        let mut counter = CounterWriter::new();
+        let accessor = VerifyingChunkAccessor::new(
+            &repo,
             Some(Arc::clone(&dec.decrypter)),
-            Arc::clone(&self.compression),
+            Arc::clone(&repo.compression),
             generations,
         );
-        let traverser = ReadContext::new(&accessor);
-        traverser.read_recursively(ReadRequest::new(
-            DataType::Data,
-            data_address.as_ref(),
-            Some(writer),
-            self.log.clone(),
-        ))
+        {
+            let traverser = ReadContext::new(&accessor);
+            traverser.read_recursively(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(&mut counter),
+                self.log.clone(),
+            ))?;
+        }
+        Ok(accessor.get_results())
     }
 
-    pub fn du(&self, name_str: &str, dec: &DecryptHandle) -> Result<DuResults> {
-        let _lock = self.aio.lock_shared();
+    /// Check that all the chunks used by `name_str` are stored, without
+    /// reading the data chunks
+    ///
+    /// Data chunks are found missing or truncated from their stored length
+    /// only, which is much faster than `verify`, but doesn't detect
+    /// corrupted content. The stored length of a compressed chunk is not
+    /// known in advance, so only chunks too short to hold any data are
+    /// reported as truncated in repos using compression. No decryption
+    /// is needed, as index chunks are not encrypted.
+    pub fn verify_quick(&self, name_str: &str) -> Result<VerifyResults> {
+        let _lock = self.lock_shared()?;
 
         let generations = self.read_generations()?;
+
         let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        let repo = self.with_params(name.params.as_ref())?;
         let data_address: DataAddress = name.into();
 
         let mut counter = CounterWriter::new();
         let accessor = VerifyingChunkAccessor::new(
-            self,
-            Some(Arc::clone(&dec.decrypter)),
-            Arc::clone(&self.compression),
+            &repo,
+            None,
+            Arc::clone(&repo.compression),
             generations,
-        );
+        )
+        .quick();
         {
             let traverser = ReadContext::new(&accessor);
             traverser.read_recursively(ReadRequest::new(
@@ -730,41 +2427,251 @@ impl Repo {
                 self.log.clone(),
             ))?;
         }
-        Ok(DuResults {
-            chunks: accessor.get_results().scanned,
-            bytes: counter.count,
-        })
+        Ok(accessor.get_results())
     }
 
-    pub fn verify(
+    /// Check every chunk stored in the repository, used by a name or not
+    ///
+    /// Each chunk is read and checked against the digest it is stored
+    /// under, so corrupted chunks are found even before a name using them
+    /// is read or verified. Chunks are shared by names stored with
+    /// different settings (see `NameParams`), so any hashing and
+    /// compression is accepted.
+    pub fn verify_stored(&self, dec: &DecryptHandle) -> Result<VerifyResults> {
+        let _lock = self.lock_shared()?;
+
+        let mut hashings = vec![self.config.hashing];
+        hashings.extend(
+            config::Hashing::all()
+                .into_iter()
+                .filter(|&hashing| hashing != self.config.hashing),
+        );
+        let hashers: Vec<_> =
+            hashings.iter().map(|hashing| hashing.to_hasher()).collect();
+
+        let mut results = VerifyResults {
+            scanned: 0,
+            errors: vec![],
+        };
+        for gen in self.read_generations()? {
+            let gen_str = gen.to_string();
+            let digests = StoredChunks::new(
+                &self.aio,
+                PathBuf::from(&gen_str).join(config::DATA_SUBDIR),
+                DIGEST_SIZE,
+                self.log.clone(),
+            )?;
+            for digest in digests {
+                let digest = digest?;
+                results.scanned += 1;
+                let path =
+                    self.chunk_rel_path_by_digest(DigestRef(&digest), &gen_str);
+                let res = self.aio.read(path).wait().and_then(|data| {
+                    if stored_chunk_intact(
+                        data,
+                        &digest,
+                        &dec.decrypter,
+                        &self.compression,
+                        &hashers,
+                    ) {
+                        Ok(())
+                    } else {
+                        Err(Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} corrupted", hex::encode(&digest)),
+                        ))
+                    }
+                });
+                if let Err(e) = res {
+                    results.errors.push((digest, e));
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Replace the chunks used by `name_str` that are missing, truncated
+    /// or corrupted with their copies in `mirror`
+    ///
+    /// `mirror` must be a copy of this repo (e.g. made with `rsync`), as
+    /// the copies are stored as they are, after checking that they read
+    /// intact with this repo's key. Chunks without an intact copy are
+    /// reported as unrepairable. Fails with `InvalidInput` if `mirror` is
+    /// this very repo.
+    pub fn repair(
         &self,
         name_str: &str,
+        mirror: &Repo,
         dec: &DecryptHandle,
-    ) -> Result<VerifyResults> {
-        let _lock = self.aio.lock_shared();
+    ) -> Result<RepairResults> {
+        // its shared lock would wait for our exclusive one forever
+        if same_location(&self.url, &mirror.url) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't repair a repository from itself",
+            ));
+        }
+        let _lock = self.lock_exclusive()?;
+        self.ensure_not_safe_mode("repair")?;
+        let _mirror_lock = mirror.lock_shared()?;
 
         let generations = self.read_generations()?;
 
         let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        let repo = self.with_params(name.params.as_ref())?;
         let data_address: DataAddress = name.into();
 
-        let mut counter = CounterWriter::new();
-        let accessor = VerifyingChunkAccessor::new(
-            self,
+        let accessor = RepairingChunkAccessor::new(
+            &repo,
+            mirror,
             Some(Arc::clone(&dec.decrypter)),
-            Arc::clone(&self.compression),
+            Arc::clone(&repo.compression),
             generations,
+            mirror.read_generations()?,
         );
         {
             let traverser = ReadContext::new(&accessor);
             traverser.read_recursively(ReadRequest::new(
                 DataType::Data,
                 data_address.as_ref(),
-                Some(&mut counter),
+                Some(&mut io::sink()),
                 self.log.clone(),
             ))?;
         }
-        Ok(accessor.get_results())
+        let results = accessor.get_results();
+        info!(self.log, "Repair finished";
+            "repaired" => results.repaired.len(),
+            "unrepairable" => results.unrepairable.len(),
+        );
+        Ok(results)
+    }
+
+    /// Write `name_str` with all the chunks it's using to `writer`
+    ///
+    /// The result is a self-contained archive that can be stored into any
+    /// repo using the same hashing and index format with `import`. Note
+    /// that the archive is not encrypted.
+    pub fn export<W: Write>(
+        &self,
+        name_str: &str,
+        writer: &mut W,
+        dec: &DecryptHandle,
+    ) -> Result<()> {
+        let _lock = self.lock_shared()?;
+
+        let generations = self.read_generations()?;
+
+        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        let repo = self.with_params(name.params.as_ref())?;
+        let data_address: DataAddress = name.into();
+
+        archive::Header::new(&repo.config, &data_address).write(writer)?;
+        {
+            let accessor = ExportingChunkAccessor::new(
+                &repo,
+                writer,
+                Some(Arc::clone(&dec.decrypter)),
+                Arc::clone(&repo.compression),
+                generations,
+            );
+            let traverser = ReadContext::new(&accessor);
+            traverser.read_recursively(ReadRequest::new(
+                DataType::Data,
+                data_address.as_ref(),
+                Some(&mut io::sink()),
+                self.log.clone(),
+            ))?;
+        }
+        Ok(archive::write_end(writer)?)
+    }
+
+    /// Store the data of an archive created by `export` as `name_str`
+    ///
+    /// Chunks already in the repo are not stored again.
+    pub fn import<R>(
+        &self,
+        name_str: &str,
+        reader: R,
+        enc: &EncryptHandle,
+    ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+    {
+        self.write_input(name_str, WriteInput::Archive(reader), enc)
+    }
+
+    /// Store all the chunks of an archive
+    fn import_chunks<R: Read>(
+        &self,
+        mut reader: R,
+        process_tx: crossbeam_channel::Sender<chunk_processor::Message>,
+        budget: Arc<MemoryBudget>,
+    ) -> io::Result<DataAddress> {
+        let header = archive::Header::read(&mut reader)?;
+        if header.hashing != self.config.hashing
+            || header.index_format != self.config.index_format
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive hashing or index format differs from the repo's",
+            ));
+        }
+        if header.chunking != self.config.chunking_fingerprint() {
+            warn!(
+                self.log,
+                "archive chunking settings differ from the repo's; \
+                 data stored later will not deduplicate with it"
+            );
+        }
+
+        let (digests_tx, digests_rx) = mpsc::channel();
+        let mut digests = vec![];
+        let read_res = loop {
+            let chunk = match archive::read_chunk(&mut reader) {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            let permit = budget.acquire(chunk.data.len() as u64);
+            process_tx
+                .send(chunk_processor::Message {
+                    data: (
+                        digests.len() as u64,
+                        SGData::from_single(chunk.data),
+                    ),
+                    response_tx: digests_tx.clone(),
+                    data_type: chunk.data_type,
+                    permit,
+                })
+                .expect("chunk process tx channel closed");
+            digests.push(chunk.digest);
+        };
+        drop(process_tx);
+        drop(digests_tx);
+        // Chunks already sent are processed anyway
+        let entries: Vec<_> = digests_rx.into_iter().collect();
+        read_res?;
+
+        for (i, entry) in entries {
+            if entry.digest.0 != digests[i as usize] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "archive chunk {} corrupted, data read: {}",
+                        hex::encode(&digests[i as usize]),
+                        hex::encode(&entry.digest.0)
+                    ),
+                ));
+            }
+        }
+
+        if !digests.contains(&header.digest) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive does not contain its root chunk",
+            ));
+        }
+        Ok(header.data_address())
     }
 
     fn read_generations(&self) -> io::Result<Vec<Generation>> {
@@ -815,17 +2722,231 @@ impl Repo {
         reader: R,
         enc: &EncryptHandle,
     ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+    {
+        self.write_input(name_str, WriteInput::Reader(reader, None), enc)
+    }
+
+    /// Like `write`, but reuse the chunks of an unchanged file
+    ///
+    /// Chunks of every file written are recorded in `cache`, along with its
+    /// size and modification time. If they did not change since, and all
+    /// the chunks are still stored in the repo, the file is not read at all.
+    pub fn write_file(
+        &self,
+        name_str: &str,
+        path: &Path,
+        enc: &EncryptHandle,
+        cache: &mut ChunkCache,
+    ) -> Result<WriteStats> {
+        let metadata = std::fs::metadata(path)?;
+        self.write_cached(
+            name_str,
+            path,
+            metadata.len(),
+            metadata.modified()?,
+            || std::fs::File::open(path),
+            enc,
+            cache,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_cached<R, F>(
+        &self,
+        name_str: &str,
+        path: &Path,
+        len: u64,
+        modified: std::time::SystemTime,
+        open: F,
+        enc: &EncryptHandle,
+        cache: &mut ChunkCache,
+    ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+        F: FnOnce() -> io::Result<R>,
+    {
+        // Chunks found below must not be gc-ed before they're referenced
+        let _lock = self.lock_for_write()?;
+        let params = self.config.chunking_fingerprint();
+
+        if let Some(entries) = cache.get(path, len, modified, &params) {
+            if self.all_chunks_current(&entries)? {
+                info!(self.log, "Reusing cached chunks"; "path" => %path.display());
+                return self.write_input_locked(
+                    name_str,
+                    WriteInput::<R>::Entries(entries),
+                    enc,
+                );
+            }
+        }
+
+        let (entries_tx, entries_rx) = mpsc::channel();
+        let stats = self.write_input_locked(
+            name_str,
+            WriteInput::Reader(open()?, Some(entries_tx)),
+            enc,
+        )?;
+        if !self.dry_run {
+            cache.insert(
+                path,
+                len,
+                modified,
+                &params,
+                entries_rx.iter().collect(),
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Remove temporary files left behind by writers that died
+    ///
+    /// Failing to remove them is not an error.
+    fn remove_orphaned_tmp(&self) {
+        match self.aio.remove_orphaned_tmp() {
+            Ok(0) => {}
+            Ok(removed) => {
+                info!(self.log, "Removed orphaned temporary files"; "count" => removed)
+            }
+            Err(e) => {
+                warn!(self.log, "Couldn't remove orphaned temporary files"; "err" => %e)
+            }
+        }
+    }
+
+    /// Lock the repository exclusively, waiting at most `lock_timeout`
+    fn lock_exclusive(&self) -> io::Result<Box<dyn backends::Lock>> {
+        match self.lock_timeout {
+            Some(timeout) => self.aio.lock_exclusive_timeout(timeout),
+            None => self.aio.lock_exclusive(),
+        }
+    }
+
+    /// Lock the repository in shared mode, waiting at most `lock_timeout`
+    fn lock_shared(&self) -> io::Result<Box<dyn backends::Lock>> {
+        match self.lock_timeout {
+            Some(timeout) => self.aio.lock_shared_timeout(timeout),
+            None => self.aio.lock_shared(),
+        }
+    }
+
+    /// Lock the repository for storing a name
+    ///
+    /// Storing a new version of a name reads the previous one first, so
+    /// it's done under the exclusive lock, for other writers not to store
+    /// theirs in between. Otherwise writers share the lock.
+    fn lock_for_write(&self) -> io::Result<Box<dyn backends::Lock>> {
+        if self.config.name_versioning && !self.dry_run {
+            self.lock_exclusive()
+        } else {
+            self.lock_shared()
+        }
+    }
+
+    /// Are all the chunks of `entries` stored in the current generation
+    fn all_chunks_current(
+        &self,
+        entries: &[index::IndexEntry],
+    ) -> Result<bool> {
+        let gen_str = match self.read_generations()?.last() {
+            Some(gen) => gen.to_string(),
+            None => return Ok(false),
+        };
+
+        for entry in entries {
+            let path = self.chunk_rel_path_by_digest(
+                entry.digest.as_digest_ref(),
+                &gen_str,
+            );
+            if !self.aio.exists(path).wait()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn write_input<R>(
+        &self,
+        name_str: &str,
+        input: WriteInput<R>,
+        enc: &EncryptHandle,
+    ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+    {
+        let _lock = self.lock_for_write()?;
+        self.write_input_locked(name_str, input, enc)
+    }
+
+    /// `write_input`, with the lock of `lock_for_write` held
+    fn write_input_locked<R>(
+        &self,
+        name_str: &str,
+        input: WriteInput<R>,
+        enc: &EncryptHandle,
+    ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+    {
+        self.check_space()?;
+        self.in_op(|repo| match (repo.compression_sample, input) {
+            (Some(chunks), WriteInput::Reader(mut reader, entries_tx)) => {
+                let (compression, sample) =
+                    repo.sample_compression(&mut reader, chunks)?;
+                let mut repo = repo.clone();
+                repo.config.compression = compression;
+                repo.compression = repo.config.compression_engine();
+                repo.store_input(
+                    name_str,
+                    WriteInput::Reader(
+                        io::Cursor::new(sample).chain(reader),
+                        entries_tx,
+                    ),
+                    enc,
+                )
+            }
+            (_, input) => repo.store_input(name_str, input, enc),
+        })
+    }
+
+    /// Fail if less than `min_space` is available
+    fn check_space(&self) -> io::Result<()> {
+        let min_space = match self.min_space {
+            Some(min_space) if !self.dry_run => min_space,
+            _ => return Ok(()),
+        };
+        let available = self.aio.space()?.available;
+        if available < min_space {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "only {} bytes available to the repository, {} required",
+                    available, min_space
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn store_input<R>(
+        &self,
+        name_str: &str,
+        input: WriteInput<R>,
+        enc: &EncryptHandle,
+    ) -> Result<WriteStats>
     where
         R: Read + Send,
     {
         info!(self.log, "Writing data"; "name" => name_str);
-        let _lock = self.aio.lock_shared();
 
         let mut generations = self.read_generations()?;
 
         if generations.is_empty() {
             let gen_first = Generation::gen_first();
-            gen_first.write(&self.aio)?;
+            if !self.dry_run {
+                gen_first.write(&self.aio)?;
+            }
             generations.push(gen_first);
         }
 
@@ -835,21 +2956,47 @@ impl Repo {
             Level::Info,
         );
         timer.start("write");
-        let num_threads = num_cpus::get();
-        let (chunker_tx, chunker_rx) =
-            mpsc::sync_channel(self.write_cpu_thread_num());
+        let num_threads = self.write_cpu_thread_num();
+        let (chunker_tx, chunker_rx) = mpsc::sync_channel(num_threads);
 
         let backend = (self.backend_select)(&self.url)?;
-        let aio = aio::AsyncIO::new(backend, self.log.clone())?;
+        let (backend, write_rate) = if self.dry_run {
+            (Box::new(aio::dry_run::DryRun::new(backend)) as Box<_>, None)
+        } else {
+            (backend, self.write_rate)
+        };
+        let aio = aio::AsyncIO::with_write_rate(
+            backend,
+            self.io_threads,
+            write_rate,
+            self.log.clone(),
+        )?;
+        aio.set_progress(self.progress.clone());
 
         let stats = aio.stats();
+        let stats_before = stats.snapshot();
+
+        // Input buffers are not accounted: the chunker holds onto them
+        // until it has sent out the chunks they end, so it could wait for
+        // itself.
+        let budget = MemoryBudget::new(self.max_buffered);
+
+        let data_bytes = AtomicU64::new(0);
+        let data_bytes_known = match input {
+            WriteInput::Reader(..) => true,
+            WriteInput::Entries(ref entries) => {
+                let len: Option<u64> =
+                    entries.iter().map(|entry| entry.len).sum();
+                data_bytes.store(len.unwrap_or(0), Ordering::Relaxed);
+                len.is_some()
+            }
+            WriteInput::Archive(_) => false,
+        };
 
         // mpmc queue used  as spmc fan-out
         let (process_tx, process_rx) = crossbeam_channel::bounded(num_threads);
 
         let data_address = crossbeam::scope(|scope| {
-            scope.spawn(move |_| self.input_reader_thread(reader, chunker_tx));
-
             for _ in 0..num_threads {
                 let process_rx = process_rx.clone();
                 let aio = aio.clone();
@@ -872,14 +3019,41 @@ impl Repo {
             }
             drop(process_rx);
 
-            let chunk_and_write = scope.spawn(move |_| {
-                self.chunk_and_write_data_thread(
-                    Box::new(chunker_rx.into_iter()),
-                    process_tx,
-                    aio,
-                    DataType::Data,
-                )
-            });
+            let budget = Arc::clone(&budget);
+            let chunk_and_write = match input {
+                WriteInput::Reader(reader, entries_tx) => {
+                    let data_bytes = &data_bytes;
+                    scope.spawn(move |_| {
+                        self.input_reader_thread(reader, chunker_tx, data_bytes)
+                    });
+                    scope.spawn(move |_| {
+                        self.chunk_and_write_data_thread(
+                            Box::new(chunker_rx.into_iter()),
+                            process_tx,
+                            aio,
+                            budget,
+                            DataType::Data,
+                            entries_tx,
+                        )
+                    })
+                }
+                WriteInput::Entries(entries) => scope.spawn(move |_| {
+                    if let Some(max_chunks) = self.max_chunks {
+                        if entries.len() as u64 > max_chunks {
+                            return Err(chunk_limit_error(max_chunks));
+                        }
+                    }
+                    self.write_index(
+                        Box::new(entries.into_iter()),
+                        process_tx,
+                        aio,
+                        budget,
+                    )
+                }),
+                WriteInput::Archive(reader) => scope.spawn(move |_| {
+                    self.import_chunks(reader, process_tx, budget)
+                }),
+            };
 
             chunk_and_write.join()
         })
@@ -893,13 +3067,132 @@ impl Repo {
             }
         })?;
 
-        let name: Name = data_address?.into();
-        name.write_as(name_str, *generations.last().unwrap(), &self.aio)?;
-        Ok(stats.get_stats())
+        let mut name: Name = data_address?.into();
+        let mut write_stats = stats.snapshot().since(&stats_before).write;
+        write_stats.peak_buffered = budget.peak();
+        if data_bytes_known {
+            write_stats.data_bytes = Some(data_bytes.into_inner());
+        }
+        name.created = Some(chrono::Utc::now());
+        name.params = Some(NameParams::new(&self.config));
+        name.stats = write_stats.data_bytes.map(|data_bytes| NameStats {
+            data_bytes,
+            new_bytes: write_stats.new_bytes,
+        });
+        if let (Some(check), Some(name_stats)) = (self.dedup_check, name.stats)
+        {
+            write_stats.low_dedup =
+                self.check_dedup(&check, &name_stats, &generations)?;
+        }
+        if self.dry_run {
+            info!(self.log, "Dry run, nothing written";
+                "new-chunks" => write_stats.new_chunks,
+                "new-bytes" => write_stats.new_bytes,
+            );
+            return Ok(write_stats);
+        }
+        if self.config.name_versioning {
+            // The chunks of the previous versions move along with the
+            // name, or the `gc` finishing the generation change would
+            // remove them.
+            let cur_gen = *generations.last().unwrap();
+            match Name::load_from_any_gen(name_str, &generations, &self.aio) {
+                Ok((_, gen)) if gen != cur_gen => {
+                    self.update_name_to(name_str, cur_gen, &generations)?
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            name.write_as_new_version(
+                name_str,
+                &generations,
+                self.overwrite_protection,
+                &self.aio,
+            )?;
+        } else {
+            name.write_as(name_str, *generations.last().unwrap(), &self.aio)?;
+        }
+        info!(self.log, "Written";
+            "new-chunks" => write_stats.new_chunks,
+            "new-bytes" => write_stats.new_bytes,
+            "peak-buffered" => write_stats.peak_buffered,
+        );
+        Ok(write_stats)
+    }
+
+    /// Is the deduplication of data stored with `stats` much worse than
+    /// the one of the names stored last (see `set_dedup_check`)
+    ///
+    /// Fails instead of returning `true`, if the check is to abort.
+    fn check_dedup(
+        &self,
+        check: &DedupCheck,
+        stats: &NameStats,
+        generations: &[Generation],
+    ) -> Result<bool> {
+        let mut recent = vec![];
+        for name_str in Name::list_all(generations, &self.aio)? {
+            let name = Name::load_from_any(&name_str, generations, &self.aio)?;
+            if let (Some(created), Some(stats)) = (name.created, name.stats) {
+                recent.push((created, stats));
+            }
+        }
+        recent.sort_by_key(|(created, _)| std::cmp::Reverse(*created));
+        recent.truncate(check.baseline);
+        if recent.is_empty() {
+            return Ok(false);
+        }
+
+        let baseline = NameStats {
+            data_bytes: recent.iter().map(|(_, stats)| stats.data_bytes).sum(),
+            new_bytes: recent.iter().map(|(_, stats)| stats.new_bytes).sum(),
+        }
+        .dedup_ratio();
+        let ratio = stats.dedup_ratio();
+        if ratio >= baseline * check.min_fraction {
+            return Ok(false);
+        }
+
+        warn!(self.log, "Data deduplicated much worse than recent names";
+            "ratio" => ratio,
+            "baseline-ratio" => baseline,
+            "baseline-names" => recent.len(),
+        );
+        if check.abort {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "deduplication ratio {:.2} is below {} of {:.2} of \
+                     recent names",
+                    ratio, check.min_fraction, baseline
+                ),
+            ));
+        }
+        Ok(true)
     }
 }
 // }}}
 
+fn chunk_limit_error(max_chunks: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("data exceeds the limit of {} chunks", max_chunks),
+    )
+}
+
+/// Tell if `a` and `b` are the same repository, even if spelled
+/// differently
+fn same_location(a: &Url, b: &Url) -> bool {
+    match (a.to_file_path(), b.to_file_path()) {
+        (Ok(a), Ok(b)) => match (a.canonicalize(), b.canonicalize()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => a == b,
+        },
+        _ => a == b,
+    }
+}
+
 #[cfg(test)]
 mod tests;
 
@@ -48,7 +48,7 @@ impl From<Name> for DataAddress {
 // }}}
 
 // {{{ Digest & DigestRef
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) struct Digest(pub(crate) Vec<u8>);
 
 impl Digest {
@@ -1,21 +1,114 @@
+use std::collections::BTreeSet;
 use std::io;
 use std::path::PathBuf;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::aio;
+use crate::backends::BatchOp;
+use crate::config;
 use crate::util::*;
 use crate::SGData;
 use crate::DIGEST_SIZE;
-use crate::{DataAddress, DataAddressRef, Generation};
+use crate::{DataAddress, DataAddressRef, Digest, Error, Generation};
 
 pub(crate) const NAME_SUBDIR: &str = "name";
 
+/// Error of `name` not found in any generation
+fn not_found(name: &str) -> io::Error {
+    Error::NotFound(PathBuf::from(NAME_SUBDIR).join(name)).into()
+}
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct Name {
     #[serde(serialize_with = "as_hex", deserialize_with = "from_hex")]
     pub(crate) digest: Vec<u8>,
     pub(crate) index_level: u32,
+    /// Number of times the name was stored to before
+    #[serde(default)]
+    pub(crate) version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created: Option<DateTime<Utc>>,
+    /// Previous versions of the name (if versioning is enabled),
+    /// oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) history: Vec<NameVersion>,
+    /// Labels set with `Repo::tag`
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) tags: BTreeSet<String>,
+    /// `None` for names stored by older rdedup versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) params: Option<NameParams>,
+    /// `None` for names stored by older rdedup versions, or if the length
+    /// of the data was not known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) stats: Option<NameStats>,
+}
+
+/// How well the data of a name deduplicated when it was stored
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub(crate) struct NameStats {
+    pub(crate) data_bytes: u64,
+    /// Bytes of the chunks stored that were not stored already
+    pub(crate) new_bytes: u64,
+}
+
+impl NameStats {
+    /// Bytes of data per new byte stored
+    pub(crate) fn dedup_ratio(&self) -> f64 {
+        self.data_bytes as f64 / std::cmp::max(self.new_bytes, 1) as f64
+    }
+}
+
+/// Repo settings the data of a name was stored with
+///
+/// Enough to read the data even if the repo config is different (or
+/// not at hand), except for the encryption key.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct NameParams {
+    pub(crate) hashing: config::Hashing,
+    pub(crate) index_format: config::IndexFormat,
+    pub(crate) compression: config::Compression,
+    /// See `config::Repo::chunking_fingerprint`
+    pub(crate) chunking: String,
+    /// See `config::Encryption::type_name`
+    pub(crate) encryption: String,
+}
+
+impl NameParams {
+    pub(crate) fn new(config: &config::Repo) -> Self {
+        NameParams {
+            hashing: config.hashing,
+            index_format: config.index_format,
+            compression: config.compression,
+            chunking: config.chunking_fingerprint(),
+            encryption: config.encryption.type_name().to_owned(),
+        }
+    }
+}
+
+/// Previous version of a `Name`
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct NameVersion {
+    #[serde(serialize_with = "as_hex", deserialize_with = "from_hex")]
+    pub(crate) digest: Vec<u8>,
+    pub(crate) index_level: u32,
+    pub(crate) version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) params: Option<NameParams>,
+}
+
+impl NameVersion {
+    pub(crate) fn data_address(&self) -> DataAddress {
+        DataAddress {
+            digest: Digest(self.digest.clone()),
+            index_level: self.index_level,
+        }
+    }
 }
 
 // TODO: I am very displeased with myself how this
@@ -31,7 +124,7 @@ impl Name {
         aio: &aio::AsyncIO,
     ) -> io::Result<()> {
         let path = Name::path(name, gen);
-        aio.remove(path).wait()
+        Ok(aio.remove(path).wait()?)
     }
 
     pub(crate) fn remove_any(
@@ -46,10 +139,7 @@ impl Name {
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("name not found: {}", name),
-        ))
+        Err(not_found(name))
     }
 
     pub(crate) fn update_generation_to(
@@ -69,15 +159,53 @@ impl Name {
             match aio.rename(src_path, dst_path.clone()).wait() {
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
                 res => {
-                    return res;
+                    return Ok(res?);
                 }
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("name not found: {}", name),
-        ))
+        Err(not_found(name))
+    }
+
+    /// Move `name` to `new_name`, with its history and tags
+    ///
+    /// The name is renamed within the generation it's stored in, so it's
+    /// either found under the old name or the new one. Fails if
+    /// `new_name` already exists, unless `force` is set, in which case it
+    /// is replaced. A replaced name stored in another generation is
+    /// removed first, in the same batch, so the rename is not attempted if
+    /// that fails.
+    pub(crate) fn rename(
+        name: &str,
+        new_name: &str,
+        force: bool,
+        gens: &[Generation],
+        aio: &aio::AsyncIO,
+    ) -> io::Result<()> {
+        let (_, gen) = Name::load_from_any_gen(name, gens, aio)?;
+        let mut ops = vec![];
+        match Name::load_from_any_gen(new_name, gens, aio) {
+            Ok((_, new_gen)) => {
+                if !force {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("name already exists: {}", new_name),
+                    ));
+                }
+                // Replaced by the rename below, if in the same generation
+                if new_gen != gen {
+                    ops.push(BatchOp::Remove(Name::path(new_name, new_gen)));
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        ops.push(BatchOp::Rename {
+            src_path: Name::path(name, gen),
+            dst_path: Name::path(new_name, gen),
+        });
+        Ok(aio.batch(ops).wait()?)
     }
 
     pub(crate) fn path(name: &str, gen: Generation) -> PathBuf {
@@ -128,9 +256,6 @@ impl Name {
         gen: Generation,
         aio: &aio::AsyncIO,
     ) -> io::Result<()> {
-        let serialized_str =
-            serde_yaml::to_string(self).expect("yaml serialization failed");
-
         let path = Name::path(name, gen);
 
         if aio.read(path.clone()).wait().is_ok() {
@@ -140,9 +265,139 @@ impl Name {
             ));
         }
 
-        aio.write(path, SGData::from_single(serialized_str.into_bytes()))
-            .wait()?;
-        Ok(())
+        self.overwrite_as(name, gen, aio)
+    }
+
+    fn overwrite_as(
+        &self,
+        name: &str,
+        gen: Generation,
+        aio: &aio::AsyncIO,
+    ) -> io::Result<()> {
+        self.store_as(name, gen, None, aio)
+    }
+
+    /// Write the name, refusing to overwrite it if it was written within
+    /// `protect` (see `AsyncIO::write_protected`)
+    fn store_as(
+        &self,
+        name: &str,
+        gen: Generation,
+        protect: Option<Duration>,
+        aio: &aio::AsyncIO,
+    ) -> io::Result<()> {
+        let serialized_str =
+            serde_yaml::to_string(self).expect("yaml serialization failed");
+        let path = Name::path(name, gen);
+        let sg = SGData::from_single(serialized_str.into_bytes());
+
+        let res = match protect {
+            Some(window) => aio.write_protected(path, sg, window),
+            None => aio.write_durable(
+                path,
+                sg,
+                aio::backend::DurabilityMode::FsyncFileAndDir,
+            ),
+        };
+        Ok(res.wait()?)
+    }
+
+    /// Like `write_as`, but if the name already exists, keep its
+    /// current version in the history instead of failing
+    ///
+    /// `gens` must end with the current generation, which the name is
+    /// written to. A name stored in an older generation has to be moved
+    /// to it first, along with the chunks of all its versions (see
+    /// `Repo::update_name_to`). If `protect` is given, a name written
+    /// within it is not overwritten, failing with `AlreadyExists`
+    /// instead.
+    pub(crate) fn write_as_new_version(
+        mut self,
+        name: &str,
+        gens: &[Generation],
+        protect: Option<Duration>,
+        aio: &aio::AsyncIO,
+    ) -> io::Result<()> {
+        let cur_gen = *gens.last().expect("no generations");
+        let (prev, prev_gen) = match Name::load_from_any_gen(name, gens, aio) {
+            Ok(prev) => prev,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return self.write_as(name, cur_gen, aio)
+            }
+            Err(e) => return Err(e),
+        };
+
+        self.version = prev.version + 1;
+        self.tags = prev.tags;
+        self.history = prev.history;
+        self.history.push(NameVersion {
+            digest: prev.digest,
+            index_level: prev.index_level,
+            version: prev.version,
+            created: prev.created,
+            params: prev.params,
+        });
+
+        if prev_gen != cur_gen {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("name {} not moved to the current generation", name),
+            ));
+        }
+        self.store_as(name, cur_gen, protect, aio)
+    }
+
+    /// Drop all but `keep` newest previous versions of `name`
+    ///
+    /// Returns the number of versions dropped.
+    pub(crate) fn prune_history(
+        name: &str,
+        keep: usize,
+        gens: &[Generation],
+        aio: &aio::AsyncIO,
+    ) -> io::Result<usize> {
+        let (mut stored, gen) = Name::load_from_any_gen(name, gens, aio)?;
+        let drop_num = stored.history.len().saturating_sub(keep);
+        if drop_num > 0 {
+            stored.history.drain(..drop_num);
+            stored.overwrite_as(name, gen, aio)?;
+        }
+        Ok(drop_num)
+    }
+
+    /// Add (`present`) or remove `tag` from the tags of `name`
+    ///
+    /// Returns `false` if there was nothing to change.
+    pub(crate) fn set_tag(
+        name: &str,
+        tag: &str,
+        present: bool,
+        gens: &[Generation],
+        aio: &aio::AsyncIO,
+    ) -> io::Result<bool> {
+        let (mut stored, gen) = Name::load_from_any_gen(name, gens, aio)?;
+        let changed = if present {
+            stored.tags.insert(tag.to_owned())
+        } else {
+            stored.tags.remove(tag)
+        };
+        if changed {
+            stored.overwrite_as(name, gen, aio)?;
+        }
+        Ok(changed)
+    }
+
+    /// All the versions, oldest first (current one last)
+    pub(crate) fn versions(&self) -> Vec<NameVersion> {
+        let mut versions = self.history.clone();
+        versions.push(NameVersion {
+            digest: self.digest.clone(),
+            index_level: self.index_level,
+            version: self.version,
+            created: self.created,
+            params: self.params.clone(),
+        });
+        versions
     }
 
     pub fn load_from(
@@ -178,17 +433,24 @@ impl Name {
         gens: &[Generation],
         aio: &aio::AsyncIO,
     ) -> io::Result<Self> {
+        Name::load_from_any_gen(name, gens, aio).map(|(name, _gen)| name)
+    }
+
+    /// Like `load_from_any`, but also return the generation the name
+    /// was found in
+    pub(crate) fn load_from_any_gen(
+        name: &str,
+        gens: &[Generation],
+        aio: &aio::AsyncIO,
+    ) -> io::Result<(Self, Generation)> {
         for gen in gens.iter().rev() {
             match Name::load_from(name, *gen, aio) {
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-                res => return res,
+                res => return res.map(|name| (name, *gen)),
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("name not found: {}", name),
-        ))
+        Err(not_found(name))
     }
 }
 
@@ -197,6 +459,12 @@ impl<'a> From<DataAddressRef<'a>> for Name {
         Name {
             digest: da.digest.0.into(),
             index_level: da.index_level,
+            version: 0,
+            created: None,
+            history: vec![],
+            tags: BTreeSet::new(),
+            params: None,
+            stats: None,
         }
     }
 }
@@ -206,6 +474,12 @@ impl From<DataAddress> for Name {
         Name {
             digest: da.digest.0,
             index_level: da.index_level,
+            version: 0,
+            created: None,
+            history: vec![],
+            tags: BTreeSet::new(),
+            params: None,
+            stats: None,
         }
     }
 }
@@ -0,0 +1,80 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::aio;
+use crate::SGData;
+
+pub(crate) const PINS_YML_FILE: &str = "pins.yml";
+
+/// Set of names that are protected from removal
+///
+/// Kept in a single file at the top of the repo, next to the repo config,
+/// so moving names between generations does not affect it.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Pins {
+    names: BTreeSet<String>,
+}
+
+impl Pins {
+    pub(crate) fn load(aio: &aio::AsyncIO) -> io::Result<Self> {
+        let data = match aio.read(PathBuf::from(PINS_YML_FILE)).wait() {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Pins::default())
+            }
+            res => res?,
+        };
+        let data = data.to_linear_vec();
+
+        serde_yaml::from_reader(data.as_slice()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't parse yaml: {}", e),
+            )
+        })
+    }
+
+    pub(crate) fn write(&self, aio: &aio::AsyncIO) -> io::Result<()> {
+        let serialized_str =
+            serde_yaml::to_string(self).expect("yaml serialization failed");
+
+        Ok(aio
+            .write_durable(
+                PathBuf::from(PINS_YML_FILE),
+                SGData::from_single(serialized_str.into_bytes()),
+                aio::backend::DurabilityMode::FsyncFileAndDir,
+            )
+            .wait()?)
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    /// Returns `false` if `name` was already pinned
+    pub(crate) fn insert(&mut self, name: &str) -> bool {
+        self.names.insert(name.to_owned())
+    }
+
+    /// Returns `false` if `name` was not pinned
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        self.names.remove(name)
+    }
+
+    pub(crate) fn list(&self) -> Vec<String> {
+        self.names.iter().cloned().collect()
+    }
+
+    /// Fail if `name` is pinned
+    pub(crate) fn ensure_not_pinned(&self, name: &str) -> io::Result<()> {
+        if self.contains(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("name is pinned: {}", name),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+//! Periodic progress updates of reading or writing a stream
+use std::io;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// Reader or writer reporting the bytes passing through it
+///
+/// At most once per `interval`, a line is written to `out`:
+///
+/// ```text
+/// progress <operation> <bytes-done> <bytes-total> <bytes-per-sec> <eta-secs>
+/// ```
+///
+/// `bytes-total` and `eta-secs` are `-` when the total is not known.
+/// Updates are only checked for as data passes through, so a stalled
+/// stream doesn't produce any.
+pub struct Progress<T, W> {
+    inner: T,
+    out: W,
+    operation: String,
+    total: Option<u64>,
+    interval: Duration,
+    done: u64,
+    start: Instant,
+    last: Instant,
+}
+
+impl<T, W: Write> Progress<T, W> {
+    pub fn new(
+        inner: T,
+        out: W,
+        operation: &str,
+        total: Option<u64>,
+        interval: Duration,
+    ) -> Self {
+        let now = Instant::now();
+        Progress {
+            inner,
+            out,
+            operation: operation.to_owned(),
+            total,
+            interval,
+            done: 0,
+            start: now,
+            last: now,
+        }
+    }
+
+    /// Write the last update, and return the wrapped stream and `out`
+    pub fn finish(mut self) -> io::Result<(T, W)> {
+        self.report()?;
+        Ok((self.inner, self.out))
+    }
+
+    fn add(&mut self, bytes: usize) -> io::Result<()> {
+        self.done += bytes as u64;
+        if self.last.elapsed() >= self.interval {
+            self.report()?;
+        }
+        Ok(())
+    }
+
+    fn report(&mut self) -> io::Result<()> {
+        self.last = Instant::now();
+
+        let secs = self.start.elapsed().as_secs_f64();
+        let rate = if secs > 0.0 {
+            (self.done as f64 / secs) as u64
+        } else {
+            0
+        };
+        let (total, eta) = match self.total {
+            Some(total) => (
+                total.to_string(),
+                total
+                    .saturating_sub(self.done)
+                    .checked_div(rate)
+                    .map(|eta| eta.to_string())
+                    .unwrap_or_else(|| "-".to_owned()),
+            ),
+            None => ("-".to_owned(), "-".to_owned()),
+        };
+
+        writeln!(
+            self.out,
+            "progress {} {} {} {} {}",
+            self.operation, self.done, total, rate, eta
+        )
+    }
+}
+
+impl<R: Read, W: Write> Read for Progress<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.add(len)?;
+        Ok(len)
+    }
+}
+
+impl<T: Write, W: Write> Write for Progress<T, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.add(len)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
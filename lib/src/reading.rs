@@ -1,132 +1,75 @@
 //! Primitives used for reading the chunked data stored in the `Repo`
 // {{{ use and mod
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashSet;
-use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::{error, fmt, io};
 
+use sgdata::SGData;
 use slog::{trace, warn, FnValue, Logger};
 
+use crate::aio::Metadata;
+use crate::archive;
+use crate::index;
+use crate::util::{CountingWriter, SkippingWriter};
 use crate::Generation;
-use crate::VerifyResults;
 use crate::{ArcCompression, ArcDecrypter};
-use crate::{
-    DataAddressRef, DataType, Digest, DigestRef, Error, Repo, DIGEST_SIZE,
-};
+use crate::{DataAddressRef, DataType, DigestRef, Error, Repo};
+use crate::{RepairResults, RestoreGap, VerifyResults};
 // }}}
 
-/// Translates index stream into data stream
+/// Chunk read that is not the data its digest identifies
 ///
-/// This type implements `io::Write` and interprets what's written to it as a
-/// stream of digests.
-///
-/// For every digest written to it, it will access the corresponding chunk and
-/// write it into `writer` that it wraps.
-struct IndexTranslator<'a, 'b> {
-    writer: Option<&'b mut dyn Write>,
-    digest_buf: Digest,
-    data_type: DataType,
-    read_context: &'a ReadContext<'a>,
-    log: Logger,
-}
-
-impl<'a, 'b> IndexTranslator<'a, 'b> {
-    pub(crate) fn new(
-        writer: Option<&'b mut dyn Write>,
-        data_type: DataType,
-        read_context: &'a ReadContext<'a>,
-        log: Logger,
-    ) -> Self {
-        IndexTranslator {
-            data_type,
-            digest_buf: Digest(Vec::with_capacity(DIGEST_SIZE)),
-            read_context,
-            writer,
-            log,
-        }
-    }
+/// The inner error of the `InvalidData` error of the read, which `Repo`
+/// operations fail with as `Error::DigestMismatch`, telling it apart from
+/// other errors of the same kind, like a chunk that fails to decrypt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestMismatch {
+    pub expected: Vec<u8>,
+    /// Digest of the data read
+    pub found: Vec<u8>,
 }
 
-impl<'a, 'b> Write for IndexTranslator<'a, 'b> {
-    fn write(&mut self, mut bytes: &[u8]) -> io::Result<usize> {
-        assert!(!bytes.is_empty());
-
-        let total_len = bytes.len();
+impl DigestMismatch {
+    /// The mismatch that caused `e`, if any
+    ///
+    /// Looks through the errors `e` wraps, as `Repo` operations add
+    /// context to the errors they fail with.
+    pub(crate) fn find(e: &io::Error) -> Option<&DigestMismatch> {
+        let mut cur: &(dyn error::Error + 'static) = e.get_ref()?;
         loop {
-            let has_already = self.digest_buf.0.len();
-            if (has_already + bytes.len()) < DIGEST_SIZE {
-                self.digest_buf.0.extend_from_slice(bytes);
-
-                trace!(
-                    self.log,
-                    "left with a buffer";
-                    "digest" => FnValue(|_| hex::encode(&self.digest_buf.0)),
-                );
-                return Ok(total_len);
-            }
-
-            let &mut IndexTranslator {
-                ref mut digest_buf,
-                data_type,
-                ref mut writer,
-                read_context,
-                ..
-            } = self;
-            let needs = DIGEST_SIZE - has_already;
-
-            if digest_buf.0.is_empty() {
-                let digest = &bytes[..needs];
-                debug_assert_eq!(digest.len(), DIGEST_SIZE);
-                bytes = &bytes[needs..];
-
-                read_context.read_recursively(ReadRequest::new(
-                    data_type,
-                    DataAddressRef {
-                        digest: DigestRef(digest),
-                        index_level: 0,
-                    },
-                    writer.as_mut().map(|w| w as &mut dyn io::Write),
-                    self.log.clone(),
-                ))?;
-            } else {
-                digest_buf.0.extend_from_slice(&bytes[..needs]);
-                debug_assert_eq!(digest_buf.0.len(), DIGEST_SIZE);
-                bytes = &bytes[needs..];
-
-                let res = read_context.read_recursively(ReadRequest::new(
-                    data_type,
-                    DataAddressRef {
-                        digest: digest_buf.as_digest_ref(),
-                        index_level: 0,
-                    },
-                    writer.as_mut().map(|w| w as &mut dyn io::Write),
-                    self.log.clone(),
-                ));
-                digest_buf.0.clear();
-                res?;
+            if let Some(mismatch) = cur.downcast_ref::<DigestMismatch>() {
+                return Some(mismatch);
             }
+            cur = match cur.downcast_ref::<io::Error>() {
+                Some(e) => e.get_ref()?,
+                None => cur.source()?,
+            };
         }
     }
-
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
-    }
 }
 
-impl<'a, 'b> Drop for IndexTranslator<'a, 'b> {
-    fn drop(&mut self) {
-        if !std::thread::panicking() {
-            debug_assert_eq!(self.digest_buf.0.len(), 0);
-        }
+impl fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} corrupted, data read: {}",
+            hex::encode(&self.expected),
+            hex::encode(&self.found)
+        )
     }
 }
 
+impl error::Error for DigestMismatch {}
+
 /// Information specific to a given read operation
 /// of a data in the Repo
 pub(crate) struct ReadRequest<'a> {
     data_address: DataAddressRef<'a>,
     data_type: DataType,
     writer: Option<&'a mut dyn Write>,
+    /// Plaintext length of the chunk, if known from the index
+    expected_len: Option<u64>,
     log: Logger,
 }
 
@@ -141,9 +84,25 @@ impl<'a> ReadRequest<'a> {
             data_type,
             data_address,
             writer,
+            expected_len: None,
             log,
         }
     }
+
+    fn with_expected_len(self, expected_len: Option<u64>) -> Self {
+        ReadRequest {
+            expected_len,
+            ..self
+        }
+    }
+}
+
+/// Progress of a lenient read
+#[derive(Default)]
+struct LenientState {
+    /// Offset in the output data
+    offset: u64,
+    gaps: Vec<RestoreGap>,
 }
 
 /// Read Context
@@ -152,11 +111,63 @@ impl<'a> ReadRequest<'a> {
 pub(crate) struct ReadContext<'a> {
     /// Writer to write the data to; `None` will discard the data
     accessor: &'a dyn ChunkAccessor,
+    /// `Some` if missing data chunks are to be zero-filled instead of
+    /// failing the whole read
+    lenient: Option<RefCell<LenientState>>,
+    /// Bytes of data left to skip before writing any
+    skip: Cell<u64>,
 }
 
 impl<'a> ReadContext<'a> {
     pub(crate) fn new(accessor: &'a dyn ChunkAccessor) -> Self {
-        ReadContext { accessor }
+        ReadContext {
+            accessor,
+            lenient: None,
+            skip: Cell::new(0),
+        }
+    }
+
+    /// Create a `ReadContext` writing the data from `offset` on
+    ///
+    /// Data chunks ending before `offset` are not read at all if their
+    /// length is recorded in the index; the one `offset` falls into is
+    /// read whole (and checked as usual), but only written from `offset`.
+    pub(crate) fn new_from(
+        accessor: &'a dyn ChunkAccessor,
+        offset: u64,
+    ) -> Self {
+        ReadContext {
+            accessor,
+            lenient: None,
+            skip: Cell::new(offset),
+        }
+    }
+
+    /// Bytes left to skip, which is more than zero only if the data
+    /// ended before the offset given to `new_from`
+    pub(crate) fn skip_left(&self) -> u64 {
+        self.skip.get()
+    }
+
+    /// Create a `ReadContext` that tolerates missing or corrupted data
+    /// chunks
+    ///
+    /// Such chunks are replaced with zeros of the length recorded in the
+    /// index, and reported in `into_gaps`. Chunks that have no length
+    /// recorded and index chunks still fail the read.
+    pub(crate) fn new_lenient(accessor: &'a dyn ChunkAccessor) -> Self {
+        ReadContext {
+            accessor,
+            lenient: Some(RefCell::new(LenientState::default())),
+            skip: Cell::new(0),
+        }
+    }
+
+    /// Gaps recorded during the lenient read
+    pub(crate) fn into_gaps(self) -> Vec<RestoreGap> {
+        self.lenient
+            .map(|state| state.into_inner().gaps)
+            .unwrap_or_default()
     }
 
     fn on_index(&self, mut req: ReadRequest<'_>) -> io::Result<()> {
@@ -166,24 +177,77 @@ impl<'a> ReadContext<'a> {
             "digest" => FnValue(|_| hex::encode(req.data_address.digest.0)),
         );
 
-        let mut translator = IndexTranslator::new(
-            req.writer.take(),
-            req.data_type,
-            self,
-            req.log.clone(),
-        );
+        let mut writer = req.writer.take();
+        let data_type = req.data_type;
+        let log = req.log.clone();
 
-        let da = DataAddressRef {
-            digest: req.data_address.digest,
-            index_level: req.data_address.index_level - 1,
-        };
-        let req = ReadRequest::new(
-            DataType::Index,
-            da,
-            Some(&mut translator),
-            req.log,
-        );
-        self.read_recursively(req)
+        self.for_each_entry(
+            req.data_address.digest,
+            req.data_address.index_level,
+            &req.log,
+            &mut |entry| {
+                self.read_recursively(
+                    ReadRequest::new(
+                        data_type,
+                        DataAddressRef {
+                            digest: entry.digest.as_digest_ref(),
+                            index_level: 0,
+                        },
+                        writer.as_mut().map(|w| &mut **w as &mut dyn Write),
+                        log.clone(),
+                    )
+                    .with_expected_len(entry.len),
+                )
+            },
+        )
+    }
+
+    /// Call `f` on every entry of the index of `level` at `digest`, in
+    /// order; these reference the chunks of the data itself
+    ///
+    /// The top chunk is read with `ChunkAccessor::read_index`. Below it,
+    /// the entries of each level reference the chunks of the index of the
+    /// level below, read in order into one `index::Decoder`, as entries
+    /// can span chunk boundaries.
+    pub(crate) fn for_each_entry(
+        &self,
+        digest: DigestRef<'_>,
+        level: u32,
+        log: &Logger,
+        f: &mut dyn FnMut(index::IndexEntry) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if level <= 1 {
+            for entry in self.accessor.read_index(digest, level)?.entries {
+                f(entry)?;
+            }
+            return Ok(());
+        }
+
+        let mut decoder =
+            index::Decoder::new(self.accessor.repo().config.index_codec());
+        self.for_each_entry(digest, level - 1, log, &mut |entry| {
+            let mut bytes = vec![];
+            self.read_recursively(
+                ReadRequest::new(
+                    DataType::Index,
+                    DataAddressRef {
+                        digest: entry.digest.as_digest_ref(),
+                        index_level: 0,
+                    },
+                    Some(&mut bytes),
+                    log.clone(),
+                )
+                .with_expected_len(entry.len),
+            )?;
+            decoder.push(&bytes, &mut *f)?;
+            trace!(
+                log,
+                "index entries decoded";
+                "buffered-empty" => decoder.is_empty(),
+            );
+            Ok(())
+        })?;
+        decoder.finish()
     }
 
     fn on_data(&self, mut req: ReadRequest<'_>) -> io::Result<()> {
@@ -193,16 +257,114 @@ impl<'a> ReadContext<'a> {
             "digest" => FnValue(|_| hex::encode(req.data_address.digest.0)),
         );
         if let Some(writer) = req.writer.take() {
-            self.accessor.read_chunk_into(
-                req.data_address.digest,
-                req.data_type,
-                writer,
-            )
+            if req.data_type == DataType::Data && self.skip.get() > 0 {
+                return self.read_chunk_skipping(
+                    req.data_address.digest,
+                    req.expected_len,
+                    writer,
+                );
+            }
+            match self.lenient {
+                Some(ref state) if req.data_type == DataType::Data => self
+                    .read_chunk_lenient(
+                        req.data_address.digest,
+                        req.expected_len,
+                        writer,
+                        state,
+                        &req.log,
+                    ),
+                _ => self.accessor.read_chunk_into(
+                    req.data_address.digest,
+                    req.data_type,
+                    req.expected_len,
+                    writer,
+                ),
+            }
         } else {
             self.accessor.touch(req.data_address.digest)
         }
     }
 
+    /// Read a data chunk, not writing what's still to be skipped
+    fn read_chunk_skipping(
+        &self,
+        digest: DigestRef<'_>,
+        expected_len: Option<u64>,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let skip = self.skip.get();
+        match expected_len {
+            Some(len) if len <= skip => {
+                self.skip.set(skip - len);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let mut skipping = SkippingWriter::new(writer, skip);
+        self.accessor.read_chunk_into(
+            digest,
+            DataType::Data,
+            expected_len,
+            &mut skipping,
+        )?;
+        self.skip.set(skipping.skip);
+        Ok(())
+    }
+
+    fn read_chunk_lenient(
+        &self,
+        digest: DigestRef<'_>,
+        expected_len: Option<u64>,
+        writer: &mut dyn Write,
+        state: &RefCell<LenientState>,
+        log: &Logger,
+    ) -> io::Result<()> {
+        let mut counting = CountingWriter::new(&mut *writer);
+        let res = self.accessor.read_chunk_into(
+            digest,
+            DataType::Data,
+            expected_len,
+            &mut counting,
+        );
+        let written = counting.count;
+
+        let mut state = state.borrow_mut();
+        let error = match res {
+            Ok(()) => {
+                state.offset += written;
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        // Only a chunk that was not written at all can be replaced, and
+        // only if we know how long it was.
+        let len = match expected_len {
+            Some(len) if written == 0 => len,
+            _ => return Err(error),
+        };
+
+        warn!(
+            log,
+            "Replacing unreadable chunk with zeros";
+            "digest" => FnValue(|_| hex::encode(digest.0)),
+            "offset" => state.offset,
+            "len" => len,
+            "err" => %error,
+        );
+        io::copy(&mut io::repeat(0).take(len), writer)?;
+        let offset = state.offset;
+        state.gaps.push(RestoreGap {
+            offset,
+            len,
+            digest: digest.0.into(),
+            error: error.into(),
+        });
+        state.offset += len;
+        Ok(())
+    }
+
     pub(crate) fn read_recursively(
         &self,
         req: ReadRequest<'_>,
@@ -226,14 +388,35 @@ pub(crate) trait ChunkAccessor {
     fn repo(&self) -> &Repo;
 
     /// Read a chunk identified by `digest` into `writer`
+    ///
+    /// Fails if the plaintext length of the chunk is not `expected_len`
+    /// (if known, from the index).
     fn read_chunk_into(
         &self,
         digest: DigestRef<'_>,
         data_type: DataType,
+        expected_len: Option<u64>,
         writer: &mut dyn Write,
     ) -> io::Result<()>;
 
     fn touch(&self, _digest: DigestRef<'_>) -> io::Result<()>;
+
+    /// Read and decode the top index chunk of data address of `level`
+    ///
+    /// See `index::IndexNode`.
+    fn read_index(
+        &self,
+        digest: DigestRef<'_>,
+        level: u32,
+    ) -> io::Result<index::IndexNode> {
+        let mut bytes = vec![];
+        self.read_chunk_into(digest, DataType::Index, None, &mut bytes)?;
+        index::IndexNode::decode(
+            level,
+            &bytes,
+            self.repo().config.index_codec(),
+        )
+    }
 }
 
 /// `ChunkAccessor` that just reads the chunks as requested, without doing
@@ -270,32 +453,11 @@ impl<'a> ChunkAccessor for DefaultChunkAccessor<'a> {
         &self,
         digest: DigestRef<'_>,
         data_type: DataType,
+        expected_len: Option<u64>,
         writer: &mut dyn Write,
     ) -> io::Result<()> {
-        let mut data = None;
         let cur_gen_str = self.gen_strings.last().unwrap();
-        let mut data_gen_str = None;
-
-        for gen_str in self.gen_strings.iter().rev() {
-            let path = self.repo.chunk_rel_path_by_digest(digest, gen_str);
-            match self.repo.aio.read(path).wait() {
-                Ok(d) => {
-                    data = Some(d);
-                    data_gen_str = Some(gen_str);
-                    break;
-                }
-                Err(_e) => {}
-            }
-        }
-
-        if data.is_none() {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Couldn't not find chunk: {}", hex::encode(digest.0),),
-            ));
-        }
-
-        let data_gen_str = data_gen_str.unwrap();
+        let (data, data_gen_str) = self.read_stored(digest)?;
 
         if cur_gen_str != data_gen_str {
             let data_gen_path =
@@ -318,12 +480,62 @@ impl<'a> ChunkAccessor for DefaultChunkAccessor<'a> {
                           "src-path" => data_gen_path.display(),
                           "dst-path" => cur_gen_path.display(),
                           "err" => %e);
-                    return Err(e);
+                    return Err(e.into());
                 }
             }
         }
 
-        let data = data.unwrap();
+        let data = self.decode(data, digest, data_type, expected_len)?;
+        for part in data.as_parts() {
+            writer.write_all(&*part)?;
+        }
+        Ok(())
+    }
+
+    fn touch(&self, _digest: DigestRef<'_>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> DefaultChunkAccessor<'a> {
+    /// Generations to look for a chunk in, in order
+    ///
+    /// The newest one is tried again at the end, in case a concurrent
+    /// `write` moved the chunk there from an older one in the meantime.
+    fn lookup_gen_strings(&self) -> impl Iterator<Item = &String> {
+        self.gen_strings.iter().rev().chain(self.gen_strings.last())
+    }
+
+    /// Read the chunk identified by `digest` as stored, from the newest
+    /// generation it's in
+    ///
+    /// Returns the data with the generation it was found in.
+    fn read_stored(
+        &self,
+        digest: DigestRef<'_>,
+    ) -> io::Result<(SGData, &String)> {
+        for gen_str in self.lookup_gen_strings() {
+            let path = self.repo.chunk_rel_path_by_digest(digest, gen_str);
+            if let Ok(data) = self.repo.aio.read(path).wait() {
+                return Ok((data, gen_str));
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Couldn't not find chunk: {}", hex::encode(digest.0),),
+        ))
+    }
+
+    /// Decrypt and decompress the chunk identified by `digest`, as read
+    /// by `read_stored`, and check it's intact
+    fn decode(
+        &self,
+        data: SGData,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+        expected_len: Option<u64>,
+    ) -> io::Result<SGData> {
         let data = if data_type.should_encrypt() {
             self.decrypter
                 .as_ref()
@@ -333,33 +545,92 @@ impl<'a> ChunkAccessor for DefaultChunkAccessor<'a> {
             data
         };
 
-        let data = if data_type.should_compress() {
-            self.compression.decompress(data)?
+        let (data, vec_result) = if data_type.should_compress() {
+            let data = self.compression.decompress(data)?;
+            let vec_result = self.repo.hasher.calculate_digest(&data);
+            (data, vec_result)
         } else {
-            data
+            let vec_result = self.repo.hasher.calculate_digest(&data);
+            (data, vec_result)
         };
 
-        let vec_result = self.repo.hasher.calculate_digest(&data);
-
         if vec_result != digest.0 {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                DigestMismatch {
+                    expected: digest.0.to_owned(),
+                    found: vec_result,
+                },
+            ))
+        } else if let Some(expected_len) =
+            expected_len.filter(|&len| len != data.len() as u64)
+        {
             Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!(
-                    "{} corrupted, data read: {}",
+                    "{} length is {}, index expects {}",
                     hex::encode(digest.0),
-                    hex::encode(vec_result)
+                    data.len(),
+                    expected_len
                 ),
             ))
         } else {
-            for part in data.as_parts() {
-                writer.write_all(&*part)?;
+            Ok(data)
+        }
+    }
+
+    /// Metadata of the chunk identified by `digest`, in any generation
+    fn stat_chunk(&self, digest: DigestRef<'_>) -> io::Result<Metadata> {
+        for gen_str in self.lookup_gen_strings() {
+            let path = self.repo.chunk_rel_path_by_digest(digest, gen_str);
+            if let Some(metadata) = self.repo.aio.stat(path).wait()? {
+                return Ok(metadata);
             }
-            Ok(())
         }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Couldn't not find chunk: {}", hex::encode(digest.0),),
+        ))
     }
 
-    fn touch(&self, _digest: DigestRef<'_>) -> io::Result<()> {
-        Ok(())
+    /// Check that the chunk identified by `digest` is stored, with the
+    /// length expected for its plaintext length
+    ///
+    /// When that length is unknown (see `config::Repo::stored_len`), only
+    /// chunks too short to hold any data are detected.
+    fn check_chunk_stored(
+        &self,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+        expected_len: Option<u64>,
+    ) -> io::Result<()> {
+        let stored_len = self.stat_chunk(digest)?.len;
+        let config = &self.repo.config;
+
+        let expected = match expected_len
+            .and_then(|len| config.stored_len(data_type, len))
+        {
+            Some(len) if len == stored_len => return Ok(()),
+            Some(len) => len.to_string(),
+            None => {
+                let min = config.encryption.overhead() + 1;
+                if stored_len >= min {
+                    return Ok(());
+                }
+                format!("at least {}", min)
+            }
+        };
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} stored length is {}, expected {}",
+                hex::encode(digest.0),
+                stored_len,
+                expected
+            ),
+        ))
     }
 }
 
@@ -401,10 +672,12 @@ impl<'a> ChunkAccessor for RecordingChunkAccessor<'a> {
         &self,
         digest: DigestRef<'_>,
         data_type: DataType,
+        expected_len: Option<u64>,
         writer: &mut dyn Write,
     ) -> io::Result<()> {
         self.touch(digest)?;
-        self.raw.read_chunk_into(digest, data_type, writer)
+        self.raw
+            .read_chunk_into(digest, data_type, expected_len, writer)
     }
 
     fn touch(&self, digest: DigestRef<'_>) -> io::Result<()> {
@@ -413,6 +686,69 @@ impl<'a> ChunkAccessor for RecordingChunkAccessor<'a> {
     }
 }
 
+/// `ChunkAccessor` that copies the chunks accessed to an archive
+///
+/// Every chunk is written to the archive once, no matter how many times
+/// it's read.
+pub(crate) struct ExportingChunkAccessor<'a> {
+    raw: DefaultChunkAccessor<'a>,
+    archive: RefCell<&'a mut dyn Write>,
+    exported: RefCell<HashSet<Vec<u8>>>,
+}
+
+impl<'a> ExportingChunkAccessor<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        archive: &'a mut dyn Write,
+        decrypter: Option<ArcDecrypter>,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+    ) -> Self {
+        ExportingChunkAccessor {
+            raw: DefaultChunkAccessor::new(
+                repo,
+                decrypter,
+                compression,
+                generations,
+            ),
+            archive: RefCell::new(archive),
+            exported: RefCell::new(HashSet::new()),
+        }
+    }
+}
+
+impl<'a> ChunkAccessor for ExportingChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.raw.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+        expected_len: Option<u64>,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut data = vec![];
+        self.raw
+            .read_chunk_into(digest, data_type, expected_len, &mut data)?;
+
+        if self.exported.borrow_mut().insert(digest.0.into()) {
+            archive::write_chunk(
+                &mut **self.archive.borrow_mut(),
+                data_type,
+                digest,
+                &data,
+            )?;
+        }
+        writer.write_all(&data)
+    }
+
+    fn touch(&self, digest: DigestRef<'_>) -> io::Result<()> {
+        self.raw.touch(digest)
+    }
+}
+
 /// `ChunkAccessor` that verifies the chunks
 /// that are accessed
 ///
@@ -421,6 +757,8 @@ pub(crate) struct VerifyingChunkAccessor<'a> {
     raw: DefaultChunkAccessor<'a>,
     accessed: RefCell<HashSet<Vec<u8>>>,
     errors: RefCell<Vec<(Vec<u8>, Error)>>,
+    /// Only check that data chunks are stored, see `quick`
+    quick: bool,
 }
 
 impl<'a> VerifyingChunkAccessor<'a> {
@@ -439,6 +777,18 @@ impl<'a> VerifyingChunkAccessor<'a> {
             ),
             accessed: RefCell::new(HashSet::new()),
             errors: RefCell::new(Vec::new()),
+            quick: false,
+        }
+    }
+
+    /// Check data chunks by their stored length instead of reading them
+    ///
+    /// Index chunks are still read (and verified), as they are needed to
+    /// find the data chunks.
+    pub(crate) fn quick(self) -> Self {
+        VerifyingChunkAccessor {
+            quick: true,
+            ..self
         }
     }
 
@@ -459,6 +809,7 @@ impl<'a> ChunkAccessor for VerifyingChunkAccessor<'a> {
         &self,
         digest: DigestRef<'_>,
         data_type: DataType,
+        expected_len: Option<u64>,
         writer: &mut dyn Write,
     ) -> io::Result<()> {
         {
@@ -468,12 +819,138 @@ impl<'a> ChunkAccessor for VerifyingChunkAccessor<'a> {
             }
             accessed.insert(digest.0.into());
         }
-        let res = self.raw.read_chunk_into(digest, data_type, writer);
+        let res = if self.quick && data_type == DataType::Data {
+            self.raw.check_chunk_stored(digest, data_type, expected_len)
+        } else {
+            self.raw
+                .read_chunk_into(digest, data_type, expected_len, writer)
+        };
+
+        if let Err(e) = res {
+            self.errors.borrow_mut().push((digest.0.into(), e.into()));
+        }
+        Ok(())
+    }
+
+    fn touch(&self, digest: DigestRef<'_>) -> io::Result<()> {
+        self.raw.touch(digest)
+    }
+}
+
+/// `ChunkAccessor` that replaces the chunks that fail to read with their
+/// copies in a mirror of the repo
+///
+/// A copy is only used if it reads intact with the repo's own key and
+/// hashing. It's stored as is to the current generation, so the mirror
+/// must be a copy of the repo (sharing its encryption).
+pub(crate) struct RepairingChunkAccessor<'a> {
+    raw: DefaultChunkAccessor<'a>,
+    mirror: DefaultChunkAccessor<'a>,
+    accessed: RefCell<HashSet<Vec<u8>>>,
+    repaired: RefCell<Vec<Vec<u8>>>,
+    unrepairable: RefCell<Vec<(Vec<u8>, Error)>>,
+}
+
+impl<'a> RepairingChunkAccessor<'a> {
+    pub(crate) fn new(
+        repo: &'a Repo,
+        mirror: &'a Repo,
+        decrypter: Option<ArcDecrypter>,
+        compression: ArcCompression,
+        generations: Vec<Generation>,
+        mirror_generations: Vec<Generation>,
+    ) -> Self {
+        RepairingChunkAccessor {
+            raw: DefaultChunkAccessor::new(
+                repo,
+                decrypter,
+                compression.clone(),
+                generations,
+            ),
+            mirror: DefaultChunkAccessor::new(
+                mirror,
+                None,
+                compression,
+                mirror_generations,
+            ),
+            accessed: RefCell::new(HashSet::new()),
+            repaired: RefCell::new(Vec::new()),
+            unrepairable: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn get_results(self) -> RepairResults {
+        RepairResults {
+            scanned: self.accessed.borrow().len(),
+            repaired: self.repaired.into_inner(),
+            unrepairable: self.unrepairable.into_inner(),
+        }
+    }
+
+    /// Store the mirror's copy of the chunk, and return its plaintext
+    fn repair(
+        &self,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+        expected_len: Option<u64>,
+    ) -> io::Result<SGData> {
+        let (stored, _) = self.mirror.read_stored(digest)?;
+        let data =
+            self.raw
+                .decode(stored.clone(), digest, data_type, expected_len)?;
+
+        let cur_gen_str = self.raw.gen_strings.last().unwrap();
+        let path = self.raw.repo.chunk_rel_path_by_digest(digest, cur_gen_str);
+        self.raw.repo.aio.write(path, stored).wait()?;
+        Ok(data)
+    }
+}
+
+impl<'a> ChunkAccessor for RepairingChunkAccessor<'a> {
+    fn repo(&self) -> &Repo {
+        self.raw.repo()
+    }
+
+    fn read_chunk_into(
+        &self,
+        digest: DigestRef<'_>,
+        data_type: DataType,
+        expected_len: Option<u64>,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        if !self.accessed.borrow_mut().insert(digest.0.into()) {
+            return Ok(());
+        }
+
+        let mut data = vec![];
+        let err = match self.raw.read_chunk_into(
+            digest,
+            data_type,
+            expected_len,
+            &mut data,
+        ) {
+            Ok(()) => return writer.write_all(&data),
+            Err(e) => e,
+        };
 
-        if res.is_err() {
-            self.errors
-                .borrow_mut()
-                .push((digest.0.into(), res.err().unwrap()));
+        warn!(self.raw.repo.log, "Repairing chunk";
+              "digest" => hex::encode(digest.0),
+              "err" => %err);
+        match self.repair(digest, data_type, expected_len) {
+            Ok(data) => {
+                self.repaired.borrow_mut().push(digest.0.into());
+                for part in data.as_parts() {
+                    writer.write_all(part)?;
+                }
+            }
+            Err(e) => {
+                warn!(self.raw.repo.log, "Couldn't repair chunk";
+                      "digest" => hex::encode(digest.0),
+                      "err" => %e);
+                self.unrepairable
+                    .borrow_mut()
+                    .push((digest.0.into(), err.into()));
+            }
         }
         Ok(())
     }
@@ -515,9 +992,11 @@ impl<'a> ChunkAccessor for GenerationUpdateChunkAccessor<'a> {
         &self,
         digest: DigestRef<'_>,
         data_type: DataType,
+        expected_len: Option<u64>,
         writer: &mut dyn Write,
     ) -> io::Result<()> {
-        self.raw.read_chunk_into(digest, data_type, writer)
+        self.raw
+            .read_chunk_into(digest, data_type, expected_len, writer)
     }
 
     fn touch(&self, digest: DigestRef<'_>) -> io::Result<()> {
@@ -563,7 +1042,7 @@ impl<'a> ChunkAccessor for GenerationUpdateChunkAccessor<'a> {
                           "src-path" => data_gen_path.display(),
                           "dst-path" => cur_gen_path.display(),
                           "err" => %e);
-                    return Err(e);
+                    return Err(e.into());
                 }
             }
         }
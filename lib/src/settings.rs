@@ -103,7 +103,7 @@ impl Nesting {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Hashing {
     Sha256,
     Blake2b,
@@ -116,6 +116,29 @@ impl Hashing {
             Hashing::Blake2b => config::Hashing::Blake2b,
         }
     }
+
+    pub(crate) fn from_config(hashing: config::Hashing) -> Self {
+        match hashing {
+            config::Hashing::Sha256 => Hashing::Sha256,
+            config::Hashing::Blake2b => Hashing::Blake2b,
+        }
+    }
+
+    /// Name used in the CLI and in `RootAddress`
+    pub(crate) fn name(&self) -> &'static str {
+        match *self {
+            Hashing::Sha256 => "sha256",
+            Hashing::Blake2b => "blake2b",
+        }
+    }
+
+    pub(crate) fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "sha256" => Some(Hashing::Sha256),
+            "blake2b" => Some(Hashing::Blake2b),
+            _ => None,
+        }
+    }
 }
 
 impl Default for Hashing {
@@ -131,8 +154,12 @@ pub struct Repo {
     pub(crate) compression: Compression,
     pub(crate) compression_level: i32,
     pub(crate) chunking: Chunking,
+    pub(crate) chunking_secondary_bits: Option<u32>,
+    pub(crate) chunking_tail: config::ChunkingTail,
     pub(crate) nesting: Nesting,
     pub(crate) hashing: Hashing,
+    pub(crate) name_versioning: bool,
+    pub(crate) safe_mode: bool,
 }
 
 impl Repo {
@@ -214,6 +241,78 @@ impl Repo {
         Ok(())
     }
 
+    /// Split data into blocks of `2^bits` bytes, instead of using
+    /// content-defined chunking
+    pub fn use_fixed_chunking(
+        &mut self,
+        bits: Option<u32>,
+    ) -> super::Result<()> {
+        let bits = bits.unwrap_or(config::DEFAULT_BUP_CHUNK_BITS);
+        let chunking = config::Chunking::Fixed { chunk_bits: bits };
+
+        if !chunking.valid() {
+            return Err(super::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid chunking algorithm defined",
+            ));
+        }
+        self.chunking = Chunking(chunking);
+        Ok(())
+    }
+
+    /// Require a secondary condition for chunk edges
+    ///
+    /// `bits` out of the chunking bits are checked against a hash of the
+    /// whole chunk instead of the rolling window, which smooths the chunk
+    /// size distribution on structured data. Must be between 1 and
+    /// half of the chunk bits. `None` disables it (the default).
+    pub fn set_secondary_chunking_bits(
+        &mut self,
+        bits: Option<u32>,
+    ) -> super::Result<()> {
+        if let Some(bits) = bits {
+            if bits == 0 || bits > 15 {
+                return Err(super::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "secondary chunking bits must be between 1 and 15",
+                ));
+            }
+        }
+        self.chunking_secondary_bits = bits;
+        Ok(())
+    }
+
+    /// Merge the final chunk of a stream with the previous one if it is
+    /// smaller than `min_size` bytes. `None` emits it as is (the default).
+    pub fn set_small_tail_merge(
+        &mut self,
+        min_size: Option<u64>,
+    ) -> super::Result<()> {
+        self.chunking_tail = match min_size {
+            None => config::ChunkingTail::Emit,
+            Some(0) => {
+                return Err(super::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "small tail merge size must be greater than zero",
+                ))
+            }
+            Some(min_size) => config::ChunkingTail::Merge { min_size },
+        };
+        Ok(())
+    }
+
+    /// Keep the previous version of a name when storing to it again,
+    /// instead of refusing to overwrite it. Disabled by default.
+    pub fn set_name_versioning(&mut self, enable: bool) {
+        self.name_versioning = enable;
+    }
+
+    /// Create the repo in safe mode, see `Repo::set_safe_mode`.
+    /// Disabled by default.
+    pub fn set_safe_mode(&mut self, enable: bool) {
+        self.safe_mode = enable;
+    }
+
     pub fn set_nesting(&mut self, level: u8) -> super::Result<()> {
         if level > 31 {
             return Err(super::Error::new(
@@ -0,0 +1,83 @@
+//! Writing files with holes in place of runs of zeros
+use std::io::{self, Seek, SeekFrom, Write};
+use std::{cmp, fs};
+
+/// Granularity of the holes
+///
+/// Filesystems allocate whole blocks, so only zeros covering a block can
+/// end up as a hole. 4KiB is the block size of most of them.
+const BLOCK_SIZE: u64 = 4096;
+
+/// File writer seeking past zeros instead of writing them
+///
+/// Data is looked at in blocks aligned to `BLOCK_SIZE` in the file, and
+/// the blocks with only zeros are skipped, so restoring a mostly-empty
+/// disk image is fast, and the file stays sparse on filesystems supporting
+/// it. Reading the file back gives the same data, with the holes read as
+/// zeros.
+///
+/// The file must be empty, since nothing is written where it's skipped,
+/// and `finish` must be called to set its length, in case the data ends
+/// with zeros.
+pub struct SparseFile {
+    file: fs::File,
+    /// Offset of the next byte of data
+    pos: u64,
+    /// Offset of the file cursor
+    file_pos: u64,
+}
+
+impl SparseFile {
+    pub fn new(file: fs::File) -> Self {
+        SparseFile {
+            file,
+            pos: 0,
+            file_pos: 0,
+        }
+    }
+
+    /// Set the length of the file to the length of the data, and return it
+    pub fn finish(self) -> io::Result<fs::File> {
+        self.file.set_len(self.pos)?;
+        Ok(self.file)
+    }
+}
+
+fn is_zero(buf: &[u8]) -> bool {
+    buf.iter().all(|&b| b == 0)
+}
+
+impl Write for SparseFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Handle the run of blocks that are all zeros, or all not, from
+        // the start of `buf`
+        let block = BLOCK_SIZE as usize;
+        let mut end =
+            cmp::min(buf.len(), block - (self.pos % BLOCK_SIZE) as usize);
+        let zeros = is_zero(&buf[..end]);
+        while end < buf.len() {
+            let next = cmp::min(buf.len(), end + block);
+            if is_zero(&buf[end..next]) != zeros {
+                break;
+            }
+            end = next;
+        }
+
+        if zeros {
+            self.pos += end as u64;
+            return Ok(end);
+        }
+        if self.file_pos != self.pos {
+            self.file.seek(SeekFrom::Start(self.pos))?;
+            self.file_pos = self.pos;
+        }
+        let written = self.file.write(&buf[..end])?;
+        self.pos += written as u64;
+        self.file_pos = self.pos;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
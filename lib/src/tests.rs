@@ -2,13 +2,14 @@ use std::collections::HashSet;
 use std::fs::OpenOptions;
 use std::io::{Result, Write};
 use std::path;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{self, fs};
 use std::{cmp, io};
 
 use crate::iterators::StoredChunks;
 use crate::settings;
 use crate::util::{ReaderVecIter, WhileOk};
+use crate::TagFilter;
 use hex;
 use rand::{self, Rng};
 use sha2::{Digest, Sha256};
@@ -349,6 +350,201 @@ fn verify_name() {
     wipe(&repo);
 }
 
+#[test]
+fn test_verify_stored() {
+    let (repo, dir) = test_repo_dir(PASS);
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    repo.write("data", &mut io::Cursor::new(rand_data(1024)), &enc_handle)
+        .unwrap();
+    repo.write("other", &mut io::Cursor::new(rand_data(1024)), &enc_handle)
+        .unwrap();
+    // chunks of "other" are left unused
+    repo.rm("other").unwrap();
+
+    let stored = list_stored_chunks(&repo).unwrap();
+    let result = repo.verify_stored(&dec_handle).unwrap();
+    assert_eq!(result.scanned, stored.len());
+    assert!(result.errors.is_empty());
+
+    let generations = repo.read_generations().unwrap();
+    let chunk_dir = dir.join(generations[0].to_string()).join("chunk");
+    let mut chunk_paths: Vec<_> = walkdir::WalkDir::new(&chunk_dir)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+    chunk_paths.sort();
+    let corrupted = &chunk_paths[0];
+    let mut chunk = fs::read(corrupted).unwrap();
+    chunk[0] ^= 1;
+    fs::write(corrupted, chunk).unwrap();
+
+    let result = repo.verify_stored(&dec_handle).unwrap();
+    assert_eq!(result.scanned, stored.len());
+    assert_eq!(result.errors.len(), 1);
+    assert_eq!(
+        hex::encode(&result.errors[0].0),
+        corrupted.file_name().unwrap().to_str().unwrap()
+    );
+
+    wipe(&repo);
+}
+
+#[test]
+fn verify_name_quick() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings
+        .set_compression(settings::Compression::None)
+        .unwrap();
+    let dir = rand_tmp_dir();
+    let repo = lib::Repo::init(
+        &Url::from_file_path(&dir).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    let result = repo.verify_quick("data").unwrap();
+    assert!(result.scanned > 2);
+    assert_eq!(result.errors.len(), 0);
+
+    let chunks = chunk_with(
+        &data,
+        repo.config.chunking_engine(),
+        repo.config.chunking_tail,
+        64 * 1024,
+    );
+    assert!(chunks.len() > 2);
+    let gen_str = repo.read_generations().unwrap().last().unwrap().to_string();
+    let chunk_path = |chunk: &Vec<u8>| {
+        let digest = repo
+            .hasher
+            .calculate_digest(&sgdata::SGData::from_single(chunk.clone()));
+        let path =
+            repo.chunk_rel_path_by_digest(lib::DigestRef(&digest), &gen_str);
+        (digest, dir.join(path))
+    };
+
+    let (deleted, path) = chunk_path(&chunks[0]);
+    fs::remove_file(path).unwrap();
+    let (truncated, path) = chunk_path(&chunks[1]);
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(fs::metadata(&path).unwrap().len() - 1)
+        .unwrap();
+
+    let result = repo.verify_quick("data").unwrap();
+    let mut corrupted: Vec<_> =
+        result.errors.iter().map(|(digest, _)| digest).collect();
+    corrupted.sort();
+    let mut expected = vec![&deleted, &truncated];
+    expected.sort();
+    assert_eq!(corrupted, expected);
+    let kinds: Vec<_> = result.errors.iter().map(|(_, e)| e.kind()).collect();
+    assert!(kinds.contains(&io::ErrorKind::NotFound));
+    assert!(kinds.contains(&io::ErrorKind::InvalidData));
+
+    wipe(&repo);
+}
+
+fn copy_dir(src: &Path, dst: &Path) {
+    fs::create_dir_all(dst).unwrap();
+    for entry in fs::read_dir(src).unwrap() {
+        let entry = entry.unwrap();
+        let dst = dst.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &dst);
+        } else {
+            fs::copy(entry.path(), dst).unwrap();
+        }
+    }
+}
+
+#[test]
+fn repair_name_from_mirror() {
+    let (repo, dir) = test_repo_dir(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    let mirror_dir = rand_tmp_dir();
+    copy_dir(&dir, &mirror_dir);
+    let mirror =
+        lib::Repo::open(&Url::from_file_path(&mirror_dir).unwrap(), None)
+            .unwrap();
+
+    let chunks = chunk_with(
+        &data,
+        repo.config.chunking_engine(),
+        repo.config.chunking_tail,
+        64 * 1024,
+    );
+    assert!(chunks.len() > 2);
+    let gen_str = repo.read_generations().unwrap().last().unwrap().to_string();
+    let chunk_path = |chunk: &Vec<u8>| {
+        let digest = repo
+            .hasher
+            .calculate_digest(&sgdata::SGData::from_single(chunk.clone()));
+        let path =
+            repo.chunk_rel_path_by_digest(lib::DigestRef(&digest), &gen_str);
+        (digest, path)
+    };
+
+    let (truncated, path) = chunk_path(&chunks[0]);
+    let path = dir.join(path);
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(fs::metadata(&path).unwrap().len() / 2)
+        .unwrap();
+    // Missing from the mirror too
+    let (deleted, path) = chunk_path(&chunks[1]);
+    fs::remove_file(dir.join(&path)).unwrap();
+    fs::remove_file(mirror_dir.join(&path)).unwrap();
+
+    let result = repo.repair("data", &mirror, &dec_handle).unwrap();
+    assert!(result.scanned > 2);
+    assert_eq!(result.repaired, vec![truncated]);
+    let unrepairable: Vec<_> = result
+        .unrepairable
+        .iter()
+        .map(|(digest, _)| digest)
+        .collect();
+    assert_eq!(unrepairable, vec![&deleted]);
+
+    let result = repo.verify("data", &dec_handle).unwrap();
+    let corrupted: Vec<_> =
+        result.errors.iter().map(|(digest, _)| digest).collect();
+    assert_eq!(corrupted, vec![&deleted]);
+
+    // not from itself, which would deadlock
+    let same = lib::Repo::open(
+        &Url::from_file_path(dir.join(".").join(".")).unwrap(),
+        None,
+    )
+    .unwrap();
+    for mirror in &[&repo, &same] {
+        assert_eq!(
+            repo.repair("data", mirror, &dec_handle)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    wipe(&repo);
+}
+
 #[test]
 fn test_stored_chunks_iter() {
     let repo = test_repo(PASS);
@@ -431,6 +627,7 @@ fn test_custom_chunking_size() {
             )
             .unwrap();
             assert_eq!(settings.chunking.0, repo.config.chunking);
+            assert_eq!(repo.avg_chunk_size(), 1 << bits);
             wipe(&repo);
         }
     }
@@ -517,3 +714,4706 @@ fn test_readerveciter() {
     assert_eq!(v, [vec![0, 1]]);
     assert!(while_ok.finish().is_some());
 }
+
+fn chunk_with(
+    data: &[u8],
+    engine: Box<dyn lib::chunking::Chunking>,
+    tail: lib::config::ChunkingTail,
+    buf_size: usize,
+) -> Vec<Vec<u8>> {
+    let input = data
+        .chunks(buf_size)
+        .map(|c| c.to_vec())
+        .collect::<Vec<_>>();
+    lib::chunking::Chunker::new(input.into_iter(), engine, tail)
+        .map(|sg| sg.to_linear().to_vec())
+        .collect()
+}
+
+fn chunk_all(data: &[u8], tail: lib::config::ChunkingTail) -> Vec<Vec<u8>> {
+    let chunking = lib::config::Chunking::Bup { chunk_bits: 10 };
+    chunk_with(data, chunking.to_engine(), tail, 7 * 1024)
+}
+
+#[test]
+fn test_chunker_small_tail() {
+    let data = rand_data(256 * 1024);
+    let chunks = chunk_all(&data, lib::config::ChunkingTail::Emit);
+    assert!(chunks.len() > 3);
+
+    // Cut the data so that it ends with a tiny tail after a
+    // couple of full chunks
+    let cut = chunks[0].len() + chunks[1].len() + 10;
+    let data = &data[..cut];
+
+    let emitted = chunk_all(data, lib::config::ChunkingTail::Emit);
+    assert_eq!(emitted.len(), 3);
+    assert_eq!(emitted[2].len(), 10);
+    assert_eq!(emitted.concat(), data);
+
+    let merged =
+        chunk_all(data, lib::config::ChunkingTail::Merge { min_size: 64 });
+    assert_eq!(merged.len(), 2);
+    assert_eq!(merged[0], emitted[0]);
+    assert_eq!(merged[1].len(), emitted[1].len() + 10);
+    assert_eq!(merged.concat(), data);
+
+    // A tail that is big enough is left alone
+    let merged =
+        chunk_all(data, lib::config::ChunkingTail::Merge { min_size: 10 });
+    assert_eq!(merged, emitted);
+
+    // Nothing to merge with
+    let single = chunk_all(
+        &data[..10],
+        lib::config::ChunkingTail::Merge { min_size: 64 },
+    );
+    assert_eq!(single, vec![data[..10].to_vec()]);
+}
+
+/// Chunking never finding an edge, like content-defined chunking on
+/// pathological data
+struct NoEdges;
+
+impl lib::chunking::Chunking for NoEdges {
+    fn find_chunk<'a>(
+        &mut self,
+        _buf: &'a [u8],
+    ) -> Option<(&'a [u8], &'a [u8])> {
+        None
+    }
+}
+
+fn chunk_bounded(
+    data: &[u8],
+    engine: Box<dyn lib::chunking::Chunking>,
+    tail: lib::config::ChunkingTail,
+    buf_size: usize,
+    max_len: usize,
+) -> Vec<Vec<u8>> {
+    let input = data
+        .chunks(buf_size)
+        .map(|c| c.to_vec())
+        .collect::<Vec<_>>();
+    lib::chunking::Chunker::with_max_len(
+        input.into_iter(),
+        engine,
+        tail,
+        max_len,
+    )
+    .map(|sg| sg.to_linear().to_vec())
+    .collect()
+}
+
+#[test]
+fn test_chunker_max_len() {
+    // A bound as low as a 16-bit platform's, on input much longer than it
+    let max_len = u16::MAX as usize;
+    let data = rand_data(16 * max_len + 1000);
+    for &buf_size in &[7, 1000, 64 * 1024, 3 * max_len] {
+        let chunks = chunk_bounded(
+            &data,
+            Box::new(NoEdges),
+            lib::config::ChunkingTail::Emit,
+            buf_size,
+            max_len,
+        );
+        assert_eq!(chunks.len(), 17);
+        assert!(chunks[..16].iter().all(|chunk| chunk.len() == max_len));
+        assert_eq!(chunks[16].len(), 1000);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    // Edges found within the bound are kept
+    let chunking = lib::config::Chunking::Bup { chunk_bits: 10 };
+    let bounded = chunk_bounded(
+        &data,
+        chunking.to_engine(),
+        lib::config::ChunkingTail::Emit,
+        7 * 1024,
+        max_len,
+    );
+    assert_eq!(bounded, chunk_all(&data, lib::config::ChunkingTail::Emit));
+
+    // The tail isn't merged past the bound
+    let data = &data[..2 * max_len + 10];
+    let chunks = chunk_bounded(
+        data,
+        Box::new(NoEdges),
+        lib::config::ChunkingTail::Merge { min_size: 64 },
+        1000,
+        max_len,
+    );
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks[2].len(), 10);
+    assert_eq!(chunks.concat(), data);
+}
+
+#[test]
+fn test_small_tail_merge_repo() {
+    let dir_path = rand_tmp_dir();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    assert!(settings.set_small_tail_merge(Some(0)).is_err());
+    settings.set_small_tail_merge(Some(4096)).unwrap();
+    lib::Repo::init(
+        &Url::from_file_path(&dir_path).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+
+    let repo = lib::Repo::open(&Url::from_file_path(&dir_path).unwrap(), None)
+        .unwrap();
+    assert_eq!(
+        repo.config.chunking_tail,
+        lib::config::ChunkingTail::Merge { min_size: 4096 }
+    );
+
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    for &len in &[0, 1, 4095, 1024 * 1024 + 7] {
+        let data = rand_data(len);
+        let name = format!("{}", len);
+        repo.write(&name, &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap();
+        let mut read_data = vec![];
+        repo.read(&name, &mut read_data, &dec_handle).unwrap();
+        assert_eq!(read_data, data);
+    }
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_aio_thread_num() {
+    let dir = rand_tmp_dir();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        Some(8),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    assert_eq!(aio.thread_num(), 8);
+
+    // More jobs than threads (and queue slots) all get done
+    let results: Vec<_> = (0..100)
+        .map(|i| {
+            aio.write(
+                PathBuf::from(format!("{}", i)),
+                lib::SGData::from_single(rand_data(10)),
+            )
+        })
+        .collect();
+    for result in results {
+        result.wait().unwrap();
+    }
+    assert_eq!(aio.stats().snapshot().write.new_chunks, 100);
+    drop(aio);
+    fs::remove_dir_all(dir).unwrap();
+
+    let mut repo = test_repo(PASS);
+    assert!(repo.set_io_thread_num(Some(0)).is_err());
+    repo.set_io_thread_num(Some(2)).unwrap();
+    assert_eq!(repo.io_thread_num(), 2);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let mut read = vec![];
+    repo.read("data", &mut read, &dec_handle).unwrap();
+    assert_eq!(read, data);
+    // with and without a worker left to read while listing the chunks
+    for &num in &[1, 2] {
+        repo.set_io_thread_num(Some(num)).unwrap();
+        let results = repo.verify_stored(&dec_handle).unwrap();
+        assert!(results.scanned > 0);
+        assert!(results.errors.is_empty());
+    }
+    wipe(&repo);
+}
+
+#[test]
+fn test_stats_snapshot() {
+    let dir = rand_tmp_dir();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        log,
+    )
+    .unwrap();
+    let stats = aio.stats();
+
+    for i in 0..3 {
+        aio.write(
+            PathBuf::from(format!("before-{}", i)),
+            lib::SGData::from_single(rand_data(100)),
+        )
+        .wait()
+        .unwrap();
+    }
+
+    let before = stats.snapshot();
+    assert_eq!(before.write.new_chunks, 3);
+
+    let sizes = [10usize, 2000, 0];
+    for (i, &size) in sizes.iter().enumerate() {
+        aio.write(
+            PathBuf::from(format!("after-{}", i)),
+            lib::SGData::from_single(rand_data(size)),
+        )
+        .wait()
+        .unwrap();
+    }
+
+    let delta = stats.snapshot().since(&before);
+    assert_eq!(delta.write.new_chunks, sizes.len());
+    assert_eq!(delta.write.new_bytes, sizes.iter().sum::<usize>() as u64);
+    assert_eq!(delta.read.chunks_read, 0);
+
+    let before = stats.snapshot();
+    aio.write_idempotent(
+        PathBuf::from("after-1"),
+        lib::SGData::from_single(rand_data(sizes[1])),
+    )
+    .wait()
+    .unwrap();
+    let delta = stats.snapshot().since(&before);
+    assert_eq!(delta.write.new_chunks, 0);
+    assert_eq!(delta.write.deduped_chunks, 1);
+    assert_eq!(delta.write.deduped_bytes, sizes[1] as u64);
+
+    let before = stats.snapshot();
+    for i in 0..sizes.len() {
+        aio.read(PathBuf::from(format!("after-{}", i)))
+            .wait()
+            .unwrap();
+    }
+    // failed reads are not counted
+    assert!(aio.read(PathBuf::from("missing")).wait().is_err());
+    let delta = stats.snapshot().since(&before);
+    assert_eq!(delta.read.chunks_read, sizes.len());
+    assert_eq!(delta.read.bytes_read, sizes.iter().sum::<usize>() as u64);
+    assert_eq!(delta.write.new_chunks, 0);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_dual_condition_resync() {
+    let chunking = lib::config::Chunking::Bup { chunk_bits: 10 };
+    let tail = lib::config::ChunkingTail::Emit;
+    let data = rand_data(512 * 1024);
+    let mut inserted = data.clone();
+    inserted.insert(128 * 1024, 0);
+
+    let chunks =
+        chunk_with(&data, chunking.to_engine_refined(Some(4)), tail, 4096);
+    let new: Vec<_> =
+        chunk_with(&inserted, chunking.to_engine_refined(Some(4)), tail, 4096)
+            .into_iter()
+            .filter(|chunk| !chunks.contains(chunk))
+            .collect();
+    // Only the chunks around the insertion change
+    assert!(!new.is_empty());
+    assert!(new.len() <= 3, "{} chunks changed", new.len());
+}
+
+#[test]
+fn test_dual_condition_chunking() {
+    let chunking = lib::config::Chunking::Bup { chunk_bits: 10 };
+    let tail = lib::config::ChunkingTail::Emit;
+    let nominal = 1 << chunking.chunk_bits();
+
+    // Find a short record template that produces an edge on every
+    // record with a single condition: a pathological, structured input.
+    let records = |template: Vec<u8>| {
+        (0..500u32)
+            .flat_map(|i| {
+                let mut record = template.clone();
+                record.extend_from_slice(&i.to_le_bytes());
+                record
+            })
+            .collect::<Vec<u8>>()
+    };
+    let (data, single) = (0..1000)
+        .map(|_| records(rand_data(196)))
+        .map(|data| {
+            let chunks = chunk_with(&data, chunking.to_engine(), tail, 4096);
+            (data, chunks)
+        })
+        .find(|(_, chunks)| chunks.len() > 450)
+        .expect("no structured input found");
+
+    let dual =
+        chunk_with(&data, chunking.to_engine_refined(Some(4)), tail, 4096);
+    assert_eq!(dual.concat(), data);
+
+    let short_ratio = |chunks: &[Vec<u8>]| {
+        chunks.iter().filter(|c| c.len() < nominal / 4).count() as f64
+            / chunks.len() as f64
+    };
+    assert!(short_ratio(&single) > 0.9);
+    assert!(short_ratio(&dual) < 0.5);
+
+    // Reproducible, no matter how the input is split
+    let dual2 =
+        chunk_with(&data, chunking.to_engine_refined(Some(4)), tail, 333);
+    assert_eq!(dual, dual2);
+
+    let mut settings = settings::Repo::new();
+    assert!(settings.set_secondary_chunking_bits(Some(0)).is_err());
+    settings.set_secondary_chunking_bits(Some(4)).unwrap();
+    settings.set_pwhash(settings::PWHash::Weak);
+    let repo = lib::Repo::init(
+        &Url::from_file_path(rand_tmp_dir()).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    assert_eq!(repo.config.chunking_secondary_bits, Some(4));
+
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_pin() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(64 * 1024);
+    for name in &["keeper", "other"] {
+        repo.write(name, &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap();
+    }
+
+    assert!(repo.pin("missing").is_err());
+    repo.pin("keeper").unwrap();
+    // pinning twice is fine
+    repo.pin("keeper").unwrap();
+    assert_eq!(repo.list_pinned().unwrap(), vec!["keeper".to_string()]);
+
+    let err = repo.rm("keeper").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    repo.rm("other").unwrap();
+    repo.gc(0).unwrap();
+    repo.gc(0).unwrap();
+
+    let mut read_data = vec![];
+    repo.read("keeper", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+    assert_eq!(repo.list_names().unwrap(), vec!["keeper".to_string()]);
+
+    repo.unpin("keeper").unwrap();
+    assert!(repo.unpin("keeper").is_err());
+    assert!(repo.list_pinned().unwrap().is_empty());
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_rename() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(64 * 1024);
+    let other_data = rand_data(16 * 1024);
+    repo.write("nightly", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    repo.write("release", &mut io::Cursor::new(&other_data), &enc_handle)
+        .unwrap();
+    repo.tag("nightly", "keep").unwrap();
+    repo.pin("nightly").unwrap();
+    let chunks = list_stored_chunks(&repo).unwrap();
+
+    match repo.rename("missing", "new", false).unwrap_err() {
+        lib::Error::NotFound(path) => {
+            assert_eq!(path, PathBuf::from("name/missing"))
+        }
+        err => panic!("not a missing name: {}", err),
+    }
+    assert_eq!(
+        repo.rename("nightly", "release", false).unwrap_err().kind(),
+        io::ErrorKind::AlreadyExists
+    );
+    repo.rename("nightly", "release", true).unwrap();
+
+    let mut read_data = vec![];
+    repo.read("release", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+    assert!(repo.read("nightly", &mut vec![], &dec_handle).is_err());
+    assert_eq!(repo.list_names().unwrap(), vec!["release".to_string()]);
+    assert_eq!(repo.list_tags("release").unwrap(), vec!["keep".to_string()]);
+    assert_eq!(repo.list_pinned().unwrap(), vec!["release".to_string()]);
+    assert_eq!(list_stored_chunks(&repo).unwrap(), chunks);
+
+    // the pinned name can't be replaced
+    repo.write("nightly", &mut io::Cursor::new(&other_data), &enc_handle)
+        .unwrap();
+    assert_eq!(
+        repo.rename("nightly", "release", true).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    repo.unpin("release").unwrap();
+
+    // replacing a name stored in an older generation
+    let gen = *repo.read_generations().unwrap().last().unwrap();
+    gen.gen_next().write(&repo.aio).unwrap();
+    repo.write("newer", &mut io::Cursor::new(&other_data), &enc_handle)
+        .unwrap();
+    repo.rename("newer", "release", true).unwrap();
+    let mut read_data = vec![];
+    repo.read("release", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, other_data);
+    let mut names = repo.list_names().unwrap();
+    names.sort();
+    assert_eq!(names, vec!["nightly".to_string(), "release".to_string()]);
+
+    while repo.read_generations().unwrap().len() > 1 {
+        repo.gc(0).unwrap();
+    }
+    wipe(&repo);
+}
+
+#[test]
+fn test_tag_prune() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(64 * 1024);
+    for name in &["jan", "feb", "mar", "daily-1", "daily-2", "pinned"] {
+        repo.write(name, &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap();
+    }
+
+    assert!(repo.tag("missing", "monthly").is_err());
+    assert!(repo.tag("jan", "").is_err());
+    for name in &["jan", "feb", "mar", "pinned"] {
+        repo.tag(name, "monthly").unwrap();
+    }
+    // tagging twice is fine
+    repo.tag("jan", "monthly").unwrap();
+    repo.tag("jan", "keep").unwrap();
+    repo.tag("pinned", "daily").unwrap();
+    repo.tag("daily-1", "daily").unwrap();
+    repo.tag("daily-2", "daily").unwrap();
+    repo.pin("pinned").unwrap();
+
+    assert_eq!(
+        repo.list_tags("jan").unwrap(),
+        vec!["keep".to_string(), "monthly".to_string()]
+    );
+    assert!(repo.untag("feb", "keep").is_err());
+
+    let sorted = |mut names: Vec<String>| {
+        names.sort();
+        names
+    };
+    let monthly = TagFilter {
+        tagged: vec!["monthly".into()],
+        not_tagged: vec!["keep".into()],
+    };
+    assert_eq!(
+        sorted(repo.list_names_tagged(&monthly).unwrap()),
+        vec!["feb", "mar", "pinned"]
+    );
+    assert_eq!(
+        sorted(repo.list_names_tagged(&TagFilter::default()).unwrap()),
+        sorted(repo.list_names().unwrap())
+    );
+
+    // selecting by no tag would remove everything
+    assert!(repo.prune(&TagFilter::default()).is_err());
+
+    assert_eq!(sorted(repo.prune(&monthly).unwrap()), vec!["feb", "mar"]);
+    assert_eq!(
+        sorted(repo.list_names().unwrap()),
+        vec!["daily-1", "daily-2", "jan", "pinned"]
+    );
+
+    repo.untag("daily-2", "daily").unwrap();
+    let daily = TagFilter {
+        tagged: vec!["daily".into()],
+        not_tagged: vec![],
+    };
+    assert_eq!(repo.prune(&daily).unwrap(), vec!["daily-1"]);
+
+    // tags don't affect what gc keeps
+    repo.gc(0).unwrap();
+    repo.gc(0).unwrap();
+    for name in &["daily-2", "jan", "pinned"] {
+        let mut read_data = vec![];
+        repo.read(name, &mut read_data, &dec_handle).unwrap();
+        assert_eq!(read_data, data);
+    }
+    // and are kept when names move between generations
+    assert_eq!(repo.list_tags("pinned").unwrap(), vec!["daily", "monthly"]);
+
+    repo.unpin("pinned").unwrap();
+    wipe(&repo);
+}
+
+#[test]
+fn test_read_lenient() {
+    let (repo, dir) = test_repo_dir(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    let chunks = chunk_with(
+        &data,
+        repo.config.chunking_engine(),
+        repo.config.chunking_tail,
+        64 * 1024,
+    );
+    assert!(chunks.len() > 2);
+    let missing = &chunks[1];
+    let offset = chunks[0].len();
+    let digest = repo
+        .hasher
+        .calculate_digest(&sgdata::SGData::from_single(missing.clone()));
+    let gen_str = repo.read_generations().unwrap().last().unwrap().to_string();
+    let path = repo.chunk_rel_path_by_digest(lib::DigestRef(&digest), &gen_str);
+    fs::remove_file(dir.join(path)).unwrap();
+
+    let mut read_data = vec![];
+    assert!(repo.read("data", &mut read_data, &dec_handle).is_err());
+
+    let mut read_data = vec![];
+    let report = repo
+        .read_lenient("data", &mut read_data, &dec_handle)
+        .unwrap();
+    assert_eq!(read_data.len(), data.len());
+    assert_eq!(report.gaps.len(), 1);
+    let gap = &report.gaps[0];
+    assert_eq!(gap.offset, offset as u64);
+    assert_eq!(gap.len, missing.len() as u64);
+    assert_eq!(gap.digest, digest);
+    assert_eq!(gap.error.kind(), io::ErrorKind::NotFound);
+
+    let gap_range = offset..offset + missing.len();
+    assert!(read_data[gap_range.clone()].iter().all(|&b| b == 0));
+    assert_eq!(read_data[..offset], data[..offset]);
+    assert_eq!(read_data[gap_range.end..], data[gap_range.end..]);
+
+    repo.rm("data").unwrap();
+    repo.gc(0).unwrap();
+}
+
+/// Writer failing once `limit` bytes were written to it
+struct FailingWriter<W> {
+    inner: W,
+    limit: usize,
+}
+
+impl<W: Write> Write for FailingWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> Result<usize> {
+        if self.limit == 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "limit reached"));
+        }
+        let len = cmp::min(bytes.len(), self.limit);
+        let written = self.inner.write(&bytes[..len])?;
+        self.limit -= written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_read_resume() {
+    let (repo, dir) = test_repo_dir(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    let mut full = vec![];
+    repo.read("data", &mut full, &dec_handle).unwrap();
+
+    // interrupted in the middle of a chunk, and resumed from the length
+    // of what was written
+    let out_path = rand_tmp_dir();
+    let mut out = FailingWriter {
+        inner: fs::File::create(&out_path).unwrap(),
+        limit: 300 * 1024 + 17,
+    };
+    assert!(repo.read("data", &mut out, &dec_handle).is_err());
+    let mut out = OpenOptions::new().append(true).open(&out_path).unwrap();
+    let offset = out.metadata().unwrap().len();
+    assert_eq!(offset, 300 * 1024 + 17);
+    repo.read_from("data", offset, &mut out, &dec_handle)
+        .unwrap();
+    drop(out);
+    assert_eq!(fs::read(&out_path).unwrap(), full);
+    fs::remove_file(&out_path).unwrap();
+
+    // chunks before the offset are not read at all
+    let chunks = chunk_with(
+        &data,
+        repo.config.chunking_engine(),
+        repo.config.chunking_tail,
+        64 * 1024,
+    );
+    let digest = repo
+        .hasher
+        .calculate_digest(&sgdata::SGData::from_single(chunks[0].clone()));
+    let gen_str = repo.read_generations().unwrap().last().unwrap().to_string();
+    let path = repo.chunk_rel_path_by_digest(lib::DigestRef(&digest), &gen_str);
+    fs::remove_file(dir.join(path)).unwrap();
+    let mut tail = vec![];
+    repo.read_from("data", chunks[0].len() as u64, &mut tail, &dec_handle)
+        .unwrap();
+    assert_eq!(tail, data[chunks[0].len()..]);
+
+    let mut tail = vec![];
+    repo.read_from("data", data.len() as u64, &mut tail, &dec_handle)
+        .unwrap();
+    assert!(tail.is_empty());
+    let err = repo
+        .read_from("data", data.len() as u64 + 1, &mut tail, &dec_handle)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    repo.rm("data").unwrap();
+    repo.gc(0).unwrap();
+}
+
+fn rand_index_entry(
+    format: lib::config::IndexFormat,
+) -> lib::index::IndexEntry {
+    let mut rng = rand::thread_rng();
+    let len = match rng.gen_range(0, 4) {
+        0 => 0,
+        1 => rng.gen_range(0, 128),
+        2 => rng.gen(),
+        _ => u64::max_value(),
+    };
+    let mut entry =
+        lib::index::IndexEntry::new(lib::Digest(rand_data(DIGEST_SIZE)), len);
+    match format {
+        lib::config::IndexFormat::Digest => entry.len = None,
+        lib::config::IndexFormat::DigestLen => {}
+        lib::config::IndexFormat::Compact => {
+            if rng.gen() {
+                entry.len = None
+            }
+        }
+    }
+    entry
+}
+
+#[test]
+fn test_index_format_round_trip() {
+    use lib::config::IndexFormat;
+
+    for &format in &[
+        IndexFormat::Digest,
+        IndexFormat::DigestLen,
+        IndexFormat::Compact,
+    ] {
+        let codec = format.to_codec(DIGEST_SIZE);
+        let entries: Vec<_> =
+            (0..1000).map(|_| rand_index_entry(format)).collect();
+
+        let mut stream = vec![];
+        for entry in &entries {
+            let encoded = codec.encode_to_vec(entry);
+            let (decoded, size) = codec.decode(&encoded).unwrap().unwrap();
+            assert_eq!(&decoded, entry);
+            assert_eq!(size, encoded.len());
+            assert!(codec.decode(&encoded[..size - 1]).unwrap().is_none());
+            stream.extend_from_slice(&encoded);
+        }
+
+        // entries spanning arbitrary boundaries
+        let mut decoded = vec![];
+        let mut decoder = lib::index::Decoder::new(codec);
+        let mut rest = &stream[..];
+        while !rest.is_empty() {
+            let split =
+                cmp::min(rest.len(), rand::thread_rng().gen_range(1, 100));
+            decoder
+                .push(&rest[..split], |entry| {
+                    decoded.push(entry);
+                    Ok(())
+                })
+                .unwrap();
+            rest = &rest[split..];
+        }
+        decoder.finish().unwrap();
+        assert_eq!(decoded, entries);
+    }
+}
+
+#[test]
+fn test_index_compact_format() {
+    use lib::index::{decode_varint, encode_varint};
+
+    let codec = lib::config::IndexFormat::Compact.to_codec(DIGEST_SIZE);
+    let entry = lib::index::IndexEntry::new(lib::Digest(vec![7; 32]), 300);
+    let encoded = codec.encode_to_vec(&entry);
+    assert_eq!(encoded.len(), DIGEST_SIZE + 3);
+    assert_eq!(encoded[DIGEST_SIZE..], [0x01, 0xac, 0x02]);
+
+    for &value in &[0, 1, 127, 128, 16383, 16384, u64::max_value()] {
+        let mut buf = vec![];
+        encode_varint(value, &mut buf);
+        assert_eq!(decode_varint(&buf).unwrap(), Some((value, buf.len())));
+    }
+
+    // overflowing `u64`
+    let mut overflow = vec![0xff; 9];
+    overflow.push(0x02);
+    assert!(decode_varint(&overflow).is_err());
+    assert!(decode_varint(&[0xff; 11]).is_err());
+    // non-canonical
+    assert!(decode_varint(&[0x80, 0x00]).is_err());
+
+    // unknown flags
+    let mut bad_flags = encoded.clone();
+    bad_flags[DIGEST_SIZE] = 0x82;
+    assert!(codec.decode(&bad_flags).is_err());
+
+    let mut decoder = lib::index::Decoder::new(codec);
+    decoder
+        .push(&encoded[..DIGEST_SIZE + 2], |_| Ok(()))
+        .unwrap();
+    assert!(decoder.finish().is_err());
+}
+
+#[test]
+fn test_codec_header_legacy_repo() {
+    let (repo, dir) = test_repo_dir(PASS);
+    assert!(repo.config.codec_header());
+
+    // as if created before chunks told their compression
+    let config_path = dir.join(lib::config::CONFIG_YML_FILE);
+    let mut config: serde_yaml::Mapping =
+        serde_yaml::from_str(&fs::read_to_string(&config_path).unwrap())
+            .unwrap();
+    config.insert("version".into(), 4.into());
+    let config = serde_yaml::to_string(&config).unwrap();
+    fs::write(&config_path, config).unwrap();
+
+    let mut repo =
+        lib::Repo::open(&Url::from_file_path(&dir).unwrap(), None).unwrap();
+    assert!(!repo.config.codec_header());
+    assert_eq!(
+        repo.set_compression_sample(Some(4)).unwrap_err().kind(),
+        io::ErrorKind::Unsupported
+    );
+
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+    assert!(repo.verify_stored(&dec_handle).unwrap().errors.is_empty());
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_index_format_legacy_repo() {
+    let (repo, dir) = test_repo_dir(PASS);
+    assert_eq!(repo.config.index_format, lib::config::IndexFormat::Compact);
+
+    // rewrite the config, as if created before the index format was recorded
+    let config_path = dir.join(lib::config::CONFIG_YML_FILE);
+    let mut config: serde_yaml::Mapping =
+        serde_yaml::from_str(&fs::read_to_string(&config_path).unwrap())
+            .unwrap();
+    config.remove(&"index_format".into());
+    config.insert("version".into(), 3.into());
+    let config = serde_yaml::to_string(&config).unwrap();
+    fs::write(&config_path, config).unwrap();
+
+    let repo =
+        lib::Repo::open(&Url::from_file_path(&dir).unwrap(), None).unwrap();
+    assert_eq!(repo.config.index_format, lib::config::IndexFormat::Digest);
+
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    wipe(&repo);
+}
+
+/// `Local` backend forwarding only the operations every backend has to
+/// implement, through `Hooks`
+///
+/// The other operations, like `copy_prefix` and `read_stream`, use their
+/// default implementations.
+struct Forwarding<H>(lib::backends::local::Local, H);
+
+struct ForwardingThread<H>(Box<dyn lib::backends::BackendThread>, H);
+
+/// What a `Forwarding` backend does besides forwarding
+trait Hooks: Clone + Send + Sync + 'static {
+    /// Called before every modification, failing it on errors
+    fn modify(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn stat(
+        &self,
+        inner: &mut dyn lib::backends::BackendThread,
+        path: PathBuf,
+    ) -> Result<Option<lib::backends::Metadata>> {
+        inner.stat(path)
+    }
+}
+
+/// Just forwarding, to exercise the default implementations
+#[derive(Clone)]
+struct NoHooks;
+
+impl Hooks for NoHooks {}
+
+impl<H: Hooks> lib::backends::Backend for Forwarding<H> {
+    fn lock_exclusive(&self) -> Result<Box<dyn lib::backends::Lock>> {
+        self.0.lock_exclusive()
+    }
+
+    fn lock_shared(&self) -> Result<Box<dyn lib::backends::Lock>> {
+        self.0.lock_shared()
+    }
+
+    fn new_thread(&self) -> Result<Box<dyn lib::backends::BackendThread>> {
+        Ok(Box::new(ForwardingThread(
+            self.0.new_thread()?,
+            self.1.clone(),
+        )))
+    }
+}
+
+impl<H: Hooks> lib::backends::BackendThread for ForwardingThread<H> {
+    fn remove_dir_all(&mut self, path: PathBuf) -> Result<()> {
+        self.1.modify()?;
+        self.0.remove_dir_all(path)
+    }
+
+    fn rename(&mut self, src_path: PathBuf, dst_path: PathBuf) -> Result<()> {
+        self.1.modify()?;
+        self.0.rename(src_path, dst_path)
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: sgdata::SGData,
+        idempotent: bool,
+    ) -> Result<lib::backends::WriteOutcome> {
+        self.1.modify()?;
+        self.0.write(path, sg, idempotent)
+    }
+
+    fn read(&mut self, path: PathBuf) -> Result<sgdata::SGData> {
+        self.0.read(path)
+    }
+
+    fn remove(&mut self, path: PathBuf) -> Result<()> {
+        self.1.modify()?;
+        self.0.remove(path)
+    }
+
+    fn read_metadata(
+        &mut self,
+        path: PathBuf,
+    ) -> Result<lib::backends::Metadata> {
+        self.0.read_metadata(path)
+    }
+
+    fn stat(
+        &mut self,
+        path: PathBuf,
+    ) -> Result<Option<lib::backends::Metadata>> {
+        self.1.stat(&mut *self.0, path)
+    }
+
+    fn list(&mut self, path: PathBuf) -> Result<Vec<PathBuf>> {
+        self.0.list(path)
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: lib::backends::ListSender,
+    ) {
+        self.0.list_recursively(path, tx)
+    }
+}
+
+fn list_objects(
+    aio: &lib::aio::AsyncIO,
+    prefix: &str,
+) -> std::collections::BTreeMap<PathBuf, Vec<u8>> {
+    aio.list_recursively(PathBuf::from(prefix))
+        // nothing under a missing prefix
+        .filter(|path| match path {
+            Err(e) => e.kind() != io::ErrorKind::NotFound,
+            Ok(_) => true,
+        })
+        .map(|path| {
+            let path = path.unwrap();
+            let data = aio.read(path.clone()).wait().unwrap();
+            (
+                path.strip_prefix(prefix).unwrap().to_owned(),
+                data.to_linear().to_vec(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_copy_prefix() {
+    for &bulk in &[true, false] {
+        let dir = rand_tmp_dir();
+        let local = lib::aio::Local::new(dir.clone());
+        let backend: Box<dyn lib::backends::Backend> = if bulk {
+            Box::new(local)
+        } else {
+            Box::new(Forwarding(local, NoHooks))
+        };
+        let mut thread = backend.new_thread().unwrap();
+        let aio = lib::aio::AsyncIO::new(
+            Box::new(lib::aio::Local::new(dir.clone())),
+            None,
+            slog::Logger::root(slog::Discard, slog::o!()),
+        )
+        .unwrap();
+
+        for &(path, len) in &[
+            ("src/a", 10),
+            ("src/b/c", 1024 * 1024),
+            ("src/b/d/e", 0),
+            ("other/f", 10),
+        ] {
+            aio.write(
+                PathBuf::from(path),
+                sgdata::SGData::from_single(rand_data(len)),
+            )
+            .wait()
+            .unwrap();
+        }
+
+        thread.copy_prefix("src".into(), "dst".into()).unwrap();
+        thread.copy_prefix("missing".into(), "dst2".into()).unwrap();
+
+        let src = list_objects(&aio, "src");
+        assert_eq!(src.len(), 3);
+        assert_eq!(list_objects(&aio, "dst"), src);
+        assert!(list_objects(&aio, "dst2").is_empty());
+
+        drop(aio);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+fn stored_root_digest(repo: &lib::Repo, name: &str) -> Vec<u8> {
+    let generations = repo.read_generations().unwrap();
+    lib::Name::load_from_any(name, &generations, &repo.aio)
+        .unwrap()
+        .digest
+}
+
+#[test]
+fn test_write_deterministic_root() {
+    let dir_path = rand_tmp_dir();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    // small chunks, so there's plenty of them to reorder
+    settings.use_bup_chunking(Some(10)).unwrap();
+    let mut repo = lib::Repo::init(
+        &Url::from_file_path(&dir_path).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    assert!(repo.set_write_thread_num(Some(0)).is_err());
+
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(1024 * 1024);
+
+    repo.set_write_thread_num(Some(1)).unwrap();
+    repo.write("single", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let expected = stored_root_digest(&repo, "single");
+
+    repo.set_write_thread_num(Some(16)).unwrap();
+    for i in 0..10 {
+        let name = format!("parallel-{}", i);
+        repo.write(&name, &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap();
+        assert_eq!(stored_root_digest(&repo, &name), expected);
+    }
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_write_overwrite_protection() {
+    let dir = rand_tmp_dir();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    let window = std::time::Duration::from_secs(3600);
+    let path = PathBuf::from("root.yml");
+    let data = |len| sgdata::SGData::from_single(rand_data(len));
+
+    // nothing to protect yet
+    aio.write_protected(path.clone(), data(10), window)
+        .wait()
+        .unwrap();
+
+    let err = aio
+        .write_protected(path.clone(), data(20), window)
+        .wait()
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(aio.read(path.clone()).wait().unwrap().len(), 10);
+
+    // make it look old
+    OpenOptions::new()
+        .write(true)
+        .open(dir.join(&path))
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() - 2 * window)
+        .unwrap();
+    aio.write_protected(path.clone(), data(30), window)
+        .wait()
+        .unwrap();
+    assert_eq!(aio.read(path.clone()).wait().unwrap().len(), 30);
+
+    // forced
+    aio.write(path.clone(), data(40)).wait().unwrap();
+    assert_eq!(aio.read(path).wait().unwrap().len(), 40);
+
+    drop(aio);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_name_versioning() {
+    let dir_path = rand_tmp_dir();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.set_name_versioning(true);
+    let repo = lib::Repo::init(
+        &Url::from_file_path(&dir_path).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let datas: Vec<_> = (0..3).map(|_| rand_data(256 * 1024)).collect();
+    for data in &datas {
+        repo.write("name", &mut io::Cursor::new(data), &enc_handle)
+            .unwrap();
+    }
+
+    let check_versions = |expected: &[u64]| {
+        let versions = repo.list_versions("name").unwrap();
+        assert_eq!(
+            versions.iter().map(|v| v.version).collect::<Vec<_>>(),
+            expected
+        );
+        assert!(versions.iter().all(|v| v.created.is_some()));
+        for &version in expected {
+            let mut read_data = vec![];
+            repo.read_version("name", version, &mut read_data, &dec_handle)
+                .unwrap();
+            assert_eq!(read_data, datas[version as usize]);
+        }
+    };
+    check_versions(&[0, 1, 2]);
+
+    // new versions are stored under the exclusive lock
+    let lock = repo.aio.lock_shared().unwrap();
+    let mut impatient = repo.clone();
+    impatient.set_lock_timeout(Some(std::time::Duration::from_millis(100)));
+    match impatient
+        .write("name", &mut io::Cursor::new(&datas[0]), &enc_handle)
+        .unwrap_err()
+    {
+        lib::Error::Locked { .. } => {}
+        err => panic!("not a lock timeout: {}", err),
+    }
+    drop(lock);
+
+    let mut read_data = vec![];
+    repo.read("name", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, datas[2]);
+    assert_eq!(repo.list_names().unwrap(), vec!["name".to_string()]);
+
+    // gc keeps all the versions
+    repo.gc(0).unwrap();
+    repo.gc(0).unwrap();
+    check_versions(&[0, 1, 2]);
+
+    repo.pin("name").unwrap();
+    assert!(repo.prune_versions("name", 1).is_err());
+    repo.unpin("name").unwrap();
+
+    assert_eq!(repo.prune_versions("name", 1).unwrap(), 1);
+    check_versions(&[1, 2]);
+    assert!(repo
+        .read_version("name", 0, &mut vec![], &dec_handle)
+        .is_err());
+    repo.gc(0).unwrap();
+    repo.gc(0).unwrap();
+    check_versions(&[1, 2]);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_name_versioning_across_generations() {
+    let dir_path = rand_tmp_dir();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.set_name_versioning(true);
+    let repo = lib::Repo::init(
+        &Url::from_file_path(&dir_path).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let datas: Vec<_> = (0..2).map(|_| rand_data(256 * 1024)).collect();
+    repo.write("name", &mut io::Cursor::new(&datas[0]), &enc_handle)
+        .unwrap();
+    // as left by an interrupted gc
+    let gen = *repo.read_generations().unwrap().last().unwrap();
+    gen.gen_next().write(&repo.aio).unwrap();
+    repo.write("name", &mut io::Cursor::new(&datas[1]), &enc_handle)
+        .unwrap();
+
+    // the gc finishing the generation change keeps the history
+    repo.gc(0).unwrap();
+    repo.gc(0).unwrap();
+    for (version, data) in datas.iter().enumerate() {
+        let mut read_data = vec![];
+        repo.read_version("name", version as u64, &mut read_data, &dec_handle)
+            .unwrap();
+        assert_eq!(&read_data, data);
+    }
+    repo.verify("name", &dec_handle).unwrap();
+
+    while repo.read_generations().unwrap().len() > 1 {
+        repo.gc(0).unwrap();
+    }
+    wipe(&repo);
+}
+
+#[test]
+fn test_name_overwrite_protection() {
+    let dir_path = rand_tmp_dir();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.set_name_versioning(true);
+    let mut repo = lib::Repo::init(
+        &Url::from_file_path(&dir_path).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let datas: Vec<_> = (0..4).map(|_| rand_data(16 * 1024)).collect();
+    let versions = |repo: &lib::Repo| -> Vec<u64> {
+        let versions = repo.list_versions("name").unwrap();
+        versions.iter().map(|v| v.version).collect()
+    };
+
+    repo.set_overwrite_protection(Some(std::time::Duration::from_secs(3600)));
+    repo.write("name", &mut io::Cursor::new(&datas[0]), &enc_handle)
+        .unwrap();
+    let err = repo
+        .write("name", &mut io::Cursor::new(&datas[1]), &enc_handle)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(versions(&repo), vec![0]);
+
+    // still protected in an older generation
+    let gen = *repo.read_generations().unwrap().last().unwrap();
+    gen.gen_next().write(&repo.aio).unwrap();
+    let err = repo
+        .write("name", &mut io::Cursor::new(&datas[1]), &enc_handle)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(versions(&repo), vec![0]);
+
+    // an old one is overwritable
+    let name_files: Vec<_> = walkdir::WalkDir::new(&dir_path)
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.file_name().unwrap() == "name.yml")
+        .collect();
+    assert_eq!(name_files.len(), 1);
+    fs::File::options()
+        .write(true)
+        .open(&name_files[0])
+        .unwrap()
+        .set_modified(
+            std::time::SystemTime::now() - std::time::Duration::from_secs(7200),
+        )
+        .unwrap();
+    repo.write("name", &mut io::Cursor::new(&datas[2]), &enc_handle)
+        .unwrap();
+    assert_eq!(versions(&repo), vec![0, 1]);
+
+    // forced without the protection
+    repo.set_overwrite_protection(None);
+    repo.write("name", &mut io::Cursor::new(&datas[3]), &enc_handle)
+        .unwrap();
+    assert_eq!(versions(&repo), vec![0, 1, 2]);
+    let mut read_data = vec![];
+    repo.read("name", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, datas[3]);
+
+    while repo.read_generations().unwrap().len() > 1 {
+        repo.gc(0).unwrap();
+    }
+    wipe(&repo);
+}
+
+#[test]
+fn test_read_index_node() {
+    let codec = lib::config::IndexFormat::Compact.to_codec(DIGEST_SIZE);
+    let entries: Vec<_> = (0..10)
+        .map(|_| rand_index_entry(lib::config::IndexFormat::Compact))
+        .collect();
+    let mut bytes = vec![];
+    for entry in &entries {
+        codec.encode(entry, &mut bytes);
+    }
+
+    let node = lib::index::IndexNode::decode(2, &bytes, codec).unwrap();
+    assert_eq!(node.level, 2);
+    assert_eq!(node.entries, entries);
+
+    // malformed
+    assert!(lib::index::IndexNode::decode(0, &bytes, codec).is_err());
+    assert!(
+        lib::index::IndexNode::decode(1, &bytes[..bytes.len() - 1], codec)
+            .is_err()
+    );
+    let mut bad_flags = bytes.clone();
+    bad_flags[DIGEST_SIZE] = 0x80;
+    assert!(lib::index::IndexNode::decode(1, &bad_flags, codec).is_err());
+
+    // top index chunk of stored data: 1024 fixed chunks need more index
+    // entries than fit in one 1KiB chunk, so the index has two levels
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_fixed_chunking(Some(10)).unwrap();
+    let repo = lib::Repo::init(
+        &Url::from_file_path(rand_tmp_dir()).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    let generations = repo.read_generations().unwrap();
+    let name =
+        lib::Name::load_from_any("data", &generations, &repo.aio).unwrap();
+    assert!(name.index_level >= 2);
+    let accessor = repo.get_chunk_accessor(
+        None,
+        std::sync::Arc::clone(&repo.compression),
+        generations,
+    );
+    let node = lib::reading::ChunkAccessor::read_index(
+        &accessor,
+        lib::DigestRef(&name.digest),
+        name.index_level,
+    )
+    .unwrap();
+    assert_eq!(node.level, name.index_level);
+    assert!(node.entries.len() > 1);
+
+    // all the levels below lead to the data chunks
+    let mut lens = vec![];
+    lib::reading::ReadContext::new(&accessor)
+        .for_each_entry(
+            lib::DigestRef(&name.digest),
+            name.index_level,
+            &repo.log,
+            &mut |entry| {
+                lens.push(entry.len.unwrap());
+                Ok(())
+            },
+        )
+        .unwrap();
+    assert_eq!(lens, vec![1024; 1024]);
+    wipe(&repo);
+
+    // restore through multiple index levels
+    let dir_path = rand_tmp_dir();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_bup_chunking(Some(10)).unwrap();
+    let repo = lib::Repo::init(
+        &Url::from_file_path(&dir_path).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let generations = repo.read_generations().unwrap();
+    let name =
+        lib::Name::load_from_any("data", &generations, &repo.aio).unwrap();
+    assert!(name.index_level > 1);
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_aio_pause_resume() {
+    let dir = rand_tmp_dir();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+
+    aio.write(PathBuf::from("before"), lib::SGData::from_single(vec![1]))
+        .wait()
+        .unwrap();
+
+    aio.pause();
+
+    let datas: Vec<_> = (0..8).map(|_| rand_data(100)).collect();
+    let results: Vec<_> = datas
+        .iter()
+        .enumerate()
+        .map(|(i, data)| {
+            aio.write(
+                PathBuf::from(format!("paused-{}", i)),
+                lib::SGData::from_single(data.clone()),
+            )
+        })
+        .collect();
+
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    for i in 0..datas.len() {
+        assert!(!dir.join(format!("paused-{}", i)).exists());
+    }
+
+    aio.resume();
+    for res in results {
+        res.wait().unwrap();
+    }
+    for (i, data) in datas.iter().enumerate() {
+        let read = aio
+            .read(PathBuf::from(format!("paused-{}", i)))
+            .wait()
+            .unwrap();
+        assert_eq!(&read.to_linear_vec(), data);
+    }
+
+    // dropping a paused pool must not hang
+    aio.pause();
+    let res =
+        aio.write(PathBuf::from("after"), lib::SGData::from_single(vec![2]));
+    drop(aio);
+    res.wait().unwrap();
+    assert!(dir.join("after").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_repo_pause_resume() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(256 * 1024);
+
+    repo.pause();
+    let writer = {
+        let repo = repo.clone();
+        let data = data.clone();
+        std::thread::spawn(move || {
+            repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        })
+    };
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert!(!writer.is_finished());
+
+    repo.resume();
+    writer.join().unwrap().unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_aio_stat() {
+    let dir = rand_tmp_dir();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+
+    assert!(aio.stat(PathBuf::from("a/b")).wait().unwrap().is_none());
+
+    aio.write(
+        PathBuf::from("a/b"),
+        lib::SGData::from_single(rand_data(123)),
+    )
+    .wait()
+    .unwrap();
+
+    let metadata = aio.stat(PathBuf::from("a/b")).wait().unwrap().unwrap();
+    assert_eq!(metadata.len, 123);
+    assert!(metadata.is_file);
+    assert!(metadata.modified.is_some());
+
+    let metadata = aio.stat(PathBuf::from("a")).wait().unwrap().unwrap();
+    assert!(!metadata.is_file);
+
+    assert!(aio.stat(PathBuf::from("a/c")).wait().unwrap().is_none());
+
+    drop(aio);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_chunk_cache() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let cache_path = rand_tmp_dir().with_extension("yml");
+    let mut cache = lib::ChunkCache::open(&cache_path).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    let path = PathBuf::from("/some/file");
+    let modified = std::time::SystemTime::now();
+    let opened = std::cell::Cell::new(0);
+    let write = |name: &str,
+                 modified: std::time::SystemTime,
+                 cache: &mut lib::ChunkCache| {
+        repo.write_cached(
+            name,
+            &path,
+            data.len() as u64,
+            modified,
+            || {
+                opened.set(opened.get() + 1);
+                Ok(io::Cursor::new(&data))
+            },
+            &enc_handle,
+            cache,
+        )
+        .unwrap();
+    };
+
+    write("first", modified, &mut cache);
+    assert_eq!(opened.get(), 1);
+    write("second", modified, &mut cache);
+    assert_eq!(opened.get(), 1);
+
+    cache.save().unwrap();
+    let mut cache = lib::ChunkCache::open(&cache_path).unwrap();
+    write("third", modified, &mut cache);
+    assert_eq!(opened.get(), 1);
+
+    // changed file
+    write(
+        "fourth",
+        modified + std::time::Duration::from_secs(1),
+        &mut cache,
+    );
+    assert_eq!(opened.get(), 2);
+
+    let root = stored_root_digest(&repo, "first");
+    for name in &["second", "third", "fourth"] {
+        assert_eq!(stored_root_digest(&repo, name), root);
+        let mut read_data = vec![];
+        repo.read(name, &mut read_data, &dec_handle).unwrap();
+        assert_eq!(read_data, data);
+    }
+
+    // chunks no longer in the repo
+    for name in &["first", "second", "third", "fourth"] {
+        repo.rm(name).unwrap();
+    }
+    repo.gc(0).unwrap();
+    repo.gc(0).unwrap();
+    write(
+        "fifth",
+        modified + std::time::Duration::from_secs(1),
+        &mut cache,
+    );
+    assert_eq!(opened.get(), 3);
+    let mut read_data = vec![];
+    repo.read("fifth", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    // real files
+    let file_path = rand_tmp_dir().with_extension("data");
+    fs::write(&file_path, &data).unwrap();
+    repo.write_file("file", &file_path, &enc_handle, &mut cache)
+        .unwrap();
+    repo.write_file("file-again", &file_path, &enc_handle, &mut cache)
+        .unwrap();
+    assert_eq!(stored_root_digest(&repo, "file-again"), root);
+
+    fs::remove_file(&file_path).unwrap();
+    fs::remove_file(&cache_path).unwrap();
+    wipe(&repo);
+}
+
+#[test]
+fn test_safe_mode() {
+    let (mut repo, dir) = test_repo_dir(PASS);
+    assert!(!repo.is_safe_mode());
+    repo.set_safe_mode(true).unwrap();
+
+    // enabled by someone else
+    let repo_other =
+        lib::Repo::open(&Url::from_file_path(&dir).unwrap(), None).unwrap();
+    assert!(repo_other.is_safe_mode());
+
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(64 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    repo.tag("data", "old").unwrap();
+    let mirror = test_repo(PASS);
+    for res in &[
+        repo.rm("data"),
+        repo.rename("data", "other", true),
+        repo.prune(&lib::TagFilter {
+            tagged: vec!["old".into()],
+            not_tagged: vec![],
+        })
+        .map(|_| ()),
+        repo.gc(0).map(|_| ()),
+        repo.prune_versions("data", 0).map(|_| ()),
+        repo.reencrypt(&|| Ok(PASS.into())).map(|_| ()),
+        repo.repair("data", &mirror, &dec_handle).map(|_| ()),
+    ] {
+        assert_eq!(
+            res.as_ref().unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    repo.set_safe_mode(false).unwrap();
+    assert!(!repo.is_safe_mode());
+    wipe(&repo);
+    wipe(&mirror);
+}
+
+#[test]
+fn test_export_import() {
+    let src = test_repo(PASS);
+    let enc_handle = src.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = src.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    src.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let mut archive = vec![];
+    src.export("data", &mut archive, &dec_handle).unwrap();
+
+    let dst = test_repo("other");
+    let dst_enc = dst.unlock_encrypt(&|| Ok("other".into())).unwrap();
+    let dst_dec = dst.unlock_decrypt(&|| Ok("other".into())).unwrap();
+    let stats = dst
+        .import("imported", io::Cursor::new(&archive), &dst_enc)
+        .unwrap();
+    assert!(stats.new_chunks > 1);
+
+    let mut read_data = vec![];
+    dst.read("imported", &mut read_data, &dst_dec).unwrap();
+    assert_eq!(read_data, data);
+    assert!(dst.verify("imported", &dst_dec).unwrap().errors.is_empty());
+
+    // deduplicated against the chunks already imported
+    let stats = dst
+        .import("again", io::Cursor::new(&archive), &dst_enc)
+        .unwrap();
+    assert_eq!(stats.new_chunks, 0);
+
+    // corrupted chunk
+    let mut corrupted = archive.clone();
+    let last = corrupted.len() - 2;
+    corrupted[last] ^= 1;
+    let err = dst
+        .import("corrupted", io::Cursor::new(&corrupted), &dst_enc)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // truncated
+    let err = dst
+        .import(
+            "truncated",
+            io::Cursor::new(&archive[..archive.len() - 1]),
+            &dst_enc,
+        )
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(dst.list_names().unwrap().len(), 2);
+
+    wipe(&src);
+    wipe(&dst);
+}
+
+#[test]
+fn test_max_chunks() {
+    let mut repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    assert!(repo.set_max_chunks(Some(0)).is_err());
+
+    let data = rand_data(4 * 1024 * 1024);
+    let stats = repo
+        .write("unlimited", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let chunks = stats.new_chunks as u64;
+    assert!(chunks > 2);
+
+    repo.set_max_chunks(Some(2)).unwrap();
+    let err = repo
+        .write("limited", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(repo.list_names().unwrap(), vec!["unlimited".to_string()]);
+
+    // small enough
+    repo.write("small", &mut io::Cursor::new(&data[..100]), &enc_handle)
+        .unwrap();
+
+    repo.set_max_chunks(None).unwrap();
+    wipe(&repo);
+}
+
+#[test]
+fn test_read_checks_chunk_len() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    let (entries_tx, entries_rx) = std::sync::mpsc::channel();
+    repo.write_input(
+        "data",
+        lib::WriteInput::Reader(io::Cursor::new(&data), Some(entries_tx)),
+        &enc_handle,
+    )
+    .unwrap();
+    let mut entries: Vec<_> = entries_rx.iter().collect();
+    assert!(entries.len() > 1);
+
+    // index recording a wrong length of an intact chunk
+    let tampered_len = entries[0].len.unwrap() + 1;
+    entries[0].len = Some(tampered_len);
+    repo.write_input(
+        "tampered",
+        lib::WriteInput::<io::Empty>::Entries(entries),
+        &enc_handle,
+    )
+    .unwrap();
+
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    let err = repo.read("tampered", &mut vec![], &dec_handle).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        repo.verify("tampered", &dec_handle).unwrap().errors.len(),
+        1
+    );
+
+    let report = repo
+        .read_lenient("tampered", &mut vec![], &dec_handle)
+        .unwrap();
+    assert_eq!(report.gaps.len(), 1);
+    assert_eq!(report.gaps[0].offset, 0);
+    assert_eq!(report.gaps[0].len, tampered_len);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_recover_roots() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    // small chunks, for multiple index levels
+    settings.use_bup_chunking(Some(10)).unwrap();
+    let repo = lib::Repo::init(
+        &Url::from_file_path(rand_tmp_dir()).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let datas: Vec<_> = (0..3).map(|_| rand_data(512 * 1024)).collect();
+    let mut roots = vec![];
+    for (i, data) in datas.iter().enumerate() {
+        let name = format!("data{}", i);
+        repo.write(&name, &mut io::Cursor::new(data), &enc_handle)
+            .unwrap();
+        let root = repo.root_address(&name).unwrap();
+        assert!(root.index_level > 1);
+        assert_eq!(root.to_string().parse::<lib::RootAddress>().unwrap(), root);
+        roots.push(root);
+    }
+    assert!("abc:1".parse::<lib::RootAddress>().is_err());
+
+    // lose the names, but not the data
+    repo.rm("data0").unwrap();
+    repo.rm("data1").unwrap();
+
+    let mut read_data = vec![];
+    repo.read_root(&roots[0], &mut read_data, &dec_handle)
+        .unwrap();
+    assert_eq!(read_data, datas[0]);
+
+    let mut scanned = repo.scan_roots().unwrap();
+    scanned.sort_by(|a, b| a.digest.cmp(&b.digest));
+    let mut expected = roots.clone();
+    expected.sort_by(|a, b| a.digest.cmp(&b.digest));
+    assert_eq!(scanned, expected);
+
+    let mut recovered = repo.recover_names("recovered-").unwrap();
+    recovered.sort();
+    let mut expected: Vec<_> = roots[..2]
+        .iter()
+        .map(|root| format!("recovered-{}", hex::encode(&root.digest)))
+        .collect();
+    expected.sort();
+    assert_eq!(recovered, expected);
+
+    for (i, data) in datas[..2].iter().enumerate() {
+        let name = format!("recovered-{}", hex::encode(&roots[i].digest));
+        let mut read_data = vec![];
+        repo.read(&name, &mut read_data, &dec_handle).unwrap();
+        assert_eq!(&read_data, data);
+    }
+    assert!(repo.recover_names("recovered-").unwrap().is_empty());
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_read_root_checks_hashing() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(512 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let root = repo.root_address("data").unwrap();
+    assert_eq!(root.hashing, Some(settings::Hashing::Blake2b));
+    assert!(root.to_string().starts_with("blake2b:"));
+
+    // addresses without the hashing are still accepted
+    let old = format!("{}:{}", hex::encode(&root.digest), root.index_level);
+    let old: lib::RootAddress = old.parse().unwrap();
+    assert_eq!(old.hashing, None);
+    let mut read_data = vec![];
+    repo.read_root(&old, &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    assert!(format!("md5:{}", old).parse::<lib::RootAddress>().is_err());
+
+    let mismatched: lib::RootAddress =
+        format!("sha256:{}", old).parse().unwrap();
+    let err = repo
+        .read_root(&mismatched, &mut vec![], &dec_handle)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(
+        err.to_string(),
+        "root address uses sha256 hashing, but the repo uses blake2b"
+    );
+
+    let short: lib::RootAddress = format!(
+        "blake2b:{}:{}",
+        hex::encode(&root.digest[..20]),
+        root.index_level
+    )
+    .parse()
+    .unwrap();
+    let err = repo
+        .read_root(&short, &mut vec![], &dec_handle)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    wipe(&repo);
+}
+
+static PROBES_IN_FLIGHT: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(0);
+static PROBES_MAX: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(0);
+
+/// Hooks recording the most `stat` calls in progress at once
+#[derive(Clone)]
+struct ProbeCounting;
+
+impl Hooks for ProbeCounting {
+    fn stat(
+        &self,
+        inner: &mut dyn lib::backends::BackendThread,
+        path: PathBuf,
+    ) -> Result<Option<lib::backends::Metadata>> {
+        use std::sync::atomic::Ordering;
+
+        let in_flight = PROBES_IN_FLIGHT.fetch_add(1, Ordering::SeqCst) + 1;
+        PROBES_MAX.fetch_max(in_flight, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let res = inner.stat(path);
+        PROBES_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+}
+
+fn probe_counting_backend(
+    url: &Url,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    Ok(Box::new(Forwarding(
+        lib::backends::local::Local::new(url.to_file_path().unwrap()),
+        ProbeCounting,
+    )))
+}
+
+/// Log records as their messages, with the `op` in their context
+///
+/// Skips the timing records of the `AsyncIO` workers.
+#[derive(Clone, Default)]
+struct OpRecords(
+    std::sync::Arc<std::sync::Mutex<Vec<(String, Option<String>)>>>,
+);
+
+impl OpRecords {
+    fn take(&self) -> Vec<(String, Option<String>)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl slog::Drain for OpRecords {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record<'_>,
+        values: &slog::OwnedKVList,
+    ) -> std::result::Result<(), slog::Never> {
+        #[derive(Default)]
+        struct Op {
+            op: Option<String>,
+            name: Option<String>,
+        }
+
+        impl slog::Serializer for Op {
+            fn emit_arguments(
+                &mut self,
+                key: slog::Key,
+                val: &std::fmt::Arguments<'_>,
+            ) -> slog::Result {
+                match key {
+                    "op" => self.op = Some(val.to_string()),
+                    "name" => self.name = Some(val.to_string()),
+                    _ => {}
+                }
+                Ok(())
+            }
+        }
+
+        let mut op = Op::default();
+        slog::KV::serialize(values, record, &mut op).unwrap();
+        slog::KV::serialize(&record.kv(), record, &mut op).unwrap();
+        if op.name.as_deref() != Some("chunk-writer") {
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.msg().to_string(), op.op));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_op_id() {
+    let records = OpRecords::default();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_bup_chunking(Some(12)).unwrap();
+    let repo = lib::Repo::init(
+        &Url::from_file_path(rand_tmp_dir()).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        Some(slog::Logger::root(records.clone(), slog::o!())),
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    // with the records of the workers too
+    let op_of = |records: Vec<(String, Option<String>)>, worker_msg: &str| {
+        assert!(records.iter().any(|(msg, _)| msg == worker_msg));
+        let op = records[0].1.clone().unwrap();
+        for (msg, record_op) in &records {
+            assert_eq!(record_op.as_ref(), Some(&op), "{}", msg);
+        }
+        op
+    };
+
+    let data = rand_data(1024 * 1024);
+    records.take();
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let write_op = op_of(records.take(), "write");
+
+    // chunks are moved to the new generation by the read
+    let gen = *repo.read_generations().unwrap().last().unwrap();
+    gen.gen_next().write(&repo.aio).unwrap();
+    records.take();
+    let mut read = vec![];
+    repo.read("data", &mut read, &dec_handle).unwrap();
+    assert_eq!(read, data);
+    let read_op = op_of(records.take(), "rename");
+    assert_ne!(write_op, read_op);
+
+    let root = repo.root_address("data").unwrap();
+    records.take();
+    let mut read = vec![];
+    repo.read_root(&root, &mut read, &dec_handle).unwrap();
+    assert_eq!(read, data);
+    let read_root_op = op_of(records.take(), "read");
+    assert_ne!(read_op, read_root_op);
+
+    let mut out = FailingWriter {
+        inner: vec![],
+        limit: 1024,
+    };
+    let err = repo.read("data", &mut out, &dec_handle).unwrap_err();
+    let failed_op = records.take()[0].1.clone().unwrap();
+    assert!(err.to_string().contains(&failed_op), "{}", err);
+
+    // errors of their own variant are kept as they are
+    let err = repo.read("missing", &mut vec![], &dec_handle).unwrap_err();
+    assert!(matches!(err, lib::Error::NotFound(_)), "{}", err);
+
+    while repo.read_generations().unwrap().len() > 1 {
+        repo.gc(0).unwrap();
+    }
+    wipe(&repo);
+}
+
+#[test]
+fn test_max_probes() {
+    use std::sync::atomic::Ordering;
+
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_bup_chunking(Some(12)).unwrap();
+    let mut repo = lib::Repo::init_custom(
+        &Url::from_file_path(rand_tmp_dir()).unwrap(),
+        &probe_counting_backend,
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    repo.set_write_thread_num(Some(8)).unwrap();
+    assert!(repo.set_max_probes(Some(0)).is_err());
+
+    repo.set_max_probes(Some(2)).unwrap();
+    repo.write(
+        "limited",
+        &mut io::Cursor::new(rand_data(512 * 1024)),
+        &enc_handle,
+    )
+    .unwrap();
+    let max = PROBES_MAX.swap(0, Ordering::SeqCst);
+    assert!(max >= 1 && max <= 2, "{} probes in flight", max);
+
+    // the limit is what kept them down
+    repo.set_max_probes(None).unwrap();
+    repo.write(
+        "unlimited",
+        &mut io::Cursor::new(rand_data(512 * 1024)),
+        &enc_handle,
+    )
+    .unwrap();
+    assert!(PROBES_MAX.load(Ordering::SeqCst) > 2);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_max_buffered() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_bup_chunking(Some(12)).unwrap();
+    let mut repo = lib::Repo::init(
+        &Url::from_file_path(rand_tmp_dir()).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    repo.set_write_thread_num(Some(8)).unwrap();
+    assert!(repo.set_max_buffered(Some(0)).is_err());
+
+    let data = rand_data(2 * 1024 * 1024);
+    let stats = repo
+        .write("unlimited", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert!(stats.peak_buffered > 0);
+
+    // ~4KiB chunks, so none should be larger than the limit by itself
+    let max = 64 * 1024;
+    repo.set_max_buffered(Some(max)).unwrap();
+    let stats = repo
+        .write("limited", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert!(
+        stats.peak_buffered > 0 && stats.peak_buffered <= max,
+        "{} bytes buffered",
+        stats.peak_buffered
+    );
+
+    let mut read = vec![];
+    repo.read("limited", &mut read, &dec_handle).unwrap();
+    assert_eq!(read, data);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_decompress_other_compression() {
+    // A corrupted chunk can tell any compression, so data of one must not
+    // make any other panic or hang
+    let all: Vec<_> = (0..=u8::MAX)
+        .filter_map(|id| lib::config::Compression::from_id(id).ok())
+        .collect();
+    let data = rand_data(64 * 1024);
+    for compression in &all {
+        let compressed = compression
+            .to_engine()
+            .compress(lib::SGData::from_single(data.clone()))
+            .unwrap();
+        for other in &all {
+            let _ = other.to_engine().decompress(compressed.clone());
+        }
+        for len in 0..64 {
+            let garbage = lib::SGData::from_single(rand_data(len * 16));
+            let _ = compression.to_engine().decompress(garbage);
+        }
+    }
+}
+
+#[test]
+fn test_compression_sample() {
+    let mut repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    assert!(repo.set_compression_sample(Some(0)).is_err());
+
+    let compression = |repo: &lib::Repo, name: &str| {
+        lib::Name::load_from_any(
+            name,
+            &repo.read_generations().unwrap(),
+            &repo.aio,
+        )
+        .unwrap()
+        .params
+        .unwrap()
+        .compression
+    };
+
+    let random = rand_data(1024 * 1024);
+    let text: Vec<u8> = b"compressible "
+        .iter()
+        .cycle()
+        .take(1024 * 1024)
+        .cloned()
+        .collect();
+
+    // stored with the repo compression first, and then the same chunks
+    // are reused by a sampled name
+    repo.write("text-plain", &mut io::Cursor::new(&text), &enc_handle)
+        .unwrap();
+    repo.set_compression_sample(Some(4)).unwrap();
+    repo.write("text", &mut io::Cursor::new(&text), &enc_handle)
+        .unwrap();
+    repo.write("random", &mut io::Cursor::new(&random), &enc_handle)
+        .unwrap();
+    assert_ne!(compression(&repo, "text"), lib::config::Compression::None);
+    assert_eq!(compression(&repo, "random"), lib::config::Compression::None);
+
+    // reuses the uncompressed chunks of the sampled name
+    repo.set_compression_sample(None).unwrap();
+    let stats = repo
+        .write("random-plain", &mut io::Cursor::new(&random), &enc_handle)
+        .unwrap();
+    assert_eq!(stats.new_chunks, 0);
+    assert_ne!(
+        compression(&repo, "random-plain"),
+        lib::config::Compression::None
+    );
+
+    for (name, data) in &[
+        ("text-plain", &text),
+        ("text", &text),
+        ("random", &random),
+        ("random-plain", &random),
+    ] {
+        let mut read = vec![];
+        repo.read(name, &mut read, &dec_handle).unwrap();
+        assert_eq!(&read, *data);
+    }
+    repo.verify("random-plain", &dec_handle).unwrap();
+
+    wipe(&repo);
+}
+
+/// Modifications left before `Interrupting` fails, per test
+static GC_OPS_LEFT: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(usize::MAX);
+static REENCRYPT_OPS_LEFT: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(usize::MAX);
+
+/// Hooks failing every modification once the operations left run out,
+/// as if the process was interrupted
+#[derive(Clone)]
+struct Interrupting(&'static std::sync::atomic::AtomicUsize);
+
+impl Hooks for Interrupting {
+    fn modify(&self) -> Result<()> {
+        use std::sync::atomic::Ordering;
+
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .map(|_| ())
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "interrupted"))
+    }
+}
+
+fn interrupting_backend(
+    url: &Url,
+    ops_left: &'static std::sync::atomic::AtomicUsize,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    Ok(Box::new(Forwarding(
+        lib::backends::local::Local::new(url.to_file_path().unwrap()),
+        Interrupting(ops_left),
+    )))
+}
+
+fn gc_interrupting_backend(
+    url: &Url,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    interrupting_backend(url, &GC_OPS_LEFT)
+}
+
+fn reencrypt_interrupting_backend(
+    url: &Url,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    interrupting_backend(url, &REENCRYPT_OPS_LEFT)
+}
+
+#[test]
+fn test_gc_interrupted() {
+    use std::sync::atomic::Ordering;
+
+    let live = rand_data(512 * 1024);
+    let dead = rand_data(512 * 1024);
+    let versions: Vec<_> = (0..2).map(|_| rand_data(128 * 1024)).collect();
+
+    // interrupt every operation of `gc` in turn, each time on a new repo
+    for ops in 0.. {
+        assert!(ops < 1000, "gc never finished");
+
+        let mut settings = settings::Repo::new();
+        settings.set_pwhash(settings::PWHash::Weak);
+        settings.set_name_versioning(true);
+        let dir = rand_tmp_dir();
+        let repo = lib::Repo::init_custom(
+            &Url::from_file_path(&dir).unwrap(),
+            &gc_interrupting_backend,
+            &|| Ok(PASS.into()),
+            settings,
+            None,
+        )
+        .unwrap();
+        let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+        let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+        repo.write("live", &mut io::Cursor::new(&live), &enc_handle)
+            .unwrap();
+        repo.write("dead", &mut io::Cursor::new(&dead), &enc_handle)
+            .unwrap();
+        repo.rm("dead").unwrap();
+        repo.write(
+            "versioned",
+            &mut io::Cursor::new(&versions[0]),
+            &enc_handle,
+        )
+        .unwrap();
+
+        GC_OPS_LEFT.store(ops, Ordering::SeqCst);
+        let res = repo.gc(0);
+        GC_OPS_LEFT.store(usize::MAX, Ordering::SeqCst);
+
+        let mut read_data = vec![];
+        repo.read("live", &mut read_data, &dec_handle).unwrap();
+        assert_eq!(read_data, live, "after {} operations", ops);
+
+        // the next gc picks up where the interrupted one left off, even
+        // with a new version stored in between
+        let finished = res.is_ok();
+        if !finished {
+            repo.write(
+                "versioned",
+                &mut io::Cursor::new(&versions[1]),
+                &enc_handle,
+            )
+            .unwrap();
+            repo.gc(0).unwrap();
+            let mut read_data = vec![];
+            repo.read("live", &mut read_data, &dec_handle).unwrap();
+            assert_eq!(read_data, live);
+            for (version, data) in versions.iter().enumerate() {
+                let mut read_data = vec![];
+                repo.read_version(
+                    "versioned",
+                    version as u64,
+                    &mut read_data,
+                    &dec_handle,
+                )
+                .unwrap();
+                assert_eq!(&read_data, data, "after {} operations", ops);
+            }
+        }
+
+        // but only after having removed all the dead chunks
+        let dead_chunk = chunk_with(
+            &dead,
+            repo.config.chunking_engine(),
+            repo.config.chunking_tail,
+            64 * 1024,
+        )
+        .remove(0);
+        let digest = hex::encode(
+            repo.hasher
+                .calculate_digest(&sgdata::SGData::from_single(dead_chunk)),
+        );
+        for entry in walkdir::WalkDir::new(&dir) {
+            let entry = entry.unwrap();
+            assert!(!entry.file_name().to_string_lossy().contains(&digest));
+        }
+
+        wipe(&repo);
+        if finished {
+            break;
+        }
+    }
+}
+
+#[test]
+fn test_read_during_write() {
+    let (repo, dir) = test_repo_dir(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(4 * 1024 * 1024);
+    repo.write("committed", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    for i in 0..4 {
+        // the chunks of `committed` are left in an older generation, for
+        // both the backup and the restore to move them to the current one
+        let gen = *repo.read_generations().unwrap().last().unwrap();
+        gen.gen_next().write(&repo.aio).unwrap();
+
+        let url = Url::from_file_path(&dir).unwrap();
+        let backup = std::thread::spawn({
+            let data = data.clone();
+            move || {
+                let repo = lib::Repo::open(&url, None).unwrap();
+                let enc_handle =
+                    repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+                let mut backup = rand_data(1024 * 1024);
+                backup.extend_from_slice(&data);
+                repo.write(
+                    &format!("backup-{}", i),
+                    &mut io::Cursor::new(&backup),
+                    &enc_handle,
+                )
+                .unwrap();
+            }
+        });
+
+        let mut read = vec![];
+        repo.read("committed", &mut read, &dec_handle).unwrap();
+        assert_eq!(read, data);
+        backup.join().unwrap();
+    }
+
+    // `gc` collects one generation at a time
+    while repo.read_generations().unwrap().len() > 1 {
+        repo.gc(0).unwrap();
+    }
+    wipe(&repo);
+}
+
+#[test]
+fn test_reencrypt() {
+    use std::sync::atomic::Ordering;
+
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    let url = Url::from_file_path(rand_tmp_dir()).unwrap();
+    let mut repo = lib::Repo::init_custom(
+        &url,
+        &reencrypt_interrupting_backend,
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let old_dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    // interrupted after writing the config and two chunks
+    REENCRYPT_OPS_LEFT.store(3, Ordering::SeqCst);
+    assert!(repo.reencrypt(&|| Ok(PASS.into())).is_err());
+    REENCRYPT_OPS_LEFT.store(usize::MAX, Ordering::SeqCst);
+
+    let mut repo =
+        lib::Repo::open_custom(&url, &reencrypt_interrupting_backend, None)
+            .unwrap();
+    let mut read_data = vec![];
+    assert!(repo.read("data", &mut read_data, &old_dec_handle).is_err());
+    // the mix of keys is readable
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    assert!(repo.reencrypt(&|| Ok(PASS.into())).unwrap() > 0);
+
+    let mut read_data = vec![];
+    assert!(repo.read("data", &mut read_data, &old_dec_handle).is_err());
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    let repo = lib::Repo::open(&url, None).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_reencrypt_corrupted_chunk() {
+    let (mut repo, dir) = test_repo_dir(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    let chunk = walkdir::WalkDir::new(&dir)
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .find(|path| {
+            path.is_file() && path.iter().any(|c| c == lib::config::DATA_SUBDIR)
+        })
+        .unwrap();
+    let stored = fs::read(&chunk).unwrap();
+    let mut corrupted = stored.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    fs::write(&chunk, corrupted).unwrap();
+
+    let err = repo.reencrypt(&|| Ok(PASS.into())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("1 chunks"));
+    // still in progress, and readable once the chunk is restored
+    fs::write(&chunk, stored).unwrap();
+    let repo_reopened =
+        lib::Repo::open(&Url::from_file_path(&dir).unwrap(), None).unwrap();
+    assert!(repo_reopened.config.encryption_next.is_some());
+
+    repo.reencrypt(&|| Ok(PASS.into())).unwrap();
+    assert!(repo.config.encryption_next.is_none());
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    wipe(&repo);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_remove_orphaned_tmp() {
+    let (repo, dir) = test_repo_dir(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    repo.write(
+        "data",
+        &mut io::Cursor::new(rand_data(1024 * 1024)),
+        &enc_handle,
+    )
+    .unwrap();
+    // nothing left behind by successful writes
+    for entry in walkdir::WalkDir::new(&dir) {
+        let entry = entry.unwrap();
+        assert!(!entry.file_name().to_string_lossy().contains(".tmp."));
+    }
+
+    let dead_pid = {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    };
+    let tmp_dir = dir.join(lib::config::TMP_DIR);
+    let orphaned = tmp_dir.join(format!("config.yml.tmp.{}.0.0", dead_pid));
+    let live =
+        tmp_dir.join(format!("config.yml.tmp.{}.0.0", std::process::id()));
+    let unrelated = tmp_dir.join("config.yml.tmp.other");
+    for path in &[&orphaned, &live, &unrelated] {
+        fs::write(path, b"partial").unwrap();
+    }
+
+    // even while another process holds the lock, the files of dead
+    // processes can go
+    let lock = repo.aio.lock_shared().unwrap();
+    let repo =
+        lib::Repo::open(&Url::from_file_path(&dir).unwrap(), None).unwrap();
+    drop(lock);
+    assert!(!orphaned.exists());
+    assert!(live.exists());
+    assert!(unrelated.exists());
+
+    fs::remove_file(live).unwrap();
+    fs::remove_file(unrelated).unwrap();
+    wipe(&repo);
+}
+
+#[test]
+fn test_fixed_chunking() {
+    let chunking = lib::config::Chunking::Fixed { chunk_bits: 10 };
+    let data = rand_data(10 * 1024 + 100);
+    // edges don't depend on how the input is buffered
+    for &buf_size in &[100, 1024, 7 * 1024] {
+        let chunks = chunk_with(
+            &data,
+            chunking.to_engine(),
+            lib::config::ChunkingTail::Emit,
+            buf_size,
+        );
+        assert_eq!(chunks.len(), 11);
+        assert!(chunks[..10].iter().all(|c| c.len() == 1024));
+        assert_eq!(chunks[10].len(), 100);
+        assert_eq!(chunks.concat(), data);
+    }
+    assert!(!chunking.valid_secondary_bits(1));
+
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_fixed_chunking(Some(12)).unwrap();
+    let repo = lib::Repo::init(
+        &Url::from_file_path(rand_tmp_dir()).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    // 64 blocks, only 2 of them distinct
+    let blocks = [rand_data(4096), rand_data(4096)];
+    let data: Vec<u8> =
+        (0..64).flat_map(|i| blocks[i % 3 % 2].clone()).collect();
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+
+    // 2 data chunks, and the index (fits in one block)
+    assert_eq!(list_stored_chunks(&repo).unwrap().len(), 3);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_aio_read_stream() {
+    use std::io::Read;
+
+    for &native in &[true, false] {
+        let dir = rand_tmp_dir();
+        let local = lib::aio::Local::new(dir.clone());
+        // without its own `read_stream`
+        let backend: Box<dyn lib::backends::Backend> = if native {
+            Box::new(local)
+        } else {
+            Box::new(Forwarding(local, NoHooks))
+        };
+        let aio = lib::aio::AsyncIO::new(
+            backend,
+            None,
+            slog::Logger::root(slog::Discard, slog::o!()),
+        )
+        .unwrap();
+
+        let data = rand_data(5 * 1024 * 1024 + 7);
+        let path = PathBuf::from("some/object");
+        aio.write(path.clone(), lib::SGData::from_single(data.clone()))
+            .wait()
+            .unwrap();
+
+        let mut stream = aio.read_stream(path.clone()).wait().unwrap();
+        let mut streamed = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = stream.read(&mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+            streamed.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(streamed, aio.read(path).wait().unwrap().to_linear_vec());
+        assert_eq!(streamed, data);
+
+        let err = aio
+            .read_stream(PathBuf::from("missing"))
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        drop(aio);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn test_aio_batch() {
+    use lib::backends::{BatchError, BatchOp};
+
+    let dir = rand_tmp_dir();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    let write = |path: &str, data: &[u8]| BatchOp::Write {
+        path: PathBuf::from(path),
+        sg: lib::SGData::from_single(data.to_vec()),
+        idempotent: false,
+    };
+    let read = |path: &str| {
+        aio.read(PathBuf::from(path))
+            .wait()
+            .map(|sg| sg.to_linear_vec())
+    };
+
+    aio.batch(vec![
+        write("a", b"1"),
+        write("a", b"2"),
+        BatchOp::Rename {
+            src_path: PathBuf::from("a"),
+            dst_path: PathBuf::from("b"),
+        },
+        write("a", b"3"),
+        write("c", b"4"),
+        BatchOp::Remove(PathBuf::from("c")),
+    ])
+    .wait()
+    .unwrap();
+    assert_eq!(read("a").unwrap(), b"3");
+    assert_eq!(read("b").unwrap(), b"2");
+    assert_eq!(read("c").unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    let err = aio
+        .batch(vec![
+            write("d", b"5"),
+            BatchOp::Remove(PathBuf::from("missing")),
+            write("e", b"6"),
+        ])
+        .wait()
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    let batch_err = match err {
+        lib::Error::Io(ref e) => e.get_ref(),
+        _ => None,
+    }
+    .and_then(|e| e.downcast_ref::<BatchError>())
+    .unwrap();
+    assert_eq!(batch_err.completed, 1);
+    assert_eq!(read("d").unwrap(), b"5");
+    assert_eq!(read("e").unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert_eq!(aio.stats().snapshot().write.new_chunks, 5);
+
+    drop(aio);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+static BATCH_FAULTS: lib::backends::fault::Faults =
+    lib::backends::fault::Faults::new();
+
+#[test]
+fn test_aio_batch_waits_for_pending() {
+    use lib::backends::fault::{Fault, FaultInjecting, Op, Rule};
+    use lib::backends::BatchOp;
+
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(FaultInjecting::new(
+            Box::new(lib::backends::memory::Memory::new()),
+            &BATCH_FAULTS,
+        )),
+        Some(2),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    aio.write(
+        PathBuf::from("slow"),
+        lib::SGData::from_single(b"data".to_vec()),
+    )
+    .wait()
+    .unwrap();
+
+    BATCH_FAULTS.add(
+        Rule::new(
+            Op::Read,
+            Fault::Delay(std::time::Duration::from_millis(300)),
+        )
+        .path("slow"),
+    );
+    let read = aio.read(PathBuf::from("slow"));
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    // removes it only after the read in progress is done
+    aio.batch(vec![BatchOp::Remove(PathBuf::from("slow"))])
+        .wait()
+        .unwrap();
+    assert_eq!(read.wait().unwrap().to_linear_vec(), b"data");
+    BATCH_FAULTS.clear();
+}
+
+#[test]
+fn test_object_keys() {
+    use crate::backends::{key_to_path, path_to_key};
+    use crate::generation::Generation;
+    use crate::name::Name;
+
+    let gen = Generation::gen_first();
+    let path = Name::path("home", gen);
+    let key = format!("{}/name/home.yml", gen);
+    assert_eq!(path_to_key(&path), key);
+    assert_eq!(key_to_path(&key), path);
+
+    // however the path was put together
+    let by_components: PathBuf = [gen.to_string().as_str(), "name", "home.yml"]
+        .iter()
+        .collect();
+    assert_eq!(path_to_key(&by_components), key);
+
+    // keys listed by a backend may have redundant separators
+    assert_eq!(key_to_path(&format!("/{}/name//home.yml", gen)), path);
+
+    let nested = path::Path::new("data").join("ab").join("cd");
+    assert_eq!(path_to_key(&nested), "data/ab/cd");
+    assert_eq!(key_to_path("data/ab/cd"), nested);
+    assert_eq!(path_to_key(path::Path::new("")), "");
+}
+
+#[test]
+fn test_progress() {
+    use std::io::Read;
+    use std::time::Duration;
+
+    /// Reader producing 1KiB every 10ms
+    struct Throttled(usize);
+
+    impl Read for Throttled {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.0 == 0 {
+                return Ok(0);
+            }
+            self.0 -= 1;
+            std::thread::sleep(Duration::from_millis(10));
+            let len = cmp::min(buf.len(), 1024);
+            Ok(len)
+        }
+    }
+
+    let start = std::time::Instant::now();
+    let mut reader = lib::Progress::new(
+        Throttled(100),
+        vec![],
+        "store",
+        Some(100 * 1024),
+        Duration::from_millis(100),
+    );
+    let mut buf = [0u8; 1024];
+    while reader.read(&mut buf).unwrap() > 0 {}
+    let (_, out) = reader.finish().unwrap();
+    // at least a second, but might be more on a busy machine
+    let intervals = (start.elapsed().as_millis() / 100) as usize;
+
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<Vec<&str>> =
+        out.lines().map(|line| line.split(' ').collect()).collect();
+    // one update per interval (give or take), plus the last one
+    assert!(
+        lines.len() >= intervals / 2 && lines.len() <= intervals + 1,
+        "{}",
+        out
+    );
+
+    let mut prev_done = 0;
+    for line in &lines {
+        assert_eq!(line.len(), 6, "{}", out);
+        assert_eq!(&line[..2], &["progress", "store"]);
+        let done: u64 = line[2].parse().unwrap();
+        assert!(done >= prev_done);
+        prev_done = done;
+        assert_eq!(line[3], "102400");
+        let _rate: u64 = line[4].parse().unwrap();
+    }
+    let last = lines.last().unwrap();
+    assert_eq!(last[2], "102400");
+    assert_eq!(last[5], "0");
+
+    // without the total
+    let mut writer = lib::Progress::new(
+        vec![],
+        vec![],
+        "load",
+        None,
+        Duration::from_secs(60),
+    );
+    writer.write_all(&[0u8; 10]).unwrap();
+    let (data, out) = writer.finish().unwrap();
+    assert_eq!(data.len(), 10);
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("progress load 10 - "), "{}", out);
+    assert!(out.ends_with(" -\n"), "{}", out);
+}
+
+#[test]
+fn test_read_with_name_params() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(512 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let root = repo.root_address("data").unwrap();
+
+    // as if the repo config was different
+    let mut other = repo.clone();
+    other.config.hashing = match repo.config.hashing {
+        lib::config::Hashing::Sha256 => lib::config::Hashing::Blake2b,
+        lib::config::Hashing::Blake2b => lib::config::Hashing::Sha256,
+    };
+    other.config.index_format = lib::config::IndexFormat::Digest;
+    other.config.compression = lib::config::Compression::None;
+    other.hasher = other.config.hashing.to_hasher();
+    other.compression = other.config.compression_engine();
+
+    let mut read_data = vec![];
+    other.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+    let results = other.verify("data", &dec_handle).unwrap();
+    assert!(results.errors.is_empty());
+
+    // without the params, the data can't be interpreted
+    let root = lib::RootAddress {
+        hashing: None,
+        ..root
+    };
+    assert!(other.read_root(&root, &mut vec![], &dec_handle).is_err());
+
+    // the encryption key is still taken from the repo
+    other.config.encryption = lib::config::Encryption::None;
+    let err = other.read("data", &mut vec![], &dec_handle).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    wipe(&repo);
+}
+
+fn null_backend(
+    _url: &Url,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    Ok(Box::new(lib::backends::null::Null::new()))
+}
+
+#[test]
+fn test_null_backend() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.set_encryption(settings::Encryption::None).unwrap();
+    let data = rand_data(4 * 1024 * 1024);
+
+    let null_dir = rand_tmp_dir();
+    let null_url = Url::from_file_path(&null_dir).unwrap();
+    let null_repo = lib::Repo::init_custom(
+        &null_url,
+        &null_backend,
+        &|| Ok(PASS.into()),
+        settings.clone(),
+        None,
+    )
+    .unwrap();
+    let enc_handle = null_repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let null_stats = null_repo
+        .write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    // nothing is stored, so storing again writes everything again
+    let again = null_repo
+        .write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert!(!null_dir.exists());
+
+    // storing into an empty repo, every chunk is new too
+    let dir = rand_tmp_dir();
+    let url = Url::from_file_path(&dir).unwrap();
+    let repo =
+        lib::Repo::init(&url, &|| Ok(PASS.into()), settings, None).unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let stats = repo
+        .write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    assert!(stats.new_chunks > 1);
+    assert!(stats.new_bytes >= data.len() as u64);
+    for null_stats in &[null_stats, again] {
+        assert_eq!(null_stats.new_chunks, stats.new_chunks);
+        assert_eq!(null_stats.new_bytes, stats.new_bytes);
+    }
+
+    // reads fail, or return zeros
+    assert!(null_repo.list_names().unwrap().is_empty());
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::backends::null::Null::with_read_len(10)),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    let read = aio.read(PathBuf::from("any")).wait().unwrap();
+    assert_eq!(read.to_linear_vec(), vec![0u8; 10]);
+    let listed: Vec<_> = aio.list_recursively(PathBuf::from("any")).collect();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(
+        listed[0].as_ref().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_coalescing_backend() {
+    use lib::backends::coalescing::Coalescing;
+    use lib::backends::local::Local;
+    use lib::backends::Backend;
+
+    let dir = rand_tmp_dir();
+    let coalescing = |dir: &Path| {
+        Coalescing::new(Box::new(Local::new(dir.to_owned())), 1024, 64 * 1024)
+    };
+    let backend = coalescing(&dir);
+    let mut thread = backend.new_thread().unwrap();
+
+    let objects: Vec<_> = (0..1000)
+        .map(|i| {
+            (
+                PathBuf::from(format!("data/{}/{}", i % 10, i)),
+                rand_data(100),
+            )
+        })
+        .collect();
+    for (path, data) in &objects {
+        thread
+            .write(
+                path.clone(),
+                sgdata::SGData::from_single(data.clone()),
+                true,
+            )
+            .unwrap();
+    }
+    // too large to coalesce
+    let large = rand_data(2048);
+    thread
+        .write(
+            PathBuf::from("data/large"),
+            sgdata::SGData::from_single(large.clone()),
+            true,
+        )
+        .unwrap();
+
+    let stored = |dir: &Path| {
+        walkdir::WalkDir::new(dir)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .count()
+    };
+    // one full pack with its header, and the large object, leaving the
+    // rest buffered
+    assert_eq!(stored(&dir), 3);
+    drop(thread);
+    drop(backend);
+
+    // read back from the packs
+    let backend = coalescing(&dir);
+    let mut thread = backend.new_thread().unwrap();
+    for (path, data) in &objects {
+        assert_eq!(&thread.read(path.clone()).unwrap().to_linear_vec(), data);
+        assert_eq!(
+            thread.read_metadata(path.clone()).unwrap().len,
+            data.len() as u64
+        );
+    }
+    assert_eq!(
+        thread
+            .read(PathBuf::from("data/large"))
+            .unwrap()
+            .to_linear_vec(),
+        large
+    );
+    let mut listed = thread.list(PathBuf::from("data")).unwrap();
+    listed.sort();
+    assert_eq!(listed.len(), 11);
+    let (tx, rx) = lib::backends::ListSender::channel(
+        lib::backends::DEFAULT_LIST_BATCH_SIZE,
+    );
+    thread.list_recursively(PathBuf::from("data/1"), tx);
+    let listed: Vec<_> =
+        rx.into_iter().flat_map(|batch| batch.unwrap()).collect();
+    assert_eq!(listed.len(), 100);
+
+    // renaming and removing change just the headers, the last pack left
+    // is rewritten
+    let (path, data) = &objects[0];
+    thread
+        .rename(path.clone(), PathBuf::from("renamed"))
+        .unwrap();
+    assert!(thread.read(path.clone()).is_err());
+    assert_eq!(
+        &thread
+            .read(PathBuf::from("renamed"))
+            .unwrap()
+            .to_linear_vec(),
+        data
+    );
+    thread.remove(objects[1].0.clone()).unwrap();
+    assert!(thread.read(objects[1].0.clone()).is_err());
+    thread.remove_dir_all(PathBuf::from("data")).unwrap();
+    assert!(thread.read(objects[2].0.clone()).is_err());
+    assert!(thread.list(PathBuf::from("data")).unwrap().is_empty());
+    assert_eq!(stored(&dir), 2);
+    drop(thread);
+    drop(backend);
+
+    let backend = coalescing(&dir);
+    let mut thread = backend.new_thread().unwrap();
+    assert_eq!(
+        &thread
+            .read(PathBuf::from("renamed"))
+            .unwrap()
+            .to_linear_vec(),
+        data
+    );
+    assert!(thread.read(objects[3].0.clone()).is_err());
+}
+
+fn coalescing_backend(
+    url: &Url,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    Ok(Box::new(lib::backends::coalescing::Coalescing::new(
+        Box::new(lib::backends::local::Local::new(
+            url.to_file_path().unwrap(),
+        )),
+        16 * 1024,
+        256 * 1024,
+    )))
+}
+
+#[test]
+fn test_coalescing_backend_repo() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_bup_chunking(Some(12)).unwrap();
+    let dir = rand_tmp_dir();
+    let url = Url::from_file_path(&dir).unwrap();
+    let repo = lib::Repo::init_custom(
+        &url,
+        &coalescing_backend,
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    let stats = repo
+        .write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert!(stats.new_chunks > 100);
+    let packs = fs::read_dir(dir.join("coalesced"))
+        .unwrap()
+        .filter(|entry| {
+            entry.as_ref().unwrap().path().extension()
+                == Some(std::ffi::OsStr::new("idx"))
+        })
+        .count();
+    assert!(packs > 0 && packs < 10, "{} packs", packs);
+
+    let mut read = vec![];
+    repo.read("data", &mut read, &dec_handle).unwrap();
+    assert_eq!(read, data);
+    assert_eq!(repo.verify("data", &dec_handle).unwrap().errors.len(), 0);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_coalescing_backend_gc() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_bup_chunking(Some(12)).unwrap();
+    let dir = rand_tmp_dir();
+    let url = Url::from_file_path(&dir).unwrap();
+    let repo = lib::Repo::init_custom(
+        &url,
+        &coalescing_backend,
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+
+    let kept = rand_data(1024 * 1024);
+    repo.write("kept", &mut io::Cursor::new(&kept), &enc_handle)
+        .unwrap();
+    repo.write(
+        "removed",
+        &mut io::Cursor::new(rand_data(1024 * 1024)),
+        &enc_handle,
+    )
+    .unwrap();
+    let packs_len = || -> u64 {
+        fs::read_dir(dir.join("coalesced"))
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    let len_before = packs_len();
+
+    repo.rm("removed").unwrap();
+    repo.gc(0).unwrap();
+    drop(repo);
+    assert!(packs_len() < len_before * 3 / 4);
+
+    // packs found again from their headers
+    let repo = lib::Repo::open_custom(&url, &coalescing_backend, None).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let mut read = vec![];
+    repo.read("kept", &mut read, &dec_handle).unwrap();
+    assert_eq!(read, kept);
+    assert_eq!(repo.verify("kept", &dec_handle).unwrap().errors.len(), 0);
+    assert!(repo.read("removed", &mut vec![], &dec_handle).is_err());
+    repo.gc(0).unwrap();
+    let mut read = vec![];
+    repo.read("kept", &mut read, &dec_handle).unwrap();
+    assert_eq!(read, kept);
+
+    wipe(&repo);
+}
+
+const SHARDS: usize = 3;
+
+fn sharded(dir: &Path, shards: usize) -> lib::backends::sharded::Sharded {
+    lib::backends::sharded::Sharded::new(
+        (0..shards)
+            .map(|i| {
+                Box::new(lib::backends::local::Local::new(
+                    dir.join(format!("shard{}", i)),
+                )) as Box<dyn lib::backends::Backend>
+            })
+            .collect(),
+    )
+}
+
+fn sharded_backend(
+    url: &Url,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    Ok(Box::new(sharded(&url.to_file_path().unwrap(), SHARDS)))
+}
+
+fn two_shards_backend(
+    url: &Url,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    Ok(Box::new(sharded(&url.to_file_path().unwrap(), 2)))
+}
+
+/// Paths of the chunks stored directly in the shard at `dir`
+fn shard_chunks(dir: &Path) -> Vec<PathBuf> {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.path().strip_prefix(dir).unwrap().to_owned())
+        .filter(|path| {
+            path.iter().nth(1) == Some(std::ffi::OsStr::new("chunk"))
+        })
+        .collect()
+}
+
+#[test]
+fn test_sharded_backend_repo() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_bup_chunking(Some(12)).unwrap();
+    let dir = rand_tmp_dir();
+    let url = Url::from_file_path(&dir).unwrap();
+    let repo = lib::Repo::init_custom(
+        &url,
+        &sharded_backend,
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    let stats = repo
+        .write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert!(stats.new_chunks > 100);
+
+    // Every chunk is in the shard it routes to, and each shard got some
+    let router = sharded(&dir, SHARDS);
+    let mut in_shards = 0;
+    for i in 0..SHARDS {
+        let chunks = shard_chunks(&dir.join(format!("shard{}", i)));
+        assert!(!chunks.is_empty(), "shard {} is empty", i);
+        for chunk in &chunks {
+            assert_eq!(router.shard(chunk), i, "{}", chunk.display());
+        }
+        in_shards += chunks.len();
+    }
+    // Listing spans all the shards
+    assert_eq!(list_stored_chunks(&repo).unwrap().len(), in_shards);
+
+    // The same chunks are found again in their shards
+    let stats = repo
+        .write("data2", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert_eq!(stats.new_chunks, 0);
+
+    let mut read = vec![];
+    repo.read("data2", &mut read, &dec_handle).unwrap();
+    assert_eq!(read, data);
+    assert_eq!(repo.verify("data", &dec_handle).unwrap().errors.len(), 0);
+
+    // Routing depends on the number of shards, so it can't change
+    assert!(lib::Repo::open_custom(&url, &two_shards_backend, None).is_err());
+    lib::Repo::open_custom(&url, &sharded_backend, None).unwrap();
+
+    wipe(&repo);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_sparse_restore() {
+    use std::os::unix::fs::MetadataExt;
+
+    let (repo, dir) = test_repo_dir(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    // Data between runs of zeros, ending with some, and not block-aligned
+    let mut data = rand_data(1000);
+    data.extend(vec![0; 8 * 1024 * 1024]);
+    data.extend(rand_data(1024 * 1024 + 17));
+    data.extend(vec![0; 4 * 1024 * 1024 + 5]);
+    repo.write("image", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    let path = dir.with_extension("restored");
+    let mut out = lib::SparseFile::new(fs::File::create(&path).unwrap());
+    repo.read("image", &mut out, &dec_handle).unwrap();
+    let file = out.finish().unwrap();
+
+    let metadata = file.metadata().unwrap();
+    assert_eq!(metadata.len(), data.len() as u64);
+    // Only the non-zero data is allocated, give or take a block at the edges
+    let allocated = metadata.blocks() * 512;
+    assert!(allocated < 2 * 1024 * 1024, "{} bytes allocated", allocated);
+    assert_eq!(fs::read(&path).unwrap(), data);
+
+    fs::remove_file(&path).unwrap();
+    wipe(&repo);
+}
+
+#[test]
+fn test_stats_since() {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    let dir = rand_tmp_dir();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        log,
+    )
+    .unwrap();
+    let stats = aio.stats();
+    assert!(stats.since(Duration::from_secs(60)).is_none());
+    let now = Arc::new(Mutex::new(Instant::now()));
+    let clock: lib::aio::ClockFn = {
+        let now = now.clone();
+        Arc::new(move || *now.lock().unwrap())
+    };
+    let advance = |time| *now.lock().unwrap() += time;
+    stats.record_history_with_clock(100, Duration::from_secs(0), clock.clone());
+
+    let write = |prefix: &str, sizes: &[usize]| {
+        for (i, &size) in sizes.iter().enumerate() {
+            aio.write(
+                PathBuf::from(format!("{}-{}", prefix, i)),
+                lib::SGData::from_single(rand_data(size)),
+            )
+            .wait()
+            .unwrap();
+        }
+    };
+    write("first", &[100, 200, 300]);
+    advance(Duration::from_millis(500));
+    write("second", &[10, 20]);
+    advance(Duration::from_millis(500));
+
+    // Nothing happened lately
+    let delta = stats.since(Duration::from_millis(250)).unwrap();
+    assert_eq!(delta.write.new_chunks, 0);
+    assert_eq!(delta.write.new_bytes, 0);
+
+    let delta = stats.since(Duration::from_millis(750)).unwrap();
+    assert_eq!(delta.write.new_chunks, 2);
+    assert_eq!(delta.write.new_bytes, 30);
+
+    // Reaching past the history, so since it was started
+    let delta = stats.since(Duration::from_secs(3600)).unwrap();
+    assert_eq!(delta.write.new_chunks, 5);
+    assert_eq!(delta.write.new_bytes, 630);
+
+    // Only the newest snapshots are kept
+    stats.record_history_with_clock(2, Duration::from_secs(0), clock.clone());
+    write("third", &[1, 2, 3]);
+    let delta = stats.since(Duration::from_secs(3600)).unwrap();
+    assert_eq!(delta.write.new_chunks, 1);
+    assert_eq!(delta.write.new_bytes, 3);
+
+    // Snapshots are taken at most once per interval
+    stats.record_history_with_clock(100, Duration::from_secs(3600), clock);
+    write("fourth", &[1000]);
+    advance(Duration::from_millis(250));
+    let delta = stats.since(Duration::from_millis(100)).unwrap();
+    assert_eq!(delta.write.new_bytes, 1000);
+
+    drop(aio);
+    fs::remove_dir_all(dir).unwrap();
+
+    let mut repo = test_repo(PASS);
+    assert!(repo.stats_since(Duration::from_secs(60)).is_none());
+    assert!(repo.set_stats_history(0, Duration::from_secs(0)).is_err());
+    repo.set_stats_history(10, Duration::from_secs(0)).unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let written = repo
+        .write("data", &mut io::Cursor::new(rand_data(1000)), &enc_handle)
+        .unwrap();
+    let delta = repo.stats_since(Duration::from_secs(60)).unwrap();
+    assert!(delta.write.new_chunks >= written.new_chunks);
+    wipe(&repo);
+}
+
+static FAULTS: lib::backends::fault::Faults =
+    lib::backends::fault::Faults::new();
+
+#[test]
+fn test_fault_injecting_backend() {
+    use lib::backends::fault::{Fault, FaultInjecting, Op, Rule};
+    use lib::backends::Backend;
+    use std::time::{Duration, Instant};
+
+    let dir = rand_tmp_dir();
+    let backend = FaultInjecting::new(
+        Box::new(lib::backends::local::Local::new(dir.clone())),
+        &FAULTS,
+    );
+    let mut thread = backend.new_thread().unwrap();
+    let data = rand_data(100);
+    let sg = || lib::SGData::from_single(data.clone());
+
+    FAULTS.add(
+        Rule::new(Op::Write, Fault::Error(io::ErrorKind::TimedOut)).nth(3),
+    );
+    for i in 0..4 {
+        let res = thread.write(PathBuf::from(i.to_string()), sg(), false);
+        match i {
+            2 => assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut),
+            _ => assert_eq!(res.unwrap(), lib::backends::WriteOutcome::Written),
+        }
+    }
+    assert!(!dir.join("2").exists());
+    assert_eq!(FAULTS.injected(), 1);
+
+    FAULTS.add(Rule::new(Op::Read, Fault::Corrupt).path("1"));
+    let read = |thread: &mut Box<dyn lib::backends::BackendThread>, path| {
+        thread.read(PathBuf::from(path)).unwrap().to_linear_vec()
+    };
+    assert_ne!(read(&mut thread, "1"), data);
+    assert_eq!(read(&mut thread, "0"), data);
+    assert_eq!(fs::read(dir.join("1")).unwrap(), data);
+
+    FAULTS.add(
+        Rule::new(Op::Read, Fault::Delay(Duration::from_millis(50))).path("3"),
+    );
+    let start = Instant::now();
+    assert_eq!(read(&mut thread, "3"), data);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    FAULTS
+        .add(Rule::new(Op::Any, Fault::Error(io::ErrorKind::Other)).path("0"));
+    assert!(thread.remove(PathBuf::from("0")).is_err());
+    assert!(thread.read_metadata(PathBuf::from("0")).is_err());
+    assert!(dir.join("0").exists());
+    assert_eq!(FAULTS.injected(), 5);
+
+    FAULTS.clear();
+    assert_eq!(read(&mut thread, "1"), data);
+    thread.remove(PathBuf::from("0")).unwrap();
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+static REPO_FAULTS: lib::backends::fault::Faults =
+    lib::backends::fault::Faults::new();
+
+fn fault_injecting_backend(
+    url: &Url,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    Ok(Box::new(lib::backends::fault::FaultInjecting::new(
+        Box::new(lib::backends::local::Local::new(
+            url.to_file_path().unwrap(),
+        )),
+        &REPO_FAULTS,
+    )))
+}
+
+#[test]
+fn test_injected_faults_repo() {
+    use lib::backends::fault::{Fault, Op, Rule};
+
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    let dir = rand_tmp_dir();
+    let url = Url::from_file_path(&dir).unwrap();
+    let repo = lib::Repo::init_custom(
+        &url,
+        &fault_injecting_backend,
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(1024 * 1024);
+
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let gen_str = repo.read_generations().unwrap().last().unwrap().to_string();
+
+    // A failed write fails the store, in the context of its operation
+    REPO_FAULTS.add(
+        Rule::new(Op::Write, Fault::Error(io::ErrorKind::TimedOut))
+            .path(PathBuf::from(&gen_str).join("name")),
+    );
+    let err = repo
+        .write("other", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(err.to_string().contains("(operation "), "{}", err);
+    assert_eq!(repo.list_names().unwrap(), vec!["data".to_owned()]);
+    REPO_FAULTS.clear();
+
+    // Chunks corrupted on the way are repaired from a mirror
+    let mirror_dir = rand_tmp_dir();
+    copy_dir(&dir, &mirror_dir);
+    let mirror =
+        lib::Repo::open(&Url::from_file_path(&mirror_dir).unwrap(), None)
+            .unwrap();
+    let chunk = chunk_with(
+        &data,
+        repo.config.chunking_engine(),
+        repo.config.chunking_tail,
+        64 * 1024,
+    )
+    .remove(0);
+    let digest = repo
+        .hasher
+        .calculate_digest(&sgdata::SGData::from_single(chunk));
+    let path = repo.chunk_rel_path_by_digest(lib::DigestRef(&digest), &gen_str);
+    REPO_FAULTS.add(Rule::new(Op::Read, Fault::Corrupt).path(path).nth(1));
+
+    let result = repo.repair("data", &mirror, &dec_handle).unwrap();
+    assert_eq!(result.repaired, vec![digest]);
+    assert!(result.unrepairable.is_empty());
+    assert_eq!(REPO_FAULTS.injected(), 2);
+    REPO_FAULTS.clear();
+
+    assert!(repo.verify("data", &dec_handle).unwrap().errors.is_empty());
+    let mut read = vec![];
+    repo.read("data", &mut read, &dec_handle).unwrap();
+    assert_eq!(read, data);
+
+    wipe(&repo);
+    fs::remove_dir_all(mirror_dir).unwrap();
+}
+
+static SHUTDOWN_FAULTS: lib::backends::fault::Faults =
+    lib::backends::fault::Faults::new();
+
+#[test]
+fn test_aio_shutdown() {
+    use lib::backends::fault::{Fault, FaultInjecting, Op, Rule};
+
+    let dir = rand_tmp_dir();
+    let new_aio = || {
+        lib::aio::AsyncIO::new(
+            Box::new(FaultInjecting::new(
+                Box::new(lib::aio::Local::new(dir.clone())),
+                &SHUTDOWN_FAULTS,
+            )),
+            Some(2),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        )
+        .unwrap()
+    };
+
+    // all the queued writes are done before shutting down
+    let aio = new_aio();
+    for i in 0..20 {
+        aio.write_checked(
+            PathBuf::from(i.to_string()),
+            lib::SGData::from_single(rand_data(10)),
+        );
+    }
+    let other = aio.clone();
+    assert!(other.shutdown().is_err());
+    aio.shutdown().unwrap();
+    let written = fs::read_dir(&dir)
+        .unwrap()
+        .filter(|entry| {
+            entry.as_ref().unwrap().file_name() != lib::config::TMP_DIR
+        })
+        .count();
+    assert_eq!(written, 20);
+
+    // the error of a write nobody waits for is not lost
+    SHUTDOWN_FAULTS.add(
+        Rule::new(Op::Write, Fault::Error(io::ErrorKind::PermissionDenied))
+            .path("bad"),
+    );
+    let aio = new_aio();
+    aio.write_checked(
+        PathBuf::from("bad"),
+        lib::SGData::from_single(rand_data(10)),
+    );
+    aio.write_checked(
+        PathBuf::from("good"),
+        lib::SGData::from_single(rand_data(10)),
+    );
+    let err = aio.shutdown().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(dir.join("good").exists());
+    SHUTDOWN_FAULTS.clear();
+
+    fs::remove_dir_all(dir).unwrap();
+
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    repo.write("data", &mut io::Cursor::new(rand_data(1024)), &enc_handle)
+        .unwrap();
+    wipe(&repo);
+    let other = repo.clone();
+    assert!(other.close().is_err());
+    repo.close().unwrap();
+}
+
+static MEMORY: std::sync::OnceLock<lib::backends::memory::Memory> =
+    std::sync::OnceLock::new();
+
+fn memory_backend(
+    _url: &Url,
+) -> Result<Box<dyn lib::backends::Backend + Send + Sync>> {
+    Ok(Box::new(MEMORY.get_or_init(Default::default).clone()))
+}
+
+#[test]
+fn test_memory_backend() {
+    use lib::backends::memory::Memory;
+
+    let memory = Memory::new();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(memory.clone()),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    let sg = |data: &[u8]| lib::SGData::from_single(data.to_vec());
+
+    aio.write(PathBuf::from("a/b/1"), sg(b"one"))
+        .wait()
+        .unwrap();
+    aio.write(PathBuf::from("./a/2"), sg(b"two"))
+        .wait()
+        .unwrap();
+    // an idempotent write leaves the stored data alone
+    aio.write_idempotent(PathBuf::from("a/2"), sg(b"new"))
+        .wait()
+        .unwrap();
+    assert_eq!(
+        aio.read(PathBuf::from("a/2"))
+            .wait()
+            .unwrap()
+            .to_linear_vec(),
+        b"two"
+    );
+    assert_eq!(
+        memory.paths(),
+        vec![PathBuf::from("a/2"), PathBuf::from("a/b/1")]
+    );
+    assert_eq!(
+        aio.list(PathBuf::from("a")).wait().unwrap(),
+        vec![PathBuf::from("a/2"), PathBuf::from("a/b")]
+    );
+    let metadata = aio.read_metadata(PathBuf::from("a/b/1")).wait().unwrap();
+    assert_eq!((metadata.len, metadata.is_file), (3, true));
+    assert!(
+        !aio.read_metadata(PathBuf::from("a/b"))
+            .wait()
+            .unwrap()
+            .is_file
+    );
+
+    aio.rename(PathBuf::from("a/b"), PathBuf::from("c"))
+        .wait()
+        .unwrap();
+    assert_eq!(
+        aio.list_recursively(PathBuf::from("."))
+            .map(|path| path.unwrap())
+            .collect::<Vec<_>>(),
+        vec![PathBuf::from("a/2"), PathBuf::from("c/1")]
+    );
+    let err = aio.read(PathBuf::from("a/b/1")).wait().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    aio.remove_dir_all(PathBuf::from("a")).wait().unwrap();
+    assert_eq!(memory.paths(), vec![PathBuf::from("c/1")]);
+    drop(aio);
+
+    // a whole repo, never touching the disk
+    let url = Url::parse("memory:").unwrap();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    let repo = lib::Repo::init_custom(
+        &url,
+        &memory_backend,
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(256 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    drop(repo);
+
+    let repo = lib::Repo::open_custom(&url, &memory_backend, None).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+    wipe(&repo);
+}
+
+#[test]
+fn test_dedup_check() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_bup_chunking(Some(12)).unwrap();
+    let dir = rand_tmp_dir();
+    let mut repo = lib::Repo::init(
+        &Url::from_file_path(&dir).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+
+    // Chunk sizes vary a lot, and so does the data a small change makes
+    // new, so the fraction is low enough to never flag that
+    let check = lib::DedupCheck {
+        baseline: 2,
+        min_fraction: 0.1,
+        abort: false,
+    };
+    assert!(repo
+        .set_dedup_check(Some(lib::DedupCheck {
+            baseline: 0,
+            ..check
+        }))
+        .is_err());
+    assert!(repo
+        .set_dedup_check(Some(lib::DedupCheck {
+            min_fraction: 2.0,
+            ..check
+        }))
+        .is_err());
+    repo.set_dedup_check(Some(check)).unwrap();
+
+    // every backup changes a bit of the data
+    let mut data = rand_data(1024 * 1024);
+    let backup = |repo: &lib::Repo, i: usize, data: &mut Vec<u8>| {
+        let offset = i * 100 * 1024;
+        data[offset..offset + 100].copy_from_slice(&rand_data(100));
+        repo.write(&i.to_string(), &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap()
+    };
+
+    // nothing to compare the first one with
+    let stats = backup(&repo, 0, &mut data);
+    assert_eq!(stats.data_bytes, Some(data.len() as u64));
+    assert!(!stats.low_dedup);
+    for i in 1..4 {
+        assert!(!backup(&repo, i, &mut data).low_dedup);
+    }
+
+    // chunked differently, so barely anything deduplicates
+    let mut rechunked = repo.clone();
+    rechunked.config.chunking = lib::config::Chunking::Bup { chunk_bits: 13 };
+    assert!(backup(&rechunked, 4, &mut data).low_dedup);
+
+    // the baseline now includes the rechunked name, so it's much lower
+    rechunked
+        .set_dedup_check(Some(lib::DedupCheck {
+            min_fraction: 0.9,
+            abort: true,
+            ..check
+        }))
+        .unwrap();
+    let data = rand_data(1024 * 1024);
+    let err = rechunked
+        .write("aborted", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(!repo.list_names().unwrap().contains(&"aborted".to_string()));
+
+    // disabled, nothing is flagged
+    repo.set_dedup_check(None).unwrap();
+    assert!(!backup(&repo, 5, &mut data.clone()).low_dedup);
+
+    wipe(&repo);
+}
+
+static WORKER_FAULTS: lib::backends::fault::Faults =
+    lib::backends::fault::Faults::new();
+
+#[test]
+fn test_aio_worker_panic() {
+    use lib::backends::fault::{Fault, FaultInjecting, Op, Rule};
+
+    let dir = rand_tmp_dir();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(FaultInjecting::new(
+            Box::new(lib::aio::Local::new(dir.clone())),
+            &WORKER_FAULTS,
+        )),
+        Some(2),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    for path in &["good", "bad"] {
+        aio.write(PathBuf::from(path), lib::SGData::from_single(rand_data(10)))
+            .wait()
+            .unwrap();
+    }
+
+    WORKER_FAULTS.add(Rule::new(Op::Read, Fault::Panic).path("bad"));
+    let err = aio.read(PathBuf::from("bad")).wait().unwrap_err();
+    assert!(matches!(err, lib::Error::WorkerDied), "{}", err);
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    // the remaining worker keeps going
+    aio.read(PathBuf::from("good")).wait().unwrap();
+    WORKER_FAULTS.clear();
+
+    assert!(aio.shutdown().is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_aio_write_rate() {
+    use std::time::{Duration, Instant};
+
+    let rate = 512 * 1024;
+    let aio = lib::aio::AsyncIO::with_write_rate(
+        Box::new(lib::backends::memory::Memory::new()),
+        Some(4),
+        Some(rate),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+
+    // 2.5s worth of writes, the first second of which goes through at once
+    let start = Instant::now();
+    let results: Vec<_> = (0..20)
+        .map(|i| {
+            aio.write(
+                PathBuf::from(i.to_string()),
+                lib::SGData::from_single(rand_data(64 * 1024)),
+            )
+        })
+        .collect();
+    for result in results {
+        result.wait().unwrap();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(1400), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+    let mut repo = test_repo(PASS);
+    assert!(repo.set_write_rate(Some(0)).is_err());
+    repo.set_write_rate(Some(rate)).unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(128 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let mut read_data = vec![];
+    repo.read("data", &mut read_data, &dec_handle).unwrap();
+    assert_eq!(read_data, data);
+    wipe(&repo);
+}
+
+#[test]
+fn test_local_write_error_has_path() {
+    let dir_path = rand_tmp_dir();
+    fs::create_dir_all(&dir_path).unwrap();
+    // a file where a directory is needed
+    fs::write(dir_path.join("chunk"), b"").unwrap();
+
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir_path.clone())),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    let err = aio
+        .write(
+            PathBuf::from("chunk/ab/abcd"),
+            lib::SGData::from_single(b"data".to_vec()),
+        )
+        .wait()
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains(&dir_path.join("chunk/ab").display().to_string()));
+
+    drop(aio);
+    fs::remove_dir_all(&dir_path).unwrap();
+}
+
+static TIMEOUT_FAULTS: lib::backends::fault::Faults =
+    lib::backends::fault::Faults::new();
+
+#[test]
+fn test_aio_wait_timeout() {
+    use lib::backends::fault::{Fault, FaultInjecting, Op, Rule};
+    use std::time::Duration;
+
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(FaultInjecting::new(
+            Box::new(lib::backends::memory::Memory::new()),
+            &TIMEOUT_FAULTS,
+        )),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    aio.write(
+        PathBuf::from("slow"),
+        lib::SGData::from_single(b"data".to_vec()),
+    )
+    .wait()
+    .unwrap();
+
+    TIMEOUT_FAULTS.add(
+        Rule::new(Op::Read, Fault::Delay(Duration::from_millis(500)))
+            .path("slow"),
+    );
+    let res = aio.read(PathBuf::from("slow"));
+    assert!(res
+        .wait_timeout(Duration::from_millis(10))
+        .unwrap()
+        .is_none());
+    // the operation is not lost
+    let data = res.wait_timeout(Duration::from_secs(10)).unwrap().unwrap();
+    assert_eq!(data.to_linear_vec(), b"data");
+    TIMEOUT_FAULTS.clear();
+
+    let err = aio
+        .read(PathBuf::from("missing"))
+        .wait_timeout(Duration::from_secs(10))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_aio_list_recursively_bounded() {
+    let memory = lib::backends::memory::Memory::new();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(memory.clone()),
+        Some(2),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    for i in 0..250 {
+        aio.write(
+            PathBuf::from(format!("dir/{}/{}", i % 7, i)),
+            lib::SGData::from_single(vec![]),
+        )
+        .wait()
+        .unwrap();
+    }
+
+    let mut listed: Vec<_> = aio
+        .list_recursively_bounded(PathBuf::from("dir"), 1, 16)
+        .map(|path| {
+            // the other worker keeps going while the listing waits
+            aio.read(path.as_ref().unwrap().clone()).wait().unwrap();
+            path.unwrap()
+        })
+        .collect();
+    listed.sort();
+    assert_eq!(listed, memory.paths());
+}
+
+#[test]
+fn test_aio_list_recursively_missing() {
+    let dir = rand_tmp_dir();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    aio.write(PathBuf::from("a/1"), lib::SGData::from_single(vec![]))
+        .wait()
+        .unwrap();
+    fs::create_dir_all(dir.join("empty")).unwrap();
+
+    let listed: Vec<_> =
+        aio.list_recursively(PathBuf::from("missing")).collect();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(
+        listed[0].as_ref().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(aio.list_recursively(PathBuf::from("empty")).count(), 0);
+    assert_eq!(
+        aio.list_recursively(PathBuf::from("a"))
+            .map(|path| path.unwrap())
+            .collect::<Vec<_>>(),
+        vec![PathBuf::from("a/1")]
+    );
+
+    drop(aio);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_aio_space() {
+    let dir = rand_tmp_dir();
+    fs::create_dir_all(&dir).unwrap();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        log.clone(),
+    )
+    .unwrap();
+    let space = aio.space().unwrap();
+    assert!(space.total > 0);
+    assert!(space.available <= space.total);
+    drop(aio);
+
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::backends::memory::Memory::new()),
+        None,
+        log,
+    )
+    .unwrap();
+    assert_eq!(aio.space().unwrap_err().kind(), io::ErrorKind::Unsupported);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_repo_min_space() {
+    let mut repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(64 * 1024);
+    let space = repo.space().unwrap();
+    assert!(space.available <= space.total);
+
+    // nothing is stored
+    repo.set_min_space(Some(u64::MAX));
+    let err = repo
+        .write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    assert!(repo.list_names().unwrap().is_empty());
+    assert!(list_stored_chunks(&repo).unwrap().is_empty());
+
+    repo.set_min_space(Some(1));
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    wipe(&repo);
+}
+
+static RETRY_FAULTS: lib::backends::fault::Faults =
+    lib::backends::fault::Faults::new();
+
+#[test]
+fn test_retrying_backend() {
+    use lib::backends::fault::{Fault, FaultInjecting, Op, Rule};
+    use lib::backends::retrying::{RetryPolicy, Retrying};
+    use std::time::Duration;
+
+    let policy = RetryPolicy {
+        attempts: 3,
+        backoff: Duration::from_millis(1),
+        retryable: |kind| {
+            kind == io::ErrorKind::Interrupted
+                || kind == io::ErrorKind::NotFound
+        },
+    };
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(Retrying::new(
+            Box::new(FaultInjecting::new(
+                Box::new(lib::backends::memory::Memory::new()),
+                &RETRY_FAULTS,
+            )),
+            policy,
+        )),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    let write = |path: &str| {
+        aio.write(PathBuf::from(path), lib::SGData::from_single(vec![1]))
+            .wait()
+    };
+    let transient = Fault::Error(io::ErrorKind::Interrupted);
+
+    // two transient failures, then success
+    RETRY_FAULTS.add(Rule::new(Op::Write, transient.clone()).nth(1));
+    RETRY_FAULTS.add(Rule::new(Op::Write, transient.clone()).nth(2));
+    write("a").unwrap();
+    assert_eq!(RETRY_FAULTS.injected(), 2);
+    RETRY_FAULTS.clear();
+
+    // out of attempts
+    RETRY_FAULTS.add(Rule::new(Op::Write, transient).path("b"));
+    assert_eq!(write("b").unwrap_err().kind(), io::ErrorKind::Interrupted);
+    assert_eq!(RETRY_FAULTS.injected(), 5);
+    RETRY_FAULTS.clear();
+
+    // not retryable
+    RETRY_FAULTS.add(Rule::new(
+        Op::Write,
+        Fault::Error(io::ErrorKind::PermissionDenied),
+    ));
+    assert_eq!(
+        write("c").unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(RETRY_FAULTS.injected(), 6);
+    RETRY_FAULTS.clear();
+
+    assert_eq!(
+        aio.read(PathBuf::from("a")).wait().unwrap().to_linear_vec(),
+        vec![1]
+    );
+}
+
+static READ_CACHE_FAULTS: lib::backends::fault::Faults =
+    lib::backends::fault::Faults::new();
+
+#[test]
+fn test_aio_read_cache() {
+    use lib::backends::fault::{Fault, FaultInjecting, Op, Rule};
+    use std::time::Duration;
+
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(FaultInjecting::new(
+            Box::new(lib::backends::memory::Memory::new()),
+            &READ_CACHE_FAULTS,
+        )),
+        Some(4),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    aio.set_read_cache(Some(250));
+    let stats = aio.stats();
+    let write = |path: &str, data: Vec<u8>| {
+        aio.write(PathBuf::from(path), lib::SGData::from_single(data))
+            .wait()
+            .unwrap()
+    };
+    let read = |path: &str| {
+        aio.read(PathBuf::from(path))
+            .wait()
+            .unwrap()
+            .to_linear_vec()
+    };
+    for path in &["a", "b", "c"] {
+        write(path, rand_data(100));
+    }
+
+    let a = read("a");
+    assert_eq!(read("a"), a);
+    assert_eq!(read("./a"), a);
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.read.chunks_read, 1);
+    assert_eq!(snapshot.read.cached_reads, 2);
+
+    // `b` was read the longest ago when `c` doesn't fit
+    read("b");
+    read("a");
+    read("c");
+    let before = stats.snapshot();
+    read("a");
+    read("b");
+    let delta = stats.snapshot().since(&before);
+    assert_eq!(delta.read.chunks_read, 1);
+    assert_eq!(delta.read.cached_reads, 1);
+
+    // writes drop the stale data
+    let new_a = rand_data(100);
+    write("a", new_a.clone());
+    assert_eq!(read("a"), new_a);
+
+    // concurrent reads of the same path share a single backend read
+    write("slow", rand_data(10));
+    READ_CACHE_FAULTS.add(
+        Rule::new(Op::Read, Fault::Delay(Duration::from_millis(200)))
+            .path("slow"),
+    );
+    let reads: Vec<_> =
+        (0..3).map(|_| aio.read(PathBuf::from("slow"))).collect();
+    for res in reads {
+        assert_eq!(res.wait().unwrap().len(), 10);
+    }
+    assert_eq!(READ_CACHE_FAULTS.injected(), 1);
+    READ_CACHE_FAULTS.clear();
+}
+
+static IN_PROGRESS_FAULTS: lib::backends::fault::Faults =
+    lib::backends::fault::Faults::new();
+
+#[test]
+fn test_aio_in_progress_wakeup() {
+    use lib::backends::fault::{Fault, FaultInjecting, Op, Rule};
+    use std::time::{Duration, Instant};
+
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(FaultInjecting::new(
+            Box::new(lib::backends::memory::Memory::new()),
+            &IN_PROGRESS_FAULTS,
+        )),
+        Some(4),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    let path = PathBuf::from("slow");
+    aio.write(path.clone(), lib::SGData::from_single(vec![1]))
+        .wait()
+        .unwrap();
+    IN_PROGRESS_FAULTS
+        .add(Rule::new(Op::Any, Fault::Delay(Duration::from_millis(50))));
+
+    // operations on the same path wait for each other, but start as soon
+    // as the previous one is done
+    let start = Instant::now();
+    let reads: Vec<_> = (0..3).map(|_| aio.read(path.clone())).collect();
+    let write = aio.write(path.clone(), lib::SGData::from_single(vec![2]));
+    for read in reads {
+        read.wait().unwrap();
+    }
+    write.wait().unwrap();
+    assert_eq!(IN_PROGRESS_FAULTS.injected(), 4);
+    assert!(start.elapsed() < Duration::from_millis(900));
+    IN_PROGRESS_FAULTS.clear();
+
+    assert_eq!(aio.read(path).wait().unwrap().to_linear_vec(), vec![2]);
+}
+
+#[test]
+fn test_write_dry_run() {
+    let mut repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    // repeated, so some chunks deduplicate within the data
+    let part = rand_data(4 * 1024 * 1024);
+    let data = [&part[..], &part[..]].concat();
+
+    repo.set_dry_run(true);
+    let dry = repo
+        .write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert!(dry.new_chunks > 0);
+    assert!(dry.deduped_chunks > 0);
+    assert!(repo.list_names().unwrap().is_empty());
+    assert!(list_stored_chunks(&repo).unwrap().is_empty());
+    assert!(repo.read_generations().unwrap().is_empty());
+
+    repo.set_dry_run(false);
+    let stats = repo
+        .write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert_eq!(stats.new_chunks, dry.new_chunks);
+    assert_eq!(stats.new_bytes, dry.new_bytes);
+
+    // only the new data would be stored
+    repo.set_dry_run(true);
+    let more = [&data[..], &rand_data(1024)[..]].concat();
+    let dry = repo
+        .write("more", &mut io::Cursor::new(&more), &enc_handle)
+        .unwrap();
+    assert!(dry.new_chunks < stats.new_chunks);
+    assert_eq!(repo.list_names().unwrap(), vec!["data".to_string()]);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_gc_results() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+
+    assert_eq!(repo.gc(0).unwrap(), lib::GcResults::default());
+
+    for name in &["kept", "removed"] {
+        repo.write(
+            name,
+            &mut io::Cursor::new(rand_data(1024 * 1024)),
+            &enc_handle,
+        )
+        .unwrap();
+    }
+    let stored = list_stored_chunks(&repo).unwrap().len();
+    repo.rm("removed").unwrap();
+
+    let results = repo.gc(0).unwrap();
+    assert_eq!(
+        results.chunks_removed,
+        stored - list_stored_chunks(&repo).unwrap().len()
+    );
+    assert!(results.chunks_removed > 0);
+    assert!(results.bytes_freed > 1024 * 1024);
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_aio_write_durable() {
+    use lib::backends::DurabilityMode;
+
+    let dir = rand_tmp_dir();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+
+    for (i, &mode) in [
+        DurabilityMode::None,
+        DurabilityMode::FsyncFile,
+        DurabilityMode::FsyncFileAndDir,
+    ]
+    .iter()
+    .enumerate()
+    {
+        let path = PathBuf::from(format!("dir-{}/obj", i));
+        let data = rand_data(1000);
+        aio.write_durable(
+            path.clone(),
+            lib::SGData::from_single(data.clone()),
+            mode,
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(aio.read(path).wait().unwrap().to_linear_vec(), data);
+    }
+
+    drop(aio);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_digest_mismatch_error() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.set_encryption(settings::Encryption::None).unwrap();
+    settings
+        .set_compression(settings::Compression::None)
+        .unwrap();
+    let dir = rand_tmp_dir();
+    let repo = lib::Repo::init(
+        &Url::from_file_path(&dir).unwrap(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    )
+    .unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    repo.write("data", &mut io::Cursor::new(rand_data(1024)), &enc_handle)
+        .unwrap();
+
+    let chunk_paths: Vec<_> = walkdir::WalkDir::new(&dir)
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| {
+            path.is_file()
+                && path.components().any(|c| c.as_os_str() == "chunk")
+        })
+        .collect();
+    // past the header telling the compression of data chunks
+    for path in &chunk_paths {
+        let mut chunk = fs::read(path).unwrap();
+        *chunk.last_mut().unwrap() ^= 1;
+        fs::write(path, chunk).unwrap();
+    }
+
+    let err = repo.read("data", &mut vec![], &dec_handle).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let (expected, found) = match err {
+        lib::Error::DigestMismatch { expected, found } => (expected, found),
+        err => panic!("not a digest mismatch: {}", err),
+    };
+    assert_ne!(expected, found);
+    assert!(chunk_paths.iter().any(|path| {
+        path.file_name().unwrap().to_str().unwrap() == hex::encode(&expected)
+    }));
+
+    // other errors of the same kind are not mistaken for it
+    let other = io::Error::new(io::ErrorKind::InvalidData, "other");
+    assert!(matches!(lib::Error::from(other), lib::Error::Io(_)));
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_aio_progress() {
+    use lib::ProgressEvent;
+    use std::sync::{Arc, Mutex};
+
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::backends::memory::Memory::new()),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+    let events = Arc::new(Mutex::new(vec![]));
+    let events_ = events.clone();
+    aio.set_progress(Some(Arc::new(move |event| {
+        events_.lock().unwrap().push(event)
+    })));
+
+    let path = PathBuf::from("dir/a");
+    aio.write(path.clone(), lib::SGData::from_single(rand_data(10)))
+        .wait()
+        .unwrap();
+    aio.write_idempotent(path.clone(), lib::SGData::from_single(rand_data(10)))
+        .wait()
+        .unwrap();
+    aio.read(path).wait().unwrap();
+    assert_eq!(aio.list_recursively(PathBuf::from("dir")).count(), 1);
+    assert!(aio.read(PathBuf::from("missing")).wait().is_err());
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            ProgressEvent::ChunkWritten {
+                bytes: 10,
+                deduplicated: false
+            },
+            ProgressEvent::ChunkWritten {
+                bytes: 10,
+                deduplicated: true
+            },
+            ProgressEvent::ChunkRead { bytes: 10 },
+            ProgressEvent::ListBatch { count: 1 },
+        ]
+    );
+
+    aio.set_progress(None);
+    aio.read(PathBuf::from("dir/a")).wait().unwrap();
+    assert_eq!(events.lock().unwrap().len(), 4);
+
+    let mut repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    events.lock().unwrap().clear();
+    let events_ = events.clone();
+    repo.set_progress(Some(Arc::new(move |event| {
+        events_.lock().unwrap().push(event)
+    })));
+    // kept across changes of the I/O threads
+    repo.set_io_thread_num(Some(2)).unwrap();
+    let data = rand_data(1024 * 1024);
+    let stats = repo
+        .write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let written: u64 = events
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|event| match *event {
+            ProgressEvent::ChunkWritten { bytes, .. } => bytes,
+            _ => 0,
+        })
+        .sum();
+    assert!(stats.new_bytes > 0);
+    // and the name, and alike
+    assert!(written >= stats.new_bytes);
+
+    events.lock().unwrap().clear();
+    repo.read("data", &mut vec![], &dec_handle).unwrap();
+    assert!(events
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|event| matches!(event, ProgressEvent::ChunkRead { .. })));
+
+    repo.set_progress(None);
+    wipe(&repo);
+}
+
+#[test]
+fn test_local_write_many_parts() {
+    let dir = rand_tmp_dir();
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::aio::Local::new(dir.clone())),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+
+    // More parts than a single vectored write takes, some of them empty
+    let parts: Vec<Vec<u8>> =
+        (0..3000).map(|i| rand_data(i % 7 * 100)).collect();
+    let data: Vec<u8> = parts.concat();
+    aio.write(PathBuf::from("a"), sgdata::SGData::from_many(parts))
+        .wait()
+        .unwrap();
+
+    let read = aio.read(PathBuf::from("a")).wait().unwrap();
+    assert_eq!(read.to_linear_vec(), data);
+    drop(aio);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_repo_version_unsupported() {
+    let (repo, dir) = test_repo_dir(PASS);
+    drop(repo);
+
+    let config_path = dir.join(lib::config::CONFIG_YML_FILE);
+    let orig = fs::read_to_string(&config_path).unwrap();
+    for &version in &[lib::config::REPO_VERSION_CURRENT + 1, 2] {
+        let mut config: serde_yaml::Mapping =
+            serde_yaml::from_str(&orig).unwrap();
+        config.insert("version".into(), version.into());
+        let config = serde_yaml::to_string(&config).unwrap();
+        fs::write(&config_path, config).unwrap();
+
+        let err = lib::Repo::open(&Url::from_file_path(&dir).unwrap(), None)
+            .err()
+            .expect("repo of unsupported version opened");
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    fs::write(&config_path, orig).unwrap();
+    let repo =
+        lib::Repo::open(&Url::from_file_path(&dir).unwrap(), None).unwrap();
+    wipe(&repo);
+}
+
+#[test]
+fn test_aio_lock_timeout() {
+    let dir = rand_tmp_dir();
+    fs::create_dir_all(&dir).unwrap();
+    let new_aio = || {
+        lib::aio::AsyncIO::new(
+            Box::new(lib::aio::Local::new(dir.clone())),
+            None,
+            slog::Logger::root(slog::Discard, slog::o!()),
+        )
+        .unwrap()
+    };
+    let (aio, other) = (new_aio(), new_aio());
+    let timeout = std::time::Duration::from_millis(50);
+
+    let lock = aio.lock_shared().unwrap();
+    drop(other.lock_shared_timeout(timeout).unwrap());
+    let err = other.lock_exclusive_timeout(timeout).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    let locked = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<lib::backends::Locked>())
+        .unwrap();
+    let owner = locked.owner.as_ref().unwrap();
+    assert_eq!(owner.pid, std::process::id());
+    assert!(owner.time <= chrono::Utc::now());
+
+    drop(lock);
+    let lock = other.lock_exclusive_timeout(timeout).unwrap();
+    assert!(aio.lock_shared_timeout(timeout).is_err());
+    drop(lock);
+
+    drop((aio, other));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_repo_lock_timeout() {
+    let (mut repo, dir) = test_repo_dir(PASS);
+    let other =
+        lib::Repo::open(&Url::from_file_path(&dir).unwrap(), None).unwrap();
+    repo.set_lock_timeout(Some(std::time::Duration::from_millis(50)));
+
+    let lock = other.aio.lock_exclusive().unwrap();
+    let err = repo.list_names().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    match err {
+        lib::Error::Locked { owner } => {
+            assert_eq!(owner.unwrap().pid, std::process::id())
+        }
+        err => panic!("not a lock timeout: {}", err),
+    }
+    drop(lock);
+    assert!(repo.list_names().unwrap().is_empty());
+
+    wipe(&repo);
+}
+
+#[test]
+fn test_local_lock_owner() {
+    use lib::backends::Backend;
+
+    let dir = rand_tmp_dir();
+    fs::create_dir_all(&dir).unwrap();
+    let local = lib::aio::Local::new(dir.clone());
+    assert_eq!(local.lock_owner().unwrap(), None);
+
+    let before = chrono::Utc::now();
+    let lock = local.lock_exclusive().unwrap();
+    let owner = local.lock_owner().unwrap().unwrap();
+    assert_eq!(owner.pid, std::process::id());
+    assert!(owner.hostname.is_some());
+    assert!(before <= owner.time && owner.time <= chrono::Utc::now());
+    assert!(local.try_lock_shared().unwrap().is_none());
+    drop(lock);
+    assert_eq!(local.lock_owner().unwrap(), None);
+
+    // every holder of a shared lock has a record, the first one is told
+    let first = local.lock_shared().unwrap();
+    let first_owner = local.lock_owner().unwrap().unwrap();
+    let second = local.try_lock_shared().unwrap().unwrap();
+    assert_eq!(local.lock_owner().unwrap().unwrap(), first_owner);
+    drop(first);
+    assert!(local.lock_owner().unwrap().unwrap().time >= first_owner.time);
+    drop(second);
+    assert_eq!(local.lock_owner().unwrap(), None);
+
+    // records that can't be read, or of holders that died, don't tell
+    let owners_dir = dir.join(lib::config::LOCK_OWNERS_DIR);
+    fs::write(owners_dir.join("garbage"), "").unwrap();
+    #[cfg(unix)]
+    {
+        let dead = lib::backends::LockInfo {
+            pid: i32::MAX as u32,
+            ..first_owner
+        };
+        fs::write(
+            owners_dir.join(format!("{}.0", dead.pid)),
+            serde_yaml::to_string(&dead).unwrap(),
+        )
+        .unwrap();
+    }
+    assert_eq!(local.lock_owner().unwrap(), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_aio_read_metadata_batch() {
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::backends::memory::Memory::new()),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+
+    for &(path, len) in &[("a", 10), ("b/c", 1024), ("d", 0)] {
+        aio.write(path.into(), sgdata::SGData::from_single(rand_data(len)))
+            .wait()
+            .unwrap();
+    }
+
+    let paths = ["d", "missing", "b/c", "a"];
+    let metadata = aio
+        .read_metadata_batch(paths.iter().map(PathBuf::from).collect())
+        .wait()
+        .unwrap();
+    assert_eq!(metadata.len(), paths.len());
+    let lens: Vec<_> = metadata
+        .iter()
+        .map(|m| m.as_ref().map(|m| m.len).map_err(|e| e.kind()))
+        .collect();
+    assert_eq!(
+        lens,
+        vec![Ok(0), Err(io::ErrorKind::NotFound), Ok(1024), Ok(10)]
+    );
+
+    assert!(aio.read_metadata_batch(vec![]).wait().unwrap().is_empty());
+}
+
+#[test]
+fn test_aio_exists() {
+    let aio = lib::aio::AsyncIO::new(
+        Box::new(lib::backends::memory::Memory::new()),
+        None,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .unwrap();
+
+    aio.write("a/b".into(), sgdata::SGData::from_single(rand_data(10)))
+        .wait()
+        .unwrap();
+    assert!(aio.exists("a/b".into()).wait().unwrap());
+    assert!(!aio.exists("a/c".into()).wait().unwrap());
+
+    aio.remove("a/b".into()).wait().unwrap();
+    assert!(!aio.exists("a/b".into()).wait().unwrap());
+}
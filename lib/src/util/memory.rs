@@ -0,0 +1,67 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Default)]
+struct MemoryUse {
+    current: u64,
+    peak: u64,
+}
+
+/// Accounting of the bytes held in buffers by a pipeline
+///
+/// With a cap, `acquire` blocks while the buffered bytes would exceed it.
+/// The cap is soft: when nothing is buffered, any amount is let through,
+/// so a buffer larger than the cap doesn't block forever.
+pub struct MemoryBudget {
+    cap: Option<u64>,
+    usage: Mutex<MemoryUse>,
+    cond: Condvar,
+}
+
+impl MemoryBudget {
+    pub fn new(cap: Option<u64>) -> Arc<Self> {
+        Arc::new(MemoryBudget {
+            cap,
+            usage: Mutex::new(MemoryUse::default()),
+            cond: Condvar::new(),
+        })
+    }
+
+    /// Account `bytes` until the permit is dropped, waiting for them to fit
+    /// under the cap first
+    pub fn acquire(self: &Arc<Self>, bytes: u64) -> MemoryPermit {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(cap) = self.cap {
+            while usage.current > 0 && usage.current + bytes > cap {
+                usage = self.cond.wait(usage).unwrap();
+            }
+        }
+        usage.current += bytes;
+        usage.peak = usage.peak.max(usage.current);
+
+        MemoryPermit {
+            budget: Arc::clone(self),
+            bytes,
+        }
+    }
+
+    /// Most bytes buffered at once so far
+    pub fn peak(&self) -> u64 {
+        self.usage.lock().unwrap().peak
+    }
+}
+
+/// Bytes of a buffer accounted in a `MemoryBudget`
+///
+/// Moves along with the buffer between the stages of the pipeline, and
+/// releases the bytes when dropped.
+pub struct MemoryPermit {
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl Drop for MemoryPermit {
+    fn drop(&mut self) {
+        self.budget.usage.lock().unwrap().current -= self.bytes;
+        self.budget.cond.notify_all();
+    }
+}
@@ -9,6 +9,12 @@ pub(crate) use self::whileok::*;
 mod readerveciter;
 pub(crate) use self::readerveciter::*;
 
+mod semaphore;
+pub(crate) use self::semaphore::*;
+
+mod memory;
+pub(crate) use self::memory::*;
+
 /// Writer that counts how many bytes were written to it
 pub struct CounterWriter {
     pub count: u64,
@@ -31,12 +37,66 @@ impl io::Write for CounterWriter {
     }
 }
 
+/// Writer passing the data through to `inner`, counting the bytes
+pub struct CountingWriter<W> {
+    inner: W,
+    pub count: u64,
+}
+
+impl<W> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+}
+
+impl<W: io::Write> io::Write for CountingWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(bytes)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer dropping the first `skip` bytes, and passing the rest through
+/// to `inner`
+pub struct SkippingWriter<W> {
+    inner: W,
+    /// Bytes left to drop
+    pub skip: u64,
+}
+
+impl<W> SkippingWriter<W> {
+    pub fn new(inner: W, skip: u64) -> Self {
+        SkippingWriter { inner, skip }
+    }
+}
+
+impl<W: io::Write> io::Write for SkippingWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.skip >= bytes.len() as u64 {
+            self.skip -= bytes.len() as u64;
+            return Ok(bytes.len());
+        }
+        let skipped = self.skip as usize;
+        self.skip = 0;
+        Ok(skipped + self.inner.write(&bytes[skipped..])?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// Substitute Err(NotFound) with something else
 ///
 /// Many places in the code ignore `NotFound`, so this function makes it
 /// convenient.
 pub(crate) fn substitute_err_not_found<T, F>(
-    res: io::Result<T>,
+    res: crate::Result<T>,
     f: F,
 ) -> io::Result<T>
 where
@@ -44,7 +104,7 @@ where
 {
     match res {
         Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(f()),
-        res => res,
+        res => Ok(res?),
     }
 }
 
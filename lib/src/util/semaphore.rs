@@ -0,0 +1,39 @@
+use std::sync::{Condvar, Mutex};
+
+/// Counting semaphore limiting how many threads do something at once
+pub struct Semaphore {
+    available: Mutex<usize>,
+    cond: Condvar,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        assert!(permits > 0);
+        Semaphore {
+            available: Mutex::new(permits),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Block until a permit is available, and take it until the guard
+    /// is dropped
+    pub fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.cond.wait(available).unwrap();
+        }
+        *available -= 1;
+        SemaphoreGuard { semaphore: self }
+    }
+}
+
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.cond.notify_one();
+    }
+}
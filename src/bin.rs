@@ -88,7 +88,13 @@
 //! * `rdedup load <name>` - load data stored under given *name* and write it
 //!   to standard output.
 //! * `rdedup rm <name>` - remove the given *name*.
-//! * `rdedup ls` - list all stored names.
+//! * `rdedup pin <name>` / `rdedup unpin <name>` - protect the given *name*
+//!   from removal, or lift the protection.
+//! * `rdedup tag <tag> <name>` / `rdedup untag <tag> <name>` - label the
+//!   given *name*, or remove the label.
+//! * `rdedup ls` - list all stored names (`--tag <tag>` to list only the
+//!   tagged ones).
+//! * `rdedup prune --tag <tag>` - remove all *names* with a given tag.
 //! * `rdedup gc` - remove any no longer reachable data.
 //!
 //!
@@ -120,6 +126,8 @@
 //! [ddar-issue]: https://github.com/basak/ddar/issues/10
 
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{env, io, path::PathBuf, process};
 
 use clap::Clap;
@@ -146,6 +154,7 @@ struct Options {
     url: Url,
     debug_level: u32,
     settings: settings::Repo,
+    lock_timeout: Option<Duration>,
 }
 
 impl Options {
@@ -154,9 +163,16 @@ impl Options {
             url,
             debug_level: 0,
             settings: settings::Repo::new(),
+            lock_timeout: None,
         }
     }
 
+    fn open_repo(&self, log: slog::Logger) -> io::Result<Repo> {
+        let mut repo = Repo::open(&self.url, log)?;
+        repo.set_lock_timeout(self.lock_timeout);
+        Ok(repo)
+    }
+
     fn set_encryption(&mut self, s: &str) {
         let encryption = match s {
             "curve25519" => lib::settings::Encryption::Curve25519,
@@ -208,6 +224,10 @@ impl Options {
                 .settings
                 .use_fastcdc_chunking(chunk_size)
                 .expect("wrong chunking settings"),
+            "fixed" => self
+                .settings
+                .use_fixed_chunking(chunk_size)
+                .expect("wrong chunking settings"),
             _ => {
                 eprintln!("unsupported encryption: {}", s);
                 process::exit(-1);
@@ -215,6 +235,12 @@ impl Options {
         };
     }
 
+    fn set_small_tail_merge(&mut self, min_size: Option<u64>) {
+        self.settings
+            .set_small_tail_merge(min_size)
+            .expect("wrong small tail merge settings");
+    }
+
     fn set_hashing(&mut self, s: &str) {
         match s {
             "sha256" => self
@@ -337,6 +363,14 @@ struct CliOpts {
     /// Increase debugging level for timings
     verbose_timings: u8,
 
+    #[clap(long, value_name = "SECONDS")]
+    /// Print a line with the progress of store, load, fsck and reencrypt to the standard error every SECONDS
+    progress: Option<f64>,
+
+    #[clap(long, value_name = "SECONDS")]
+    /// Give up waiting for the lock of the repository after SECONDS, telling who holds it
+    lock_timeout: Option<f64>,
+
     #[clap(subcommand)]
     command: Command,
 }
@@ -349,7 +383,7 @@ enum Command {
     Init {
         #[clap(
             long,
-            possible_values = &["bup", "gear", "fastcdc"],
+            possible_values = &["bup", "gear", "fastcdc", "fixed"],
             default_value = "fastcdc",
             value_name = "SCHEME",
         )]
@@ -365,6 +399,14 @@ enum Command {
         /// Set average chunk size
         chunk_size: String,
 
+        #[clap(long, value_name = "BITS")]
+        /// Require a secondary condition on BITS of the chunk size bits when looking for chunk edges
+        secondary_chunk_bits: Option<u32>,
+
+        #[clap(long, validator = validate_chunk_size, value_name = "N")]
+        /// Merge the final chunk of a stream with the previous one if it's smaller than N
+        small_tail_merge: Option<String>,
+
         #[clap(
             long,
             possible_values = &["deflate", "xz2", "zstd", "bzip2", "none"],
@@ -408,6 +450,14 @@ enum Command {
         )]
         /// Set pwhash strength
         pwhash: String,
+
+        #[clap(long)]
+        /// Keep previous versions of names stored to again, instead of refusing to overwrite them
+        name_versioning: bool,
+
+        #[clap(long)]
+        /// Refuse operations removing or replacing data (rm, rename --force, prune, prune-versions, gc, reencrypt, repair)
+        safe_mode: bool,
     },
 
     /// Store data to repository
@@ -415,6 +465,45 @@ enum Command {
         #[clap(name = "NAME")]
         /// Name to store to
         name: String,
+        #[clap(long, value_name = "PATH")]
+        /// Store the file at PATH instead of the standard input
+        file: Option<PathBuf>,
+        #[clap(long, requires = "file", value_name = "PATH")]
+        /// Reuse chunks of the file if unchanged since it was stored using the cache at PATH
+        chunk_cache: Option<PathBuf>,
+        #[clap(long, value_name = "N")]
+        /// Fail instead of storing data that is split into more than N chunks
+        max_chunks: Option<u64>,
+        #[clap(long, value_name = "N")]
+        /// Check if at most N chunks are already stored at once
+        max_probes: Option<usize>,
+        #[clap(long, validator = validate_chunk_size, value_name = "N")]
+        /// Hold at most N bytes of chunks not yet stored at once
+        max_buffered: Option<String>,
+        #[clap(long, value_name = "N")]
+        /// Store uncompressed unless compressing the first N chunks pays off
+        sample_compression: Option<usize>,
+        #[clap(long, validator = validate_chunk_size, value_name = "N")]
+        /// Write at most N bytes per second to the repository
+        max_rate: Option<String>,
+        #[clap(long)]
+        /// Only count the new chunks and bytes that would be stored, without storing anything
+        dry_run: bool,
+        #[clap(long, value_name = "FRACTION")]
+        /// Warn if the data deduplicates worse than FRACTION of the names stored last
+        min_dedup: Option<f64>,
+        #[clap(long, value_name = "N", requires = "min-dedup")]
+        /// Number of names stored last to compare the deduplication with [default: 5]
+        dedup_baseline: Option<usize>,
+        #[clap(long, requires = "min-dedup")]
+        /// Fail instead of warning if the data deduplicates worse
+        abort_low_dedup: bool,
+        #[clap(long, value_name = "SECONDS")]
+        /// Refuse to store a new version over a name stored less than SECONDS ago
+        protect: Option<u64>,
+        #[clap(long, validator = validate_chunk_size, value_name = "N")]
+        /// Fail before storing anything if less than N bytes of space is available to the repository
+        min_space: Option<String>,
     },
 
     /// Load data from repository
@@ -422,11 +511,140 @@ enum Command {
         #[clap(name = "NAME")]
         /// Name to load from
         name: String,
+        #[clap(long = "lenient")]
+        /// Replace missing or corrupted data chunks with zeros, and report
+        /// them, instead of failing
+        lenient: bool,
+        #[clap(long, value_name = "N", conflicts_with = "lenient")]
+        /// Load a given version of the name instead of the current one
+        version: Option<u64>,
+        #[clap(long, value_name = "PATH")]
+        /// Write the data to a new file at PATH instead of the standard output
+        file: Option<PathBuf>,
+        #[clap(long, requires = "file")]
+        /// Leave holes in the file where the data is zeros, instead of writing them
+        sparse: bool,
+        #[clap(
+            long,
+            requires = "file",
+            conflicts_with_all = &["lenient", "version", "sparse"]
+        )]
+        /// Continue an interrupted load to the file at PATH, from its length
+        resume: bool,
+        #[clap(long, validator = validate_chunk_size, value_name = "N")]
+        /// Keep up to N bytes of the chunks read last in memory, to read
+        /// repeated chunks only once
+        read_cache: Option<String>,
+    },
+
+    /// Write a name with all its data as a single archive to the standard output
+    ///
+    /// The archive is not encrypted.
+    Export {
+        #[clap(name = "NAME")]
+        /// Name to export
+        name: String,
+    },
+
+    /// Store an archive created by `export` read from the standard input
+    Import {
+        #[clap(name = "NAME")]
+        /// Name to store to
+        name: String,
+    },
+
+    /// Load data at a root address (see `root`) from repository
+    LoadRoot {
+        #[clap(name = "ADDRESS")]
+        /// Root address to load from
+        address: lib::RootAddress,
+    },
+
+    /// Print the root address of a name, to load it even if the name is lost
+    Root {
+        #[clap(name = "NAME")]
+        /// Name to print the root address of
+        name: String,
+    },
+
+    /// Find root addresses of all the data in the repository, by reading all of it
+    ScanRoots {
+        #[clap(long, value_name = "PREFIX")]
+        /// Store roots no name refers to as PREFIX<digest>
+        recover: Option<String>,
+    },
+
+    /// List versions of a name stored in the repository
+    Versions {
+        #[clap(name = "NAME")]
+        /// Name to list versions of
+        name: String,
+    },
+
+    /// Remove old versions of names stored in the repository
+    PruneVersions {
+        #[clap(long, default_value = "0", value_name = "N")]
+        /// Number of previous versions to keep
+        keep: usize,
+        #[clap(name = "NAME", required = true)]
+        /// Names to prune
+        names: Vec<String>,
+    },
+
+    /// Enable or disable safe mode, refusing operations removing or replacing data
+    SafeMode {
+        #[clap(possible_values = &["on", "off"])]
+        /// New state
+        state: String,
     },
 
     #[clap(visible_alias = "ls")]
     /// List names stored in the repository
-    List,
+    List {
+        #[clap(long = "tag", value_name = "TAG", number_of_values = 1)]
+        /// List only names with the tag
+        tagged: Vec<String>,
+        #[clap(long = "not-tag", value_name = "TAG", number_of_values = 1)]
+        /// List only names without the tag
+        not_tagged: Vec<String>,
+    },
+
+    /// Remove all names selected by their tags (pinned names are kept)
+    Prune {
+        #[clap(long = "tag", value_name = "TAG", number_of_values = 1)]
+        /// Remove only names with the tag
+        tagged: Vec<String>,
+        #[clap(long = "not-tag", value_name = "TAG", number_of_values = 1)]
+        /// Remove only names without the tag
+        not_tagged: Vec<String>,
+    },
+
+    /// Add a tag to names
+    Tag {
+        #[clap(name = "TAG")]
+        /// Tag to add
+        tag: String,
+        #[clap(name = "NAME", required = true)]
+        /// Names to tag
+        names: Vec<String>,
+    },
+
+    /// Remove a tag from names
+    Untag {
+        #[clap(name = "TAG")]
+        /// Tag to remove
+        tag: String,
+        #[clap(name = "NAME", required = true)]
+        /// Names to untag
+        names: Vec<String>,
+    },
+
+    /// List tags of a name
+    Tags {
+        #[clap(name = "NAME")]
+        /// Name to list tags of
+        name: String,
+    },
 
     #[clap(visible_alias = "rm")]
     /// Remove names stored in the repository
@@ -436,10 +654,46 @@ enum Command {
         names: Vec<String>,
     },
 
+    #[clap(visible_alias = "mv")]
+    /// Rename a name stored in the repository
+    Rename {
+        #[clap(long, short)]
+        /// Replace NEW_NAME if it exists already
+        force: bool,
+        #[clap(name = "NAME")]
+        /// Name to rename
+        name: String,
+        #[clap(name = "NEW_NAME")]
+        /// New name
+        new_name: String,
+    },
+
+    /// Pin names, protecting them from removal
+    Pin {
+        #[clap(name = "NAME", required = true)]
+        /// Names to pin
+        names: Vec<String>,
+    },
+
+    /// Unpin names, allowing them to be removed again
+    Unpin {
+        #[clap(name = "NAME", required = true)]
+        /// Names to unpin
+        names: Vec<String>,
+    },
+
     #[clap(name = "change_passphrase", visible_alias = "chpasswd")]
     /// Change the passphrase protecting the encryption key (if any)
     ChangePassphrase,
 
+    /// Re-encrypt all data under a new encryption key
+    ///
+    /// Resumes the previous run, if it was interrupted.
+    Reencrypt,
+
+    /// Show the total and available space of the storage of the repository
+    Space,
+
     /// Calculate disk usage due to the data stored for a set of names
     Du {
         #[clap(name = "NAME", required = true)]
@@ -460,10 +714,140 @@ enum Command {
 
     /// Verify integrity of data stored in the repository
     Verify {
+        #[clap(long)]
+        /// Only check that data chunks are stored with the expected length,
+        /// without reading them
+        quick: bool,
         #[clap(name = "NAME", required = true)]
         /// Names to verify
         names: Vec<String>,
     },
+
+    /// Verify every chunk stored in the repository, used by a name or not
+    Fsck,
+
+    /// Replace corrupted chunks of names with their copies in a mirror
+    Repair {
+        #[clap(long, value_name = "URI")]
+        /// Repository copy to take intact chunks from
+        mirror: String,
+        #[clap(name = "NAME", required = true)]
+        /// Names to repair
+        names: Vec<String>,
+    },
+}
+
+fn store<R: io::Read + Send>(
+    repo: &Repo,
+    name: &str,
+    reader: R,
+    total: Option<u64>,
+    enc: &lib::EncryptHandle,
+    progress: Option<Duration>,
+) -> io::Result<lib::WriteStats> {
+    match progress {
+        Some(interval) => {
+            let mut reader = lib::Progress::new(
+                reader,
+                io::stderr(),
+                "store",
+                total,
+                interval,
+            );
+            let stats = repo.write(name, &mut reader, enc)?;
+            reader.finish()?;
+            Ok(stats)
+        }
+        None => Ok(repo.write(name, reader, enc)?),
+    }
+}
+
+/// Print a line with the chunks read and written by `repo` to the
+/// standard error, at most once per `interval`
+///
+/// ```text
+/// progress <operation> <chunks> <bytes>
+/// ```
+fn report_chunks(repo: &mut Repo, operation: &str, interval: Duration) {
+    let operation = operation.to_owned();
+    // last report, chunks, bytes
+    let state = Mutex::new((Instant::now(), 0u64, 0u64));
+    repo.set_progress(Some(Arc::new(move |event| {
+        let bytes = match event {
+            lib::ProgressEvent::ChunkRead { bytes }
+            | lib::ProgressEvent::ChunkWritten { bytes, .. } => bytes,
+            lib::ProgressEvent::ListBatch { .. } => return,
+        };
+        let mut state = state.lock().unwrap();
+        state.1 += 1;
+        state.2 += bytes;
+        if state.0.elapsed() >= interval {
+            state.0 = Instant::now();
+            eprintln!("progress {} {} {}", operation, state.1, state.2);
+        }
+    })));
+}
+
+/// Which data of a name `load` reads (the options are exclusive)
+#[derive(Clone, Copy)]
+enum LoadMode {
+    Current,
+    Lenient,
+    Version(u64),
+    /// Only the data from the offset on
+    From(u64),
+}
+
+fn load<W: io::Write>(
+    repo: &Repo,
+    name: &str,
+    mode: LoadMode,
+    out: &mut W,
+    dec: &lib::DecryptHandle,
+) -> io::Result<()> {
+    match mode {
+        LoadMode::Current => repo.read(name, out, dec)?,
+        LoadMode::Lenient => {
+            let report = repo.read_lenient(name, out, dec)?;
+            for gap in report.gaps {
+                eprintln!(
+                    "missing {} bytes at offset {} (chunk {}) - {}",
+                    gap.len,
+                    gap.offset,
+                    hex::encode(&gap.digest),
+                    gap.error
+                );
+            }
+        }
+        LoadMode::Version(version) => {
+            repo.read_version(name, version, out, dec)?
+        }
+        LoadMode::From(offset) => repo.read_from(name, offset, out, dec)?,
+    }
+    Ok(())
+}
+
+/// `load` to `out`, and return it
+fn load_to<W: io::Write>(
+    repo: &Repo,
+    name: &str,
+    mode: LoadMode,
+    mut out: W,
+    dec: &lib::DecryptHandle,
+    progress: Option<Duration>,
+) -> io::Result<W> {
+    match progress {
+        Some(interval) => {
+            let mut out =
+                lib::Progress::new(out, io::stderr(), "load", None, interval);
+            load(repo, name, mode, &mut out, dec)?;
+            Ok(out.finish()?.0)
+        }
+        None => {
+            load(repo, name, mode, &mut out, dec)?;
+            Ok(out)
+        }
+    }
 }
 
 fn run() -> io::Result<()> {
@@ -515,6 +899,21 @@ fn run() -> io::Result<()> {
 
     let mut options = Options::new(url);
 
+    let progress = match cli_opts.progress {
+        Some(secs) if !(secs > 0.0 && secs.is_finite()) => {
+            eprintln!("progress interval must be positive");
+            process::exit(-1);
+        }
+        secs => secs.map(Duration::from_secs_f64),
+    };
+    options.lock_timeout = match cli_opts.lock_timeout {
+        Some(secs) if !(secs >= 0.0 && secs.is_finite()) => {
+            eprintln!("lock timeout must not be negative");
+            process::exit(-1);
+        }
+        secs => secs.map(Duration::from_secs_f64),
+    };
+
     let log =
         create_logger(cli_opts.verbose as u32, cli_opts.verbose_timings as u32);
 
@@ -522,12 +921,16 @@ fn run() -> io::Result<()> {
         Command::Init {
             chunking,
             chunk_size,
+            secondary_chunk_bits,
+            small_tail_merge,
             encryption,
             pwhash,
             compression,
             compression_level,
             nesting,
             hashing,
+            name_versioning,
+            safe_mode,
         } => {
             let chunk_size = Some(
                 util::parse_size(&chunk_size)
@@ -535,6 +938,13 @@ fn run() -> io::Result<()> {
                     .trailing_zeros(),
             );
             options.set_chunking(&chunking, chunk_size);
+            options
+                .settings
+                .set_secondary_chunking_bits(secondary_chunk_bits)
+                .expect("wrong secondary chunking settings");
+            options.set_small_tail_merge(small_tail_merge.map(|s| {
+                util::parse_size(&s).expect("Invalid small tail merge option")
+            }));
             options.set_encryption(&encryption);
             options
                 .settings
@@ -543,6 +953,8 @@ fn run() -> io::Result<()> {
             options.settings.set_compression_level(compression_level);
             options.set_nesting(nesting);
             options.set_hashing(&hashing);
+            options.settings.set_name_versioning(name_versioning);
+            options.settings.set_safe_mode(safe_mode);
             let _ = Repo::init(
                 &options.url,
                 &|| util::read_new_passphrase(),
@@ -550,32 +962,241 @@ fn run() -> io::Result<()> {
                 log,
             )?;
         }
-        Command::Store { name } => {
-            let repo = Repo::open(&options.url, log)?;
+        Command::Store {
+            name,
+            file,
+            chunk_cache,
+            max_chunks,
+            max_probes,
+            max_buffered,
+            sample_compression,
+            max_rate,
+            dry_run,
+            min_dedup,
+            dedup_baseline,
+            abort_low_dedup,
+            protect,
+            min_space,
+        } => {
+            let mut repo = options.open_repo(log)?;
+            repo.set_dedup_check(min_dedup.map(|min_fraction| {
+                lib::DedupCheck {
+                    baseline: dedup_baseline.unwrap_or(5),
+                    min_fraction,
+                    abort: abort_low_dedup,
+                }
+            }))?;
+            repo.set_max_chunks(max_chunks)?;
+            repo.set_max_probes(max_probes)?;
+            repo.set_max_buffered(max_buffered.map(|s| {
+                util::parse_size(&s).expect("Invalid max buffered option")
+            }))?;
+            repo.set_compression_sample(sample_compression)?;
+            repo.set_write_rate(max_rate.map(|s| {
+                util::parse_size(&s).expect("Invalid max rate option")
+            }))?;
+            repo.set_dry_run(dry_run);
+            repo.set_overwrite_protection(protect.map(Duration::from_secs));
+            repo.set_min_space(min_space.map(|s| {
+                util::parse_size(&s).expect("Invalid min space option")
+            }));
             let enc = repo.unlock_encrypt(&|| util::read_passphrase())?;
-            let stats = repo.write(&name, &mut io::stdin(), &enc)?;
+            let stats = match (file, chunk_cache) {
+                (Some(file), Some(chunk_cache)) => {
+                    let mut cache = lib::ChunkCache::open(chunk_cache)?;
+                    let stats =
+                        repo.write_file(&name, &file, &enc, &mut cache)?;
+                    cache.save()?;
+                    stats
+                }
+                (Some(file), None) => {
+                    let file = std::fs::File::open(file)?;
+                    let len = file.metadata()?.len();
+                    store(&repo, &name, file, Some(len), &enc, progress)?
+                }
+                (None, _) => {
+                    store(&repo, &name, io::stdin(), None, &enc, progress)?
+                }
+            };
+            repo.close()?;
             println!("{} new chunks", stats.new_chunks);
             println!("{} new bytes", stats.new_bytes);
+            println!("{} deduplicated chunks", stats.deduped_chunks);
+            println!("{} deduplicated bytes", stats.deduped_bytes);
+            println!("{} peak buffered bytes", stats.peak_buffered);
+            if dry_run {
+                println!("dry run: would store {} new bytes", stats.new_bytes);
+            }
+            if stats.low_dedup {
+                eprintln!(
+                    "warning: data deduplicated much worse than the names \
+                     stored last"
+                );
+            }
+        }
+        Command::Load {
+            name,
+            lenient,
+            version,
+            file,
+            sparse,
+            resume,
+            read_cache,
+        } => {
+            let mut repo = options.open_repo(log)?;
+            repo.set_read_cache(read_cache.map(|s| {
+                util::parse_size(&s).expect("Invalid read cache option")
+            }))?;
+            let dec = repo.unlock_decrypt(&|| util::read_passphrase())?;
+            let mode = if lenient {
+                LoadMode::Lenient
+            } else if let Some(version) = version {
+                LoadMode::Version(version)
+            } else {
+                LoadMode::Current
+            };
+            match file {
+                Some(path) if resume => {
+                    let file = std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)?;
+                    let offset = file.metadata()?.len();
+                    load_to(
+                        &repo,
+                        &name,
+                        LoadMode::From(offset),
+                        file,
+                        &dec,
+                        progress,
+                    )?;
+                }
+                Some(path) => {
+                    let file = std::fs::File::create(path)?;
+                    if sparse {
+                        load_to(
+                            &repo,
+                            &name,
+                            mode,
+                            lib::SparseFile::new(file),
+                            &dec,
+                            progress,
+                        )?
+                        .finish()?;
+                    } else {
+                        load_to(&repo, &name, mode, file, &dec, progress)?;
+                    }
+                }
+                None => {
+                    load_to(&repo, &name, mode, io::stdout(), &dec, progress)?;
+                }
+            }
+        }
+        Command::LoadRoot { address } => {
+            let repo = options.open_repo(log)?;
+            let dec = repo.unlock_decrypt(&|| util::read_passphrase())?;
+            repo.read_root(&address, &mut io::stdout(), &dec)?;
         }
-        Command::Load { name } => {
-            let repo = Repo::open(&options.url, log)?;
+        Command::Root { name } => {
+            let repo = options.open_repo(log)?;
+            println!("{}", repo.root_address(&name)?);
+        }
+        Command::ScanRoots { recover } => {
+            let repo = options.open_repo(log)?;
+            match recover {
+                Some(prefix) => {
+                    for name in repo.recover_names(&prefix)? {
+                        println!("{}", name);
+                    }
+                }
+                None => {
+                    for root in repo.scan_roots()? {
+                        println!("{}", root);
+                    }
+                }
+            }
+        }
+        Command::Export { name } => {
+            let repo = options.open_repo(log)?;
             let dec = repo.unlock_decrypt(&|| util::read_passphrase())?;
-            repo.read(&name, &mut io::stdout(), &dec)?;
+            let mut out = io::BufWriter::new(io::stdout());
+            repo.export(&name, &mut out, &dec)?;
+            io::Write::flush(&mut out)?;
+        }
+        Command::Import { name } => {
+            let repo = options.open_repo(log)?;
+            let enc = repo.unlock_encrypt(&|| util::read_passphrase())?;
+            let stats = repo.import(&name, io::stdin(), &enc)?;
+            println!("{} new chunks", stats.new_chunks);
+            println!("{} new bytes", stats.new_bytes);
+            println!("{} deduplicated chunks", stats.deduped_chunks);
+            println!("{} deduplicated bytes", stats.deduped_bytes);
+            println!("{} peak buffered bytes", stats.peak_buffered);
+        }
+        Command::Versions { name } => {
+            let repo = options.open_repo(log)?;
+            for version in repo.list_versions(&name)? {
+                match version.created {
+                    Some(created) => {
+                        println!("{} {}", version.version, created.to_rfc3339())
+                    }
+                    None => println!("{}", version.version),
+                }
+            }
+        }
+        Command::PruneVersions { keep, names } => {
+            let repo = options.open_repo(log)?;
+            for name in names {
+                let removed = repo.prune_versions(&name, keep)?;
+                println!("{}: removed {} version(s)", name, removed);
+            }
+        }
+        Command::SafeMode { state } => {
+            let mut repo = options.open_repo(log)?;
+            repo.set_safe_mode(state == "on")?;
         }
         Command::ChangePassphrase => {
-            let mut repo = Repo::open(&options.url, log)?;
+            let mut repo = options.open_repo(log)?;
             repo.change_passphrase(&|| read_passphrase(), &|| {
                 read_new_passphrase()
             })?;
         }
+        Command::Reencrypt => {
+            let mut repo = options.open_repo(log)?;
+            if let Some(interval) = progress {
+                report_chunks(&mut repo, "reencrypt", interval);
+            }
+            let count = repo.reencrypt(&|| read_passphrase())?;
+            println!("{} chunks re-encrypted", count);
+        }
         Command::Remove { names } => {
-            let repo = Repo::open(&options.url, log)?;
+            let repo = options.open_repo(log)?;
             for name in names {
                 repo.rm(&name)?;
             }
         }
+        Command::Rename {
+            force,
+            name,
+            new_name,
+        } => {
+            let repo = options.open_repo(log)?;
+            repo.rename(&name, &new_name, force)?;
+        }
+        Command::Pin { names } => {
+            let repo = options.open_repo(log)?;
+            for name in names {
+                repo.pin(&name)?;
+            }
+        }
+        Command::Unpin { names } => {
+            let repo = options.open_repo(log)?;
+            for name in names {
+                repo.unpin(&name)?;
+            }
+        }
         Command::Du { names } => {
-            let repo = Repo::open(&options.url, log)?;
+            let repo = options.open_repo(log)?;
             let dec = repo.unlock_decrypt(&|| read_passphrase())?;
 
             for name in names {
@@ -584,23 +1205,66 @@ fn run() -> io::Result<()> {
                 println!("{} bytes", result.bytes);
             }
         }
+        Command::Space => {
+            let repo = options.open_repo(log)?;
+
+            let space = repo.space()?;
+            println!("{} bytes total", space.total);
+            println!("{} bytes available", space.available);
+        }
         Command::Gc { grace_time } => {
-            let repo = Repo::open(&options.url, log)?;
+            let repo = options.open_repo(log)?;
 
-            repo.gc(grace_time)?;
+            let results = repo.gc(grace_time)?;
+            println!("{} chunks removed", results.chunks_removed);
+            println!("{} bytes freed", results.bytes_freed);
         }
-        Command::List => {
-            let repo = Repo::open(&options.url, log)?;
+        Command::List { tagged, not_tagged } => {
+            let repo = options.open_repo(log)?;
 
-            for name in repo.list_names()? {
+            let filter = lib::TagFilter { tagged, not_tagged };
+            for name in repo.list_names_tagged(&filter)? {
                 println!("{}", name);
             }
         }
-        Command::Verify { names } => {
-            let repo = Repo::open(&options.url, log)?;
-            let dec = repo.unlock_decrypt(&|| read_passphrase())?;
+        Command::Prune { tagged, not_tagged } => {
+            let repo = options.open_repo(log)?;
+
+            let filter = lib::TagFilter { tagged, not_tagged };
+            for name in repo.prune(&filter)? {
+                println!("removed {}", name);
+            }
+        }
+        Command::Tag { tag, names } => {
+            let repo = options.open_repo(log)?;
+            for name in names {
+                repo.tag(&name, &tag)?;
+            }
+        }
+        Command::Untag { tag, names } => {
+            let repo = options.open_repo(log)?;
             for name in names {
-                let results = repo.verify(&name, &dec)?;
+                repo.untag(&name, &tag)?;
+            }
+        }
+        Command::Tags { name } => {
+            let repo = options.open_repo(log)?;
+            for tag in repo.list_tags(&name)? {
+                println!("{}", tag);
+            }
+        }
+        Command::Verify { quick, names } => {
+            let repo = options.open_repo(log)?;
+            let dec = if quick {
+                None
+            } else {
+                Some(repo.unlock_decrypt(&|| read_passphrase())?)
+            };
+            for name in names {
+                let results = match dec {
+                    Some(ref dec) => repo.verify(&name, dec)?,
+                    None => repo.verify_quick(&name)?,
+                };
                 println!("scanned {} chunk(s)", results.scanned);
                 println!("found {} corrupted chunk(s)", results.errors.len());
                 for err in results.errors {
@@ -608,14 +1272,68 @@ fn run() -> io::Result<()> {
                 }
             }
         }
+        Command::Fsck => {
+            let mut repo = options.open_repo(log)?;
+            if let Some(interval) = progress {
+                report_chunks(&mut repo, "fsck", interval);
+            }
+            let dec = repo.unlock_decrypt(&|| read_passphrase())?;
+            let results = repo.verify_stored(&dec)?;
+            println!("scanned {} chunk(s)", results.scanned);
+            println!("found {} corrupted chunk(s)", results.errors.len());
+            for err in results.errors {
+                println!("chunk {} - {}", hex::encode(&err.0), err.1);
+            }
+        }
+        Command::Repair { mirror, names } => {
+            let repo = options.open_repo(log.clone())?;
+            let mut mirror = Repo::open(&parse_url(&mirror)?, log)?;
+            mirror.set_lock_timeout(options.lock_timeout);
+            let dec = repo.unlock_decrypt(&|| read_passphrase())?;
+            for name in names {
+                let results = repo.repair(&name, &mirror, &dec)?;
+                println!("scanned {} chunk(s)", results.scanned);
+                println!("repaired {} chunk(s)", results.repaired.len());
+                for digest in results.repaired {
+                    println!("chunk {} - repaired", hex::encode(&digest));
+                }
+                println!(
+                    "found {} unrepairable chunk(s)",
+                    results.unrepairable.len()
+                );
+                for err in results.unrepairable {
+                    println!("chunk {} - {}", hex::encode(&err.0), err.1);
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
+/// Exit code of a failure with `e`
+///
+/// Lets scripts tell the common failures apart without parsing the
+/// message: 2 for something not found (like a name), 3 for corrupted
+/// data, 4 for an operation refused (like removing data in safe mode),
+/// 5 for timing out on the lock of the repository, and -1 for anything
+/// else.
+fn exit_code(e: &lib::Error) -> i32 {
+    match *e {
+        lib::Error::DigestMismatch { .. } => 3,
+        lib::Error::Locked { .. } => 5,
+        _ => match e.kind() {
+            io::ErrorKind::NotFound => 2,
+            io::ErrorKind::PermissionDenied => 4,
+            _ => -1,
+        },
+    }
+}
+
 fn main() {
     if let Err(e) = run() {
+        let e = lib::Error::from(e);
         eprintln!("Error: {}", e);
-        process::exit(-1);
+        process::exit(exit_code(&e));
     }
 }